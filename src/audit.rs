@@ -0,0 +1,109 @@
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::dirfd::Stat;
+use crate::rawpath;
+use crate::ssh::SshTransfer;
+use crate::utils;
+
+/// File name used when the audit log is kept in the destination root
+pub const DEST_AUDIT_LOG: &str = ".cpx-audit.log";
+
+#[derive(Serialize)]
+struct AuditEntry<'a> {
+    time: u64,
+    transfer_id: &'a str,
+    action: &'a str,
+    destination: &'a str,
+    #[serde(serialize_with = "rawpath::serialize")]
+    path: &'a Path,
+    old_size: Option<u64>,
+    old_mtime: Option<u64>,
+    old_hash: Option<&'a str>,
+}
+
+enum Sink {
+    Local(Mutex<File>),
+    // Appended on the destination host through the worker's own session
+    Remote(PathBuf),
+}
+
+/// Append-only JSON-lines record of every destructive action (overwrite,
+/// delete, trash) for change-control
+pub struct AuditLog {
+    transfer_id: String,
+    sink: Sink,
+}
+
+impl AuditLog {
+    pub fn local(path: &Path, transfer_id: &str) -> anyhow::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog {
+            transfer_id: transfer_id.to_string(),
+            sink: Sink::Local(Mutex::new(file)),
+        })
+    }
+
+    pub fn remote(remote_path: PathBuf, transfer_id: &str) -> Self {
+        AuditLog {
+            transfer_id: transfer_id.to_string(),
+            sink: Sink::Remote(remote_path),
+        }
+    }
+
+    /// Record that `path` under `destination` is about to be replaced.
+    /// `remote` is the session to append with when the log lives on the host.
+    pub fn overwrite(
+        &self,
+        destination: &str,
+        path: &Path,
+        old: Stat,
+        remote: Option<&SshTransfer>,
+    ) -> anyhow::Result<()> {
+        self.record("overwrite", destination, path, old, remote)
+    }
+
+    /// Record that `path` under `destination` is about to be deleted
+    pub fn delete(
+        &self,
+        destination: &str,
+        path: &Path,
+        old: Stat,
+        remote: Option<&SshTransfer>,
+    ) -> anyhow::Result<()> {
+        self.record("delete", destination, path, old, remote)
+    }
+
+    fn record(
+        &self,
+        action: &str,
+        destination: &str,
+        path: &Path,
+        old: Stat,
+        remote: Option<&SshTransfer>,
+    ) -> anyhow::Result<()> {
+        let entry = AuditEntry {
+            time: utils::now_secs(),
+            transfer_id: &self.transfer_id,
+            action,
+            destination,
+            path,
+            old_size: Some(old.size),
+            old_mtime: Some(old.mtime),
+            old_hash: None,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        match &self.sink {
+            Sink::Local(file) => file.lock().unwrap().write_all(line.as_bytes())?,
+            Sink::Remote(remote_path) => match remote {
+                Some(transfer) => transfer.append_remote(remote_path, line.as_bytes())?,
+                None => anyhow::bail!("no session to append to remote audit log {}", remote_path.display()),
+            },
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,502 @@
+// Azure Blob Storage over its REST API.
+//
+// The account and its credentials come from AZURE_STORAGE_CONNECTION_STRING
+// (as the portal shows it, or UseDevelopmentStorage=true for Azurite), else
+// from AZURE_STORAGE_ACCOUNT with AZURE_STORAGE_KEY (Shared Key),
+// AZURE_STORAGE_SAS_TOKEN, or a service principal in AZURE_TENANT_ID /
+// AZURE_CLIENT_ID / AZURE_CLIENT_SECRET, as the az CLI and the Azure SDKs
+// read them.
+
+use anyhow::{Context, Result, anyhow};
+use base64::Engine as _;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::dirfd::Stat;
+use crate::{http, utils};
+
+// Smallest block of a block blob upload; smaller files are sent with one request
+const MIN_BLOCK: u64 = 8 << 20;
+// Azure allows 50000 blocks, some are left for a file that grows while read
+const MAX_BLOCKS: u64 = 45000;
+const VERSION: &str = "2021-12-02";
+// The account and key every Azurite instance has
+const AZURITE_ACCOUNT: &str = "devstoreaccount1";
+const AZURITE_KEY: &str = "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==";
+// A token this close to expiring is replaced before use
+const TOKEN_MARGIN: u64 = 300;
+
+/// az://container/prefix, split
+pub fn parse(url: &str) -> Result<(String, String)> {
+    let rest = url.strip_prefix("az://").ok_or_else(|| anyhow!("{} is not an az:// URL", url))?;
+    let (container, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if container.is_empty() {
+        anyhow::bail!("{} names no container", url);
+    }
+    Ok((container.to_string(), prefix.to_string()))
+}
+
+// Blocks of an upload of `size` bytes
+fn block_size(size: u64) -> u64 {
+    MIN_BLOCK.max(size.div_ceil(MAX_BLOCKS))
+}
+
+/// Requests writing a blob of `size` bytes takes
+pub fn upload_requests(size: u64) -> u64 {
+    match size < block_size(size) {
+        true => 1,
+        // Blocks, then the block list
+        false => size.div_ceil(block_size(size)) + 1,
+    }
+}
+
+/// Requests reading a blob of `size` bytes takes (see `Client::read`)
+pub fn download_requests(size: u64) -> u64 {
+    match size < http::PARALLEL_MIN {
+        true => 2,
+        false => 1 + size.div_ceil(http::RANGE),
+    }
+}
+
+/// The access tier Azure spells `tier` as (Hot, Cool, Cold, Archive)
+pub fn tier(tier: &str) -> String {
+    let mut chars = tier.chars();
+    chars.next().map_or_else(String::new, |first| first.to_uppercase().chain(chars.flat_map(char::to_lowercase)).collect())
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+enum Auth {
+    SharedKey(Zeroizing<Vec<u8>>),
+    // The query string of a SAS, without "?"
+    Sas(Zeroizing<String>),
+    ServicePrincipal { authority: String, tenant: String, client_id: String, secret: Zeroizing<String> },
+}
+
+// An access token and when it expires
+struct Token {
+    value: Zeroizing<String>,
+    expires: u64,
+}
+
+// Account, blob endpoint and credentials, from the environment
+fn account() -> Result<(String, String, Auth)> {
+    let shared_key = |key: &str| -> Result<Auth> {
+        let key = base64::engine::general_purpose::STANDARD.decode(key.trim()).context("The storage account key is not base64")?;
+        Ok(Auth::SharedKey(Zeroizing::new(key)))
+    };
+    if let Some(connection) = env("AZURE_STORAGE_CONNECTION_STRING").map(Zeroizing::new) {
+        let fields: HashMap<&str, &str> = connection.split(';').filter_map(|field| field.split_once('=')).collect();
+        if fields.get("UseDevelopmentStorage") == Some(&"true") {
+            let endpoint = format!("http://127.0.0.1:10000/{}", AZURITE_ACCOUNT);
+            return Ok((AZURITE_ACCOUNT.to_string(), endpoint, shared_key(AZURITE_KEY)?));
+        }
+        let account = fields.get("AccountName").map(|name| name.to_string());
+        let endpoint = match (fields.get("BlobEndpoint"), &account) {
+            (Some(endpoint), _) => endpoint.trim_end_matches('/').to_string(),
+            (None, Some(account)) => format!(
+                "{}://{}.blob.{}",
+                fields.get("DefaultEndpointsProtocol").unwrap_or(&"https"),
+                account,
+                fields.get("EndpointSuffix").unwrap_or(&"core.windows.net")
+            ),
+            (None, None) => anyhow::bail!("AZURE_STORAGE_CONNECTION_STRING has neither AccountName nor BlobEndpoint"),
+        };
+        let auth = match (fields.get("AccountKey"), fields.get("SharedAccessSignature")) {
+            // The key is base64 and may end in "=", which split_once left alone
+            (Some(key), _) => shared_key(key)?,
+            (None, Some(sas)) => Auth::Sas(Zeroizing::new(sas.trim_start_matches('?').to_string())),
+            (None, None) => anyhow::bail!("AZURE_STORAGE_CONNECTION_STRING has neither AccountKey nor SharedAccessSignature"),
+        };
+        // A SAS-only string may name no account; it is only needed to sign with a key
+        let account = account.unwrap_or_default();
+        return Ok((account, endpoint, auth));
+    }
+    let account = env("AZURE_STORAGE_ACCOUNT")
+        .context("No Azure storage account: set AZURE_STORAGE_ACCOUNT or AZURE_STORAGE_CONNECTION_STRING")?;
+    let endpoint = format!("https://{}.blob.core.windows.net", account);
+    let auth = if let Some(key) = env("AZURE_STORAGE_KEY").map(Zeroizing::new) {
+        shared_key(&key)?
+    } else if let Some(sas) = env("AZURE_STORAGE_SAS_TOKEN") {
+        Auth::Sas(Zeroizing::new(sas.trim_start_matches('?').to_string()))
+    } else if let (Some(tenant), Some(client_id), Some(secret)) = (env("AZURE_TENANT_ID"), env("AZURE_CLIENT_ID"), env("AZURE_CLIENT_SECRET")) {
+        let authority = env("AZURE_AUTHORITY_HOST").unwrap_or_else(|| "https://login.microsoftonline.com".to_string());
+        Auth::ServicePrincipal { authority: authority.trim_end_matches('/').to_string(), tenant, client_id, secret: Zeroizing::new(secret) }
+    } else {
+        anyhow::bail!(
+            "No credentials for Azure storage account {}: set AZURE_STORAGE_KEY, AZURE_STORAGE_SAS_TOKEN \
+             or AZURE_TENANT_ID, AZURE_CLIENT_ID and AZURE_CLIENT_SECRET",
+            account
+        );
+    };
+    Ok((account, endpoint, auth))
+}
+
+/// One container of an Azure storage account
+pub struct Client {
+    agent: ureq::Agent,
+    account: String,
+    // Blob endpoint, https://account.blob.core.windows.net or an emulator's
+    base: String,
+    // The path of the endpoint, "/devstoreaccount1" for an emulator's
+    root: String,
+    container: String,
+    auth: Auth,
+    token: Mutex<Option<Token>>,
+}
+
+impl Client {
+    pub fn new(container: &str) -> Result<Self> {
+        let (account, base, auth) = account()?;
+        let host = base.split_once("://").map_or(base.as_str(), |(_, rest)| rest);
+        let root = host.find('/').map_or("", |slash| &host[slash..]).to_string();
+        Ok(Client { agent: http::agent(), account, base, root, container: container.to_string(), auth, token: Mutex::new(None) })
+    }
+
+    pub fn container(&self) -> &str {
+        &self.container
+    }
+
+    /// The blob endpoint, blobs are only copied by the store itself between
+    /// containers of the same account
+    pub fn store(&self) -> &str {
+        &self.base
+    }
+
+    fn describe(&self, blob: &str) -> String {
+        format!("az://{}/{}", self.container, blob)
+    }
+
+    // The path of `blob` of `container` below the endpoint, "" for the container
+    fn path(container: &str, blob: &str) -> String {
+        match blob.is_empty() {
+            true => format!("/{}", http::encode(container, false)),
+            false => format!("/{}/{}", http::encode(container, false), http::encode(blob, true)),
+        }
+    }
+
+    // The Authorization header of a service principal, fetching a token as needed
+    fn bearer(&self) -> Result<String> {
+        let Auth::ServicePrincipal { authority, tenant, client_id, secret } = &self.auth else {
+            unreachable!("only service principals use tokens");
+        };
+        let mut token = self.token.lock().unwrap();
+        let now = utils::now_secs();
+        if token.as_ref().is_none_or(|token| token.expires < now + TOKEN_MARGIN) {
+            let what = format!("Cannot get a token for Azure client {}", client_id);
+            let url = format!("{}/{}/oauth2/v2.0/token", authority, tenant);
+            let body = Zeroizing::new(format!(
+                "grant_type=client_credentials&client_id={}&client_secret={}&scope={}",
+                http::encode(client_id, false),
+                http::encode(secret, false),
+                http::encode("https://storage.azure.com/.default", false)
+            ));
+            let response = http::call(&what, || {
+                self.agent.post(&url).set("Content-Type", "application/x-www-form-urlencoded")
+            }, Some(body.as_bytes()))?;
+            let answer: serde_json::Value = serde_json::from_reader(response.into_reader()).with_context(|| what.clone())?;
+            let value = answer["access_token"].as_str().with_context(|| format!("{}: no access_token in answer", what))?;
+            let expires = now + answer["expires_in"].as_u64().unwrap_or(3600);
+            *token = Some(Token { value: Zeroizing::new(value.to_string()), expires });
+        }
+        Ok(format!("Bearer {}", token.as_ref().unwrap().value.as_str()))
+    }
+
+    // The Shared Key signature of a request, over the headers it is sent with
+    fn sign(&self, key: &[u8], method: &str, path: &str, query: &[(&str, &str)], headers: &[(String, String)], length: usize) -> String {
+        let header = |name: &str| {
+            headers.iter().find(|(header, _)| header.eq_ignore_ascii_case(name)).map_or("", |(_, value)| value.as_str())
+        };
+        let length = match length {
+            0 => String::new(),
+            length => length.to_string(),
+        };
+        let mut ms = headers.iter()
+            .map(|(name, value)| (name.to_lowercase(), value.trim()))
+            .filter(|(name, _)| name.starts_with("x-ms-"))
+            .collect::<Vec<_>>();
+        ms.sort();
+        let mut query = query.iter().map(|(name, value)| (name.to_lowercase(), *value)).collect::<Vec<_>>();
+        query.sort();
+        let mut text = format!(
+            "{}\n{}\n{}\n{}\n\n{}\n\n{}\n{}\n{}\n{}\n{}\n",
+            method,
+            header("Content-Encoding"),
+            header("Content-Language"),
+            length,
+            header("Content-Type"),
+            header("If-Modified-Since"),
+            header("If-Match"),
+            header("If-None-Match"),
+            header("If-Unmodified-Since"),
+            header("Range"),
+        );
+        for (name, value) in ms {
+            text += &format!("{}:{}\n", name, value);
+        }
+        text += &format!("/{}{}{}", self.account, self.root, path);
+        for (name, value) in query {
+            text += &format!("\n{}:{}", name, value);
+        }
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes any key");
+        mac.update(text.as_bytes());
+        let signature = base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+        format!("SharedKey {}:{}", self.account, signature)
+    }
+
+    // Send a request for `path` (see `path`), retrying what can be
+    fn send(&self, what: &str, method: &str, path: &str, query: &[(&str, &str)], headers: &[(&str, &str)], body: Option<&[u8]>) -> Result<ureq::Response> {
+        let mut url = format!("{}{}", self.base, path);
+        let mut separator = '?';
+        for (name, value) in query {
+            url += &format!("{}{}={}", separator, http::encode(name, false), http::encode(value, false));
+            separator = '&';
+        }
+        if let Auth::Sas(sas) = &self.auth {
+            url += &format!("{}{}", separator, sas.as_str());
+        }
+        let bearer = match &self.auth {
+            Auth::ServicePrincipal { .. } => Some(self.bearer()?),
+            _ => None,
+        };
+        http::call(what, || {
+            let mut headers = headers.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect::<Vec<_>>();
+            headers.push(("x-ms-date".to_string(), http::http_date(utils::now_secs())));
+            headers.push(("x-ms-version".to_string(), VERSION.to_string()));
+            let authorization = match &self.auth {
+                Auth::SharedKey(key) => Some(self.sign(key, method, path, query, &headers, body.map_or(0, <[u8]>::len))),
+                Auth::Sas(_) => None,
+                Auth::ServicePrincipal { .. } => bearer.clone(),
+            };
+            let mut request = self.agent.request(method, &url);
+            for (name, value) in headers.iter().chain(authorization.map(|value| ("Authorization".to_string(), value)).as_ref()) {
+                request = request.set(name, value);
+            }
+            request
+        }, body)
+    }
+
+    // Size, modification time and ETag of `blob`
+    fn properties(&self, blob: &str) -> Result<Option<(Stat, Option<String>)>> {
+        let path = Self::path(&self.container, blob);
+        match self.send(&format!("Cannot stat {}", self.describe(blob)), "HEAD", &path, &[], &[], None) {
+            Ok(response) => Ok(Some((
+                Stat {
+                    size: response.header("content-length").and_then(|size| size.parse().ok()).unwrap_or(0),
+                    mtime: response.header("last-modified").and_then(http::parse_time).unwrap_or(0),
+                },
+                response.header("etag").map(str::to_string),
+            ))),
+            Err(e) if http::status(&e) == Some(404) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Size and modification time of `blob`, None when there is no such blob
+    pub fn head(&self, blob: &str) -> Result<Option<Stat>> {
+        Ok(self.properties(blob)?.map(|(stat, _)| stat))
+    }
+
+    /// Read `blob` from byte `offset` on, several ranges at once for a large
+    /// one, all of the version found first
+    pub fn read(self: &Arc<Self>, blob: &str, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let what = format!("Cannot read {}", self.describe(blob));
+        let (stat, etag) = self.properties(blob)?.with_context(|| format!("{}: no such blob", what))?;
+        let path = Self::path(&self.container, blob);
+        if stat.size.saturating_sub(offset) < http::PARALLEL_MIN {
+            let range = format!("bytes={}-", offset);
+            let headers: &[(&str, &str)] = match offset {
+                0 => &[],
+                _ => &[("x-ms-range", &range)],
+            };
+            return Ok(Box::new(self.send(&what, "GET", &path, &[], headers, None)?.into_reader()));
+        }
+        let client = self.clone();
+        Ok(http::parallel_ranges(offset, stat.size, move |start, end| {
+            let range = format!("bytes={}-{}", start, end);
+            let mut headers = vec![("x-ms-range", range.as_str())];
+            // A blob replaced during the download fails instead of mixing versions
+            if let Some(etag) = &etag {
+                headers.push(("If-Match", etag));
+            }
+            let response = client.send(&what, "GET", &path, &[], &headers, None)?;
+            let mut data = Vec::with_capacity((end - start + 1) as usize);
+            response.into_reader().take(end - start + 1).read_to_end(&mut data)?;
+            if data.len() as u64 != end - start + 1 {
+                anyhow::bail!("{}: the download ended early", what);
+            }
+            Ok(data)
+        }))
+    }
+
+    /// Delete `blob`; one that is already gone is not an error
+    pub fn delete(&self, blob: &str) -> Result<()> {
+        let path = Self::path(&self.container, blob);
+        match self.send(&format!("Cannot delete {}", self.describe(blob)), "DELETE", &path, &[], &[], None) {
+            Err(e) if http::status(&e) != Some(404) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // A page of the blobs below `prefix` and the marker of the next
+    fn list_page(&self, prefix: &str, max: Option<&str>, marker: Option<&str>) -> Result<(String, Option<String>)> {
+        let mut query = vec![("restype", "container"), ("comp", "list"), ("prefix", prefix)];
+        query.extend(max.map(|max| ("maxresults", max)));
+        query.extend(marker.map(|marker| ("marker", marker)));
+        let path = Self::path(&self.container, "");
+        let body = self.send(&format!("Cannot list {}", self.describe(prefix)), "GET", &path, &query, &[], None)?.into_string()?;
+        let next = http::tag(&body, "NextMarker").filter(|marker| !marker.is_empty()).map(http::unescape);
+        Ok((body, next))
+    }
+
+    /// Every blob below `prefix`, as (name, size, mtime), in name order
+    pub fn list(&self, prefix: &str) -> Result<Vec<(String, Stat)>> {
+        let mut blobs = vec![];
+        let mut marker: Option<String> = None;
+        loop {
+            let (body, next) = self.list_page(prefix, None, marker.as_deref())?;
+            for blob in http::tags(&body, "Blob") {
+                let name = http::unescape(http::tag(blob, "Name").unwrap_or_default());
+                let size = http::tag(blob, "Content-Length").and_then(|size| size.parse().ok()).unwrap_or(0);
+                let mtime = http::tag(blob, "Last-Modified").and_then(http::parse_time).unwrap_or(0);
+                blobs.push((name, Stat { size, mtime }));
+            }
+            match next {
+                Some(next) => marker = Some(next),
+                None => return Ok(blobs),
+            }
+        }
+    }
+
+    /// Fail unless the container can be listed below `prefix`
+    pub fn check(&self, prefix: &str) -> Result<()> {
+        self.list_page(prefix, Some("1"), None)?;
+        Ok(())
+    }
+
+    /// Store `data` as `blob` in one request
+    pub fn put(&self, blob: &str, data: &[u8], headers: &[(&str, &str)]) -> Result<()> {
+        let mut headers = headers.to_vec();
+        headers.push(("x-ms-blob-type", "BlockBlob"));
+        let path = Self::path(&self.container, blob);
+        self.send(&format!("Cannot write {}", self.describe(blob)), "PUT", &path, &[], &headers, Some(data))?;
+        Ok(())
+    }
+
+    /// Copy the blob `from` of `container` to `to` in this container without
+    /// the data leaving the account. The copy has the properties and metadata
+    /// of the original; `headers` (x-ms-meta-*, x-ms-access-tier, x-ms-tags)
+    /// replace those they name, and `properties` (x-ms-blob-content-type...)
+    /// are set once it is done.
+    pub fn copy(&self, container: &str, from: &str, to: &str, headers: &[(String, String)], properties: &[(String, String)]) -> Result<()> {
+        let what = format!("Cannot copy az://{}/{} to {}", container, from, self.describe(to));
+        let mut source = format!("{}{}", self.base, Self::path(container, from));
+        if let Auth::Sas(sas) = &self.auth {
+            source += &format!("?{}", sas.as_str());
+        }
+        let mut request = pairs(headers);
+        request.push(("x-ms-copy-source", &source));
+        let path = Self::path(&self.container, to);
+        let mut response = self.send(&what, "PUT", &path, &[], &request, Some(&[]))?;
+        // Copies within an account are usually done at once, larger ones may take a while
+        while response.header("x-ms-copy-status") == Some("pending") {
+            std::thread::sleep(Duration::from_secs(1));
+            response = self.send(&what, "HEAD", &path, &[], &[], None)?;
+        }
+        match response.header("x-ms-copy-status") {
+            Some("success") | None => {}
+            Some(status) => anyhow::bail!("{}: {} ({})", what, status, response.header("x-ms-copy-status-description").unwrap_or_default()),
+        }
+        if !properties.is_empty() {
+            self.send(&what, "PUT", &path, &[("comp", "properties")], &pairs(properties), Some(&[]))?;
+        }
+        Ok(())
+    }
+}
+
+// Headers as `send` takes them
+fn pairs(headers: &[(String, String)]) -> Vec<(&str, &str)> {
+    headers.iter().map(|(name, value)| (name.as_str(), value.as_str())).collect()
+}
+
+/// Writes a block blob: small ones with one request, larger ones block by
+/// block as they fill, committed by the block list at the end. Blocks of an
+/// upload that is never committed are discarded by Azure after a week.
+pub struct BlobWriter {
+    client: Arc<Client>,
+    blob: String,
+    headers: Vec<(String, String)>,
+    block_size: usize,
+    buffer: Vec<u8>,
+    blocks: Vec<String>,
+}
+
+impl BlobWriter {
+    /// Write `blob`, expected to be `size` bytes, with extra request headers
+    pub fn new(client: Arc<Client>, blob: String, size: u64, headers: Vec<(String, String)>) -> Self {
+        let block_size = block_size(size) as usize;
+        let buffer = Vec::with_capacity(block_size.min(size as usize));
+        BlobWriter { client, blob, headers, block_size, buffer, blocks: vec![] }
+    }
+
+    fn put_block(&mut self, len: usize) -> Result<()> {
+        // IDs must all have the same length
+        let id = base64::engine::general_purpose::STANDARD.encode(format!("{:06}", self.blocks.len()));
+        let what = format!("Cannot upload block {} of {}", self.blocks.len() + 1, self.client.describe(&self.blob));
+        let path = Client::path(&self.client.container, &self.blob);
+        let query = [("comp", "block"), ("blockid", id.as_str())];
+        self.client.send(&what, "PUT", &path, &query, &[], Some(&self.buffer[..len]))?;
+        self.buffer.drain(..len);
+        self.blocks.push(id);
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        if self.blocks.is_empty() {
+            return self.client.put(&self.blob, &self.buffer, &pairs(&self.headers));
+        }
+        if !self.buffer.is_empty() {
+            self.put_block(self.buffer.len())?;
+        }
+        let what = format!("Cannot complete upload of {}", self.client.describe(&self.blob));
+        let list: String = self.blocks.iter().map(|id| format!("<Latest>{}</Latest>", id)).collect();
+        let body = format!("<?xml version=\"1.0\" encoding=\"utf-8\"?><BlockList>{}</BlockList>", list);
+        let path = Client::path(&self.client.container, &self.blob);
+        self.client.send(&what, "PUT", &path, &[("comp", "blocklist")], &pairs(&self.headers), Some(body.as_bytes()))?;
+        Ok(())
+    }
+}
+
+impl Write for BlobWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        while self.buffer.len() >= self.block_size {
+            self.put_block(self.block_size).map_err(io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(parse("az://container/a/b").unwrap(), ("container".to_string(), "a/b".to_string()));
+        assert!(parse("az:///a").is_err());
+        assert_eq!(tier("cool"), "Cool");
+        assert_eq!(tier("ARCHIVE"), "Archive");
+    }
+}
@@ -0,0 +1,1194 @@
+// Storage endpoints behind one interface.
+//
+// A backend is a directory tree somewhere, addressed by paths relative to its
+// root. Code written against `Backend` (every copy path, --verify-sample)
+// works with any of them. A new kind of endpoint implements the trait and,
+// for URLs, registers an opener for its scheme with `register`, from this
+// crate or from a program linking it. SSH hosts are reached through their
+// connection pools and are not URLs: their backend overrides `send` for what
+// only it does (zstd on the wire, channel retries).
+
+use anyhow::{Context, Result};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+use indicatif::ProgressBar;
+use tokio::sync::Semaphore;
+
+use crate::azure;
+use crate::cpu::CpuLimit;
+use crate::dav;
+use crate::dirfd::{self, Stat};
+use crate::fault;
+use crate::gcs;
+use crate::hash;
+use crate::http;
+use crate::pipe;
+use crate::power;
+use crate::preflight;
+use crate::s3;
+use crate::smb;
+use crate::source;
+use crate::ssh::{self, Lease, RemoteUpload, SshConnectionPool, SshTransfer};
+use crate::throttle::Throttled;
+use crate::utils;
+
+pub use crate::ssh::{SendOptions, Sent};
+
+/// A directory tree files can be read from and written to
+pub trait Backend: Send + Sync {
+    /// Where the backend points, for messages
+    fn describe(&self) -> String;
+
+    /// Fail if the root does not exist or cannot be written to
+    fn check(&self) -> Result<()>;
+
+    /// Create the directory `rel` and any missing parents; "" is the root
+    fn mkdir(&self, rel: &Path) -> Result<()>;
+
+    /// Size and modification time of `rel`, None when it does not exist
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>>;
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>>;
+
+    /// Read `rel` from byte `offset` on
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let mut input = self.open(rel)?;
+        std::io::copy(&mut (&mut input).take(offset), &mut std::io::sink())?;
+        Ok(input)
+    }
+
+    /// Start writing the `size` bytes of `rel`, creating missing parents. A
+    /// new file gets `mode` minus the umask; special bits in `mode` are kept.
+    fn create(&self, rel: &Path, mode: u32, size: u64) -> Result<Box<dyn Upload>>;
+
+    /// Remove the file `rel`; one that is already gone is not an error
+    fn remove(&self, rel: &Path) -> Result<()>;
+
+    /// Move the file `from` to `to`, replacing any file there
+    fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+
+    /// Remove the directory `rel`, which must be empty
+    fn remove_dir(&self, rel: &Path) -> Result<()>;
+
+    /// Every file below the directory `rel` ("" for the root), with paths
+    /// relative to the root
+    fn list(&self, _rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        anyhow::bail!("Cannot list {}, only reading files is supported", self.describe())
+    }
+
+    /// Whether an interrupted download can be continued with `append`
+    fn resumable(&self) -> bool {
+        false
+    }
+
+    /// Write more at the end of the existing file `rel`
+    fn append(&self, rel: &Path) -> Result<Box<dyn Upload>> {
+        anyhow::bail!("Cannot append to {} on {}", rel.display(), self.describe())
+    }
+
+    /// Where `rel` is kept, when it is an object a store can copy itself
+    fn object(&self, _rel: &Path) -> Option<Object> {
+        None
+    }
+
+    /// Have the store copy `from`, of `size` bytes, to `rel` without the data
+    /// passing through here; false when `from` is in a different store
+    fn copy_object(&self, _from: &Object, _rel: &Path, _size: u64) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Let one file be written over several connections while permits of
+    /// `jobs`, the semaphore bounding the transfers running at once, are free
+    fn share_jobs(&self, _jobs: Arc<Semaphore>) {}
+
+    /// Copy the local file `src_root/path` to `rel`, showing progress on `pb`
+    fn send(&self, src_root: &Path, path: &Path, rel: &Path, options: &SendOptions, pb: &ProgressBar) -> Result<Sent> {
+        let input = match &options.read_cache {
+            Some(cache) => cache.open(src_root, path, options.grown)?,
+            None => source::open(src_root, path, options.grown)?,
+        };
+        let (mode, stripped) = utils::dest_mode(input.mode, options.preserve_special);
+        // The file may have changed since the scan, what is sent is its size now
+        let size = input.size;
+        pb.set_length(size);
+        let input = BufReader::new(Throttled::new(input, options.read_limit.clone()));
+        let mut input = pipe::reader(input, options.read_ahead, options.disk_slots.clone());
+        let mut output = self.create(rel, mode, size)?;
+        let mut written = 0u64;
+        let mut hasher = options.verify.map(hash::Hasher::new);
+        power::with_buffer(power::BUFFER, |buffer| loop {
+            power::wait();
+            fault::check(fault::Kind::Read)?;
+            let n = input.read(buffer)?;
+            if n == 0 {
+                return anyhow::Ok(());
+            }
+            let data = &buffer[..n];
+            fault::check(fault::Kind::Write)?;
+            output.write_all(data)?;
+            if let Some(hasher) = hasher.as_mut() {
+                let _slot = options.cpu_limit.as_deref().map(CpuLimit::slot);
+                hasher.update(data);
+            }
+            written += n as u64;
+            pb.set_position(written);
+        })?;
+        output.finalize()?;
+        pb.finish_and_clear();
+        Ok(Sent { digest: hasher.map(hash::Hasher::finalize), wire_bytes: written, stripped, session: None })
+    }
+}
+
+/// An object of a store, see `Backend::object`
+pub struct Object {
+    pub store: String,
+    pub bucket: String,
+    pub key: String,
+}
+
+/// A file being written. It is only complete once finalized; dropping it
+/// unfinished leaves a partial or no file behind, depending on the backend.
+pub trait Upload: Write + Send {
+    fn finalize(self: Box<Self>) -> Result<()>;
+}
+
+/// The backend for a destination that is not user@host:path
+pub fn open(destination: &Path) -> Arc<dyn Backend> {
+    Arc::new(LocalBackend { root: destination.to_path_buf() })
+}
+
+/// Opens the backend of a URL, storing objects as the options say
+pub type Opener = fn(&str, ObjectOptions) -> Result<Arc<dyn Backend>>;
+
+// Openers by URL scheme, the built-in ones first
+fn registry() -> &'static RwLock<HashMap<String, Opener>> {
+    static REGISTRY: OnceLock<RwLock<HashMap<String, Opener>>> = OnceLock::new();
+    REGISTRY.get_or_init(|| {
+        let builtin: [(&str, Opener); 8] = [
+            ("s3", |url, options| Ok(Arc::new(S3Backend::new(url, options)?))),
+            ("gs", |url, options| Ok(Arc::new(GcsBackend::new(url, options)?))),
+            ("az", |url, options| Ok(Arc::new(AzureBackend::new(url, options)?))),
+            ("dav", open_dav),
+            ("davs", open_dav),
+            ("smb", |url, options| {
+                no_object_options(&options)?;
+                Ok(Arc::new(SmbBackend { client: Arc::new(smb::Client::new(url)?) }))
+            }),
+            ("http", |url, _| Ok(Arc::new(HttpBackend::new(url)))),
+            ("https", |url, _| Ok(Arc::new(HttpBackend::new(url)))),
+        ];
+        RwLock::new(builtin.into_iter().map(|(scheme, open)| (scheme.to_string(), open)).collect())
+    })
+}
+
+/// Have `open_url` open URLs of `scheme` (s3, dav...) with `open`, replacing
+/// the backend the scheme had
+pub fn register(scheme: &str, open: Opener) {
+    registry().write().unwrap().insert(scheme.to_string(), open);
+}
+
+fn open_dav(url: &str, options: ObjectOptions) -> Result<Arc<dyn Backend>> {
+    no_object_options(&options)?;
+    Ok(Arc::new(DavBackend::new(url)?))
+}
+
+// File servers store files, not objects with a class, headers and tags
+fn no_object_options(options: &ObjectOptions) -> Result<()> {
+    if !options.is_empty() {
+        anyhow::bail!("--storage-class, --cache-control, --metadata and --tag only apply to objects uploaded to a store");
+    }
+    Ok(())
+}
+
+/// Whether `spec` is a URL of a backend rather than a path or user@host:path
+pub fn is_url(spec: &str) -> bool {
+    spec.split_once("://").is_some_and(|(scheme, _)| registry().read().unwrap().contains_key(scheme))
+}
+
+/// Whether the URL `url` names a directory rather than maybe a file: it ends
+/// with a slash or has no path after the bucket or host
+pub fn names_dir(url: &str) -> bool {
+    let rest = url.split_once("://").map_or(url, |(_, rest)| rest);
+    url.ends_with('/') || !rest.contains('/')
+}
+
+/// Split a URL that does not name a directory into the URL of its parent
+/// and the file's name, decoded from the URL's own spelling
+pub fn split_url(url: &str) -> Option<(String, PathBuf)> {
+    if url.starts_with("http") || url.starts_with("dav") || url.starts_with("smb") {
+        // The query (a signed download link's token) goes with every request
+        let (path, query) = url.split_once('?').map_or((url, ""), |(path, query)| (path, query));
+        let (parent, name) = path.rsplit_once('/')?;
+        let parent = match query.is_empty() {
+            true => parent.to_string(),
+            false => format!("{}?{}", parent, query),
+        };
+        return Some((parent, PathBuf::from(http::decode(name))));
+    }
+    let (parent, name) = url.rsplit_once('/')?;
+    Some((parent.to_string(), PathBuf::from(name)))
+}
+
+/// How objects written to a store are stored and served
+#[derive(Clone, Debug, Default)]
+pub struct ObjectOptions {
+    pub storage_class: Option<String>,
+    pub cache_control: Option<String>,
+    pub metadata: Vec<(String, String)>,
+    pub tags: Vec<(String, String)>,
+}
+
+impl ObjectOptions {
+    pub fn is_empty(&self) -> bool {
+        self.storage_class.is_none() && self.cache_control.is_none() && self.metadata.is_empty() && self.tags.is_empty()
+    }
+}
+
+/// The backend for a URL (see `is_url`)
+pub fn open_url(url: &str) -> Result<Arc<dyn Backend>> {
+    open_url_with(url, ObjectOptions::default())
+}
+
+/// The backend for a URL to write to, storing objects as `options` say
+pub fn open_url_with(url: &str, options: ObjectOptions) -> Result<Arc<dyn Backend>> {
+    let open = url.split_once("://").and_then(|(scheme, _)| registry().read().unwrap().get(scheme).copied());
+    match open {
+        Some(open) => open(url, options),
+        None => anyhow::bail!("{} is not a supported URL", url),
+    }
+}
+
+/// A local directory, accessed through `dirfd` so nothing escapes the root
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+impl Backend for LocalBackend {
+    fn describe(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    fn check(&self) -> Result<()> {
+        preflight::check_local_dest(&self.root)
+    }
+
+    fn mkdir(&self, rel: &Path) -> Result<()> {
+        let dir = self.root.join(rel);
+        std::fs::create_dir_all(&dir).with_context(|| format!("Cannot create {}", dir.display()))
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        Ok(dirfd::stat_beneath(&self.root, rel)?)
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        let file = dirfd::open_beneath(&self.root, rel)
+            .with_context(|| format!("Cannot open {}", self.root.join(rel).display()))?;
+        Ok(Box::new(file))
+    }
+
+    fn create(&self, rel: &Path, mode: u32, _size: u64) -> Result<Box<dyn Upload>> {
+        let file = dirfd::create_beneath(&self.root, rel, mode)?;
+        Ok(Box::new(LocalUpload { output: BufWriter::new(file), mode }))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        dirfd::remove_beneath(&self.root, rel).with_context(|| format!("Cannot delete {}", rel.display()))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        dirfd::rename_beneath(&self.root, from, to)
+            .with_context(|| format!("Cannot rename {} to {}", from.display(), to.display()))
+    }
+
+    fn remove_dir(&self, rel: &Path) -> Result<()> {
+        dirfd::remove_dir_beneath(&self.root, rel).with_context(|| format!("Cannot delete {}", rel.display()))
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        let mut files = vec![];
+        for entry in dirfd::walk(&self.root.join(rel)) {
+            let entry = entry?;
+            if entry.is_file() {
+                files.push((entry.path().strip_prefix(&self.root)?.to_path_buf(), entry.stat()));
+            }
+        }
+        Ok(files)
+    }
+
+    fn resumable(&self) -> bool {
+        true
+    }
+
+    fn append(&self, rel: &Path) -> Result<Box<dyn Upload>> {
+        let file = dirfd::append_beneath(&self.root, rel)
+            .with_context(|| format!("Cannot open {}", self.root.join(rel).display()))?;
+        Ok(Box::new(LocalUpload { output: BufWriter::new(file), mode: 0 }))
+    }
+}
+
+struct LocalUpload {
+    output: BufWriter<File>,
+    mode: u32,
+}
+
+impl Write for LocalUpload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.output.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.output.flush()
+    }
+}
+
+impl Upload for LocalUpload {
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        self.output.flush()?;
+        // Writing clears the special bits again, set them once the data is in
+        if self.mode & utils::SPECIAL_BITS != 0 {
+            dirfd::set_mode(self.output.get_ref(), self.mode)?;
+        }
+        Ok(())
+    }
+}
+
+/// A directory on an SSH host; every operation borrows a connection from the pool
+pub struct SshBackend {
+    pool: Arc<SshConnectionPool>,
+    root: PathBuf,
+}
+
+impl SshBackend {
+    /// `root` must already be resolved (see `SshTransfer::resolve_path`)
+    pub fn new(pool: Arc<SshConnectionPool>, root: PathBuf) -> Self {
+        SshBackend { pool, root }
+    }
+
+    /// Run `f` on a connection of the pool, with the root
+    pub fn with<T>(&self, f: impl FnOnce(&SshTransfer, &Path) -> Result<T>) -> Result<T> {
+        let lease = self.pool.get_connection()?;
+        let r = f(&self.pool.transfer(&lease), &self.root);
+        self.pool.return_connection(lease);
+        r
+    }
+
+    // Run `f` on a connection that stays leased as long as what it returns lives
+    fn leased<T>(&self, f: impl FnOnce(&SshTransfer, &Path) -> Result<T>) -> Result<Leased<T>> {
+        let lease = self.pool.get_connection()?;
+        match f(&self.pool.transfer(&lease), &self.root) {
+            Ok(inner) => Ok(Leased { inner, pool: self.pool.clone(), lease: Some(lease) }),
+            Err(e) => {
+                self.pool.return_connection(lease);
+                Err(e)
+            }
+        }
+    }
+}
+
+impl Backend for SshBackend {
+    fn describe(&self) -> String {
+        format!("{}:{}", self.pool.ssh_dest(), self.root.display())
+    }
+
+    fn check(&self) -> Result<()> {
+        self.with(|transfer, root| transfer.check_remote_dir(root))
+    }
+
+    fn mkdir(&self, rel: &Path) -> Result<()> {
+        self.with(|transfer, root| transfer.create_remote_dir(&root.join(rel)))
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.with(|transfer, root| transfer.remote_stat(&root.join(rel)))
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.leased(|transfer, root| transfer.open_file(&root.join(rel)))?))
+    }
+
+    fn create(&self, rel: &Path, mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        Ok(Box::new(self.leased(|transfer, root| transfer.create_file(&root.join(rel), mode, size))?))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.with(|transfer, root| transfer.remove_remote(&root.join(rel)))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.with(|transfer, root| transfer.rename_remote(&root.join(from), &root.join(to)))
+    }
+
+    fn remove_dir(&self, rel: &Path) -> Result<()> {
+        self.with(|transfer, root| transfer.remove_remote_dir(&root.join(rel)))
+    }
+
+    // Over scp, SFTP or zstd as the pool is set up; the server may cap
+    // channels per connection, the file then moves to another session
+    fn send(&self, src_root: &Path, path: &Path, rel: &Path, options: &SendOptions, pb: &ProgressBar) -> Result<Sent> {
+        let acquire = || loop {
+            match self.pool.get_connection() {
+                Ok(lease) => break lease,
+                Err(e) => {
+                    eprintln!("Failed to get SSH connection from pool: {}. Retrying in 1 second...", e);
+                    std::thread::sleep(std::time::Duration::from_secs(1));
+                }
+            }
+        };
+        let send = |lease: &Lease| utils::catch_panic(|| {
+            let transfer = self.pool.transfer(lease);
+            transfer.send_file(src_root.to_path_buf(), self.root.clone(), path.to_path_buf(), rel, options, pb.clone())
+        });
+        let mut lease = acquire();
+        let mut r = send(&lease);
+        let mut retries = 0;
+        while let Err(e) = &r && ssh::is_channel_refused(e) && retries < ssh::CHANNEL_RETRIES {
+            self.pool.throttle(lease);
+            lease = acquire();
+            r = send(&lease);
+            retries += 1;
+        }
+        let session = format!("{}#{}", self.pool.ssh_dest(), lease.id());
+        self.pool.return_connection(lease);
+        r.map(|sent| Sent { session: Some(session), ..sent })
+    }
+}
+
+// A remote stream together with the connection it runs over
+struct Leased<T> {
+    inner: T,
+    pool: Arc<SshConnectionPool>,
+    lease: Option<Lease>,
+}
+
+impl<T> Drop for Leased<T> {
+    fn drop(&mut self) {
+        if let Some(lease) = self.lease.take() {
+            self.pool.return_connection(lease);
+        }
+    }
+}
+
+impl<T: Read> Read for Leased<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl<T: Write> Write for Leased<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Upload for Leased<RemoteUpload> {
+    fn finalize(mut self: Box<Self>) -> Result<()> {
+        // The lease goes back when `self` is dropped
+        self.inner.finish()
+    }
+}
+
+/// Objects below a prefix of an S3 bucket (s3://bucket/prefix). There are no
+/// directories: creating or removing one does nothing, and a rename is a
+/// copy within the store followed by a delete.
+pub struct S3Backend {
+    client: Arc<s3::Client>,
+    prefix: String,
+    options: ObjectOptions,
+    jobs: OnceLock<Arc<Semaphore>>,
+}
+
+impl S3Backend {
+    pub fn new(url: &str, options: ObjectOptions) -> Result<Self> {
+        let (bucket, prefix) = s3::parse(url)?;
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Ok(S3Backend { client: Arc::new(s3::Client::new(&bucket)?), prefix, options, jobs: OnceLock::new() })
+    }
+
+    // The headers that store a new object `key` as the options say
+    fn headers(&self, key: &str) -> Vec<(String, String)> {
+        let options = &self.options;
+        let mut headers = vec![("content-type".to_string(), http::content_type(key).to_string())];
+        headers.extend(options.cache_control.iter().map(|value| ("cache-control".to_string(), value.clone())));
+        headers.extend(options.storage_class.iter().map(|class| ("x-amz-storage-class".to_string(), class.to_uppercase())));
+        headers.extend(options.metadata.iter().map(|(name, value)| (format!("x-amz-meta-{}", name.to_lowercase()), value.clone())));
+        if !options.tags.is_empty() {
+            let tags = options.tags.iter()
+                .map(|(name, value)| format!("{}={}", http::encode(name, false), http::encode(value, false)))
+                .collect::<Vec<_>>();
+            headers.push(("x-amz-tagging".to_string(), tags.join("&")));
+        }
+        headers
+    }
+
+    fn key(&self, rel: &Path) -> String {
+        object_key(&self.prefix, rel)
+    }
+}
+
+// The key of `rel` below `prefix` of a bucket
+fn object_key(prefix: &str, rel: &Path) -> String {
+    let rel = rel.to_string_lossy();
+    match (prefix.is_empty(), rel.is_empty()) {
+        (true, _) => rel.into_owned(),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{}/{}", prefix, rel),
+    }
+}
+
+// Every object below the directory `rel` of `prefix`, listed by `list`, with
+// paths relative to the prefix
+fn list_objects(prefix: &str, rel: &Path, list: impl Fn(&str) -> Result<Vec<(String, Stat)>>) -> Result<Vec<(PathBuf, Stat)>> {
+    let dir = object_key(prefix, rel);
+    let objects = match dir.is_empty() {
+        true => list("")?,
+        false => list(&format!("{}/", dir))?,
+    };
+    let root = match prefix.is_empty() {
+        true => 0,
+        false => prefix.len() + 1,
+    };
+    // Keys ending in a slash are the markers consoles create for folders
+    Ok(objects.into_iter()
+        .filter(|(key, _)| !key.ends_with('/'))
+        .map(|(key, stat)| (PathBuf::from(&key[root..]), stat))
+        .collect())
+}
+
+impl Backend for S3Backend {
+    fn describe(&self) -> String {
+        format!("s3://{}/{}", self.client.bucket(), self.prefix)
+    }
+
+    fn check(&self) -> Result<()> {
+        self.client.check(&self.prefix)
+    }
+
+    fn mkdir(&self, _rel: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.client.head(&self.key(rel))
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.open_at(rel, 0)
+    }
+
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        self.client.read(&self.key(rel), offset)
+    }
+
+    fn create(&self, rel: &Path, _mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        let key = self.key(rel);
+        Ok(Box::new(s3::ObjectWriter::new(self.client.clone(), key.clone(), size, self.headers(&key), self.jobs.get().cloned())))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.client.delete(&self.key(rel))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (self.key(from), self.key(to));
+        let Some(stat) = self.client.head(&from)? else {
+            anyhow::bail!("Cannot rename s3://{}/{}, it does not exist", self.client.bucket(), from);
+        };
+        self.client.copy(self.client.bucket(), &from, &to, stat.size, &[])?;
+        self.client.delete(&from)
+    }
+
+    fn object(&self, rel: &Path) -> Option<Object> {
+        Some(Object {
+            store: format!("s3:{}", self.client.store()),
+            bucket: self.client.bucket().to_string(),
+            key: self.key(rel),
+        })
+    }
+
+    fn copy_object(&self, from: &Object, rel: &Path, size: u64) -> Result<bool> {
+        if from.store != format!("s3:{}", self.client.store()) {
+            return Ok(false);
+        }
+        // The copy keeps the metadata of the original unless options replace it
+        let key = self.key(rel);
+        let headers = match self.options.is_empty() {
+            true => vec![],
+            false => self.headers(&key),
+        };
+        self.client.copy(&from.bucket, &from.key, &key, size, &headers)?;
+        Ok(true)
+    }
+
+    fn remove_dir(&self, _rel: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        list_objects(&self.prefix, rel, |prefix| self.client.list(prefix))
+    }
+
+    fn share_jobs(&self, jobs: Arc<Semaphore>) {
+        let _ = self.jobs.set(jobs);
+    }
+}
+
+impl Upload for s3::ObjectWriter {
+    fn finalize(self: Box<Self>) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Objects below a prefix of a Google Cloud Storage bucket
+/// (gs://bucket/prefix), which has no directories either
+pub struct GcsBackend {
+    client: Arc<gcs::Client>,
+    prefix: String,
+    options: ObjectOptions,
+}
+
+impl GcsBackend {
+    pub fn new(url: &str, options: ObjectOptions) -> Result<Self> {
+        if !options.tags.is_empty() {
+            anyhow::bail!("GCS objects have no tags, store them with --metadata instead");
+        }
+        let (bucket, prefix) = gcs::parse(url)?;
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Ok(GcsBackend { client: Arc::new(gcs::Client::new(&bucket)?), prefix, options })
+    }
+
+    fn key(&self, rel: &Path) -> String {
+        object_key(&self.prefix, rel)
+    }
+
+    // The object resource of a new object `key`, as the options say
+    fn metadata(&self, key: &str) -> serde_json::Value {
+        let options = &self.options;
+        let mut metadata = serde_json::json!({ "contentType": http::content_type(key) });
+        if let Some(cache_control) = &options.cache_control {
+            metadata["cacheControl"] = cache_control.as_str().into();
+        }
+        if let Some(class) = &options.storage_class {
+            metadata["storageClass"] = class.to_uppercase().into();
+        }
+        if !options.metadata.is_empty() {
+            metadata["metadata"] = options.metadata.iter().map(|(name, value)| (name.clone(), serde_json::Value::from(value.as_str()))).collect();
+        }
+        metadata
+    }
+}
+
+impl Backend for GcsBackend {
+    fn describe(&self) -> String {
+        format!("gs://{}/{}", self.client.bucket(), self.prefix)
+    }
+
+    fn check(&self) -> Result<()> {
+        self.client.check(&self.prefix)
+    }
+
+    fn mkdir(&self, _rel: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.client.head(&self.key(rel))
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.open_at(rel, 0)
+    }
+
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        self.client.read(&self.key(rel), offset)
+    }
+
+    fn create(&self, rel: &Path, _mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        let key = self.key(rel);
+        let metadata = self.metadata(&key);
+        Ok(Box::new(gcs::ObjectWriter::new(self.client.clone(), key, size, metadata)))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.client.delete(&self.key(rel))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (self.key(from), self.key(to));
+        self.client.copy(self.client.bucket(), &from, &to, None)?;
+        self.client.delete(&from)
+    }
+
+    fn object(&self, rel: &Path) -> Option<Object> {
+        Some(Object {
+            store: format!("gs:{}", self.client.store()),
+            bucket: self.client.bucket().to_string(),
+            key: self.key(rel),
+        })
+    }
+
+    fn copy_object(&self, from: &Object, rel: &Path, _size: u64) -> Result<bool> {
+        if from.store != format!("gs:{}", self.client.store()) {
+            return Ok(false);
+        }
+        // The copy keeps the metadata of the original unless options replace it
+        let key = self.key(rel);
+        let metadata = (!self.options.is_empty()).then(|| self.metadata(&key));
+        self.client.copy(&from.bucket, &from.key, &key, metadata.as_ref())?;
+        Ok(true)
+    }
+
+    fn remove_dir(&self, _rel: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        list_objects(&self.prefix, rel, |prefix| self.client.list(prefix))
+    }
+}
+
+impl Upload for gcs::ObjectWriter {
+    fn finalize(self: Box<Self>) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Blobs below a prefix of an Azure Blob Storage container
+/// (az://container/prefix), which has no directories either
+pub struct AzureBackend {
+    client: Arc<azure::Client>,
+    prefix: String,
+    options: ObjectOptions,
+}
+
+impl AzureBackend {
+    pub fn new(url: &str, options: ObjectOptions) -> Result<Self> {
+        let (container, prefix) = azure::parse(url)?;
+        let prefix = prefix.trim_end_matches('/').to_string();
+        Ok(AzureBackend { client: Arc::new(azure::Client::new(&container)?), prefix, options })
+    }
+
+    fn key(&self, rel: &Path) -> String {
+        object_key(&self.prefix, rel)
+    }
+
+    // The tier, metadata and tags of a new blob, which a copy takes from its
+    // original unless they are given
+    fn metadata(&self) -> Vec<(String, String)> {
+        let options = &self.options;
+        let mut headers = vec![];
+        headers.extend(options.storage_class.iter().map(|tier| ("x-ms-access-tier".to_string(), azure::tier(tier))));
+        headers.extend(options.metadata.iter().map(|(name, value)| (format!("x-ms-meta-{}", name.to_lowercase()), value.clone())));
+        if !options.tags.is_empty() {
+            let tags = options.tags.iter()
+                .map(|(name, value)| format!("{}={}", http::encode(name, false), http::encode(value, false)))
+                .collect::<Vec<_>>();
+            headers.push(("x-ms-tags".to_string(), tags.join("&")));
+        }
+        headers
+    }
+
+    // The properties of a new blob `key`, which setting them replaces as a whole
+    fn properties(&self, key: &str) -> Vec<(String, String)> {
+        let mut headers = vec![("x-ms-blob-content-type".to_string(), http::content_type(key).to_string())];
+        headers.extend(self.options.cache_control.iter().map(|value| ("x-ms-blob-cache-control".to_string(), value.clone())));
+        headers
+    }
+}
+
+impl Backend for AzureBackend {
+    fn describe(&self) -> String {
+        format!("az://{}/{}", self.client.container(), self.prefix)
+    }
+
+    fn check(&self) -> Result<()> {
+        self.client.check(&self.prefix)
+    }
+
+    fn mkdir(&self, _rel: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.client.head(&self.key(rel))
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.open_at(rel, 0)
+    }
+
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        self.client.read(&self.key(rel), offset)
+    }
+
+    fn create(&self, rel: &Path, _mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        let key = self.key(rel);
+        let mut headers = self.properties(&key);
+        headers.extend(self.metadata());
+        Ok(Box::new(azure::BlobWriter::new(self.client.clone(), key, size, headers)))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.client.delete(&self.key(rel))
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let (from, to) = (self.key(from), self.key(to));
+        self.client.copy(self.client.container(), &from, &to, &[], &[])?;
+        self.client.delete(&from)
+    }
+
+    fn object(&self, rel: &Path) -> Option<Object> {
+        Some(Object {
+            store: format!("az:{}", self.client.store()),
+            bucket: self.client.container().to_string(),
+            key: self.key(rel),
+        })
+    }
+
+    fn copy_object(&self, from: &Object, rel: &Path, _size: u64) -> Result<bool> {
+        if from.store != format!("az:{}", self.client.store()) {
+            return Ok(false);
+        }
+        // The copy keeps the properties of the original unless options replace them
+        let key = self.key(rel);
+        let (metadata, properties) = match self.options.is_empty() {
+            true => (vec![], vec![]),
+            false => (self.metadata(), self.properties(&key)),
+        };
+        self.client.copy(&from.bucket, &from.key, &key, &metadata, &properties)?;
+        Ok(true)
+    }
+
+    fn remove_dir(&self, _rel: &Path) -> Result<()> {
+        Ok(())
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        list_objects(&self.prefix, rel, |prefix| self.client.list(prefix))
+    }
+}
+
+impl Upload for azure::BlobWriter {
+    fn finalize(self: Box<Self>) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// A directory tree on a WebDAV server (dav://host/path, davs:// for HTTPS),
+/// read the way `HttpBackend` reads files
+pub struct DavBackend {
+    client: Arc<dav::Client>,
+    http: HttpBackend,
+}
+
+impl DavBackend {
+    pub fn new(url: &str) -> Result<Self> {
+        let client = Arc::new(dav::Client::new(url)?);
+        let http = HttpBackend {
+            agent: http::agent(),
+            base: client.base().to_string(),
+            query: String::new(),
+            authorization: client.authorization(),
+        };
+        Ok(DavBackend { client, http })
+    }
+}
+
+impl Backend for DavBackend {
+    fn describe(&self) -> String {
+        self.client.describe()
+    }
+
+    fn check(&self) -> Result<()> {
+        self.client.check()
+    }
+
+    fn mkdir(&self, rel: &Path) -> Result<()> {
+        self.client.mkdir(rel)
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.client.stat(rel)
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.http.open_at(rel, 0)
+    }
+
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        self.http.open_at(rel, offset)
+    }
+
+    fn create(&self, rel: &Path, _mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        if let Some(parent) = rel.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            self.client.mkdir(parent)?;
+        }
+        Ok(Box::new(dav::FileWriter::new(self.client.clone(), rel, size)?))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.client.delete(rel)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.client.rename(from, to)
+    }
+
+    fn remove_dir(&self, rel: &Path) -> Result<()> {
+        self.client.remove_dir(rel)
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        self.client.list(rel)
+    }
+}
+
+impl Upload for dav::FileWriter {
+    fn finalize(self: Box<Self>) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// A directory on an SMB share (smb://server/share/path)
+pub struct SmbBackend {
+    client: Arc<smb::Client>,
+}
+
+impl Backend for SmbBackend {
+    fn describe(&self) -> String {
+        self.client.describe()
+    }
+
+    fn check(&self) -> Result<()> {
+        self.client.check()
+    }
+
+    fn mkdir(&self, rel: &Path) -> Result<()> {
+        self.client.mkdir(rel)
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.client.stat(rel)
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.open_at(rel, 0)
+    }
+
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        Ok(Box::new(self.client.read(rel, offset)?))
+    }
+
+    fn create(&self, rel: &Path, _mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        if let Some(parent) = rel.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            self.client.mkdir(parent)?;
+        }
+        Ok(Box::new(smb::FileWriter::new(&self.client, rel, size)?))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.client.delete(rel)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.client.rename(from, to)
+    }
+
+    fn remove_dir(&self, rel: &Path) -> Result<()> {
+        self.client.remove_dir(rel)
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        self.client.list(rel)
+    }
+}
+
+impl Upload for smb::FileWriter {
+    fn finalize(self: Box<Self>) -> Result<()> {
+        self.finish()
+    }
+}
+
+/// Files served over HTTP(S), below the URL of their directory; they can only
+/// be read, by name, as web servers do not list directories. Large files are
+/// fetched as several ranges at once when the server takes range requests.
+#[derive(Clone)]
+pub struct HttpBackend {
+    agent: ureq::Agent,
+    base: String,
+    // "?..." of the URL, sent with every request
+    query: String,
+    // From user:password@ in the URL
+    authorization: Option<String>,
+}
+
+// What a HEAD request tells about a file
+struct Head {
+    stat: Stat,
+    ranges: bool,
+    etag: Option<String>,
+}
+
+impl HttpBackend {
+    pub fn new(url: &str) -> Self {
+        let (url, query) = url.split_once('?').map_or((url, String::new()), |(url, query)| (url, format!("?{}", query)));
+        let (scheme, rest) = url.split_once("://").unwrap_or(("https", url));
+        let (authority, path) = rest.split_once('/').map_or((rest, ""), |(authority, path)| (authority, path));
+        let (authorization, host) = match authority.rsplit_once('@') {
+            Some((userinfo, host)) => {
+                use base64::Engine as _;
+                let credentials = base64::engine::general_purpose::STANDARD.encode(http::decode(userinfo));
+                (Some(format!("Basic {}", credentials)), host)
+            }
+            None => (None, authority),
+        };
+        let base = format!("{}://{}/{}", scheme, host, path).trim_end_matches('/').to_string();
+        HttpBackend { agent: http::agent(), base, query, authorization }
+    }
+
+    fn url(&self, rel: &Path) -> String {
+        match rel.as_os_str().is_empty() {
+            true => format!("{}{}", self.base, self.query),
+            false => format!("{}/{}{}", self.base, http::encode(&rel.to_string_lossy(), true), self.query),
+        }
+    }
+
+    fn request(&self, method: &str, url: &str) -> ureq::Request {
+        let request = self.agent.request(method, url);
+        match &self.authorization {
+            Some(authorization) => request.set("Authorization", authorization),
+            None => request,
+        }
+    }
+
+    fn head(&self, rel: &Path) -> Result<Option<Head>> {
+        let url = self.url(rel);
+        match http::call(&format!("Cannot stat {}", self.base_of(rel)), || self.request("HEAD", &url), None) {
+            Ok(response) => Ok(Some(Head {
+                stat: Stat {
+                    size: response.header("content-length").and_then(|size| size.parse().ok()).unwrap_or(0),
+                    mtime: response.header("last-modified").and_then(http::parse_time).unwrap_or(0),
+                },
+                ranges: response.header("accept-ranges") == Some("bytes"),
+                etag: response.header("etag").map(str::to_string),
+            })),
+            Err(e) if http::status(&e) == Some(404) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    // The URL of `rel` for messages, without query or credentials
+    fn base_of(&self, rel: &Path) -> String {
+        format!("{}/{}", self.base, rel.display())
+    }
+
+    fn read_only<T>(&self) -> Result<T> {
+        anyhow::bail!("{} is an HTTP(S) URL, which can only be read from", self.base)
+    }
+}
+
+impl Backend for HttpBackend {
+    fn describe(&self) -> String {
+        self.base.clone()
+    }
+
+    fn check(&self) -> Result<()> {
+        self.read_only()
+    }
+
+    fn mkdir(&self, _rel: &Path) -> Result<()> {
+        self.read_only()
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        Ok(self.head(rel)?.map(|head| head.stat))
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.open_at(rel, 0)
+    }
+
+    fn open_at(&self, rel: &Path, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let url = self.url(rel);
+        let what = format!("Cannot read {}", self.base_of(rel));
+        let head = self.head(rel)?.with_context(|| format!("{}: not found", what))?;
+        if head.ranges && head.stat.size.saturating_sub(offset) >= http::PARALLEL_MIN {
+            let backend = self.clone();
+            return Ok(http::parallel_ranges(offset, head.stat.size, move |start, end| {
+                let range = format!("bytes={}-{}", start, end);
+                let response = http::call(&what, || {
+                    let request = backend.request("GET", &url).set("Range", &range);
+                    // A file replaced during the download fails instead of mixing versions
+                    match &head.etag {
+                        Some(etag) => request.set("If-Match", etag),
+                        None => request,
+                    }
+                }, None)?;
+                if response.status() != 206 {
+                    anyhow::bail!("{}: the server ignored the range request", what);
+                }
+                let mut data = Vec::with_capacity((end - start + 1) as usize);
+                response.into_reader().take(end - start + 1).read_to_end(&mut data)?;
+                if data.len() as u64 != end - start + 1 {
+                    anyhow::bail!("{}: the download ended early", what);
+                }
+                Ok(data)
+            }));
+        }
+        let range = format!("bytes={}-", offset);
+        let response = http::call(&what, || match offset {
+            0 => self.request("GET", &url),
+            _ => self.request("GET", &url).set("Range", &range),
+        }, None)?;
+        let partial = response.status() == 206;
+        let mut input: Box<dyn Read + Send> = Box::new(response.into_reader());
+        // A server without ranges sends it all, skip what is there already
+        if partial {
+            return Ok(input);
+        }
+        std::io::copy(&mut (&mut input).take(offset), &mut std::io::sink())?;
+        Ok(input)
+    }
+
+    fn create(&self, _rel: &Path, _mode: u32, _size: u64) -> Result<Box<dyn Upload>> {
+        self.read_only()
+    }
+
+    fn remove(&self, _rel: &Path) -> Result<()> {
+        self.read_only()
+    }
+
+    fn rename(&self, _from: &Path, _to: &Path) -> Result<()> {
+        self.read_only()
+    }
+
+    fn remove_dir(&self, _rel: &Path) -> Result<()> {
+        self.read_only()
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        anyhow::bail!("Cannot list {}, name the files to download", self.base_of(rel))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn registered_scheme() {
+        assert!(!is_url("mem://box/dir"));
+        register("mem", |url, _| Ok(Arc::new(LocalBackend { root: PathBuf::from(url.trim_start_matches("mem://")) })));
+        assert!(is_url("mem://box/dir"));
+        assert_eq!(open_url("mem://box/dir").unwrap().describe(), "box/dir");
+        assert!(open_url("nope://box").is_err());
+    }
+}
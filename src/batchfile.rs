@@ -0,0 +1,415 @@
+// Self-contained batch files for offline (sneakernet) transfers.
+//
+// Layout: the magic and a version, the contents of every copied file back to
+// back, then the JSON manifest, its length as a little-endian u64 and the
+// magic again. Writing is a single streaming pass; readers find the manifest
+// from the end of the file.
+//
+// A batch can span several volumes of a fixed size (one per removable
+// medium). Every volume is a complete batch file with the manifest of its own
+// contents; files that do not fit are split and continue on the next volume.
+
+use anyhow::Context;
+use indicatif::ProgressBar;
+use serde::{Deserialize, Serialize};
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter, IsTerminal, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::dirfd;
+use crate::hash;
+use crate::plan::{Action, Plan};
+use crate::rawpath;
+use crate::throttle::{Throttle, Throttled};
+use crate::utils;
+
+const MAGIC: &[u8; 8] = b"CPXBATCH";
+const VERSION: u32 = 1;
+const HEADER_LEN: u64 = 12;
+const TRAILER_LEN: u64 = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BatchEntry {
+    pub action: Action,
+    // Relative to the destination root the batch is applied to
+    #[serde(with = "rawpath")]
+    pub path: PathBuf,
+    pub size: u64,
+    // Where the contents start in the batch file
+    pub offset: u64,
+    // Where the contents go in the destination file, non-zero for the
+    // continuation of a file split across volumes
+    #[serde(default)]
+    pub file_offset: u64,
+    pub hash: Option<String>,
+}
+
+/// Position of a volume within a spanned batch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Volume {
+    // Shared by all volumes of one batch
+    pub id: String,
+    // Starting at 1
+    pub index: u32,
+    pub last: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Manifest {
+    pub version: u32,
+    pub created: u64,
+    #[serde(with = "rawpath")]
+    pub source: PathBuf,
+    pub hash: hash::Algorithm,
+    #[serde(default)]
+    pub volume: Option<Volume>,
+    pub entries: Vec<BatchEntry>,
+}
+
+fn progress(total: u64) -> ProgressBar {
+    let pb = ProgressBar::new(total);
+    let window = Duration::from_secs(crate::progress::RATE_WINDOW);
+    pb.set_style(crate::progress::file_style("{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})", window));
+    pb
+}
+
+// Copy `input` to `output`, hashing on the way; returns the bytes copied
+fn copy_hashed(
+    mut input: impl Read,
+    output: &mut impl Write,
+    hasher: &mut hash::Hasher,
+    pb: &ProgressBar,
+) -> std::io::Result<u64> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut copied = 0u64;
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        output.write_all(&buffer[..n])?;
+        hasher.update(&buffer[..n]);
+        copied += n as u64;
+        pb.inc(n as u64);
+    }
+    Ok(copied)
+}
+
+/// File name of volume `index` of a spanned batch written to `output`
+fn volume_path(output: &Path, index: u32) -> PathBuf {
+    let mut name = OsString::from(output.as_os_str());
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+// Manifest bytes an entry for `path` can take at most, so a volume always
+// keeps room for the manifest describing it
+fn entry_reserve(path: &Path) -> u64 {
+    let widest = BatchEntry {
+        action: Action::Delete,
+        path: path.to_path_buf(),
+        size: u64::MAX,
+        offset: u64::MAX,
+        file_offset: u64::MAX,
+        hash: Some("0".repeat(128)),
+    };
+    serde_json::to_vec(&widest).map(|json| json.len() as u64 + 1).unwrap_or(u64::MAX)
+}
+
+struct Writer<'a> {
+    output: &'a Path,
+    volume_size: Option<u64>,
+    manifest: Manifest,
+    out: BufWriter<File>,
+    // Bytes in the current volume so far
+    offset: u64,
+    // Upper bound for the manifest of the current volume
+    reserved: u64,
+    volumes: u32,
+}
+
+impl<'a> Writer<'a> {
+    fn new(output: &'a Path, volume_size: Option<u64>, source: &Path, algorithm: hash::Algorithm) -> anyhow::Result<Self> {
+        let volume = volume_size.map(|_| Volume { id: uuid::Uuid::new_v4().to_string(), index: 1, last: false });
+        let manifest = Manifest {
+            version: VERSION,
+            created: utils::now_secs(),
+            source: source.to_path_buf(),
+            hash: algorithm,
+            volume,
+            entries: vec![],
+        };
+        let path = match volume_size {
+            Some(_) => volume_path(output, 1),
+            None => output.to_path_buf(),
+        };
+        let out = BufWriter::new(create_volume(&path)?);
+        // The widest the empty manifest gets, with the index and flag at their longest
+        let reserved = serde_json::to_vec(&manifest)?.len() as u64 + 16;
+        Ok(Writer { output, volume_size, manifest, out, offset: HEADER_LEN, reserved, volumes: 1 })
+    }
+
+    // Bytes of content that still fit in the current volume next to a new entry for `path`
+    fn room(&self, path: &Path) -> u64 {
+        match self.volume_size {
+            Some(size) => size.saturating_sub(self.offset + self.reserved + entry_reserve(path) + TRAILER_LEN),
+            None => u64::MAX,
+        }
+    }
+
+    fn push(&mut self, entry: BatchEntry) {
+        self.reserved += entry_reserve(&entry.path);
+        self.offset += entry.size;
+        self.manifest.entries.push(entry);
+    }
+
+    // Close the current volume and start the next one
+    fn next_volume(&mut self) -> anyhow::Result<()> {
+        if self.manifest.entries.is_empty() {
+            anyhow::bail!("Volume size is too small to hold any data");
+        }
+        self.write_manifest()?;
+        self.volumes += 1;
+        let volume = self.manifest.volume.as_mut().unwrap();
+        volume.index = self.volumes;
+        self.manifest.entries.clear();
+        let out = BufWriter::new(create_volume(&volume_path(self.output, self.volumes))?);
+        let full = std::mem::replace(&mut self.out, out);
+        full.into_inner()?.sync_all()?;
+        self.offset = HEADER_LEN;
+        self.reserved = serde_json::to_vec(&self.manifest)?.len() as u64 + 16;
+        Ok(())
+    }
+
+    fn write_manifest(&mut self) -> anyhow::Result<()> {
+        let json = serde_json::to_vec(&self.manifest)?;
+        self.out.write_all(&json)?;
+        self.out.write_all(&(json.len() as u64).to_le_bytes())?;
+        self.out.write_all(MAGIC)?;
+        Ok(())
+    }
+
+    fn add_file(&mut self, path: &Path, mut input: impl BufRead, pb: &ProgressBar) -> anyhow::Result<()> {
+        let mut file_offset = 0;
+        loop {
+            if file_offset > 0 && input.fill_buf()?.is_empty() {
+                return Ok(());
+            }
+            let room = self.room(path);
+            if room == 0 {
+                self.next_volume()?;
+                continue;
+            }
+            let mut hasher = hash::Hasher::new(self.manifest.hash);
+            // The file may have changed since the scan, record what was actually read
+            let size = copy_hashed((&mut input).take(room), &mut self.out, &mut hasher, pb)?;
+            self.push(BatchEntry {
+                action: Action::Copy,
+                path: path.to_path_buf(),
+                size,
+                offset: self.offset,
+                file_offset,
+                hash: Some(hasher.finalize().to_string()),
+            });
+            file_offset += size;
+            if size < room {
+                return Ok(());
+            }
+        }
+    }
+
+    fn add_delete(&mut self, path: &Path, size: u64) -> anyhow::Result<()> {
+        if self.room(path) == 0 {
+            self.next_volume()?;
+        }
+        self.manifest.entries.push(BatchEntry { action: Action::Delete, path: path.to_path_buf(), size, offset: 0, file_offset: 0, hash: None });
+        self.reserved += entry_reserve(path);
+        Ok(())
+    }
+
+    fn finish(mut self) -> anyhow::Result<u32> {
+        if let Some(volume) = self.manifest.volume.as_mut() {
+            volume.last = true;
+        }
+        self.write_manifest()?;
+        self.out.into_inner()?.sync_all()?;
+        Ok(self.volumes)
+    }
+}
+
+fn create_volume(path: &Path) -> anyhow::Result<File> {
+    let mut file = File::create(path).with_context(|| format!("Cannot create batch {}", path.display()))?;
+    file.write_all(MAGIC)?;
+    file.write_all(&VERSION.to_le_bytes())?;
+    Ok(file)
+}
+
+/// Write the pending entries of `plan` to a batch file, reading file contents
+/// from `src_root`. With `volume_size` the batch is split into volumes named
+/// `<output>.001`, `<output>.002`, ... of at most that size. Returns the
+/// number of volumes written.
+pub fn write(
+    plan: &Plan,
+    src_root: &Path,
+    output: &Path,
+    volume_size: Option<u64>,
+    algorithm: hash::Algorithm,
+    read_limit: Option<Arc<Throttle>>,
+) -> anyhow::Result<u32> {
+    let mut writer = Writer::new(output, volume_size, &plan.source, algorithm)?;
+    let total = plan.pending(Action::Copy).map(|(_, entry)| entry.size).sum();
+    let pb = progress(total);
+    pb.set_message("📦 writing batch");
+    for (_, entry) in plan.pending(Action::Copy) {
+        let input = dirfd::open_beneath(src_root, &entry.path)
+            .with_context(|| format!("Cannot read {}", entry.path.display()))?;
+        writer.add_file(&entry.path, BufReader::new(Throttled::new(input, read_limit.clone())), &pb)?;
+    }
+    for (_, entry) in plan.pending(Action::Delete) {
+        writer.add_delete(&entry.path, entry.size)?;
+    }
+    pb.finish_and_clear();
+    writer.finish()
+}
+
+/// Read the manifest of a batch file, leaving the file open for the contents
+pub fn open(batch: &Path) -> anyhow::Result<(File, Manifest)> {
+    let mut file = File::open(batch).with_context(|| format!("Cannot open batch {}", batch.display()))?;
+    let mut header = [0u8; HEADER_LEN as usize];
+    file.read_exact(&mut header).with_context(|| format!("{} is not a cpx batch", batch.display()))?;
+    if &header[..8] != MAGIC {
+        anyhow::bail!("{} is not a cpx batch", batch.display());
+    }
+    let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+    if version != VERSION {
+        anyhow::bail!("Batch {} has unsupported version {}", batch.display(), version);
+    }
+    let end = file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    file.read_exact(&mut trailer)?;
+    if &trailer[8..] != MAGIC {
+        anyhow::bail!("Batch {} is truncated", batch.display());
+    }
+    let len = u64::from_le_bytes(trailer[..8].try_into().unwrap());
+    let start = end.checked_sub(len).filter(|start| *start >= HEADER_LEN)
+        .ok_or_else(|| anyhow::anyhow!("Batch {} has a corrupt manifest", batch.display()))?;
+    file.seek(SeekFrom::Start(start))?;
+    let mut json = vec![0; len as usize];
+    file.read_exact(&mut json)?;
+    let manifest = serde_json::from_slice(&json)
+        .with_context(|| format!("Batch {} has a corrupt manifest", batch.display()))?;
+    Ok((file, manifest))
+}
+
+/// Apply a batch file to a local destination, checking every file against the
+/// hash recorded when the batch was written. For a spanned batch, start with
+/// the first volume; the user is asked for each following one.
+pub fn apply(batch: &Path, dest_root: &Path) -> anyhow::Result<()> {
+    let (mut file, mut manifest) = open(batch)?;
+    if manifest.volume.as_ref().is_some_and(|volume| volume.index != 1) {
+        anyhow::bail!("{} is not the first volume of its batch", batch.display());
+    }
+    let mut path = batch.to_path_buf();
+    let mut failed = 0;
+    loop {
+        failed += apply_volume(&path, &mut file, &manifest, dest_root)?;
+        let Some(volume) = manifest.volume.filter(|volume| !volume.last) else {
+            break;
+        };
+        println!("💿 Volume {} done", volume.index);
+        (path, file, manifest) = next_volume(&path, &volume)?;
+    }
+    if failed > 0 {
+        anyhow::bail!("{} entries in the batch are corrupt or were rejected", failed);
+    }
+    Ok(())
+}
+
+// Ask for the volume after `current` until one of the right batch is given
+fn next_volume(previous: &Path, current: &Volume) -> anyhow::Result<(PathBuf, File, Manifest)> {
+    let index = current.index + 1;
+    let default = match previous.extension() {
+        Some(_) => previous.with_extension(format!("{:03}", index)),
+        None => previous.to_path_buf(),
+    };
+    let interactive = std::io::stdin().is_terminal();
+    loop {
+        let path = if interactive {
+            print!("💿 Insert volume {} and press Enter, or type its path [{}]: ", index, default.display());
+            std::io::stdout().flush()?;
+            let mut line = String::new();
+            if std::io::stdin().read_line(&mut line)? == 0 {
+                anyhow::bail!("Import stopped before volume {}", index);
+            }
+            match line.trim() {
+                "" => default.clone(),
+                typed => PathBuf::from(typed),
+            }
+        } else {
+            default.clone()
+        };
+        let opened = open(&path).and_then(|(file, manifest)| match &manifest.volume {
+            Some(volume) if volume.id == current.id && volume.index == index => Ok((file, manifest)),
+            _ => Err(anyhow::anyhow!("{} is not volume {} of this batch", path.display(), index)),
+        });
+        match opened {
+            Ok((file, manifest)) => return Ok((path, file, manifest)),
+            Err(e) if interactive => eprintln!("Error: {:#}", e),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+// Apply the entries of one volume, returning the number of corrupt or
+// rejected entries
+fn apply_volume(path: &Path, file: &mut File, manifest: &Manifest, dest_root: &Path) -> anyhow::Result<usize> {
+    println!(
+        "📦 Batch {} from {}: {} entries",
+        path.display(),
+        manifest.source.display(),
+        manifest.entries.len()
+    );
+    let total = manifest.entries.iter().filter(|entry| entry.action == Action::Copy).map(|entry| entry.size).sum();
+    let pb = progress(total);
+    pb.set_message("📥 applying batch");
+    let mut failed = 0;
+    for entry in &manifest.entries {
+        let Some(path) = utils::contained_path(&entry.path) else {
+            pb.suspend(|| eprintln!("Error: {}: rejected, the path escapes the destination", entry.path.display()));
+            failed += 1;
+            continue;
+        };
+        let entry = BatchEntry { path, ..entry.clone() };
+        match entry.action {
+            Action::Copy => {
+                file.seek(SeekFrom::Start(entry.offset))?;
+                let input = (&mut *file).take(entry.size);
+                let output = if entry.file_offset == 0 {
+                    dirfd::create_beneath(dest_root, &entry.path, 0o666)
+                } else {
+                    // Continuation of a split file, the earlier pieces must all be there
+                    match dirfd::stat_beneath(dest_root, &entry.path)? {
+                        Some(stat) if stat.size == entry.file_offset => dirfd::append_beneath(dest_root, &entry.path),
+                        _ => anyhow::bail!("{}: earlier volumes of this file are missing", entry.path.display()),
+                    }
+                };
+                let mut output = BufWriter::new(output.with_context(|| format!("Cannot write {}", entry.path.display()))?);
+                let mut hasher = hash::Hasher::new(manifest.hash);
+                let copied = copy_hashed(input, &mut output, &mut hasher, &pb)?;
+                output.flush()?;
+                let digest = hasher.finalize().to_string();
+                if copied != entry.size || entry.hash.as_ref().is_some_and(|hash| *hash != digest) {
+                    pb.suspend(|| eprintln!("Error: {}: contents do not match the batch manifest", entry.path.display()));
+                    failed += 1;
+                }
+            }
+            Action::Delete => dirfd::remove_beneath(dest_root, &entry.path)
+                .with_context(|| format!("Cannot delete {}", entry.path.display()))?,
+        }
+    }
+    pb.finish_and_clear();
+    Ok(failed)
+}
@@ -0,0 +1,173 @@
+// Destination inventories for trees too large to list: `cpx inventory --bloom`
+// writes a bloom filter of the files instead of their list, a few bytes per
+// file, and `cpx plan --dest-bloom` plans against it. A bloom filter tells for
+// sure that something is not in it and with a small chance of error
+// (FALSE_POSITIVES) that it is.
+//
+// Files are entered by path and size, in bands of modification time that each
+// hold as many files. A copy is only as old as when it was made, so a source
+// file found in a band newer than itself is taken as already copied, and one
+// found in an older band, or not at all, is copied. Only the files found in the
+// band their own time falls into are looked up on the destination, along with
+// a sample of the others (CHECKED): one of those missing there has every file
+// the filter has as copied looked up, not skipped.
+
+use anyhow::Context;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::dirfd::Stat;
+use crate::plan::Listing;
+use crate::utils;
+
+const BLOOM_VERSION: u32 = 1;
+// Chance that a file not in the filter is taken for one that is
+const FALSE_POSITIVES: f64 = 1e-7;
+const BANDS: usize = 16;
+// Files the filter has as copied that are looked up anyway, picked at random,
+// before the others are taken on its word
+pub const CHECKED: usize = 1000;
+
+#[derive(Serialize, Deserialize)]
+pub struct Bloom {
+    pub version: u32,
+    pub created: u64,
+    pub root: String,
+    pub files: u64,
+    hashes: u32,
+    // Modification times each band holds, oldest first, both ends included
+    bands: Vec<(u64, u64)>,
+    #[serde(with = "bits")]
+    bits: Vec<u8>,
+}
+
+/// What the filter knows about a source file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Lookup {
+    /// The destination has it at this size, copied after it last changed
+    Unchanged,
+    /// The destination does not have it at this size, or has an older copy
+    Stale,
+    /// The destination has it at this size, from around when it changed
+    Uncertain,
+}
+
+impl Bloom {
+    /// A filter of the files in `listing`, listed under `root`
+    pub fn new(root: &str, listing: &Listing) -> Self {
+        let mut times = listing.values().map(|stat| stat.mtime).collect::<Vec<_>>();
+        times.sort_unstable();
+        let mut bands: Vec<(u64, u64)> = vec![];
+        for chunk in times.chunks(times.len().div_ceil(BANDS).max(1)) {
+            let last = chunk[chunk.len() - 1];
+            // Files of the same second stay in one band
+            match bands.last() {
+                Some(&(_, end)) if end >= last => {}
+                Some(&(_, end)) => bands.push((end + 1, last)),
+                None => bands.push((chunk[0], last)),
+            }
+        }
+        let n = listing.len().max(1) as f64;
+        let m = (-n * FALSE_POSITIVES.ln() / std::f64::consts::LN_2.powi(2)).ceil().max(64.0);
+        let hashes = ((m / n) * std::f64::consts::LN_2).round().max(1.0) as u32;
+        let mut bloom = Bloom {
+            version: BLOOM_VERSION,
+            created: utils::now_secs(),
+            root: root.to_string(),
+            files: listing.len() as u64,
+            hashes,
+            bands,
+            bits: vec![0; (m as usize).div_ceil(8)],
+        };
+        for (path, stat) in listing {
+            let band = bloom.bands.iter().position(|&(_, end)| end >= stat.mtime).unwrap_or(0);
+            for bit in bloom.positions(path, stat.size, band) {
+                bloom.bits[bit / 8] |= 1 << (bit % 8);
+            }
+        }
+        bloom
+    }
+
+    /// Look up the source file `path` (keyed like the listing the filter was
+    /// made from) with its `stat`
+    pub fn lookup(&self, path: &Path, stat: &Stat) -> Lookup {
+        // A path is in one band only, the newest are tried first
+        for (band, &(start, end)) in self.bands.iter().enumerate().rev() {
+            if self.positions(path, stat.size, band).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0) {
+                return if start >= stat.mtime {
+                    Lookup::Unchanged
+                } else if end < stat.mtime {
+                    Lookup::Stale
+                } else {
+                    Lookup::Uncertain
+                };
+            }
+        }
+        Lookup::Stale
+    }
+
+    // Bits of a file, by double hashing one 128-bit hash
+    fn positions(&self, path: &Path, size: u64, band: usize) -> impl Iterator<Item = usize> + use<> {
+        let mut key = path.as_os_str().as_encoded_bytes().to_vec();
+        key.push(0);
+        key.extend_from_slice(&size.to_le_bytes());
+        key.push(band as u8);
+        let hash = xxhash_rust::xxh3::xxh3_128(&key);
+        let (h1, h2) = (hash as u64, (hash >> 64) as u64 | 1);
+        let m = self.bits.len() as u64 * 8;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+    }
+
+    pub fn load(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(file).with_context(|| format!("Cannot read bloom filter {}", file.display()))?;
+        let bloom: Bloom = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid bloom filter {}", file.display()))?;
+        if bloom.version != BLOOM_VERSION {
+            anyhow::bail!("Bloom filter {} has unsupported version {}", file.display(), bloom.version);
+        }
+        if bloom.bits.is_empty() || bloom.hashes == 0 || bloom.bands.is_empty() {
+            anyhow::bail!("Invalid bloom filter {}", file.display());
+        }
+        Ok(bloom)
+    }
+
+    /// Write the filter to `file`, or to stdout without one
+    pub fn save(&self, file: Option<&Path>) -> anyhow::Result<()> {
+        let data = serde_json::to_vec(self)?;
+        match file {
+            Some(file) => {
+                let tmp = file.with_extension("tmp");
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, file).with_context(|| format!("Cannot write bloom filter {}", file.display()))?;
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&data)?;
+                writeln!(stdout)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Size of the filter in bytes
+    pub fn bytes(&self) -> usize {
+        self.bits.len()
+    }
+}
+
+// The bit array as one base64 string
+mod bits {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(bits: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        STANDARD.encode(bits).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        STANDARD.decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+    }
+}
@@ -0,0 +1,79 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::utils;
+
+const AUTH_CACHE_FILE: &str = "auth.json";
+
+/// Authentication method that last succeeded for a host
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "method", rename_all = "lowercase")]
+pub enum CachedAuth {
+    Agent,
+    Key { path: PathBuf },
+    Password,
+    KeyboardInteractive,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct AuthEntry {
+    auth: CachedAuth,
+    updated: u64,
+}
+
+/// Remembers how we authenticated to each host so repeated invocations
+/// go straight to the method that worked instead of probing every one again.
+/// Connections are not kept between runs, each still does the handshake.
+/// Secrets are never written, only the method (and key path).
+pub struct AuthCache {
+    ttl_secs: u64,
+    path: Option<PathBuf>,
+}
+
+impl AuthCache {
+    pub fn new(ttl_minutes: u64) -> Self {
+        AuthCache {
+            ttl_secs: ttl_minutes * 60,
+            path: utils::cache_dir().map(|dir| dir.join(AUTH_CACHE_FILE)),
+        }
+    }
+
+    fn load(&self) -> HashMap<String, AuthEntry> {
+        let mut entries = self.path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        self.prune(&mut entries);
+        entries
+    }
+
+    // Drop the entries older than the TTL
+    fn prune(&self, entries: &mut HashMap<String, AuthEntry>) {
+        let now = utils::now_secs();
+        entries.retain(|_, entry| now.saturating_sub(entry.updated) <= self.ttl_secs);
+    }
+
+    pub fn lookup(&self, key: &str) -> Option<CachedAuth> {
+        if self.ttl_secs == 0 {
+            return None;
+        }
+        Some(self.load().remove(key)?.auth)
+    }
+
+    pub fn store(&self, key: &str, auth: CachedAuth) {
+        if self.ttl_secs == 0 {
+            return;
+        }
+        let Some(path) = &self.path else {
+            return;
+        };
+        // The cache is only an optimisation, failing to persist it is not an error
+        let _ = utils::update_state(path, |entries| {
+            self.prune(entries);
+            entries.insert(key.to_string(), AuthEntry { auth, updated: utils::now_secs() });
+        });
+    }
+}
@@ -0,0 +1,76 @@
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::Semaphore;
+
+use crate::ssh::SshConnectionPool;
+
+/// A destination host's connection pool and the remote root to copy into
+pub type Destination = (Arc<SshConnectionPool>, PathBuf);
+
+/// Open the first session to every destination concurrently, with at most
+/// `connectors` handshakes in flight, and report the result per host in the
+/// same order as `destinations`.
+pub async fn connect_all(
+    destinations: &[Destination],
+    connectors: usize,
+    m: &MultiProgress,
+) -> Vec<anyhow::Result<()>> {
+    let pb = m.add(ProgressBar::new(destinations.len() as u64));
+    let sty = ProgressStyle::with_template("{msg} {bar:40} {pos}/{len} hosts")
+        .unwrap()
+        .progress_chars("=>-");
+    pb.set_style(sty);
+    pb.set_message("🔗 connecting");
+
+    let semaphore = Arc::new(Semaphore::new(connectors.max(1)));
+    let mut handles = vec![];
+    for (pool, _) in destinations {
+        let pool = pool.clone();
+        let sem = semaphore.clone();
+        let pb = pb.clone();
+        handles.push(tokio::spawn(async move {
+            let _permit = sem.acquire().await.unwrap();
+            let r = tokio::task::spawn_blocking(move || pool.warm_up()).await;
+            pb.inc(1);
+            match r {
+                Ok(r) => r,
+                Err(e) => Err(anyhow::anyhow!("connection task failed: {}", e)),
+            }
+        }));
+    }
+
+    let mut results = vec![];
+    for h in handles {
+        results.push(match h.await {
+            Ok(r) => r,
+            Err(e) => Err(anyhow::anyhow!("connection task failed: {}", e)),
+        });
+    }
+    pb.finish_and_clear();
+    results
+}
+
+/// Split destinations into the hosts that passed the connection check and the
+/// ones that did not, printing a report of the failures.
+pub fn partition(
+    destinations: Vec<Destination>,
+    results: Vec<anyhow::Result<()>>,
+) -> (Vec<Destination>, Vec<String>) {
+    let total = destinations.len();
+    let mut healthy = vec![];
+    let mut failed = vec![];
+    for ((pool, remote_root), r) in destinations.into_iter().zip(results) {
+        match r {
+            Ok(()) => healthy.push((pool, remote_root)),
+            Err(e) => {
+                eprintln!("   ❌ {}: {:#}", pool.ssh_dest(), e);
+                failed.push(pool.ssh_dest().to_string());
+            }
+        }
+    }
+    if !failed.is_empty() {
+        eprintln!("⚠️  {} of {} hosts failed the connection check", failed.len(), total);
+    }
+    (healthy, failed)
+}
@@ -0,0 +1,53 @@
+// -C/--compress: file data crosses an SSH connection as a zstd stream, which
+// zstd on the host unpacks. The host needs a shell and zstd in the login
+// PATH; both are checked once per destination before any data moves, so a
+// host without zstd fails up front instead of on every file.
+
+use anyhow::Context;
+use std::io::Read;
+use std::path::Path;
+
+// Exit status of a shell that cannot find a command
+const NOT_FOUND: i32 = 127;
+// Exit status of an unpack that could not rename the file into place
+const NOT_MOVED: i32 = 3;
+
+/// Shell command run in the directory of a file: unpack the zstd stream on
+/// stdin into the temporary name `tmp`, give it `mode` minus the remote umask
+/// as scp does and rename it to `name`. Nothing is left behind when zstd fails.
+pub fn unpack_command(tmp: &str, name: &str, mode: u32) -> String {
+    format!(
+        "command -v zstd >/dev/null || exit {nf}; \
+         zstd -dcq > {tmp} || {{ rm -f {tmp}; exit 1; }}; \
+         chmod \"$(printf %o $((0{mode:o} & ~0$(umask))))\" {tmp} && mv -f {tmp} {name} || {{ rm -f {tmp}; exit {nm}; }}",
+        nf = NOT_FOUND,
+        nm = NOT_MOVED,
+    )
+}
+
+/// Check that zstd can be run on the host of `session`
+pub fn check_remote(session: &ssh2::Session) -> anyhow::Result<()> {
+    let mut channel = session.channel_session()?;
+    channel.exec(&format!("command -v zstd >/dev/null || exit {}", NOT_FOUND))?;
+    channel.read_to_string(&mut String::new())?;
+    channel.wait_close()?;
+    match channel.exit_status()? {
+        0 => Ok(()),
+        NOT_FOUND => Err(not_installed()),
+        code => Err(anyhow::anyhow!("Cannot look for zstd on the host (exit status {})", code)),
+    }
+}
+
+/// What the exit status of `unpack_command` for `remote_path` means
+pub fn check_unpacked(status: i32, remote_path: &Path) -> anyhow::Result<()> {
+    match status {
+        0 => Ok(()),
+        NOT_FOUND => Err(not_installed()).with_context(|| format!("Cannot write {}", remote_path.display())),
+        NOT_MOVED => Err(anyhow::anyhow!("Cannot move the unpacked {} into place on the host", remote_path.display())),
+        code => Err(anyhow::anyhow!("zstd on the host failed to unpack {} (exit status {})", remote_path.display(), code)),
+    }
+}
+
+fn not_installed() -> anyhow::Error {
+    anyhow::anyhow!("--compress needs zstd on the host, and it is not installed there (or not in the login PATH)")
+}
@@ -0,0 +1,53 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::credentials::ProviderConfig;
+use crate::utils;
+
+/// Named settings for recurring jobs, selected with --profile, kept in
+/// ~/.config/cpx/config.json:
+///
+/// {"profiles": {"prod": {"host_keys": {"web1.example.com": "SHA256:..."}}}}
+#[derive(Debug, Default, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Profile {
+    // Expected host key fingerprint per host, as printed by ssh-keygen -l
+    #[serde(default)]
+    pub host_keys: HashMap<String, String>,
+    // Where to fetch SSH credentials at connect time, e.g.
+    // {"provider": "ssm", "parameter": "/prod/ssh/{host}"}
+    pub credentials: Option<ProviderConfig>,
+}
+
+pub fn config_path() -> Option<PathBuf> {
+    utils::config_dir().map(|dir| dir.join("config.json"))
+}
+
+impl Config {
+    /// Load the config file; a missing file is an empty config
+    pub fn load() -> anyhow::Result<Self> {
+        let Some(path) = config_path() else {
+            return Ok(Config::default());
+        };
+        match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).with_context(|| format!("Invalid config {}", path.display())),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(e).with_context(|| format!("Cannot read config {}", path.display())),
+        }
+    }
+
+    pub fn profile(&self, name: &str) -> anyhow::Result<Profile> {
+        self.profiles.get(name).cloned().ok_or_else(|| {
+            let path = config_path().map(|path| path.display().to_string()).unwrap_or_default();
+            anyhow::anyhow!("Profile {} is not defined in {}", name, path)
+        })
+    }
+}
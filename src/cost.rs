@@ -0,0 +1,238 @@
+use indicatif::HumanBytes;
+use std::collections::BTreeMap;
+
+use crate::{azure, gcs, s3};
+
+/// The stores `Backend::object` names for AWS S3 and Google Cloud Storage
+pub const AWS_S3: &str = "s3:amazonaws.com";
+pub const GCS: &str = "gs:https://storage.googleapis.com";
+
+// Whether `store` is an account of Azure's public cloud, az:https://account.blob.core.windows.net
+fn is_azure(store: &str) -> bool {
+    store.starts_with("az:https://") && store.ends_with(".blob.core.windows.net")
+}
+
+const GIB: f64 = (1u64 << 30) as f64;
+
+// List prices in USD of a store: per 1000 requests that write (PUT, COPY,
+// POST, LIST) and that read (GET, HEAD), per GiB-month stored and per GiB
+// sent out to the internet
+struct Prices {
+    name: &'static str,
+    // The region the prices are those of, which most others are close to
+    region: &'static str,
+    write: f64,
+    read: f64,
+    storage: f64,
+    egress: f64,
+}
+
+// Other stores are not estimated: their prices are not public or not per request
+fn prices(store: &str, storage_class: Option<&str>) -> Option<Prices> {
+    let class = storage_class.map(str::to_uppercase);
+    match store {
+        AWS_S3 => {
+            let (write, read, storage) = match class.as_deref() {
+                None | Some("STANDARD" | "REDUCED_REDUNDANCY" | "INTELLIGENT_TIERING") => (0.005, 0.0004, 0.023),
+                Some("STANDARD_IA") => (0.01, 0.001, 0.0125),
+                Some("ONEZONE_IA") => (0.01, 0.001, 0.01),
+                Some("GLACIER_IR") => (0.02, 0.01, 0.004),
+                Some("GLACIER") => (0.03, 0.0004, 0.0036),
+                Some("DEEP_ARCHIVE") => (0.05, 0.0004, 0.00099),
+                Some("EXPRESS_ONEZONE") => (0.00113, 0.00003, 0.11),
+                Some(_) => return None,
+            };
+            Some(Prices { name: "AWS S3", region: "us-east-1", write, read, storage, egress: 0.09 })
+        }
+        GCS => {
+            // Class A and class B operations
+            let (write, read, storage) = match class.as_deref() {
+                None | Some("STANDARD") => (0.005, 0.0004, 0.02),
+                Some("NEARLINE") => (0.01, 0.001, 0.01),
+                Some("COLDLINE") => (0.02, 0.01, 0.004),
+                Some("ARCHIVE") => (0.05, 0.05, 0.0012),
+                Some(_) => return None,
+            };
+            Some(Prices { name: "Google Cloud Storage", region: "us-central1", write, read, storage, egress: 0.12 })
+        }
+        store if is_azure(store) => {
+            // Block blobs in locally redundant storage
+            let (write, read, storage) = match class.as_deref() {
+                None | Some("HOT") => (0.0055, 0.00044, 0.018),
+                Some("COOL") => (0.01, 0.001, 0.01),
+                Some("COLD") => (0.018, 0.01, 0.0036),
+                Some("ARCHIVE") => (0.011, 0.55, 0.00099),
+                Some(_) => return None,
+            };
+            Some(Prices { name: "Azure Blob Storage", region: "eastus", write, read, storage, egress: 0.087 })
+        }
+        _ => None,
+    }
+}
+
+// Requests writing, reading and copying an object take on `store`
+fn upload_requests(store: &str, size: u64) -> u64 {
+    match store.split_once(':').map(|(scheme, _)| scheme) {
+        Some("gs") => gcs::upload_requests(size),
+        Some("az") => azure::upload_requests(size),
+        _ => s3::upload_requests(size),
+    }
+}
+
+fn download_requests(store: &str, size: u64) -> u64 {
+    match store.split_once(':').map(|(scheme, _)| scheme) {
+        Some("gs") => gcs::download_requests(size),
+        Some("az") => azure::download_requests(size),
+        _ => s3::download_requests(size),
+    }
+}
+
+fn copy_requests(store: &str, size: u64) -> u64 {
+    match store.split_once(':').map(|(scheme, _)| scheme) {
+        // One rewrite call, more only between locations or storage classes
+        Some("gs") => 1,
+        // One Copy Blob, done at once within an account
+        Some("az") => 1,
+        _ => s3::copy_requests(size),
+    }
+}
+
+// What a run does on one store
+#[derive(Default)]
+struct Usage {
+    writes: u64,
+    reads: u64,
+    stored: u64,
+    egress: u64,
+}
+
+/// Requests and bytes a run is about to cost on the object stores it reads
+/// and writes, printed before it starts so a large transfer does not first
+/// show up on the bill. Copies between buckets of one store count as
+/// requests only; transfer between regions is not included.
+pub struct Estimate {
+    storage_class: Option<String>,
+    stores: BTreeMap<String, Usage>,
+}
+
+impl Estimate {
+    pub fn new(storage_class: Option<&str>) -> Self {
+        Estimate { storage_class: storage_class.map(str::to_string), stores: BTreeMap::new() }
+    }
+
+    fn usage(&mut self, store: &str) -> &mut Usage {
+        self.stores.entry(store.to_string()).or_default()
+    }
+
+    /// A file of `size` bytes written to `store`
+    pub fn upload(&mut self, store: &str, size: u64) {
+        let usage = self.usage(store);
+        usage.writes += upload_requests(store, size);
+        usage.stored += size;
+    }
+
+    /// A file of `size` bytes read from `store` to somewhere else
+    pub fn download(&mut self, store: &str, size: u64) {
+        let usage = self.usage(store);
+        usage.reads += download_requests(store, size);
+        usage.egress += size;
+    }
+
+    /// A file of `size` bytes copied by `store` itself
+    pub fn copy(&mut self, store: &str, size: u64) {
+        let usage = self.usage(store);
+        usage.writes += copy_requests(store, size);
+        usage.stored += size;
+    }
+
+    // The cost on each store prices are known for
+    fn costs(&self) -> Vec<Cost> {
+        self.stores.iter()
+            .filter_map(|(store, usage)| {
+                // --storage-class is that of what is written, not of what is read
+                let read = prices(store, None)?.read;
+                let prices = prices(store, self.storage_class.as_deref())?;
+                Some(Cost {
+                    name: prices.name,
+                    region: prices.region,
+                    requests: usage.writes + usage.reads,
+                    request_cost: (usage.writes as f64 * prices.write + usage.reads as f64 * read) / 1000.0,
+                    stored: usage.stored,
+                    storage_cost: usage.stored as f64 / GIB * prices.storage,
+                    egress: usage.egress,
+                    egress_cost: usage.egress as f64 / GIB * prices.egress,
+                })
+            })
+            .collect()
+    }
+
+    pub fn print(&self) {
+        for cost in self.costs() {
+            let mut line = format!("💰 Estimated {} cost: {} requests {}", cost.name, cost.requests, dollars(cost.request_cost));
+            if cost.stored > 0 {
+                line += &format!(", {} stored {} a month", HumanBytes(cost.stored), dollars(cost.storage_cost));
+            }
+            if cost.egress > 0 {
+                line += &format!(", {} out {}", HumanBytes(cost.egress), dollars(cost.egress_cost));
+            }
+            println!("{} ({} list prices)", line, cost.region);
+        }
+    }
+}
+
+// An estimate for one store, with storage per month
+struct Cost {
+    name: &'static str,
+    region: &'static str,
+    requests: u64,
+    request_cost: f64,
+    stored: u64,
+    storage_cost: f64,
+    egress: u64,
+    egress_cost: f64,
+}
+
+fn dollars(amount: f64) -> String {
+    match amount {
+        0.0 => "$0".to_string(),
+        amount if amount < 0.01 => "<$0.01".to_string(),
+        amount => format!("${:.2}", amount),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn estimates() {
+        let mut estimate = Estimate::new(None);
+        // 1000 small files, one PUT each
+        for _ in 0..1000 {
+            estimate.upload(AWS_S3, 1 << 20);
+        }
+        // 1 GiB in 128 parts of 8 MiB, plus start and complete
+        estimate.upload(AWS_S3, 1 << 30);
+        // 10 GiB in ranges of 4 MiB, plus a HEAD
+        estimate.download(AWS_S3, 10 << 30);
+        estimate.upload("s3:http://localhost:9000", 1 << 30);
+        let costs = estimate.costs();
+        assert_eq!(costs.len(), 1);
+        let cost = &costs[0];
+        assert_eq!(cost.requests, 1000 + 130 + 2561);
+        assert!((cost.request_cost - (1130.0 * 0.005 + 2561.0 * 0.0004) / 1000.0).abs() < 1e-9);
+        assert_eq!(cost.stored, (1 << 30) + 1000 * (1 << 20));
+        assert!((cost.storage_cost - (1.0 + 1000.0 / 1024.0) * 0.023).abs() < 1e-9);
+        assert_eq!(cost.egress, 10 << 30);
+        assert!((cost.egress_cost - 0.9).abs() < 1e-9);
+
+        assert!(prices(AWS_S3, Some("glacier_ir")).is_some_and(|prices| prices.storage == 0.004));
+        assert!(prices(AWS_S3, Some("NO_SUCH_CLASS")).is_none());
+        assert!(prices(GCS, Some("nearline")).is_some_and(|prices| prices.storage == 0.01));
+        assert!(prices("az:https://account.blob.core.windows.net", Some("Cool")).is_some_and(|prices| prices.storage == 0.01));
+        assert!(prices("az:http://127.0.0.1:10000/devstoreaccount1", None).is_none());
+        assert_eq!(dollars(0.0), "$0");
+        assert_eq!(dollars(0.004), "<$0.01");
+        assert_eq!(dollars(12.345), "$12.35");
+    }
+}
@@ -0,0 +1,38 @@
+// --cpu-limit: a cap on the cores that compression and hashing take, for runs
+// on production hosts whose service must keep its CPU. Hashing pools get at
+// most that many threads, and the transfers take one of that many slots for
+// each chunk they compress or hash, so however many transfers run at once no
+// more cores than the limit are busy with either.
+
+use std::sync::{Condvar, Mutex};
+
+pub struct CpuLimit {
+    // Slots not taken
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl CpuLimit {
+    pub fn new(cores: usize) -> Self {
+        CpuLimit { free: Mutex::new(cores.max(1)), freed: Condvar::new() }
+    }
+
+    /// Wait for a core, held until the slot is dropped
+    pub fn slot(&self) -> Slot<'_> {
+        let mut free = self.free.lock().unwrap();
+        while *free == 0 {
+            free = self.freed.wait(free).unwrap();
+        }
+        *free -= 1;
+        Slot(self)
+    }
+}
+
+pub struct Slot<'a>(&'a CpuLimit);
+
+impl Drop for Slot<'_> {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
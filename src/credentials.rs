@@ -0,0 +1,341 @@
+use anyhow::Context;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+use zeroize::Zeroizing;
+
+#[derive(Default)]
+struct Known {
+    // Typed first (or from SSH_PASSWORD), tried on every host
+    shared: Option<Zeroizing<String>>,
+    // For hosts that rejected the shared password
+    per_host: HashMap<String, Zeroizing<String>>,
+    // Of encrypted private keys, by key file
+    passphrases: HashMap<PathBuf, Zeroizing<String>>,
+    // Keys and PKCS#11 providers this run added to ssh-agent
+    in_agent: HashSet<PathBuf>,
+}
+
+/// Passwords of one run. In cluster mode the password is asked for once and
+/// reused for every host that accepts it; hosts that do not get their own
+/// prompt. Everything is wiped from memory when the run ends.
+pub struct Passwords {
+    known: Mutex<Known>,
+}
+
+impl Passwords {
+    /// Starts from SSH_PASSWORD when it is set, so it is not a `Default`
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        let shared = env::var("SSH_PASSWORD").ok().map(Zeroizing::new);
+        Passwords { known: Mutex::new(Known { shared, ..Known::default() }) }
+    }
+
+    /// The password to try first for `host`, if one is known
+    pub fn get(&self, host: &str) -> Option<Zeroizing<String>> {
+        let known = self.known.lock().unwrap();
+        known.per_host.get(host).or(known.shared.as_ref()).cloned()
+    }
+
+    /// Ask for the password of `user@host`. `rejected` is the password the
+    /// host just refused; if another connection learned a different one while
+    /// we waited for the prompt, that one is returned instead of asking again.
+    pub fn prompt(&self, user: &str, host: &str, rejected: Option<&str>) -> anyhow::Result<Zeroizing<String>> {
+        // Held across the prompt so parallel handshakes ask one at a time
+        let mut known = self.known.lock().unwrap();
+        if let Some(password) = known.per_host.get(host).or(known.shared.as_ref())
+            && Some(password.as_str()) != rejected {
+            return Ok(password.clone());
+        }
+        eprint!("Password for {}@{}: ", user, host);
+        io::stderr().flush()?;
+        let password = Zeroizing::new(rpassword::read_password()?);
+        if known.shared.is_none() {
+            known.shared = Some(password.clone());
+        } else {
+            known.per_host.insert(host.to_string(), password.clone());
+        }
+        Ok(password)
+    }
+
+    /// The passphrase of the private key `key`, asked for the first time it
+    /// is needed and reused for every connection after that
+    pub fn passphrase(&self, key: &Path) -> anyhow::Result<Zeroizing<String>> {
+        let mut known = self.known.lock().unwrap();
+        if let Some(passphrase) = known.passphrases.get(key) {
+            return Ok(passphrase.clone());
+        }
+        eprint!("Enter passphrase for key {}: ", key.display());
+        io::stderr().flush()?;
+        let passphrase = Zeroizing::new(rpassword::read_password()?);
+        known.passphrases.insert(key.to_path_buf(), passphrase.clone());
+        Ok(passphrase)
+    }
+
+    /// Prompts for a keyboard-interactive login of `user@host`
+    pub fn interactive<'a>(&'a self, user: &'a str, host: &'a str) -> Interactive<'a> {
+        Interactive { passwords: self, user, host, tried_known: false }
+    }
+
+    /// Drop a passphrase the key did not accept, so it is asked for again
+    pub fn forget_passphrase(&self, key: &Path) {
+        self.known.lock().unwrap().passphrases.remove(key);
+    }
+
+    /// Add the encrypted key `key` to the running ssh-agent, once per run.
+    /// ssh-add asks for its passphrase itself; false when there is no agent
+    /// or the key was not added.
+    pub fn add_to_agent(&self, key: &Path) -> bool {
+        self.ssh_add(key, false)
+    }
+
+    /// Load the keys of a smartcard or token into the running ssh-agent
+    /// through its PKCS#11 library, once per run. ssh-add asks for the PIN;
+    /// false when there is no agent or the keys were not added.
+    pub fn add_provider_to_agent(&self, provider: &Path) -> bool {
+        self.ssh_add(provider, true)
+    }
+
+    fn ssh_add(&self, path: &Path, provider: bool) -> bool {
+        let mut known = self.known.lock().unwrap();
+        if known.in_agent.contains(path) {
+            return true;
+        }
+        if env::var_os("SSH_AUTH_SOCK").is_none() {
+            return false;
+        }
+        let mut command = Command::new("ssh-add");
+        if provider {
+            command.arg("-s");
+        } else {
+            command.arg("--");
+        }
+        let added = command.arg(path).status().is_ok_and(|status| status.success());
+        if added {
+            known.in_agent.insert(path.to_path_buf());
+        }
+        added
+    }
+}
+
+/// Answers keyboard-interactive challenges (one-time codes, PAM prompts) on
+/// the terminal. A hidden password prompt is answered with the password this
+/// run already knows the first time, and a password typed is kept like one
+/// typed for password authentication.
+pub struct Interactive<'a> {
+    passwords: &'a Passwords,
+    user: &'a str,
+    host: &'a str,
+    tried_known: bool,
+}
+
+impl ssh2::KeyboardInteractivePrompt for Interactive<'_> {
+    fn prompt<'b>(&mut self, _username: &str, instructions: &str, prompts: &[ssh2::Prompt<'b>]) -> Vec<String> {
+        // Held across the prompts so parallel handshakes ask one at a time
+        let mut known = self.passwords.known.lock().unwrap();
+        if !instructions.trim().is_empty() {
+            eprintln!("{}", instructions.trim());
+        }
+        let mut answers = vec![];
+        for prompt in prompts {
+            let password = !prompt.echo && prompt.text.to_lowercase().contains("password");
+            if password && !self.tried_known
+                && let Some(known) = known.per_host.get(self.host).or(known.shared.as_ref()) {
+                self.tried_known = true;
+                answers.push(known.to_string());
+                continue;
+            }
+            eprint!("({}@{}) {} ", self.user, self.host, prompt.text.trim_end());
+            let _ = io::stderr().flush();
+            let answer = if prompt.echo {
+                let mut line = String::new();
+                io::stdin().read_line(&mut line).map(|_| line.trim_end_matches(['\r', '\n']).to_string())
+            } else {
+                rpassword::read_password()
+            };
+            let answer = answer.unwrap_or_default();
+            if password {
+                let typed = Zeroizing::new(answer.clone());
+                if known.shared.is_none() {
+                    known.shared = Some(typed);
+                } else {
+                    known.per_host.insert(self.host.to_string(), typed);
+                }
+            }
+            answers.push(answer);
+        }
+        answers
+    }
+}
+
+/// A credential obtained from a provider at connect time
+pub enum Credential {
+    Password(Zeroizing<String>),
+    // Private key to authenticate with, alongside a freshly signed certificate
+    Certificate { key: PathBuf, certificate: TempFile },
+}
+
+/// A file only this user can read, removed when dropped
+pub struct TempFile(PathBuf);
+
+impl TempFile {
+    fn new(name: &str, contents: &str) -> anyhow::Result<Self> {
+        let path = std::env::temp_dir().join(format!("cpx-{}-{}", uuid::Uuid::new_v4(), name));
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create_new(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+        let file = TempFile(path);
+        options.open(&file.0)
+            .and_then(|mut out| out.write_all(contents.as_bytes()))
+            .with_context(|| format!("Cannot write {}", file.0.display()))?;
+        Ok(file)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Source of short-lived SSH credentials, asked for each new connection
+pub trait CredentialProvider: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn fetch(&self, user: &str, host: &str) -> anyhow::Result<Credential>;
+}
+
+/// Credential provider settings of a profile
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "provider", rename_all = "kebab-case")]
+pub enum ProviderConfig {
+    // Password read from a Vault KV secret
+    Vault {
+        path: String,
+        #[serde(default = "default_field")]
+        field: String,
+    },
+    // Certificate signed by Vault's SSH secrets engine for an existing key
+    VaultSsh {
+        #[serde(default = "default_mount")]
+        mount: String,
+        role: String,
+        key: PathBuf,
+    },
+    // Password read from an AWS SSM SecureString parameter
+    Ssm {
+        parameter: String,
+        region: Option<String>,
+    },
+}
+
+fn default_field() -> String {
+    "password".to_string()
+}
+
+fn default_mount() -> String {
+    "ssh".to_string()
+}
+
+impl ProviderConfig {
+    pub fn build(&self) -> Box<dyn CredentialProvider> {
+        match self.clone() {
+            ProviderConfig::Vault { path, field } => Box::new(VaultKv { path, field }),
+            ProviderConfig::VaultSsh { mount, role, key } => Box::new(VaultSsh { mount, role, key }),
+            ProviderConfig::Ssm { parameter, region } => Box::new(Ssm { parameter, region }),
+        }
+    }
+}
+
+// "{host}" and "{user}" in provider settings select per-host secrets
+fn expand(template: &str, user: &str, host: &str) -> String {
+    template.replace("{host}", host).replace("{user}", user)
+}
+
+// Run a helper CLI and return its trimmed output
+fn run(command: &mut Command) -> anyhow::Result<Zeroizing<String>> {
+    let program = command.get_program().to_string_lossy().into_owned();
+    let output = command
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Cannot run {} (is it installed?)", program))?;
+    let stdout = Zeroizing::new(String::from_utf8_lossy(&output.stdout).trim_end().to_string());
+    if !output.status.success() {
+        anyhow::bail!("{} failed: {}", program, String::from_utf8_lossy(&output.stderr).trim());
+    }
+    Ok(stdout)
+}
+
+struct VaultKv {
+    path: String,
+    field: String,
+}
+
+impl CredentialProvider for VaultKv {
+    fn name(&self) -> &'static str {
+        "vault"
+    }
+
+    fn fetch(&self, user: &str, host: &str) -> anyhow::Result<Credential> {
+        let password = run(Command::new("vault")
+            .args(["kv", "get", &format!("-field={}", self.field)])
+            .arg(expand(&self.path, user, host)))?;
+        Ok(Credential::Password(password))
+    }
+}
+
+struct VaultSsh {
+    mount: String,
+    role: String,
+    key: PathBuf,
+}
+
+impl CredentialProvider for VaultSsh {
+    fn name(&self) -> &'static str {
+        "vault-ssh"
+    }
+
+    fn fetch(&self, user: &str, _host: &str) -> anyhow::Result<Credential> {
+        let mut public = self.key.clone().into_os_string();
+        public.push(".pub");
+        let signed = run(Command::new("vault")
+            .args(["write", "-field=signed_key"])
+            .arg(format!("{}/sign/{}", self.mount, self.role))
+            .arg(format!("public_key=@{}", Path::new(&public).display()))
+            .arg(format!("valid_principals={}", user)))?;
+        // Kept out of the key's directory, it only lives for this login
+        let certificate = TempFile::new("cert.pub", &format!("{}\n", signed.as_str()))?;
+        Ok(Credential::Certificate { key: self.key.clone(), certificate })
+    }
+}
+
+struct Ssm {
+    parameter: String,
+    region: Option<String>,
+}
+
+impl CredentialProvider for Ssm {
+    fn name(&self) -> &'static str {
+        "ssm"
+    }
+
+    fn fetch(&self, user: &str, host: &str) -> anyhow::Result<Credential> {
+        let mut command = Command::new("aws");
+        command
+            .args(["ssm", "get-parameter", "--with-decryption", "--query", "Parameter.Value", "--output", "text", "--name"])
+            .arg(expand(&self.parameter, user, host));
+        if let Some(region) = &self.region {
+            command.args(["--region", region]);
+        }
+        Ok(Credential::Password(run(&mut command)?))
+    }
+}
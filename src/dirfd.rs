@@ -0,0 +1,716 @@
+// File operations relative to a root directory handle.
+//
+// On Unix every component below the root is resolved with openat/mkdirat on
+// the parent's descriptor, so no operation ever needs the full absolute path
+// and trees deeper than PATH_MAX still work. Elsewhere we fall back to std.
+
+use std::ffi::OsString;
+use std::fs::File;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// --skip-pseudo-fs, for every walk of the run
+static SKIP_PSEUDO_FS: AtomicBool = AtomicBool::new(false);
+
+/// A file or directory found by `walk`
+#[derive(Debug, Clone)]
+pub struct Entry {
+    path: PathBuf,
+    is_dir: bool,
+    is_file: bool,
+    size: u64,
+    mtime: u64,
+    skipped: bool,
+    id: Option<u64>,
+    pruned: bool,
+}
+
+impl Entry {
+    /// Full path of the entry, for display and for operations that are known
+    /// to stay short; use the `*_beneath` helpers for file IO
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Regular file, or a symlink pointing at one
+    pub fn is_file(&self) -> bool {
+        self.is_file
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Size and modification time, as `stat_beneath` would report them
+    pub fn stat(&self) -> Stat {
+        Stat { size: self.size, mtime: self.mtime }
+    }
+
+    /// A directory on a pseudo filesystem that the walk did not enter
+    pub fn skipped(&self) -> bool {
+        self.skipped
+    }
+
+    /// For a directory the walk entered, a hash of its device, inode and
+    /// change times; it stays the same as long as no entry is added to,
+    /// removed from or renamed in the directory. None where this cannot be told.
+    pub fn id(&self) -> Option<u64> {
+        self.id
+    }
+
+    /// A directory that was not read, only the subdirectories its `Known`
+    /// callback returned were entered
+    pub fn pruned(&self) -> bool {
+        self.pruned
+    }
+}
+
+/// Called by a walk with the path and `Entry::id` of each directory before
+/// reading it. Returning names enters only those subdirectories instead:
+/// the directory's files are neither listed nor looked at.
+pub type Known = Box<dyn FnMut(&Path, u64) -> Option<Vec<OsString>> + Send>;
+
+/// Size and modification time (seconds since the epoch) of an existing file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stat {
+    pub size: u64,
+    pub mtime: u64,
+}
+
+/// Stat `rel` (relative to `root`) without following a final symlink,
+/// returning None when it does not exist
+pub fn stat_beneath(root: &Path, rel: &Path) -> io::Result<Option<Stat>> {
+    match imp::stat_beneath(root, rel) {
+        Ok(stat) => Ok(Some(stat)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Make walks leave out directories on kernel pseudo filesystems (/proc,
+/// /sys and the like), whose files have made-up sizes
+pub fn skip_pseudo_fs(skip: bool) {
+    SKIP_PSEUDO_FS.store(skip, Ordering::Relaxed);
+}
+
+/// Whether an open file is on a kernel pseudo filesystem, where sizes say
+/// nothing about the contents; always false where this cannot be told
+pub fn is_pseudo_fs(file: &File) -> bool {
+    imp::is_pseudo_fs(file)
+}
+
+/// Walk `root` recursively, yielding the root itself first. Symlinks to
+/// directories are reported but not followed.
+pub fn walk(root: &Path) -> impl Iterator<Item = io::Result<Entry>> {
+    imp::Walk::new(root, None)
+}
+
+/// `walk`, skipping the directories `known` has already seen
+pub fn walk_known(root: &Path, known: Option<Known>) -> impl Iterator<Item = io::Result<Entry>> {
+    imp::Walk::new(root, known)
+}
+
+/// Open `rel` (relative to `root`) for reading
+pub fn open_beneath(root: &Path, rel: &Path) -> io::Result<File> {
+    imp::open_beneath(root, rel)
+}
+
+/// Create or truncate `rel` (relative to `root`) for writing, creating any
+/// missing parent directories on the way. A new file gets `mode` minus the
+/// umask, an existing one keeps its permissions. Symlinks below `root`, in
+/// the parents or as the file itself, are refused rather than followed.
+pub fn create_beneath(root: &Path, rel: &Path, mode: u32) -> io::Result<File> {
+    imp::create_beneath(root, rel, mode)
+}
+
+/// Permission bits of an open file, including setuid/setgid/sticky; None
+/// where the platform has no such thing
+pub fn file_mode(file: &File) -> io::Result<Option<u32>> {
+    imp::file_mode(file)
+}
+
+/// Set the permission bits of an open file, as chmod would (no umask)
+pub fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+    imp::set_mode(file, mode)
+}
+
+/// Open the existing file `rel` (relative to `root`) for appending
+pub fn append_beneath(root: &Path, rel: &Path) -> io::Result<File> {
+    imp::append_beneath(root, rel)
+}
+
+/// Remove the file `rel` (relative to `root`); a file that is already gone
+/// is not an error
+pub fn remove_beneath(root: &Path, rel: &Path) -> io::Result<()> {
+    match imp::remove_beneath(root, rel) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        r => r,
+    }
+}
+
+/// Move the file `from` to `to` (both relative to `root`), replacing any
+/// file there
+pub fn rename_beneath(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+    imp::rename_beneath(root, from, to)
+}
+
+/// Remove the empty directory `rel` (relative to `root`)
+pub fn remove_dir_beneath(root: &Path, rel: &Path) -> io::Result<()> {
+    imp::remove_dir_beneath(root, rel)
+}
+
+#[cfg(unix)]
+mod imp {
+    use std::ffi::CString;
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::{Component, Path, PathBuf};
+
+    use super::{Entry, Known, Ordering, Stat, SKIP_PSEUDO_FS};
+    use crate::utils;
+
+    // statfs magic numbers of proc, sysfs, debugfs, tracefs, cgroup, cgroup2,
+    // securityfs, pstore, bpf, configfs, efivarfs and devpts
+    #[cfg(target_os = "linux")]
+    const PSEUDO_FS: [i64; 12] = [
+        0x9fa0, 0x62656572, 0x64626720, 0x74726163, 0x27e0eb, 0x63677270,
+        0x73636673, 0x6165676c, 0xcafe4a11, 0x62656570, 0xde5e81e4, 0x1cd1,
+    ];
+
+    #[cfg(target_os = "linux")]
+    pub fn is_pseudo_fs(fd: &impl AsFd) -> bool {
+        let mut st: libc::statfs = unsafe { std::mem::zeroed() };
+        let ok = unsafe { libc::fstatfs(fd.as_fd().as_raw_fd(), &mut st) } == 0;
+        // f_type is an i64 on some targets and narrower on others
+        #[allow(clippy::useless_conversion)]
+        let fs_type = i64::from(st.f_type);
+        ok && PSEUDO_FS.contains(&fs_type)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn is_pseudo_fs(_fd: &impl AsFd) -> bool {
+        false
+    }
+
+    // Whether the walk should leave out the directory `fd`
+    fn skip_dir(fd: &OwnedFd) -> bool {
+        SKIP_PSEUDO_FS.load(Ordering::Relaxed) && is_pseudo_fs(fd)
+    }
+
+    fn cstr(name: &std::ffi::OsStr) -> io::Result<CString> {
+        CString::new(name.as_bytes()).map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains NUL byte"))
+    }
+
+    fn open_root(root: &Path) -> io::Result<OwnedFd> {
+        // The parent of a bare relative name is the empty path
+        let root = if root.as_os_str().is_empty() { Path::new(".") } else { root };
+        let path = cstr(root.as_os_str())?;
+        let fd = unsafe { libc::open(path.as_ptr(), libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    fn openat(dir: &OwnedFd, name: &CString, flags: libc::c_int, mode: libc::c_uint) -> io::Result<OwnedFd> {
+        let fd = unsafe { libc::openat(dir.as_raw_fd(), name.as_ptr(), flags | libc::O_CLOEXEC, mode) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+    }
+
+    // Split `rel` into its directory components and file name, rejecting
+    // anything that is not a plain relative path
+    fn split(rel: &Path) -> io::Result<(Vec<CString>, CString)> {
+        let mut names = vec![];
+        for component in rel.components() {
+            match component {
+                Component::Normal(name) => names.push(cstr(name)?),
+                Component::CurDir => {}
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("not a relative path: {}", rel.display()))),
+            }
+        }
+        let file = names.pop().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "empty path"))?;
+        Ok((names, file))
+    }
+
+    // Directory descriptors kept open while walking; deeper levels give up
+    // their descriptor and reopen it through ".." on the way back up
+    const MAX_OPEN: usize = 64;
+
+    struct Level {
+        fd: Option<OwnedFd>,
+        names: std::vec::IntoIter<CString>,
+        path: PathBuf,
+    }
+
+    pub struct Walk {
+        root: Option<PathBuf>,
+        stack: Vec<Level>,
+        known: Option<Known>,
+    }
+
+    impl Walk {
+        pub fn new(root: &Path, known: Option<Known>) -> Self {
+            Walk { root: Some(root.to_path_buf()), stack: vec![], known }
+        }
+
+        // Enter the directory `fd` of `entry`, reading it unless it is known
+        fn enter(&mut self, fd: OwnedFd, entry: &mut Entry, id: u64) -> io::Result<()> {
+            entry.id = Some(id);
+            let names = match self.known.as_mut().and_then(|known| known(&entry.path, id)) {
+                Some(subdirs) => {
+                    entry.pruned = true;
+                    subdirs.iter().map(|name| cstr(name)).collect::<io::Result<Vec<_>>>()?
+                }
+                None => read_names(&fd)?,
+            };
+            self.push(fd, entry.path.clone(), names)
+        }
+
+        fn push(&mut self, fd: OwnedFd, path: PathBuf, names: Vec<CString>) -> io::Result<()> {
+            self.stack.push(Level { fd: Some(fd), names: names.into_iter(), path });
+            if self.stack.len() > MAX_OPEN {
+                let idx = self.stack.len() - MAX_OPEN - 1;
+                self.stack[idx].fd = None;
+            }
+            Ok(())
+        }
+
+        fn pop(&mut self) -> io::Result<()> {
+            let level = self.stack.pop().unwrap();
+            if let Some(parent) = self.stack.last_mut()
+                && parent.fd.is_none() {
+                let child = level.fd.ok_or_else(|| io::Error::other("lost directory handle"))?;
+                parent.fd = Some(openat(&child, &CString::new("..").unwrap(), libc::O_RDONLY | libc::O_DIRECTORY, 0)?);
+            }
+            Ok(())
+        }
+
+        fn root_entry(&mut self, root: PathBuf) -> io::Result<Entry> {
+            let meta = std::fs::metadata(&root)?;
+            let mut entry = Entry {
+                is_dir: meta.is_dir(),
+                is_file: meta.is_file(),
+                size: meta.len(),
+                mtime: meta.mtime().max(0) as u64,
+                path: root.clone(),
+                skipped: false,
+                id: None,
+                pruned: false,
+            };
+            if entry.is_dir {
+                let fd = open_root(&root)?;
+                entry.skipped = skip_dir(&fd);
+                if !entry.skipped {
+                    let id = dir_id(meta.dev(), meta.ino(), [meta.mtime(), meta.mtime_nsec(), meta.ctime(), meta.ctime_nsec()]);
+                    self.enter(fd, &mut entry, id)?;
+                }
+            }
+            Ok(entry)
+        }
+
+        fn child_entry(&mut self, name: CString) -> io::Result<Entry> {
+            let level = self.stack.last().unwrap();
+            let dir = level.fd.as_ref().unwrap();
+            let path = level.path.join(std::ffi::OsStr::from_bytes(name.as_bytes()));
+            let with_path = |err: io::Error| io::Error::new(err.kind(), format!("{}: {}", path.display(), err));
+            let mut st = fstatat(dir, &name, libc::AT_SYMLINK_NOFOLLOW).map_err(with_path)?;
+            let is_link = st.st_mode & libc::S_IFMT == libc::S_IFLNK;
+            if is_link {
+                // Report what the link points at, dangling links as neither file nor dir
+                st = fstatat(dir, &name, 0).unwrap_or(st);
+            }
+            let fmt = st.st_mode & libc::S_IFMT;
+            let mut entry = Entry {
+                path: path.clone(),
+                is_dir: fmt == libc::S_IFDIR,
+                is_file: fmt == libc::S_IFREG,
+                size: st.st_size as u64,
+                mtime: st.st_mtime.max(0) as u64,
+                skipped: false,
+                id: None,
+                pruned: false,
+            };
+            if entry.is_dir && !is_link {
+                let fd = openat(dir, &name, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0).map_err(with_path)?;
+                entry.skipped = skip_dir(&fd);
+                if !entry.skipped {
+                    #[allow(clippy::unnecessary_cast)]
+                    let times = [st.st_mtime as i64, st.st_mtime_nsec as i64, st.st_ctime as i64, st.st_ctime_nsec as i64];
+                    #[allow(clippy::unnecessary_cast)]
+                    let id = dir_id(st.st_dev as u64, st.st_ino as u64, times);
+                    self.enter(fd, &mut entry, id).map_err(with_path)?;
+                }
+            }
+            Ok(entry)
+        }
+    }
+
+    impl Iterator for Walk {
+        type Item = io::Result<Entry>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if let Some(root) = self.root.take() {
+                return Some(self.root_entry(root));
+            }
+            loop {
+                let level = self.stack.last_mut()?;
+                match level.names.next() {
+                    Some(name) => return Some(self.child_entry(name)),
+                    None => {
+                        if let Err(e) = self.pop() {
+                            // Without a handle on the parent the rest of the walk is unreachable
+                            self.stack.clear();
+                            return Some(Err(e));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    fn dir_id(dev: u64, ino: u64, times: [i64; 4]) -> u64 {
+        let mut hash = utils::fnv1a(&dev.to_le_bytes(), 0);
+        hash = utils::fnv1a(&ino.to_le_bytes(), hash);
+        for time in times {
+            hash = utils::fnv1a(&time.to_le_bytes(), hash);
+        }
+        hash
+    }
+
+    fn fstatat(dir: &OwnedFd, name: &CString, flags: libc::c_int) -> io::Result<libc::stat> {
+        let mut st: libc::stat = unsafe { std::mem::zeroed() };
+        if unsafe { libc::fstatat(dir.as_raw_fd(), name.as_ptr(), &mut st, flags) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(st)
+    }
+
+    // Read all names in a directory, leaving `dir` itself open
+    fn read_names(dir: &OwnedFd) -> io::Result<Vec<CString>> {
+        let dup = unsafe { libc::dup(dir.as_raw_fd()) };
+        if dup < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let stream = unsafe { libc::fdopendir(dup) };
+        if stream.is_null() {
+            let err = io::Error::last_os_error();
+            unsafe { libc::close(dup) };
+            return Err(err);
+        }
+        let mut names = vec![];
+        loop {
+            let ent = unsafe { libc::readdir(stream) };
+            if ent.is_null() {
+                break;
+            }
+            let name = unsafe { std::ffi::CStr::from_ptr((*ent).d_name.as_ptr()) };
+            if name.to_bytes() != b"." && name.to_bytes() != b".." {
+                names.push(name.to_owned());
+            }
+        }
+        unsafe { libc::closedir(stream) };
+        Ok(names)
+    }
+
+    // Open a directory below the root without following symlinks, so a link
+    // planted in a shared destination cannot redirect us outside the root
+    fn open_dir(dir: &OwnedFd, name: &CString) -> io::Result<OwnedFd> {
+        openat(dir, name, libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW, 0).map_err(|err| {
+            // A link to a directory fails with ENOTDIR rather than ELOOP
+            let is_link = fstatat(dir, name, libc::AT_SYMLINK_NOFOLLOW)
+                .is_ok_and(|st| st.st_mode & libc::S_IFMT == libc::S_IFLNK);
+            if is_link { symlink_refused() } else { err }
+        })
+    }
+
+    fn symlink_refused() -> io::Error {
+        io::Error::new(io::ErrorKind::PermissionDenied, "path goes through a symlink below the root, refusing to follow it")
+    }
+
+    fn refuse_symlink(err: io::Error) -> io::Error {
+        if err.raw_os_error() == Some(libc::ELOOP) {
+            return symlink_refused();
+        }
+        err
+    }
+
+    fn open_parent(root: &Path, dirs: &[CString]) -> io::Result<OwnedFd> {
+        let mut dir = open_root(root)?;
+        for name in dirs {
+            dir = open_dir(&dir, name)?;
+        }
+        Ok(dir)
+    }
+
+    pub fn open_beneath(root: &Path, rel: &Path) -> io::Result<File> {
+        let (dirs, file) = split(rel)?;
+        let dir = open_parent(root, &dirs)?;
+        Ok(File::from(openat(&dir, &file, libc::O_RDONLY, 0)?))
+    }
+
+    pub fn stat_beneath(root: &Path, rel: &Path) -> io::Result<Stat> {
+        let (dirs, file) = split(rel)?;
+        let dir = open_parent(root, &dirs)?;
+        let st = fstatat(&dir, &file, libc::AT_SYMLINK_NOFOLLOW)?;
+        Ok(Stat { size: st.st_size as u64, mtime: st.st_mtime.max(0) as u64 })
+    }
+
+    pub fn create_beneath(root: &Path, rel: &Path, mode: u32) -> io::Result<File> {
+        let (dirs, file) = split(rel)?;
+        let mut dir = open_root(root)?;
+        for name in &dirs {
+            if unsafe { libc::mkdirat(dir.as_raw_fd(), name.as_ptr(), 0o777) } < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() != io::ErrorKind::AlreadyExists {
+                    return Err(err);
+                }
+            }
+            dir = open_dir(&dir, name)?;
+        }
+        let flags = libc::O_WRONLY | libc::O_CREAT | libc::O_TRUNC | libc::O_NOFOLLOW;
+        Ok(File::from(openat(&dir, &file, flags, mode & 0o777).map_err(refuse_symlink)?))
+    }
+
+    pub fn file_mode(file: &File) -> io::Result<Option<u32>> {
+        Ok(Some(file.metadata()?.mode() & 0o7777))
+    }
+
+    pub fn set_mode(file: &File, mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(mode & 0o7777))
+    }
+
+    pub fn append_beneath(root: &Path, rel: &Path) -> io::Result<File> {
+        let (dirs, file) = split(rel)?;
+        let dir = open_parent(root, &dirs)?;
+        Ok(File::from(openat(&dir, &file, libc::O_WRONLY | libc::O_APPEND | libc::O_NOFOLLOW, 0).map_err(refuse_symlink)?))
+    }
+
+    pub fn remove_beneath(root: &Path, rel: &Path) -> io::Result<()> {
+        let (dirs, file) = split(rel)?;
+        let dir = open_parent(root, &dirs)?;
+        if unsafe { libc::unlinkat(dir.as_raw_fd(), file.as_ptr(), 0) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn rename_beneath(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_dirs, from_name) = split(from)?;
+        let (to_dirs, to_name) = split(to)?;
+        let from_dir = open_parent(root, &from_dirs)?;
+        let to_dir = open_parent(root, &to_dirs)?;
+        if unsafe { libc::renameat(from_dir.as_raw_fd(), from_name.as_ptr(), to_dir.as_raw_fd(), to_name.as_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn remove_dir_beneath(root: &Path, rel: &Path) -> io::Result<()> {
+        let (dirs, name) = split(rel)?;
+        let dir = open_parent(root, &dirs)?;
+        if unsafe { libc::unlinkat(dir.as_raw_fd(), name.as_ptr(), libc::AT_REMOVEDIR) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(unix))]
+mod imp {
+    use std::fs::{self, File};
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    use super::{Entry, Known, Stat};
+
+    pub struct Walk {
+        root: Option<PathBuf>,
+        // Entries left to yield in each directory being walked, deepest last
+        stack: Vec<std::vec::IntoIter<PathBuf>>,
+        known: Option<Known>,
+    }
+
+    impl Walk {
+        // Directories cannot be told apart by inode here, `known` gets 0 for an id
+        pub fn new(root: &Path, known: Option<Known>) -> Self {
+            Walk { root: Some(root.to_path_buf()), stack: vec![], known }
+        }
+
+        fn entry(&mut self, path: PathBuf) -> io::Result<Entry> {
+            let meta = fs::metadata(&path)?;
+            let mut entry = Entry {
+                is_dir: meta.is_dir(),
+                is_file: meta.is_file(),
+                size: meta.len(),
+                mtime: meta.modified().ok()
+                    .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+                    .map(|d| d.as_secs())
+                    .unwrap_or(0),
+                path,
+                skipped: false,
+                id: None,
+                pruned: false,
+            };
+            // Symlinks to directories are reported but not entered
+            if entry.is_dir && !fs::symlink_metadata(&entry.path)?.file_type().is_symlink() {
+                let paths = match self.known.as_mut().and_then(|known| known(&entry.path, 0)) {
+                    Some(subdirs) => {
+                        entry.pruned = true;
+                        subdirs.iter().map(|name| entry.path.join(name)).collect()
+                    }
+                    None => fs::read_dir(&entry.path)?
+                        .map(|child| child.map(|child| child.path()))
+                        .collect::<io::Result<Vec<_>>>()?,
+                };
+                self.stack.push(paths.into_iter());
+            }
+            Ok(entry)
+        }
+    }
+
+    impl Iterator for Walk {
+        type Item = io::Result<Entry>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if let Some(root) = self.root.take() {
+                return Some(self.entry(root));
+            }
+            loop {
+                match self.stack.last_mut()?.next() {
+                    Some(path) => return Some(self.entry(path)),
+                    None => {
+                        self.stack.pop();
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn is_pseudo_fs(_file: &File) -> bool {
+        false
+    }
+
+    pub fn open_beneath(root: &Path, rel: &Path) -> io::Result<File> {
+        File::open(root.join(rel))
+    }
+
+    pub fn stat_beneath(root: &Path, rel: &Path) -> io::Result<Stat> {
+        let meta = fs::symlink_metadata(root.join(rel))?;
+        let mtime = meta.modified()?.duration_since(std::time::UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        Ok(Stat { size: meta.len(), mtime })
+    }
+
+    // Best effort without openat: refuse existing symlinks on the way down
+    fn check_no_symlinks(root: &Path, rel: &Path) -> io::Result<()> {
+        let mut path = root.to_path_buf();
+        for component in rel.components() {
+            path.push(component);
+            if fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_symlink()) {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, "path goes through a symlink below the root, refusing to follow it"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn create_beneath(root: &Path, rel: &Path, _mode: u32) -> io::Result<File> {
+        check_no_symlinks(root, rel)?;
+        let path = root.join(rel);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        File::create(path)
+    }
+
+    pub fn file_mode(_file: &File) -> io::Result<Option<u32>> {
+        Ok(None)
+    }
+
+    pub fn set_mode(_file: &File, _mode: u32) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn append_beneath(root: &Path, rel: &Path) -> io::Result<File> {
+        check_no_symlinks(root, rel)?;
+        fs::OpenOptions::new().append(true).open(root.join(rel))
+    }
+
+    pub fn remove_beneath(root: &Path, rel: &Path) -> io::Result<()> {
+        fs::remove_file(root.join(rel))
+    }
+
+    pub fn rename_beneath(root: &Path, from: &Path, to: &Path) -> io::Result<()> {
+        check_no_symlinks(root, from)?;
+        check_no_symlinks(root, to)?;
+        fs::rename(root.join(from), root.join(to))
+    }
+
+    pub fn remove_dir_beneath(root: &Path, rel: &Path) -> io::Result<()> {
+        check_no_symlinks(root, rel)?;
+        fs::remove_dir(root.join(rel))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::io::{Read, Write};
+
+    // Deeper than PATH_MAX (4096) and than the descriptors a walk keeps open
+    const DEPTH: usize = 150;
+
+    fn deep_tree(name: &str) -> (PathBuf, PathBuf) {
+        let root = std::env::temp_dir().join(format!("cpx-dirfd-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir(&root).unwrap();
+        let rel = (0..DEPTH).map(|i| format!("{:0>48}", i)).collect::<PathBuf>();
+        assert!(rel.as_os_str().len() > 4096);
+        (root, rel)
+    }
+
+    #[test]
+    fn create_and_stat_beyond_path_max() {
+        let (root, rel) = deep_tree("create");
+        let file = rel.join("file");
+        create_beneath(&root, &file, 0o644).unwrap().write_all(b"deep").unwrap();
+        assert_eq!(stat_beneath(&root, &file).unwrap().map(|stat| stat.size), Some(4));
+        assert!(stat_beneath(&root, &rel.join("missing")).unwrap().is_none());
+        let mut data = String::new();
+        open_beneath(&root, &file).unwrap().read_to_string(&mut data).unwrap();
+        assert_eq!(data, "deep");
+        rename_beneath(&root, &file, &rel.join("moved")).unwrap();
+        remove_beneath(&root, &rel.join("moved")).unwrap();
+        assert!(stat_beneath(&root, &rel.join("moved")).unwrap().is_none());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn walk_beyond_path_max() {
+        let (root, rel) = deep_tree("walk");
+        // A file at every level, so each one is read after the deepest
+        let mut dir = PathBuf::new();
+        for component in rel.components() {
+            dir.push(component);
+            create_beneath(&root, &dir.join("file"), 0o644).unwrap().write_all(b"x").unwrap();
+        }
+        let entries = walk(&root).collect::<io::Result<Vec<_>>>().unwrap();
+        let files = entries.iter().filter(|entry| entry.is_file()).collect::<Vec<_>>();
+        assert_eq!(files.len(), DEPTH);
+        assert!(files.iter().all(|entry| entry.size() == 1 && entry.path().starts_with(&root)));
+        assert_eq!(entries.iter().filter(|entry| entry.is_dir()).count(), DEPTH + 1);
+        assert!(entries.iter().any(|entry| entry.path() == root.join(&rel).join("file")));
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
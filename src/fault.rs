@@ -0,0 +1,104 @@
+// Fault injection, to exercise the retry and resume paths on purpose.
+//
+// Off unless CPX_FAULTS is set (or `cpx selftest` turns it on), e.g.
+// CPX_FAULTS="read=0.01,write=0.01,connect=0.1": each read or write of a file
+// chunk, and each SSH connection attempt, then fails with that probability.
+
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{OnceLock, RwLock};
+
+/// Operations a fault can be injected into
+#[derive(Debug, Clone, Copy)]
+pub enum Kind {
+    Read,
+    Write,
+    Connect,
+}
+
+/// Failure probability per operation
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Faults {
+    pub read: f64,
+    pub write: f64,
+    pub connect: f64,
+}
+
+impl Faults {
+    /// Parse "read=P,write=P,connect=P"; kinds left out never fail
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut faults = Faults::default();
+        for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+            let Some((kind, probability)) = part.split_once('=') else {
+                return Err(format!("expected KIND=PROBABILITY: {}", part));
+            };
+            let probability: f64 = probability.trim().parse()
+                .ok()
+                .filter(|p| (0.0..=1.0).contains(p))
+                .ok_or_else(|| format!("probability must be between 0 and 1: {}", part))?;
+            match kind.trim() {
+                "read" => faults.read = probability,
+                "write" => faults.write = probability,
+                "connect" => faults.connect = probability,
+                other => return Err(format!("unknown fault kind {}, expected read, write or connect", other)),
+            }
+        }
+        Ok(faults)
+    }
+}
+
+fn state() -> &'static RwLock<Option<Faults>> {
+    static STATE: OnceLock<RwLock<Option<Faults>>> = OnceLock::new();
+    STATE.get_or_init(|| {
+        let faults = std::env::var("CPX_FAULTS").ok().map(|spec| match Faults::parse(&spec) {
+            Ok(faults) => faults,
+            Err(e) => {
+                eprintln!("⚠️  Ignoring CPX_FAULTS: {}", e);
+                Faults::default()
+            }
+        });
+        RwLock::new(faults)
+    })
+}
+
+static INJECTED: AtomicU64 = AtomicU64::new(0);
+static RNG: AtomicU64 = AtomicU64::new(0);
+
+/// Replace the faults in effect; None turns injection off
+pub fn set(faults: Option<Faults>) {
+    *state().write().unwrap() = faults;
+}
+
+/// Faults injected so far in this process
+pub fn injected() -> u64 {
+    INJECTED.load(Ordering::Relaxed)
+}
+
+/// Fail the operation about to happen, if the dice say so
+pub fn check(kind: Kind) -> io::Result<()> {
+    let Some(faults) = *state().read().unwrap() else {
+        return Ok(());
+    };
+    let probability = match kind {
+        Kind::Read => faults.read,
+        Kind::Write => faults.write,
+        Kind::Connect => faults.connect,
+    };
+    if probability > 0.0 && roll() < probability {
+        INJECTED.fetch_add(1, Ordering::Relaxed);
+        return Err(io::Error::other(format!("injected {:?} fault", kind).to_lowercase()));
+    }
+    Ok(())
+}
+
+// Uniform in [0, 1): splitmix64 over a shared counter, seeded once per process
+fn roll() -> f64 {
+    if RNG.load(Ordering::Relaxed) == 0 {
+        let _ = RNG.compare_exchange(0, uuid::Uuid::new_v4().as_u64_pair().0 | 1, Ordering::Relaxed, Ordering::Relaxed);
+    }
+    let mut z = RNG.fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed).wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^= z >> 31;
+    (z >> 11) as f64 / (1u64 << 53) as f64
+}
@@ -0,0 +1,526 @@
+// Google Cloud Storage over its JSON API.
+//
+// Credentials are found the way Google's client libraries find them:
+// GOOGLE_OAUTH_ACCESS_TOKEN for a token made elsewhere (`gcloud auth
+// print-access-token`), else the JSON file of GOOGLE_APPLICATION_CREDENTIALS
+// or of `gcloud auth application-default login` (a service account key or a
+// user's refresh token), else the metadata server of a GCE, GKE or Cloud Run
+// host. STORAGE_EMULATOR_HOST points at an emulator (fake-gcs-server), which
+// is used without credentials unless one of the variables names some.
+
+use anyhow::{Context, Result, anyhow};
+use base64::Engine as _;
+use serde_json::{Value, json};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use zeroize::Zeroizing;
+
+use crate::dirfd::Stat;
+use crate::{http, sshconfig, utils};
+
+/// Chunks of a resumable upload; files up to this size are sent in one request
+pub const CHUNK: u64 = 8 << 20;
+const API: &str = "https://storage.googleapis.com";
+const TOKEN_URI: &str = "https://oauth2.googleapis.com/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+// A token this close to expiring is replaced before use
+const TOKEN_MARGIN: u64 = 300;
+
+/// gs://bucket/prefix, split
+pub fn parse(url: &str) -> Result<(String, String)> {
+    let rest = url.strip_prefix("gs://").ok_or_else(|| anyhow!("{} is not a gs:// URL", url))?;
+    let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+    if bucket.is_empty() {
+        anyhow::bail!("{} names no bucket", url);
+    }
+    Ok((bucket.to_string(), prefix.to_string()))
+}
+
+/// Requests writing an object of `size` bytes takes
+pub fn upload_requests(size: u64) -> u64 {
+    match size <= CHUNK {
+        true => 1,
+        // Start, then chunks
+        false => 1 + size.div_ceil(CHUNK),
+    }
+}
+
+/// Requests reading an object of `size` bytes takes (see `Client::read`)
+pub fn download_requests(size: u64) -> u64 {
+    match size < http::PARALLEL_MIN {
+        true => 2,
+        false => 1 + size.div_ceil(http::RANGE),
+    }
+}
+
+fn env(name: &str) -> Option<String> {
+    std::env::var(name).ok().filter(|value| !value.is_empty())
+}
+
+// Where access tokens come from
+enum Credentials {
+    // Emulators take requests without any
+    Anonymous,
+    Token(Zeroizing<String>),
+    ServiceAccount { email: String, key: Box<ring::signature::RsaKeyPair>, token_uri: String },
+    User { client_id: String, client_secret: Zeroizing<String>, refresh_token: Zeroizing<String> },
+    Metadata(String),
+}
+
+fn credentials(emulator: bool) -> Result<Credentials> {
+    if let Some(token) = env("GOOGLE_OAUTH_ACCESS_TOKEN") {
+        return Ok(Credentials::Token(Zeroizing::new(token)));
+    }
+    let named = env("GOOGLE_APPLICATION_CREDENTIALS").map(PathBuf::from);
+    if named.is_none() && emulator {
+        return Ok(Credentials::Anonymous);
+    }
+    let file = named.clone().or_else(|| {
+        let config = env("CLOUDSDK_CONFIG").map(PathBuf::from)
+            .or_else(|| Some(sshconfig::home_dir()?.join(".config").join("gcloud")))?;
+        Some(config.join("application_default_credentials.json")).filter(|path| path.exists())
+    });
+    let Some(file) = file else {
+        let host = env("GCE_METADATA_HOST").unwrap_or_else(|| "metadata.google.internal".to_string());
+        return Ok(Credentials::Metadata(host));
+    };
+    let text = Zeroizing::new(std::fs::read_to_string(&file).with_context(|| format!("Cannot read {}", file.display()))?);
+    let key: Value = serde_json::from_str(&text).with_context(|| format!("{} is not a credentials file", file.display()))?;
+    let field = |name: &str| {
+        key[name].as_str().map(str::to_string).with_context(|| format!("{} has no {}", file.display(), name))
+    };
+    match key["type"].as_str() {
+        Some("service_account") => {
+            let pem = Zeroizing::new(field("private_key")?);
+            let der: Zeroizing<Vec<u8>> = Zeroizing::new(base64::engine::general_purpose::STANDARD.decode(
+                pem.lines().filter(|line| !line.starts_with("-----")).collect::<String>(),
+            )?);
+            let key_pair = ring::signature::RsaKeyPair::from_pkcs8(&der)
+                .map_err(|e| anyhow!("The private key in {} is not usable: {}", file.display(), e))?;
+            Ok(Credentials::ServiceAccount {
+                email: field("client_email")?,
+                key: Box::new(key_pair),
+                token_uri: field("token_uri").unwrap_or_else(|_| TOKEN_URI.to_string()),
+            })
+        }
+        Some("authorized_user") => Ok(Credentials::User {
+            client_id: field("client_id")?,
+            client_secret: Zeroizing::new(field("client_secret")?),
+            refresh_token: Zeroizing::new(field("refresh_token")?),
+        }),
+        other => anyhow::bail!(
+            "{}: credentials of type {} are not supported, use a service account key or `gcloud auth application-default login`",
+            file.display(),
+            other.unwrap_or("(none)")
+        ),
+    }
+}
+
+// An access token and when it expires
+struct Token {
+    value: Zeroizing<String>,
+    expires: u64,
+}
+
+fn base64url(data: &[u8]) -> String {
+    base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(data)
+}
+
+/// One bucket of a GCS store
+pub struct Client {
+    agent: ureq::Agent,
+    // scheme://authority requests go to
+    base: String,
+    bucket: String,
+    credentials: Credentials,
+    token: Mutex<Option<Token>>,
+}
+
+impl Client {
+    pub fn new(bucket: &str) -> Result<Self> {
+        let emulator = env("STORAGE_EMULATOR_HOST").map(|host| match host.contains("://") {
+            true => host.trim_end_matches('/').to_string(),
+            false => format!("http://{}", host.trim_end_matches('/')),
+        });
+        Ok(Client {
+            agent: http::agent(),
+            credentials: credentials(emulator.is_some())?,
+            base: emulator.unwrap_or_else(|| API.to_string()),
+            bucket: bucket.to_string(),
+            token: Mutex::new(None),
+        })
+    }
+
+    pub fn bucket(&self) -> &str {
+        &self.bucket
+    }
+
+    /// The endpoint, objects are only copied by the store itself between
+    /// buckets of the same one
+    pub fn store(&self) -> &str {
+        &self.base
+    }
+
+    fn describe(&self, key: &str) -> String {
+        format!("gs://{}/{}", self.bucket, key)
+    }
+
+    // A fresh token from where the credentials say
+    fn fetch_token(&self) -> Result<Token> {
+        let now = utils::now_secs();
+        let form = |pairs: &[(&str, &str)]| {
+            pairs.iter().map(|(name, value)| format!("{}={}", name, http::encode(value, false))).collect::<Vec<_>>().join("&")
+        };
+        let (what, response) = match &self.credentials {
+            Credentials::Anonymous => return Ok(Token { value: Zeroizing::new(String::new()), expires: u64::MAX }),
+            Credentials::Token(token) => return Ok(Token { value: token.clone(), expires: u64::MAX }),
+            Credentials::ServiceAccount { email, key, token_uri } => {
+                let header = base64url(br#"{"alg":"RS256","typ":"JWT"}"#);
+                let claims = json!({ "iss": email, "scope": SCOPE, "aud": token_uri, "iat": now, "exp": now + 3600 });
+                let message = format!("{}.{}", header, base64url(claims.to_string().as_bytes()));
+                let mut signature = vec![0; key.public().modulus_len()];
+                key.sign(&ring::signature::RSA_PKCS1_SHA256, &ring::rand::SystemRandom::new(), message.as_bytes(), &mut signature)
+                    .map_err(|_| anyhow!("Cannot sign a token request for {}", email))?;
+                let assertion = format!("{}.{}", message, base64url(&signature));
+                let body = form(&[("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"), ("assertion", &assertion)]);
+                let what = format!("Cannot get a token for service account {}", email);
+                let response = http::call(&what, || {
+                    self.agent.post(token_uri).set("Content-Type", "application/x-www-form-urlencoded")
+                }, Some(body.as_bytes()))?;
+                (what, response)
+            }
+            Credentials::User { client_id, client_secret, refresh_token } => {
+                let body = Zeroizing::new(form(&[
+                    ("grant_type", "refresh_token"),
+                    ("client_id", client_id),
+                    ("client_secret", client_secret),
+                    ("refresh_token", refresh_token),
+                ]));
+                let what = "Cannot refresh the application default credentials (run `gcloud auth application-default login`)".to_string();
+                let response = http::call(&what, || {
+                    self.agent.post(TOKEN_URI).set("Content-Type", "application/x-www-form-urlencoded")
+                }, Some(body.as_bytes()))?;
+                (what, response)
+            }
+            Credentials::Metadata(host) => {
+                let url = format!("http://{}/computeMetadata/v1/instance/service-accounts/default/token", host);
+                let what = "No GCS credentials: set GOOGLE_APPLICATION_CREDENTIALS, run `gcloud auth application-default login` \
+                            or set GOOGLE_OAUTH_ACCESS_TOKEN (the metadata server cannot be reached)".to_string();
+                // Off Google Cloud the name does not resolve; fail fast rather than retry
+                let agent = ureq::AgentBuilder::new().timeout(Duration::from_secs(3)).build();
+                let response = agent.get(&url).set("Metadata-Flavor", "Google").call().map_err(|e| http::error(&what, e))?;
+                (what, response)
+            }
+        };
+        let answer: Value = serde_json::from_reader(response.into_reader()).with_context(|| what.clone())?;
+        let value = answer["access_token"].as_str().with_context(|| format!("{}: no access_token in answer", what))?;
+        let expires_in = answer["expires_in"].as_u64().unwrap_or(3600);
+        Ok(Token { value: Zeroizing::new(value.to_string()), expires: now + expires_in })
+    }
+
+    // The Authorization header, None for an emulator
+    fn authorization(&self) -> Result<Option<String>> {
+        if let Credentials::Anonymous = self.credentials {
+            return Ok(None);
+        }
+        let mut token = self.token.lock().unwrap();
+        if token.as_ref().is_none_or(|token| token.expires < utils::now_secs() + TOKEN_MARGIN) {
+            *token = Some(self.fetch_token()?);
+        }
+        Ok(token.as_ref().map(|token| format!("Bearer {}", token.value.as_str())))
+    }
+
+    // Send a request, retrying what can be
+    fn send(&self, what: &str, method: &str, url: &str, headers: &[(&str, &str)], body: Option<&[u8]>) -> Result<ureq::Response> {
+        let authorization = self.authorization()?;
+        http::call(what, || {
+            let mut request = self.agent.request(method, url);
+            if let Some(authorization) = &authorization {
+                request = request.set("Authorization", authorization);
+            }
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            request
+        }, body)
+    }
+
+    fn object_url(&self, bucket: &str, key: &str) -> String {
+        format!("{}/storage/v1/b/{}/o/{}", self.base, http::encode(bucket, false), http::encode(key, false))
+    }
+
+    // Size, modification time and generation of `key`
+    fn metadata(&self, key: &str) -> Result<Option<(Stat, String)>> {
+        let url = format!("{}?fields=size,updated,generation", self.object_url(&self.bucket, key));
+        match self.send(&format!("Cannot stat {}", self.describe(key)), "GET", &url, &[], None) {
+            Ok(response) => {
+                let object: Value = serde_json::from_reader(response.into_reader())?;
+                let generation = object["generation"].as_str().unwrap_or_default().to_string();
+                Ok(Some((stat(&object), generation)))
+            }
+            Err(e) if http::status(&e) == Some(404) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Size and modification time of `key`, None when there is no such object
+    pub fn head(&self, key: &str) -> Result<Option<Stat>> {
+        Ok(self.metadata(key)?.map(|(stat, _)| stat))
+    }
+
+    /// Read `key` from byte `offset` on, several ranges at once for a large
+    /// object. All of them come from the generation found first, so an
+    /// object replaced meanwhile is read whole as it was.
+    pub fn read(self: &Arc<Self>, key: &str, offset: u64) -> Result<Box<dyn Read + Send>> {
+        let what = format!("Cannot read {}", self.describe(key));
+        let (stat, generation) = self.metadata(key)?.with_context(|| format!("{}: no such object", what))?;
+        let url = format!("{}?alt=media&generation={}", self.object_url(&self.bucket, key), generation);
+        if stat.size.saturating_sub(offset) < http::PARALLEL_MIN {
+            let range = format!("bytes={}-", offset);
+            let headers: &[(&str, &str)] = match offset {
+                0 => &[],
+                _ => &[("Range", &range)],
+            };
+            return Ok(Box::new(self.send(&what, "GET", &url, headers, None)?.into_reader()));
+        }
+        let client = self.clone();
+        Ok(http::parallel_ranges(offset, stat.size, move |start, end| {
+            let range = format!("bytes={}-{}", start, end);
+            let response = client.send(&what, "GET", &url, &[("Range", &range)], None)?;
+            let mut data = Vec::with_capacity((end - start + 1) as usize);
+            response.into_reader().take(end - start + 1).read_to_end(&mut data)?;
+            if data.len() as u64 != end - start + 1 {
+                anyhow::bail!("{}: the download ended early", what);
+            }
+            Ok(data)
+        }))
+    }
+
+    /// Delete `key`; one that is already gone is not an error
+    pub fn delete(&self, key: &str) -> Result<()> {
+        match self.send(&format!("Cannot delete {}", self.describe(key)), "DELETE", &self.object_url(&self.bucket, key), &[], None) {
+            Err(e) if http::status(&e) != Some(404) => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    // A page of the objects below `prefix`, and the token of the next
+    fn list_page(&self, prefix: &str, max: Option<u32>, token: Option<&str>) -> Result<(Value, Option<String>)> {
+        let mut url = format!(
+            "{}/storage/v1/b/{}/o?prefix={}&fields=items(name,size,updated),nextPageToken",
+            self.base,
+            http::encode(&self.bucket, false),
+            http::encode(prefix, false)
+        );
+        if let Some(max) = max {
+            url += &format!("&maxResults={}", max);
+        }
+        if let Some(token) = token {
+            url += &format!("&pageToken={}", http::encode(token, false));
+        }
+        let response = self.send(&format!("Cannot list {}", self.describe(prefix)), "GET", &url, &[], None)?;
+        let mut page: Value = serde_json::from_reader(response.into_reader())?;
+        let next = page["nextPageToken"].as_str().map(str::to_string);
+        Ok((page["items"].take(), next))
+    }
+
+    /// Every object below `prefix`, as (key, size, mtime), in key order
+    pub fn list(&self, prefix: &str) -> Result<Vec<(String, Stat)>> {
+        let mut objects = vec![];
+        let mut token: Option<String> = None;
+        loop {
+            let (items, next) = self.list_page(prefix, None, token.as_deref())?;
+            for item in items.as_array().into_iter().flatten() {
+                if let Some(name) = item["name"].as_str() {
+                    objects.push((name.to_string(), stat(item)));
+                }
+            }
+            match next {
+                Some(next) => token = Some(next),
+                None => return Ok(objects),
+            }
+        }
+    }
+
+    /// Fail if the bucket cannot be listed, as with wrong credentials
+    pub fn check(&self, prefix: &str) -> Result<()> {
+        self.list_page(prefix, Some(1), None)?;
+        Ok(())
+    }
+
+    /// Copy the object `from` of `bucket` to `to` in this bucket without the
+    /// data leaving the store, with the metadata of the original or that of
+    /// `metadata` when given. Copies between locations or storage classes
+    /// take several calls.
+    pub fn copy(&self, bucket: &str, from: &str, to: &str, metadata: Option<&Value>) -> Result<()> {
+        let what = format!("Cannot copy gs://{}/{} to {}", bucket, from, self.describe(to));
+        let url = format!(
+            "{}/rewriteTo/b/{}/o/{}",
+            self.object_url(bucket, from),
+            http::encode(&self.bucket, false),
+            http::encode(to, false)
+        );
+        let body = metadata.map_or_else(String::new, Value::to_string);
+        let mut token: Option<String> = None;
+        loop {
+            let url = match &token {
+                Some(token) => format!("{}?rewriteToken={}", url, http::encode(token, false)),
+                None => url.clone(),
+            };
+            let headers: &[(&str, &str)] = match body.is_empty() {
+                true => &[],
+                false => &[("Content-Type", "application/json")],
+            };
+            let response = self.send(&what, "POST", &url, headers, Some(body.as_bytes()))?;
+            let answer: Value = serde_json::from_reader(response.into_reader())?;
+            if answer["done"].as_bool() == Some(true) {
+                return Ok(());
+            }
+            token = Some(answer["rewriteToken"].as_str().with_context(|| format!("{}: no rewriteToken in answer", what))?.to_string());
+        }
+    }
+
+    // Start a resumable upload of the object `metadata` describes, returning its session URL
+    fn start_upload(&self, key: &str, metadata: &Value) -> Result<String> {
+        let what = format!("Cannot start upload of {}", self.describe(key));
+        let url = format!("{}/upload/storage/v1/b/{}/o?uploadType=resumable", self.base, http::encode(&self.bucket, false));
+        let body = metadata.to_string();
+        let response = self.send(&what, "POST", &url, &[("Content-Type", "application/json")], Some(body.as_bytes()))?;
+        response.header("location").map(str::to_string).with_context(|| format!("{}: no session in answer", what))
+    }
+
+    // Store `data` as the object `metadata` describes in one request
+    fn put(&self, key: &str, metadata: &Value, data: &[u8]) -> Result<()> {
+        let what = format!("Cannot write {}", self.describe(key));
+        let url = format!("{}/upload/storage/v1/b/{}/o?uploadType=multipart", self.base, http::encode(&self.bucket, false));
+        let boundary = format!("cpx-{}", uuid::Uuid::new_v4().simple());
+        let content_type = metadata["contentType"].as_str().unwrap_or("application/octet-stream");
+        let mut body = format!(
+            "--{}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{}\r\n--{}\r\nContent-Type: {}\r\n\r\n",
+            boundary, metadata, boundary, content_type
+        ).into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+        let content_type = format!("multipart/related; boundary={}", boundary);
+        self.send(&what, "POST", &url, &[("Content-Type", &content_type)], Some(&body))?;
+        Ok(())
+    }
+}
+
+// Size and update time of an object resource
+fn stat(object: &Value) -> Stat {
+    Stat {
+        size: object["size"].as_str().and_then(|size| size.parse().ok()).unwrap_or(0),
+        mtime: object["updated"].as_str().and_then(http::parse_time).unwrap_or(0),
+    }
+}
+
+/// Writes an object: small ones with one request, larger ones as a
+/// resumable upload sent chunk by chunk as they fill. Dropped unfinished,
+/// the upload is cancelled.
+pub struct ObjectWriter {
+    client: Arc<Client>,
+    key: String,
+    metadata: Value,
+    buffer: Vec<u8>,
+    // Session URL, once the first chunk is full
+    session: Option<String>,
+    // Bytes the store has
+    sent: u64,
+}
+
+impl ObjectWriter {
+    /// Write `key`, expected to be `size` bytes, as the object resource
+    /// `metadata` describes (contentType, cacheControl, storageClass...)
+    pub fn new(client: Arc<Client>, key: String, size: u64, mut metadata: Value) -> Self {
+        metadata["name"] = Value::String(key.clone());
+        let buffer = Vec::with_capacity(size.min(CHUNK + 1) as usize);
+        ObjectWriter { client, key, metadata, buffer, session: None, sent: 0 }
+    }
+
+    // Send the first chunk of the buffer; the store may keep less of it, the
+    // rest is sent again with the next
+    fn send_chunk(&mut self) -> Result<()> {
+        if self.session.is_none() {
+            self.session = Some(self.client.start_upload(&self.key, &self.metadata)?);
+        }
+        let session = self.session.as_ref().unwrap();
+        let what = format!("Cannot upload {}", self.client.describe(&self.key));
+        let range = format!("bytes {}-{}/*", self.sent, self.sent + CHUNK - 1);
+        let response = self.client.send(&what, "PUT", session, &[("Content-Range", &range)], Some(&self.buffer[..CHUNK as usize]))?;
+        if response.status() != 308 {
+            anyhow::bail!("{}: the upload ended early (HTTP {})", what, response.status());
+        }
+        let kept = response.header("range")
+            .and_then(|range| range.rsplit_once('-'))
+            .and_then(|(_, end)| end.parse::<u64>().ok())
+            .map_or(0, |end| end + 1);
+        if kept <= self.sent {
+            anyhow::bail!("{}: the store kept nothing of a chunk", what);
+        }
+        self.buffer.drain(..(kept - self.sent) as usize);
+        self.sent = kept;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> Result<()> {
+        let Some(session) = self.session.take() else {
+            return self.client.put(&self.key, &self.metadata, &self.buffer);
+        };
+        let what = format!("Cannot upload {}", self.client.describe(&self.key));
+        let total = self.sent + self.buffer.len() as u64;
+        let range = match self.buffer.is_empty() {
+            true => format!("bytes */{}", total),
+            false => format!("bytes {}-{}/{}", self.sent, total - 1, total),
+        };
+        let finished = self.client.send(&what, "PUT", &session, &[("Content-Range", &range)], Some(&self.buffer));
+        if finished.is_err() {
+            self.session = Some(session);
+        }
+        finished?;
+        Ok(())
+    }
+}
+
+impl Write for ObjectWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        // The last chunk says how large the object is, so one is always kept back
+        while self.buffer.len() as u64 > CHUNK {
+            self.send_chunk().map_err(io::Error::other)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for ObjectWriter {
+    fn drop(&mut self) {
+        // The store answers a cancelled session with 499
+        if let Some(session) = self.session.take()
+            && let Err(e) = self.client.send("Cannot cancel upload", "DELETE", &session, &[], None)
+            && http::status(&e) != Some(499)
+        {
+            eprintln!("Error: {:#}, the upload of {} is left behind", e, self.client.describe(&self.key));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn urls() {
+        assert_eq!(parse("gs://bucket/a/b").unwrap(), ("bucket".to_string(), "a/b".to_string()));
+        assert_eq!(parse("gs://bucket").unwrap(), ("bucket".to_string(), String::new()));
+        assert!(parse("gs:///a").is_err());
+        assert_eq!(upload_requests(CHUNK), 1);
+        assert_eq!(upload_requests(CHUNK + 1), 3);
+    }
+}
@@ -0,0 +1,136 @@
+// Glob patterns in local sources (`cpx "logs/**/*.gz" host:/backup`).
+//
+// `*` and `?` match within one path component, `[abc]`, `[a-z]` and `[!a]`
+// match one character, and a `**` component matches any number of
+// directories. The walk starts at the directory before the first wildcard and
+// matched files keep their path below it, so `logs/**/*.gz` lands as
+// backup/2024/app.gz. A pattern matching a directory takes everything in it,
+// as the shell's expansion would. Patterns must be quoted: a shell that
+// expands `*` itself hands cpx a list of paths instead and does not know `**`.
+// Remote sources (`host:/var/log/*.log`) are matched the same way on the
+// listing read over SFTP.
+
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::dirfd;
+
+/// Whether `source` should be expanded: it has wildcards and is not itself
+/// an existing path
+pub fn is_pattern(source: &Path) -> bool {
+    source.to_str().is_some_and(has_wildcard) && !source.exists()
+}
+
+/// A parsed pattern: the directory to walk and what to match below it
+pub struct Pattern {
+    pub base: PathBuf,
+    components: Vec<Vec<char>>,
+}
+
+impl Pattern {
+    pub fn new(pattern: &Path) -> Self {
+        let mut base = PathBuf::new();
+        let mut components = vec![];
+        for component in pattern.components() {
+            let text = component.as_os_str().to_string_lossy();
+            if components.is_empty() && !has_wildcard(&text) {
+                base.push(component);
+            } else {
+                components.push(text.chars().collect());
+            }
+        }
+        if base.as_os_str().is_empty() {
+            base.push(".");
+        }
+        Pattern { base, components }
+    }
+
+    /// Whether `rel` (relative to `base`) or one of its parent directories matches
+    pub fn matches(&self, rel: &Path) -> bool {
+        let names = names(rel);
+        (1..=names.len()).any(|n| match_path(&self.components, &names[..n]))
+    }
+
+    /// Whether anything below the directory `rel` can match, so walks can
+    /// skip directories that cannot
+    pub fn may_contain(&self, rel: &Path) -> bool {
+        self.matches(rel) || match_prefix(&self.components, &names(rel))
+    }
+
+    /// Walk `base`, yielding the entries that match
+    pub fn walk(&self) -> impl Iterator<Item = io::Result<dirfd::Entry>> + '_ {
+        dirfd::walk(&self.base).filter(|entry| match entry {
+            Ok(entry) => entry.path().strip_prefix(&self.base).is_ok_and(|rel| self.matches(rel)),
+            Err(_) => true,
+        })
+    }
+}
+
+/// Whether `text` matches the single-component pattern `pattern`
+pub fn match_text(pattern: &str, text: &str) -> bool {
+    match_name(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>())
+}
+
+/// Whether `text` has any of the characters a pattern is told apart by
+pub fn has_wildcard(text: &str) -> bool {
+    text.contains(['*', '?', '['])
+}
+
+fn names(rel: &Path) -> Vec<Vec<char>> {
+    rel.components()
+        .map(|component| component.as_os_str().to_string_lossy().chars().collect())
+        .collect()
+}
+
+// Whether `names` can be the start of a path matching `pattern`
+fn match_prefix(pattern: &[Vec<char>], names: &[Vec<char>]) -> bool {
+    let Some((name, rest_names)) = names.split_first() else {
+        return true;
+    };
+    match pattern.split_first() {
+        None => false,
+        Some((first, rest)) if first[..] == ['*', '*'] => match_prefix(rest, names) || match_prefix(pattern, rest_names),
+        Some((first, rest)) => match_name(first, name) && match_prefix(rest, rest_names),
+    }
+}
+
+fn match_path(pattern: &[Vec<char>], names: &[Vec<char>]) -> bool {
+    match pattern.split_first() {
+        None => names.is_empty(),
+        Some((first, rest)) if first[..] == ['*', '*'] => (0..=names.len()).any(|i| match_path(rest, &names[i..])),
+        Some((first, rest)) => names.split_first().is_some_and(|(name, names)| match_name(first, name) && match_path(rest, names)),
+    }
+}
+
+fn match_name(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => (0..=name.len()).any(|i| match_name(rest, &name[i..])),
+        Some(('?', rest)) => !name.is_empty() && match_name(rest, &name[1..]),
+        Some(('[', rest)) if let Some((matched, len)) = name.first().and_then(|c| match_class(rest, *c)) => {
+            matched && match_name(&rest[len..], &name[1..])
+        }
+        Some((c, rest)) => name.first() == Some(c) && match_name(rest, &name[1..]),
+    }
+}
+
+// Match `c` against the class after a '[', returning whether it matched and
+// how much of the pattern the class took up; None when the class is unclosed
+// and the '[' is a literal character
+fn match_class(class: &[char], c: char) -> Option<(bool, usize)> {
+    let negated = matches!(class.first(), Some('!' | '^'));
+    let mut i = usize::from(negated);
+    let mut matched = false;
+    // A ']' right after the opening bracket is a member, not the end
+    let start = i;
+    while i < class.len() && (class[i] != ']' || i == start) {
+        if i + 2 < class.len() && class[i + 1] == '-' && class[i + 2] != ']' {
+            matched |= (class[i]..=class[i + 2]).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    (i < class.len()).then_some((matched != negated, i + 1))
+}
@@ -0,0 +1,160 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::Digest as _;
+use std::fmt;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::oneshot;
+
+use crate::cpu::CpuLimit;
+use crate::dirfd;
+use crate::throttle::{Throttle, Throttled};
+
+/// Hash algorithms selectable with --hash
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Algorithm {
+    /// BLAKE3, fast and cryptographically strong
+    #[default]
+    Blake3,
+    /// SHA-256, for compliance requirements
+    Sha256,
+    /// XXH3-128, fastest, not cryptographic
+    Xxh3,
+}
+
+pub enum Hasher {
+    Blake3(Box<blake3::Hasher>),
+    Sha256(sha2::Sha256),
+    Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: Algorithm) -> Self {
+        match algorithm {
+            Algorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Xxh3 => Hasher::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Blake3(h) => {
+                h.update(data);
+            }
+            Hasher::Sha256(h) => h.update(data),
+            Hasher::Xxh3(h) => h.update(data),
+        }
+    }
+
+    pub fn finalize(self) -> Digest {
+        match self {
+            Hasher::Blake3(h) => Digest(h.finalize().as_bytes().to_vec()),
+            Hasher::Sha256(h) => Digest(h.finalize().to_vec()),
+            Hasher::Xxh3(h) => Digest(h.digest128().to_be_bytes().to_vec()),
+        }
+    }
+}
+
+/// A finished hash, displayed as lowercase hex
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Digest(Vec<u8>);
+
+impl fmt::Display for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash everything `input` yields
+pub fn hash_reader(algorithm: Algorithm, mut input: impl Read) -> io::Result<Digest> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let n = input.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buffer[..n]);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Re-read a source file after it was copied and compare it with the hash of
+/// the bytes that were sent, catching bit rot and concurrent modification
+pub fn verify_source(
+    algorithm: Algorithm,
+    src_root: &Path,
+    path: &Path,
+    sent: &Digest,
+    read_limit: Option<Arc<Throttle>>,
+) -> anyhow::Result<()> {
+    let input = Throttled::new(dirfd::open_beneath(src_root, path)?, read_limit);
+    let reread = hash_reader(algorithm, input)?;
+    if reread != *sent {
+        anyhow::bail!(
+            "{}: source changed while copying or read back differently (sent {}, now {})",
+            path.display(),
+            sent,
+            reread
+        );
+    }
+    Ok(())
+}
+
+/// Thread pool dedicated to verification hashing, so re-reading sources does
+/// not occupy the IO workers
+pub struct HashPool {
+    pool: rayon::ThreadPool,
+    algorithm: Algorithm,
+    // Re-reads count against --read-bwlimit like the copies themselves
+    read_limit: Option<Arc<Throttle>>,
+    // Hashing shares the --cpu-limit cores with compression
+    cpu_limit: Option<Arc<CpuLimit>>,
+}
+
+impl HashPool {
+    pub fn new(
+        algorithm: Algorithm,
+        threads: Option<usize>,
+        read_limit: Option<Arc<Throttle>>,
+        cpu_limit: Option<Arc<CpuLimit>>,
+    ) -> anyhow::Result<Self> {
+        let mut builder = rayon::ThreadPoolBuilder::new().thread_name(|i| format!("cpx-hash-{}", i));
+        if let Some(threads) = threads {
+            builder = builder.num_threads(threads);
+        }
+        Ok(HashPool { pool: builder.build()?, algorithm, read_limit, cpu_limit })
+    }
+
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm
+    }
+
+    fn submit(&self, src_root: PathBuf, path: PathBuf, sent: Digest) -> oneshot::Receiver<anyhow::Result<()>> {
+        let (tx, rx) = oneshot::channel();
+        let algorithm = self.algorithm;
+        let read_limit = self.read_limit.clone();
+        let cpu_limit = self.cpu_limit.clone();
+        self.pool.spawn(move || {
+            let _slot = cpu_limit.as_deref().map(CpuLimit::slot);
+            let _ = tx.send(verify_source(algorithm, &src_root, &path, &sent, read_limit));
+        });
+        rx
+    }
+
+    /// Verify a copied file on the pool, waiting asynchronously
+    pub async fn verify(&self, src_root: PathBuf, path: PathBuf, sent: Digest) -> anyhow::Result<()> {
+        self.submit(src_root, path, sent).await?
+    }
+
+    /// Verify a copied file on the pool, blocking the calling thread
+    pub fn verify_blocking(&self, src_root: PathBuf, path: PathBuf, sent: Digest) -> anyhow::Result<()> {
+        self.submit(src_root, path, sent).blocking_recv()?
+    }
+}
@@ -0,0 +1,164 @@
+// Checking the key an SSH server presents against ~/.ssh/known_hosts, as ssh
+// does, so a copy cannot be diverted to a machine impersonating the host.
+// Keys pinned in the profile are checked instead for the hosts they name.
+//
+// A host whose key changed is always refused. What happens with a host that
+// has no entry yet depends on --strict-host-key-checking: by default its
+// fingerprint is shown and the user asked to accept it (trust on first use),
+// after which it is appended to known_hosts like ssh would.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::{STANDARD, STANDARD_NO_PAD};
+use clap::ValueEnum;
+use ssh2::{CheckResult, HashType, HostKeyType, KnownHostFileKind, Session};
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+/// What to do with a host that is not in known_hosts yet
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum Strict {
+    /// Ask whether to trust its key, and remember it if so
+    #[default]
+    Ask,
+    /// Refuse to connect
+    Yes,
+    /// Trust its key and remember it without asking
+    AcceptNew,
+    /// Do not check host keys at all
+    No,
+}
+
+/// A host key fingerprint given with --fingerprint, for one host or all
+#[derive(Debug, Clone)]
+pub struct Pin {
+    pub host: Option<String>,
+    pub fingerprint: String,
+}
+
+/// Parse [HOST=]SHA256:base64, as ssh-keygen -lf prints it
+pub fn parse_pin(spec: &str) -> Result<Pin, String> {
+    let (host, fingerprint) = match spec.split_once('=') {
+        // base64 padding is also '=', a host comes before the "SHA256:"
+        Some((host, fingerprint)) if !host.contains(':') => (Some(host.to_string()), fingerprint),
+        _ => (None, spec),
+    };
+    match fingerprint.strip_prefix("SHA256:") {
+        Some(hash) if !hash.is_empty() => Ok(Pin { host, fingerprint: fingerprint.to_string() }),
+        _ => Err(format!("expected a SHA256:... fingerprint (ssh-keygen -lf), got {}", fingerprint)),
+    }
+}
+
+pub struct KnownHosts {
+    policy: Strict,
+    path: Option<PathBuf>,
+    // Held from reading the file to writing it, so parallel handshakes with a
+    // new host ask about it once
+    lock: Mutex<()>,
+}
+
+impl KnownHosts {
+    pub fn new(policy: Strict) -> Self {
+        let home = std::env::var("HOME").or_else(|_err| std::env::var("USERPROFILE")).ok();
+        let path = home.map(|home| PathBuf::from(home).join(".ssh").join("known_hosts"));
+        KnownHosts { policy, path, lock: Mutex::new(()) }
+    }
+
+    /// Check the key `session` got from `host`:`port`
+    pub fn check(&self, session: &Session, host: &str, port: u16) -> Result<()> {
+        if self.policy == Strict::No {
+            return Ok(());
+        }
+        let (key, key_type) = session.host_key()
+            .ok_or_else(|| anyhow::anyhow!("{} did not present a host key", host))?;
+        let _lock = self.lock.lock().unwrap();
+        let mut known = session.known_hosts()?;
+        if let Some(path) = self.path.as_ref().filter(|path| path.exists()) {
+            let text = fs::read_to_string(path).with_context(|| format!("Cannot read {}", path.display()))?;
+            // Line by line, libssh2 stops at the first entry it cannot parse
+            // (markers, newer key types) and those are no reason to fail
+            for line in text.lines().map(str::trim).filter(|line| !line.is_empty() && !line.starts_with('#')) {
+                let _ = known.read_str(line, KnownHostFileKind::OpenSSH);
+            }
+        }
+        let fingerprint = session.host_key_hash(HashType::Sha256)
+            .map(|hash| format!("SHA256:{}", STANDARD_NO_PAD.encode(hash)))
+            .unwrap_or_default();
+        match known.check_port(host, port, key) {
+            CheckResult::Match => return Ok(()),
+            CheckResult::Mismatch => anyhow::bail!(
+                "Host key of {} has changed (now {}), someone may be impersonating it; refusing to connect. \
+                 If the change is expected, remove its old key from known_hosts",
+                host,
+                fingerprint
+            ),
+            CheckResult::Failure => anyhow::bail!("Cannot check the host key of {}", host),
+            CheckResult::NotFound => {}
+        }
+        match self.policy {
+            Strict::Yes => anyhow::bail!(
+                "No host key known for {} ({}); add it to known_hosts or use --strict-host-key-checking accept-new",
+                host,
+                fingerprint
+            ),
+            Strict::Ask => {
+                if !io::stdin().is_terminal() {
+                    anyhow::bail!(
+                        "No host key known for {} ({}) and no terminal to ask on; use --strict-host-key-checking accept-new",
+                        host,
+                        fingerprint
+                    );
+                }
+                eprintln!("The authenticity of host '{}' can't be established.", host);
+                eprintln!("{} key fingerprint is {}.", key_name(key_type).unwrap_or("Host"), fingerprint);
+                eprint!("Are you sure you want to continue connecting (yes/no)? ");
+                io::stderr().flush()?;
+                let mut answer = String::new();
+                io::stdin().lock().read_line(&mut answer)?;
+                if !answer.trim().eq_ignore_ascii_case("yes") {
+                    anyhow::bail!("Host key of {} not accepted", host);
+                }
+            }
+            Strict::AcceptNew | Strict::No => {}
+        }
+        self.remember(host, port, key, key_type)?;
+        eprintln!("⚠️  Permanently added {} ({}) to the list of known hosts", host, fingerprint);
+        Ok(())
+    }
+
+    // Append the key to known_hosts, leaving the entries already there as they
+    // are rather than rewriting the file
+    fn remember(&self, host: &str, port: u16, key: &[u8], key_type: HostKeyType) -> Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let Some(name) = key_name(key_type) else {
+            anyhow::bail!("Cannot record the host key of {}, its type is unknown", host);
+        };
+        let entry = match port {
+            22 => host.to_string(),
+            port => format!("[{}]:{}", host, port),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(path)
+            .with_context(|| format!("Cannot write {}", path.display()))?;
+        writeln!(file, "{} {} {}", entry, name, STANDARD.encode(key))?;
+        Ok(())
+    }
+}
+
+fn key_name(key_type: HostKeyType) -> Option<&'static str> {
+    Some(match key_type {
+        HostKeyType::Rsa => "ssh-rsa",
+        HostKeyType::Dss => "ssh-dss",
+        HostKeyType::Ecdsa256 => "ecdsa-sha2-nistp256",
+        HostKeyType::Ecdsa384 => "ecdsa-sha2-nistp384",
+        HostKeyType::Ecdsa521 => "ecdsa-sha2-nistp521",
+        HostKeyType::Ed25519 => "ssh-ed25519",
+        HostKeyType::Unknown => return None,
+    })
+}
@@ -0,0 +1,442 @@
+// Plumbing shared by the endpoints spoken to over HTTP(S): S3, GCS, Azure
+// Blob, WebDAV and plain URL sources. One agent per endpoint keeps
+// connections alive between requests; failures carry the status and what the
+// server said; requests that can be sent again are retried a few times.
+
+use anyhow::anyhow;
+use std::collections::BTreeMap;
+use std::io::{self, Read};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::Duration;
+
+// Tries of a request that failed on the way or got 429/5xx
+const TRIES: u32 = 4;
+// Most of an error response kept for the message
+const ERROR_BODY: u64 = 4096;
+
+/// Downloads of at least this many bytes fetch several ranges at once
+pub const PARALLEL_MIN: u64 = 64 << 20;
+/// Size of each range of a parallel download
+pub const RANGE: u64 = 4 << 20;
+// Ranges fetched at once
+const RANGES: usize = 4;
+
+pub fn agent() -> ureq::Agent {
+    ureq::AgentBuilder::new()
+        .timeout_connect(Duration::from_secs(30))
+        .timeout_read(Duration::from_secs(300))
+        .user_agent(concat!("cpx/", env!("CARGO_PKG_VERSION")))
+        .try_proxy_from_env(true)
+        .build()
+}
+
+/// Send a request, made anew by `request` for each try, with `body` if it
+/// has one, until it gets an answer that is not 429 or 5xx. `what` names it
+/// in errors.
+pub fn call(what: &str, request: impl Fn() -> ureq::Request, body: Option<&[u8]>) -> anyhow::Result<ureq::Response> {
+    let mut tries = 0;
+    loop {
+        tries += 1;
+        let response = match body {
+            Some(body) => request().send_bytes(body),
+            None => request().call(),
+        };
+        match response {
+            Ok(response) => return Ok(response),
+            Err(e) if tries < TRIES && retryable(&e) => {
+                std::thread::sleep(Duration::from_millis(250 << tries));
+            }
+            Err(e) => return Err(error(what, e)),
+        }
+    }
+}
+
+/// The HTTP status of an error from `call`, if the server answered
+pub fn status(e: &anyhow::Error) -> Option<u16> {
+    e.downcast_ref::<Status>().map(|status| status.0)
+}
+
+// Kept in the error chain so callers can tell a 404 from a failure
+#[derive(Debug)]
+struct Status(u16);
+
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "HTTP {}", self.0)
+    }
+}
+
+impl std::error::Error for Status {}
+
+fn retryable(e: &ureq::Error) -> bool {
+    match e {
+        ureq::Error::Status(code, _) => *code == 429 || *code >= 500,
+        ureq::Error::Transport(_) => true,
+    }
+}
+
+/// Turn a failed request into an error with the server's own message
+pub fn error(what: &str, e: ureq::Error) -> anyhow::Error {
+    match e {
+        ureq::Error::Status(code, response) => {
+            // Azure also names the error in a header, the only place it is in answers to HEAD
+            let code_header = response.header("x-ms-error-code").map(str::to_string);
+            let mut body = String::new();
+            let _ = response.into_reader().take(ERROR_BODY).read_to_string(&mut body);
+            // S3, Azure and WebDAV servers answer in XML, GCS in JSON
+            let message = tag(&body, "Message").or_else(|| tag(&body, "Code")).or_else(|| tag(&body, "message")).map(unescape)
+                .or_else(|| serde_json::from_str::<serde_json::Value>(&body).ok()
+                    .and_then(|json| json["error"]["message"].as_str().map(str::to_string)))
+                .or(code_header)
+                .unwrap_or_else(|| body.trim().chars().take(200).collect());
+            let message = match message.trim() {
+                "" => format!("HTTP {}", code),
+                message => message.to_string(),
+            };
+            anyhow::Error::new(Status(code)).context(format!("{}: {}", what, message))
+        }
+        ureq::Error::Transport(e) => anyhow!("{}: {}", what, e),
+    }
+}
+
+/// Percent-encode `s` as RFC 3986 asks, leaving `/` alone with `keep_slash`
+pub fn encode(s: &str, keep_slash: bool) -> String {
+    let mut encoded = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+            b'/' if keep_slash => encoded.push('/'),
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+/// Undo percent-encoding, as found in URL paths
+pub fn decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes.get(i + 1..i + 3).and_then(|hex| std::str::from_utf8(hex).ok());
+        match (bytes[i], hex.and_then(|hex| u8::from_str_radix(hex, 16).ok())) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+            }
+            (byte, _) => {
+                decoded.push(byte);
+                i += 1;
+            }
+        }
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+/// The text of every element named `name` in `xml`, whatever its namespace
+/// prefix. Enough for the flat listings object stores answer with, not a
+/// general XML parser.
+pub fn tags<'a>(xml: &'a str, name: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find('<')?;
+        rest = &rest[start + 1..];
+        let end = rest.find('>')?;
+        let open = &rest[..end];
+        let element = open.split([' ', '\t', '\r', '\n', '/']).next().unwrap_or("");
+        let local = element.rsplit(':').next().unwrap_or("");
+        if local != name || open.starts_with('/') {
+            continue;
+        }
+        rest = &rest[end + 1..];
+        if open.ends_with('/') {
+            return Some("");
+        }
+        let close = format!("</{}>", element);
+        let stop = rest.find(&close)?;
+        let text = &rest[..stop];
+        rest = &rest[stop + close.len()..];
+        return Some(text);
+    })
+}
+
+/// The first element named `name` in `xml`
+pub fn tag<'a>(xml: &'a str, name: &'a str) -> Option<&'a str> {
+    tags(xml, name).next()
+}
+
+/// Replace the five predefined XML entities and character references
+pub fn unescape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let Some(end) = rest.find(';') else {
+            break;
+        };
+        let entity = &rest[1..end];
+        let c = match entity {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match c {
+            Some(c) => {
+                out.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// Escape text for an XML element
+pub fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+const MONTHS: [&str; 12] = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+// From the weekday of 1970-01-01
+const WEEKDAYS: [&str; 7] = ["Thu", "Fri", "Sat", "Sun", "Mon", "Tue", "Wed"];
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146097 + doe - 719468
+}
+
+// The date of a day since 1970-01-01
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + (month <= 2) as i64, month, day)
+}
+
+fn split_time(secs: u64) -> (i64, u32, u32, u64, u64, u64) {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    (year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// `secs` as ISO 8601 basic format (20130524T000000Z), for AWS signatures
+pub fn amz_date(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = split_time(secs);
+    format!("{:04}{:02}{:02}T{:02}{:02}{:02}Z", year, month, day, hour, minute, second)
+}
+
+/// `secs` as an HTTP date (Fri, 24 May 2013 00:00:00 GMT)
+pub fn http_date(secs: u64) -> String {
+    let (year, month, day, hour, minute, second) = split_time(secs);
+    format!(
+        "{}, {:02} {} {:04} {:02}:{:02}:{:02} GMT",
+        WEEKDAYS[(secs / 86400 % 7) as usize], day, MONTHS[month as usize - 1], year, hour, minute, second
+    )
+}
+
+/// Seconds since the epoch of an ISO 8601 time (2013-05-24T00:00:00.000Z) or
+/// an HTTP date, None when it is neither
+pub fn parse_time(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let (year, month, day, clock) = if text.as_bytes().get(4) == Some(&b'-') {
+        let (date, clock) = text.split_once('T')?;
+        let mut date = date.split('-');
+        let (year, month, day) = (date.next()?.parse().ok()?, date.next()?.parse().ok()?, date.next()?.parse().ok()?);
+        (year, month, day, clock.get(..8)?)
+    } else {
+        // Fri, 24 May 2013 00:00:00 GMT
+        let mut fields = text.split_whitespace().skip(1);
+        let day = fields.next()?.parse().ok()?;
+        let month = MONTHS.iter().position(|month| Some(*month) == fields.clone().next())? as u32 + 1;
+        fields.next();
+        (fields.next()?.parse().ok()?, month, day, fields.next()?)
+    };
+    let mut clock = clock.split(':').map(|field| field.parse::<u64>().ok());
+    let (hour, minute, second) = (clock.next()??, clock.next()??, clock.next()??);
+    let days = days_from_civil(year, month, day);
+    (days >= 0).then(|| days as u64 * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// The Content-Type of a file named `name`, from its extension, for the web
+/// servers and browsers that read it from object stores
+pub fn content_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, extension)| extension.to_ascii_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "html" | "htm" => "text/html",
+        "css" => "text/css",
+        "js" | "mjs" => "text/javascript",
+        "json" | "map" => "application/json",
+        "xml" => "application/xml",
+        "txt" | "log" => "text/plain",
+        "csv" => "text/csv",
+        "md" => "text/markdown",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/vnd.microsoft.icon",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "zst" => "application/zstd",
+        "tar" => "application/x-tar",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "mp3" => "audio/mpeg",
+        "ogg" => "audio/ogg",
+        "wav" => "audio/wav",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Read bytes `offset..size` of a resource as RANGES threads fetch it range
+/// by range with `fetch(start, end)` (end inclusive). The threads stay at
+/// most RANGES ranges ahead of the reader, so memory stays bounded however
+/// slowly the data is consumed.
+pub fn parallel_ranges(
+    offset: u64,
+    size: u64,
+    fetch: impl Fn(u64, u64) -> anyhow::Result<Vec<u8>> + Send + Sync + 'static,
+) -> Box<dyn Read + Send> {
+    let count = (size - offset).div_ceil(RANGE);
+    let shared = Arc::new(Ranges { queue: Mutex::new(RangeQueue::default()), changed: Condvar::new() });
+    let fetch = Arc::new(fetch);
+    for _ in 0..RANGES.min(count as usize) {
+        let (shared, fetch) = (shared.clone(), fetch.clone());
+        std::thread::spawn(move || loop {
+            let mut queue = shared.changed.wait_while(shared.queue.lock().unwrap(), |queue| {
+                !queue.closed && queue.fetch < count && queue.fetch >= queue.read + RANGES as u64
+            }).unwrap();
+            if queue.closed || queue.fetch >= count {
+                return;
+            }
+            let i = queue.fetch;
+            queue.fetch += 1;
+            drop(queue);
+            let start = offset + i * RANGE;
+            let end = (start + RANGE).min(size) - 1;
+            let data = fetch(start, end).map_err(|e| format!("{:#}", e));
+            shared.queue.lock().unwrap().done.insert(i, data);
+            shared.changed.notify_all();
+        });
+    }
+    Box::new(RangeReader { shared, count, current: io::Cursor::new(vec![]) })
+}
+
+struct Ranges {
+    queue: Mutex<RangeQueue>,
+    changed: Condvar,
+}
+
+#[derive(Default)]
+struct RangeQueue {
+    // Next range to fetch, and next to hand to the reader
+    fetch: u64,
+    read: u64,
+    done: BTreeMap<u64, Result<Vec<u8>, String>>,
+    // The reader is gone, fetch nothing more
+    closed: bool,
+}
+
+struct RangeReader {
+    shared: Arc<Ranges>,
+    count: u64,
+    current: io::Cursor<Vec<u8>>,
+}
+
+impl Read for RangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let n = self.current.read(buf)?;
+            if n > 0 || buf.is_empty() {
+                return Ok(n);
+            }
+            let mut queue = self.shared.queue.lock().unwrap();
+            if queue.read == self.count {
+                return Ok(0);
+            }
+            let next = queue.read;
+            queue = self.shared.changed.wait_while(queue, |queue| !queue.done.contains_key(&next)).unwrap();
+            let data = queue.done.remove(&next).unwrap().map_err(io::Error::other)?;
+            queue.read += 1;
+            self.shared.changed.notify_all();
+            self.current = io::Cursor::new(data);
+        }
+    }
+}
+
+impl Drop for RangeReader {
+    fn drop(&mut self) {
+        self.shared.queue.lock().unwrap().closed = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn times() {
+        assert_eq!(amz_date(1369353600), "20130524T000000Z");
+        assert_eq!(http_date(1369353600), "Fri, 24 May 2013 00:00:00 GMT");
+        assert_eq!(parse_time("2013-05-24T00:00:00.000Z"), Some(1369353600));
+        assert_eq!(parse_time("Fri, 24 May 2013 00:00:00 GMT"), Some(1369353600));
+        assert_eq!(parse_time("Thu, 29 Feb 2024 23:59:59 GMT"), Some(1709251199));
+        assert_eq!(parse_time("yesterday"), None);
+    }
+
+    #[test]
+    fn xml() {
+        let xml = "<r><d:href>/a b/</d:href><Key>x &amp; y</Key><Key/><Size>3</Size></r>";
+        assert_eq!(tag(xml, "href"), Some("/a b/"));
+        assert_eq!(tags(xml, "Key").map(unescape).collect::<Vec<_>>(), ["x & y", ""]);
+        assert_eq!(decode("a%20b%2"), "a b%2");
+        assert_eq!(encode("a b/c~", true), "a%20b/c~");
+    }
+
+    #[test]
+    fn ranges() {
+        let size = RANGE * 5 + 7;
+        let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let source = data.clone();
+        let mut read = vec![];
+        parallel_ranges(RANGE - 3, size, move |start, end| Ok(source[start as usize..=end as usize].to_vec()))
+            .read_to_end(&mut read)
+            .unwrap();
+        assert!(read == data[(RANGE - 3) as usize..]);
+        let mut failing = parallel_ranges(0, size, |start, _| match start {
+            0 => Ok(vec![1; RANGE as usize]),
+            _ => anyhow::bail!("gone"),
+        });
+        assert!(failing.read_to_end(&mut vec![]).is_err());
+    }
+}
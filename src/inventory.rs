@@ -0,0 +1,211 @@
+// Destination inventories: `cpx inventory /data > inv.json` lists a tree where
+// it lives, and `cpx plan --dest-inventory inv.json` plans against that list
+// instead of scanning the destination over the network. A remote tree
+// (`cpx inventory user@host:/data`) is listed over SFTP on several
+// connections at once.
+
+use anyhow::Context;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+
+use crate::backend::{Backend, SshBackend};
+use crate::dirfd::{self, Stat};
+use crate::hash::{self, Algorithm};
+use crate::plan::{self, Listing};
+use crate::rawpath;
+use crate::ssh::SshConnectionPool;
+use crate::utils;
+
+const INVENTORY_VERSION: u32 = 1;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct InventoryFile {
+    // Relative to the inventory root
+    #[serde(with = "rawpath")]
+    pub path: PathBuf,
+    pub size: u64,
+    pub mtime: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Inventory {
+    pub version: u32,
+    pub created: u64,
+    pub root: String,
+    // Set when the files were hashed (--with-hashes)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<Algorithm>,
+    pub files: Vec<InventoryFile>,
+}
+
+impl Inventory {
+    /// Hash every file in `listing` with `open` on `threads` threads when an
+    /// algorithm is given. Files that cannot be hashed are listed without a
+    /// hash and reported in the returned errors.
+    pub fn new<R: std::io::Read>(
+        root: &str,
+        listing: Listing,
+        hash: Option<Algorithm>,
+        threads: usize,
+        open: impl Fn(&Path) -> anyhow::Result<R> + Sync,
+    ) -> anyhow::Result<(Self, Vec<String>)> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .thread_name(|i| format!("cpx-inventory-{}", i))
+            .build()?;
+        let hashed = pool.install(|| {
+            listing.into_par_iter()
+                .map(|(path, stat)| {
+                    let digest = hash.map(|algorithm| {
+                        open(&path).and_then(|input| Ok(hash::hash_reader(algorithm, input)?))
+                            .map_err(|e| format!("{}: {:#}", path.display(), e))
+                    });
+                    (path, stat, digest.transpose())
+                })
+                .collect::<Vec<_>>()
+        });
+        let mut files = vec![];
+        let mut errors = vec![];
+        for (path, stat, digest) in hashed {
+            let hash = digest.unwrap_or_else(|e| {
+                errors.push(e);
+                None
+            });
+            files.push(InventoryFile { path, size: stat.size, mtime: stat.mtime, hash: hash.map(|digest| digest.to_string()) });
+        }
+        let inventory = Inventory { version: INVENTORY_VERSION, created: utils::now_secs(), root: root.to_string(), hash, files };
+        Ok((inventory, errors))
+    }
+
+    /// List the files under a local directory; unreadable entries are
+    /// returned as errors and left out
+    pub fn scan_local(root: &Path, hash: Option<Algorithm>, threads: usize) -> anyhow::Result<(Self, Vec<String>)> {
+        if !root.is_dir() {
+            anyhow::bail!("{} is not a directory", root.display());
+        }
+        let (listing, mut errors) = plan::scan_local(root, root);
+        let (inventory, hash_errors) = Inventory::new(&root.to_string_lossy(), listing, hash, threads, |path| {
+            Ok(dirfd::open_beneath(root, path)?)
+        })?;
+        errors.extend(hash_errors);
+        Ok((inventory, errors))
+    }
+
+    /// List the files under `root` on the pool's host, reading directories
+    /// on `workers` connections at once. `root` must already be resolved.
+    pub fn scan_remote(
+        pool: Arc<SshConnectionPool>,
+        root: &Path,
+        hash: Option<Algorithm>,
+        workers: usize,
+    ) -> anyhow::Result<(Self, Vec<String>)> {
+        let walk = Walk { state: Mutex::new((vec![PathBuf::new()], 0)), changed: Condvar::new() };
+        let listing = Mutex::new(Listing::new());
+        let errors = Mutex::new(vec![]);
+        let failed = std::thread::scope(|scope| {
+            let handles = (0..workers.max(1))
+                .map(|_| scope.spawn(|| -> anyhow::Result<()> {
+                    let lease = pool.get_connection()?;
+                    let reader = pool.transfer(&lease).dir_reader();
+                    pool.return_connection(lease);
+                    let reader = reader?;
+                    while let Some(dir) = walk.next() {
+                        match reader.read(root, &dir) {
+                            Ok(found) => {
+                                listing.lock().unwrap().extend(found.files);
+                                walk.done(found.dirs);
+                            }
+                            Err(e) => {
+                                errors.lock().unwrap().push(e);
+                                walk.done(vec![]);
+                            }
+                        }
+                    }
+                    Ok(())
+                }))
+                .collect::<Vec<_>>();
+            handles.into_iter().map(|h| h.join().unwrap()).collect::<Vec<_>>()
+        });
+        // Workers that could not start leave the tree to the others
+        if failed.iter().all(Result::is_err) {
+            return Err(failed.into_iter().find_map(Result::err).unwrap());
+        }
+        let backend = SshBackend::new(pool, root.to_path_buf());
+        let (inventory, mut hash_errors) = Inventory::new(
+            &root.to_string_lossy(),
+            listing.into_inner().unwrap(),
+            hash,
+            workers,
+            |path| backend.open(path),
+        )?;
+        let mut errors = errors.into_inner().unwrap();
+        errors.append(&mut hash_errors);
+        Ok((inventory, errors))
+    }
+
+    pub fn load(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(file).with_context(|| format!("Cannot read inventory {}", file.display()))?;
+        let inventory: Inventory = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid inventory {}", file.display()))?;
+        if inventory.version != INVENTORY_VERSION {
+            anyhow::bail!("Inventory {} has unsupported version {}", file.display(), inventory.version);
+        }
+        Ok(inventory)
+    }
+
+    /// Write the inventory to `file`, or to stdout without one
+    pub fn save(&self, file: Option<&Path>) -> anyhow::Result<()> {
+        let data = serde_json::to_vec_pretty(self)?;
+        match file {
+            Some(file) => {
+                let tmp = file.with_extension("tmp");
+                fs::write(&tmp, data)?;
+                fs::rename(&tmp, file).with_context(|| format!("Cannot write inventory {}", file.display()))?;
+            }
+            None => {
+                let mut stdout = std::io::stdout().lock();
+                stdout.write_all(&data)?;
+                writeln!(stdout)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The files under `top`, keyed like `plan::scan_local(root, root/top)`
+    /// would list them
+    pub fn listing(&self, top: &Path) -> Listing {
+        self.files.iter()
+            .filter(|file| file.path.starts_with(top))
+            .filter_map(|file| Some((utils::contained_path(&file.path)?, Stat { size: file.size, mtime: file.mtime })))
+            .collect()
+    }
+}
+
+// Directories still to read, shared by the workers of a remote scan, and how
+// many are being read right now; the walk is over when both run out
+struct Walk {
+    state: Mutex<(Vec<PathBuf>, usize)>,
+    changed: Condvar,
+}
+
+impl Walk {
+    fn next(&self) -> Option<PathBuf> {
+        let mut state = self.changed.wait_while(self.state.lock().unwrap(), |(dirs, busy)| dirs.is_empty() && *busy > 0).unwrap();
+        let dir = state.0.pop()?;
+        state.1 += 1;
+        Some(dir)
+    }
+
+    fn done(&self, found: Vec<PathBuf>) {
+        let mut state = self.state.lock().unwrap();
+        state.0.extend(found);
+        state.1 -= 1;
+        self.changed.notify_all();
+    }
+}
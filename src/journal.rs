@@ -0,0 +1,449 @@
+// Change journals: the record a filesystem keeps of what changed on it, read
+// so that --prune-unchanged journal finds the directories changed since the
+// last run without reading the others.
+//
+// NTFS keeps an update sequence number (USN) journal per volume, which needs
+// an administrator to read; macOS keeps FSEvents history per device. Both are
+// read from the position stored after the previous run up to now. They are
+// finite: once the stored position has been overwritten (or the journal was
+// deleted and recreated) `changes` returns None and the whole tree is walked.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+/// A position in the change journal of one volume
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Cursor {
+    // Which journal: the USN journal id, or the FSEvents UUID of the device
+    journal: String,
+    // The next USN or FSEvents event id
+    position: u64,
+}
+
+/// Directories below a root with entries added, removed, renamed or written
+/// since a cursor, relative to the root
+#[derive(Default)]
+pub struct Changes {
+    dirs: HashSet<PathBuf>,
+    // Changed somewhere below, as far as the journal can tell
+    trees: Vec<PathBuf>,
+}
+
+impl Changes {
+    /// Whether the directory `rel` has to be read again
+    pub fn changed(&self, rel: &Path) -> bool {
+        self.dirs.contains(rel) || self.trees.iter().any(|tree| rel.starts_with(tree))
+    }
+
+    // Note a change in the directory `path`, ignored when it is outside `root`
+    #[cfg_attr(not(any(windows, target_os = "macos")), allow(dead_code))]
+    fn add(&mut self, root: &Path, path: &Path, tree: bool) {
+        if let Ok(rel) = path.strip_prefix(root) {
+            match tree {
+                true => self.trees.push(rel.to_path_buf()),
+                false => {
+                    self.dirs.insert(rel.to_path_buf());
+                }
+            }
+        }
+    }
+}
+
+/// The current end of the change journal of the volume holding `root`
+pub fn cursor(root: &Path) -> Result<Cursor> {
+    imp::cursor(&root.canonicalize()?)
+}
+
+/// What changed below `root` since `since`; None when the journal no longer
+/// goes back that far
+pub fn changes(root: &Path, since: &Cursor) -> Result<Option<Changes>> {
+    imp::changes(&root.canonicalize()?, since)
+}
+
+#[cfg(windows)]
+mod imp {
+    use anyhow::{Context, Result};
+    use std::collections::HashSet;
+    use std::ffi::OsString;
+    use std::fs::{File, OpenOptions};
+    use std::io;
+    use std::os::windows::ffi::{OsStrExt, OsStringExt};
+    use std::os::windows::fs::OpenOptionsExt;
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+    use std::path::{Path, PathBuf};
+    use windows_sys::Win32::Foundation::{ERROR_JOURNAL_ENTRY_DELETED, INVALID_HANDLE_VALUE};
+    use windows_sys::Win32::Storage::FileSystem::{
+        FILE_FLAG_BACKUP_SEMANTICS, FILE_ID_DESCRIPTOR, FILE_ID_DESCRIPTOR_0, FILE_NAME_NORMALIZED, FILE_READ_ATTRIBUTES,
+        FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, FileIdType, GetFinalPathNameByHandleW,
+        GetVolumeNameForVolumeMountPointW, GetVolumePathNameW, OpenFileById, VOLUME_NAME_DOS,
+    };
+    use windows_sys::Win32::System::IO::DeviceIoControl;
+    use windows_sys::Win32::System::Ioctl::{
+        FSCTL_QUERY_USN_JOURNAL, FSCTL_READ_USN_JOURNAL, READ_USN_JOURNAL_DATA_V0, USN_JOURNAL_DATA_V0, USN_RECORD_V2,
+    };
+
+    use super::{Changes, Cursor};
+
+    // Records read per FSCTL_READ_USN_JOURNAL
+    const BUFFER: usize = 1 << 20;
+
+    fn wide(path: &Path) -> Vec<u16> {
+        path.as_os_str().encode_wide().chain([0]).collect()
+    }
+
+    fn from_wide(buffer: &[u16]) -> PathBuf {
+        let end = buffer.iter().position(|&c| c == 0).unwrap_or(buffer.len());
+        PathBuf::from(OsString::from_wide(&buffer[..end]))
+    }
+
+    // The volume holding `root`, opened for reading its journal
+    fn open_volume(root: &Path) -> Result<File> {
+        let mut mount = [0u16; 1024];
+        let mut volume = [0u16; 64];
+        // SAFETY: the buffers are as long as the lengths passed
+        let found = unsafe {
+            GetVolumePathNameW(wide(root).as_ptr(), mount.as_mut_ptr(), mount.len() as u32) != 0
+                && GetVolumeNameForVolumeMountPointW(mount.as_ptr(), volume.as_mut_ptr(), volume.len() as u32) != 0
+        };
+        if !found {
+            return Err(io::Error::last_os_error()).with_context(|| format!("Cannot find the volume of {}", root.display()));
+        }
+        // \\?\Volume{GUID}\ names the root directory, without the slash the volume
+        let volume = from_wide(&volume);
+        let volume = volume.to_string_lossy().trim_end_matches('\\').to_string();
+        OpenOptions::new()
+            .read(true)
+            .share_mode(FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE)
+            .open(&volume)
+            .with_context(|| format!("Cannot open volume {} to read its change journal (needs an administrator)", volume))
+    }
+
+    fn query(volume: &File) -> Result<USN_JOURNAL_DATA_V0> {
+        // SAFETY: all zeroes is a valid USN_JOURNAL_DATA_V0
+        let mut data: USN_JOURNAL_DATA_V0 = unsafe { std::mem::zeroed() };
+        let mut returned = 0;
+        // SAFETY: the output buffer is a USN_JOURNAL_DATA_V0 of the size passed
+        let ok = unsafe {
+            DeviceIoControl(
+                volume.as_raw_handle(),
+                FSCTL_QUERY_USN_JOURNAL,
+                std::ptr::null(),
+                0,
+                (&raw mut data).cast(),
+                size_of::<USN_JOURNAL_DATA_V0>() as u32,
+                &mut returned,
+                std::ptr::null_mut(),
+            )
+        };
+        if ok == 0 {
+            return Err(io::Error::last_os_error()).context("The volume has no USN journal");
+        }
+        Ok(data)
+    }
+
+    pub fn cursor(root: &Path) -> Result<Cursor> {
+        let data = query(&open_volume(root)?)?;
+        Ok(Cursor { journal: format!("{:016x}", data.UsnJournalID), position: data.NextUsn as u64 })
+    }
+
+    pub fn changes(root: &Path, since: &Cursor) -> Result<Option<Changes>> {
+        let volume = open_volume(root)?;
+        let data = query(&volume)?;
+        if since.journal != format!("{:016x}", data.UsnJournalID) || (since.position as i64) < data.FirstUsn {
+            return Ok(None);
+        }
+        // Directories that had an entry changed, by file reference number
+        let mut parents = HashSet::new();
+        let mut buffer = vec![0u64; BUFFER / 8];
+        let mut read = READ_USN_JOURNAL_DATA_V0 {
+            StartUsn: since.position as i64,
+            ReasonMask: u32::MAX,
+            ReturnOnlyOnClose: 0,
+            Timeout: 0,
+            BytesToWaitFor: 0,
+            UsnJournalID: data.UsnJournalID,
+        };
+        while read.StartUsn < data.NextUsn {
+            let mut returned = 0u32;
+            // SAFETY: the input is a READ_USN_JOURNAL_DATA_V0 and the output
+            // buffer is as long as the size passed
+            let ok = unsafe {
+                DeviceIoControl(
+                    volume.as_raw_handle(),
+                    FSCTL_READ_USN_JOURNAL,
+                    (&raw const read).cast(),
+                    size_of::<READ_USN_JOURNAL_DATA_V0>() as u32,
+                    buffer.as_mut_ptr().cast(),
+                    BUFFER as u32,
+                    &mut returned,
+                    std::ptr::null_mut(),
+                )
+            };
+            if ok == 0 {
+                let e = io::Error::last_os_error();
+                if e.raw_os_error() == Some(ERROR_JOURNAL_ENTRY_DELETED as i32) {
+                    return Ok(None);
+                }
+                return Err(e).context("Cannot read the USN journal");
+            }
+            // The next USN to read from, then whole records
+            let bytes = &as_bytes(&buffer)[..returned as usize];
+            let next = i64::from_le_bytes(bytes[..8].try_into().unwrap());
+            let mut offset = 8;
+            while offset + size_of::<USN_RECORD_V2>() <= bytes.len() {
+                // SAFETY: a record is at least a USN_RECORD_V2 long and starts
+                // 8-byte aligned; `buffer` is made of u64s
+                let record = unsafe { &*(bytes.as_ptr().add(offset) as *const USN_RECORD_V2) };
+                if record.RecordLength == 0 {
+                    break;
+                }
+                if record.MajorVersion == 2 {
+                    parents.insert(record.ParentFileReferenceNumber);
+                }
+                offset += record.RecordLength as usize;
+            }
+            if next <= read.StartUsn {
+                break;
+            }
+            read.StartUsn = next;
+        }
+        let mut changes = Changes::default();
+        for id in parents {
+            // Directories deleted since are gone from their parent, which is listed too
+            if let Some(path) = path_of(&volume, id) {
+                changes.add(root, &path, false);
+            }
+        }
+        Ok(Some(changes))
+    }
+
+    fn as_bytes(buffer: &[u64]) -> &[u8] {
+        // SAFETY: any u64 is 8 valid bytes
+        unsafe { std::slice::from_raw_parts(buffer.as_ptr().cast(), buffer.len() * 8) }
+    }
+
+    // The path of the file with reference number `id`, as canonicalize spells it
+    fn path_of(volume: &File, id: u64) -> Option<PathBuf> {
+        let descriptor = FILE_ID_DESCRIPTOR {
+            dwSize: size_of::<FILE_ID_DESCRIPTOR>() as u32,
+            Type: FileIdType,
+            Anonymous: FILE_ID_DESCRIPTOR_0 { FileId: id as i64 },
+        };
+        // SAFETY: the descriptor is filled in; a valid handle is owned by the File
+        let file = unsafe {
+            let handle = OpenFileById(
+                volume.as_raw_handle(),
+                &descriptor,
+                FILE_READ_ATTRIBUTES,
+                FILE_SHARE_READ | FILE_SHARE_WRITE | FILE_SHARE_DELETE,
+                std::ptr::null(),
+                FILE_FLAG_BACKUP_SEMANTICS,
+            );
+            if handle == INVALID_HANDLE_VALUE {
+                return None;
+            }
+            File::from_raw_handle(handle)
+        };
+        let mut path = vec![0u16; 1024];
+        loop {
+            // SAFETY: the buffer is as long as the length passed
+            let length = unsafe {
+                GetFinalPathNameByHandleW(file.as_raw_handle(), path.as_mut_ptr(), path.len() as u32, FILE_NAME_NORMALIZED | VOLUME_NAME_DOS)
+            } as usize;
+            match length {
+                0 => return None,
+                length if length >= path.len() => path.resize(length + 1, 0),
+                _ => return Some(from_wide(&path)),
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "macos")]
+mod imp {
+    use anyhow::{Result, anyhow};
+    use std::ffi::{CStr, c_char, c_void};
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::fs::MetadataExt;
+    use std::path::Path;
+
+    use super::{Changes, Cursor};
+
+    type CFRef = *const c_void;
+
+    const UTF8: u32 = 0x0800_0100;
+    // FSEventStreamEventFlags
+    const MUST_SCAN_SUBDIRS: u32 = 0x01;
+    const USER_DROPPED: u32 = 0x02;
+    const KERNEL_DROPPED: u32 = 0x04;
+    const EVENT_IDS_WRAPPED: u32 = 0x08;
+    const HISTORY_DONE: u32 = 0x10;
+    const ROOT_CHANGED: u32 = 0x20;
+    const MOUNT: u32 = 0x40;
+    const UNMOUNT: u32 = 0x80;
+    // Seconds to wait for the history to be replayed
+    const TIMEOUT: u32 = 600;
+
+    #[repr(C)]
+    struct StreamContext {
+        version: isize,
+        info: *mut c_void,
+        retain: *const c_void,
+        release: *const c_void,
+        copy_description: *const c_void,
+    }
+
+    type StreamCallback = extern "C" fn(*const c_void, *mut c_void, usize, *mut c_void, *const u32, *const u64);
+
+    #[link(name = "CoreFoundation", kind = "framework")]
+    unsafe extern "C" {
+        static kCFTypeArrayCallBacks: u8;
+        static kCFRunLoopDefaultMode: CFRef;
+        fn CFStringCreateWithBytes(alloc: CFRef, bytes: *const u8, length: isize, encoding: u32, external: u8) -> CFRef;
+        fn CFStringGetCString(string: CFRef, buffer: *mut c_char, size: isize, encoding: u32) -> u8;
+        fn CFUUIDCreateString(alloc: CFRef, uuid: CFRef) -> CFRef;
+        fn CFArrayCreate(alloc: CFRef, values: *const CFRef, count: isize, callbacks: *const c_void) -> CFRef;
+        fn CFRunLoopGetCurrent() -> CFRef;
+        fn CFRunLoopRunInMode(mode: CFRef, seconds: f64, return_after_source: u8) -> i32;
+        fn CFRunLoopStop(run_loop: CFRef);
+        fn CFRelease(object: CFRef);
+    }
+
+    #[link(name = "CoreServices", kind = "framework")]
+    unsafe extern "C" {
+        fn FSEventsGetCurrentEventId() -> u64;
+        fn FSEventsCopyUUIDForDevice(device: libc::dev_t) -> CFRef;
+        fn FSEventStreamCreate(
+            alloc: CFRef,
+            callback: StreamCallback,
+            context: *const StreamContext,
+            paths: CFRef,
+            since: u64,
+            latency: f64,
+            flags: u32,
+        ) -> *mut c_void;
+        fn FSEventStreamScheduleWithRunLoop(stream: *mut c_void, run_loop: CFRef, mode: CFRef);
+        fn FSEventStreamStart(stream: *mut c_void) -> u8;
+        fn FSEventStreamStop(stream: *mut c_void);
+        fn FSEventStreamInvalidate(stream: *mut c_void);
+        fn FSEventStreamRelease(stream: *mut c_void);
+    }
+
+    // The UUID of the FSEvents history of the device holding `root`; it
+    // changes when the history is purged
+    fn device_uuid(root: &Path) -> Result<String> {
+        let device = std::fs::metadata(root)?.dev();
+        // SAFETY: the UUID and its string are released once copied out
+        unsafe {
+            let uuid = FSEventsCopyUUIDForDevice(device as libc::dev_t);
+            if uuid.is_null() {
+                return Err(anyhow!("{} keeps no FSEvents history", root.display()));
+            }
+            let string = CFUUIDCreateString(std::ptr::null(), uuid);
+            CFRelease(uuid);
+            let mut buffer = [0 as c_char; 64];
+            let ok = CFStringGetCString(string, buffer.as_mut_ptr(), buffer.len() as isize, UTF8);
+            CFRelease(string);
+            match ok {
+                0 => Err(anyhow!("Cannot read the FSEvents UUID of {}", root.display())),
+                _ => Ok(CStr::from_ptr(buffer.as_ptr()).to_string_lossy().into_owned()),
+            }
+        }
+    }
+
+    pub fn cursor(root: &Path) -> Result<Cursor> {
+        let journal = device_uuid(root)?;
+        // SAFETY: no arguments
+        Ok(Cursor { journal, position: unsafe { FSEventsGetCurrentEventId() } })
+    }
+
+    // What the stream callback fills in
+    struct Replay<'a> {
+        root: &'a Path,
+        changes: Changes,
+        wrapped: bool,
+        done: bool,
+    }
+
+    extern "C" fn collect(_stream: *const c_void, info: *mut c_void, count: usize, paths: *mut c_void, flags: *const u32, _ids: *const u64) {
+        // SAFETY: `info` is the Replay passed to FSEventStreamCreate, and the
+        // arrays hold `count` entries
+        let (replay, paths, flags) = unsafe {
+            (&mut *(info as *mut Replay), std::slice::from_raw_parts(paths as *const *const c_char, count), std::slice::from_raw_parts(flags, count))
+        };
+        for (&path, &flags) in paths.iter().zip(flags) {
+            // SAFETY: FSEvents passes NUL-terminated paths
+            let path = Path::new(std::ffi::OsStr::from_bytes(unsafe { CStr::from_ptr(path) }.to_bytes()));
+            if flags & (EVENT_IDS_WRAPPED | ROOT_CHANGED) != 0 {
+                replay.wrapped = true;
+            }
+            let tree = flags & (MUST_SCAN_SUBDIRS | USER_DROPPED | KERNEL_DROPPED | MOUNT | UNMOUNT) != 0;
+            if flags & HISTORY_DONE != 0 {
+                replay.done = true;
+                // SAFETY: the callback runs on the run loop being stopped
+                unsafe { CFRunLoopStop(CFRunLoopGetCurrent()) };
+            } else {
+                replay.changes.add(replay.root, path, tree);
+            }
+        }
+    }
+
+    pub fn changes(root: &Path, since: &Cursor) -> Result<Option<Changes>> {
+        if device_uuid(root)? != since.journal {
+            return Ok(None);
+        }
+        let mut replay = Replay { root, changes: Changes::default(), wrapped: false, done: false };
+        let context = StreamContext {
+            version: 0,
+            info: (&raw mut replay).cast(),
+            retain: std::ptr::null(),
+            release: std::ptr::null(),
+            copy_description: std::ptr::null(),
+        };
+        let bytes = root.as_os_str().as_bytes();
+        // SAFETY: every object created is released; the stream is stopped
+        // before `replay` goes out of scope
+        unsafe {
+            let path = CFStringCreateWithBytes(std::ptr::null(), bytes.as_ptr(), bytes.len() as isize, UTF8, 0);
+            let paths = CFArrayCreate(std::ptr::null(), &path, 1, (&raw const kCFTypeArrayCallBacks).cast());
+            CFRelease(path);
+            let stream = FSEventStreamCreate(std::ptr::null(), collect, &context, paths, since.position, 0.0, 0);
+            CFRelease(paths);
+            if stream.is_null() {
+                return Err(anyhow!("Cannot read the FSEvents history of {}", root.display()));
+            }
+            FSEventStreamScheduleWithRunLoop(stream, CFRunLoopGetCurrent(), kCFRunLoopDefaultMode);
+            let started = FSEventStreamStart(stream) != 0;
+            let mut waited = 0;
+            while started && !replay.done && waited < TIMEOUT {
+                CFRunLoopRunInMode(kCFRunLoopDefaultMode, 1.0, 0);
+                waited += 1;
+            }
+            FSEventStreamStop(stream);
+            FSEventStreamInvalidate(stream);
+            FSEventStreamRelease(stream);
+            if !started || !replay.done {
+                return Err(anyhow!("Cannot read the FSEvents history of {}", root.display()));
+            }
+        }
+        Ok((!replay.wrapped).then_some(replay.changes))
+    }
+}
+
+#[cfg(not(any(windows, target_os = "macos")))]
+mod imp {
+    use anyhow::Result;
+    use std::path::Path;
+
+    use super::{Changes, Cursor};
+
+    pub fn cursor(root: &Path) -> Result<Cursor> {
+        anyhow::bail!("{} keeps no change journal cpx can read (only NTFS and macOS do)", root.display())
+    }
+
+    pub fn changes(root: &Path, _since: &Cursor) -> Result<Option<Changes>> {
+        cursor(root).map(|_| None)
+    }
+}
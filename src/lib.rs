@@ -0,0 +1,51 @@
+//! cpx copies files to and from local directories, SSH hosts, object
+//! stores and file servers. The `cpx` binary is a command line over these
+//! modules; another program can use them too, in particular to plug a
+//! backend of its own in with `backend::register`.
+
+pub mod audit;
+pub mod azure;
+pub mod backend;
+pub mod batchfile;
+pub mod bloom;
+pub mod cache;
+pub mod cluster;
+pub mod compress;
+pub mod config;
+pub mod cost;
+pub mod cpu;
+pub mod credentials;
+pub mod dav;
+pub mod dirfd;
+pub mod fault;
+pub mod gcs;
+pub mod glob;
+pub mod hash;
+pub mod hostkeys;
+pub mod http;
+pub mod inventory;
+pub mod journal;
+pub mod netsim;
+pub mod phase;
+pub mod plan;
+pub mod pipe;
+pub mod power;
+pub mod preflight;
+pub mod progress;
+pub mod proxy;
+pub mod prune;
+pub mod quota;
+pub mod rawpath;
+pub mod readcache;
+pub mod route;
+pub mod s3;
+pub mod sample;
+pub mod smb;
+pub mod source;
+pub mod ssh;
+pub mod sshconfig;
+pub mod stage;
+pub mod summary;
+pub mod throttle;
+pub mod tune;
+pub mod utils;
@@ -1,207 +1,2948 @@
-use clap::Parser;
-use std::fs::{self, File};
-use std::io::{BufReader, BufWriter, Read, Write};
+use anyhow::Context;
+use clap::{Parser, Subcommand};
+use std::io::{Read, Write};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::Semaphore;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar};
+use rayon::prelude::*;
 
-mod ssh;
-mod utils;
+use cpx::{
+    audit, backend, batchfile, bloom, cache, cluster, config, cost, cpu, credentials, dirfd, fault, glob, hash,
+    hostkeys, inventory, netsim, phase, plan, pipe, power, preflight, progress, proxy, prune, quota, readcache,
+    route, sample, source, ssh, stage, summary, throttle, utils,
+};
+use cpx::backend::Backend as _;
 
 const PARALLELISM: usize = 8;
+// Transfers at once with --efficiency
+const EFFICIENT_WORKERS: usize = 2;
+const AUTH_CACHE_TTL: u64 = 30;
+const CONNECTORS: usize = 16;
+const CHANNELS_PER_SESSION: usize = 4;
+// How often --follow checks the remote files for new data
+const FOLLOW_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// Source directory or files
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Source directories or files; with several, the destination must be a
+    /// directory. Quote glob patterns ("logs/**/*.gz", "host:/var/log/*.log")
+    /// so cpx expands them: matched files keep their path below the part
+    /// before the first wildcard. A directory is copied into the destination
+    /// (dest/src/...) unless it ends in a slash: `cpx src/ dest` copies what
+    /// is inside src straight into dest. Sources may also be s3://bucket/prefix,
+    /// gs://bucket/prefix, az://container/prefix, dav(s)://host/path,
+    /// smb://server/share/path or http(s):// URLs of files, downloaded several
+    /// ranges at a time and streamed to any destination, a remote host
+    /// included, without being stored here
+    #[clap(required = true, num_args = 1..)]
+    sources: Vec<PathBuf>,
+
+    /// Destination in format user@host:path, s3://bucket/prefix,
+    /// gs://bucket/prefix, az://container/prefix, dav(s)://host/path,
+    /// smb://server/share/path or local/path; {date}, {hostname} and {user}
+    /// are replaced at run time
     #[clap(required = true)]
+    destination: Option<String>,
+
+    #[command(flatten)]
+    args: Args,
+}
+
+impl Cli {
+    // The copy settings of the command, for those that have them
+    fn args(&self) -> Option<&Args> {
+        match &self.command {
+            None => Some(&self.args),
+            Some(Command::Plan { args, .. } | Command::Apply { args, .. } | Command::WriteBatch { args, .. }
+                | Command::Inventory { args, .. } | Command::Cat { args, .. } | Command::Selftest { args, .. }) => Some(args),
+            Some(Command::ApplyBatch { .. }) => None,
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Compare source and destination and write a migration plan without copying anything
+    Plan {
+        /// Source directory or files
+        source: PathBuf,
+
+        /// Destination in format user@host:path, s3://bucket/prefix, gs://bucket/prefix,
+        /// az://container/prefix, dav(s)://host/path, smb://server/share/path or local/path
+        destination: String,
+
+        /// Plan file to write
+        #[arg(short, long, default_value = "plan.json")]
+        output: PathBuf,
+
+        /// Also plan to delete destination files that are not in the source
+        #[arg(long)]
+        delete: bool,
+
+        /// Split the copies into batches of about this size (e.g. 100G); each batch
+        /// is verified and recorded in the plan before the next one starts
+        #[arg(long, value_parser = utils::parse_size)]
+        batch_size: Option<u64>,
+
+        /// Plan against this inventory of the destination (from `cpx inventory`)
+        /// instead of scanning it
+        #[arg(long)]
+        dest_inventory: Option<PathBuf>,
+
+        /// Plan against this bloom filter of the destination (from `cpx inventory
+        /// --bloom`), looking up only the files it cannot tell about and a
+        /// sample of the others
+        #[arg(long, conflicts_with = "dest_inventory")]
+        dest_bloom: Option<PathBuf>,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Carry out a plan written by `cpx plan`, resuming where the last run stopped
+    Apply {
+        /// Plan file to execute; progress is recorded in it
+        plan: PathBuf,
+
+        /// Stop after this many batches, to spread a batched plan over several sessions
+        #[arg(long)]
+        batches: Option<usize>,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Write the differences between source and destination to a self-contained
+    /// batch file, to be carried to an offline system
+    WriteBatch {
+        /// Source directory or files
+        source: PathBuf,
+
+        /// Destination the batch will be applied to, in format user@host:path or local/path
+        destination: String,
+
+        /// Batch file to write
+        #[arg(short, long)]
+        output: PathBuf,
+
+        /// Also delete destination files that are not in the source
+        #[arg(long)]
+        delete: bool,
+
+        /// Span the batch over volumes of at most this size (e.g. 4G), written as
+        /// OUTPUT.001, OUTPUT.002, ... with a manifest in each
+        #[arg(long, value_parser = utils::parse_size)]
+        volume_size: Option<u64>,
+
+        /// Compare against this inventory of the destination (from `cpx inventory`)
+        /// instead of scanning it
+        #[arg(long)]
+        dest_inventory: Option<PathBuf>,
+
+        /// Compare against this bloom filter of the destination (from `cpx
+        /// inventory --bloom`), looking up only the files it cannot tell about
+        /// and a sample of the others
+        #[arg(long, conflicts_with = "dest_inventory")]
+        dest_bloom: Option<PathBuf>,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// List the files under a local or remote directory with their sizes and
+    /// modification times, for planning against it offline with --dest-inventory
+    Inventory {
+        /// Directory to list, in format user@host:path or local/path
+        root: String,
+
+        /// Inventory file to write instead of stdout
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+
+        /// Also hash every file (with --hash), reading all of the tree
+        #[arg(long)]
+        with_hashes: bool,
+
+        /// Write a bloom filter of the files (a few bytes each) instead of
+        /// their list, for trees too large to list; plan with --dest-bloom
+        #[arg(long, conflicts_with = "with_hashes")]
+        bloom: bool,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Write a remote file (user@host:path) to stdout
+    Cat {
+        /// Remote file, in format user@host:path
+        source: String,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Copy a generated tree between two temporary directories with injected
+    /// faults, resuming until the copy is complete, and check the result
+    Selftest {
+        /// Faults to inject, as KIND=PROBABILITY pairs (kinds: read, write, connect)
+        #[arg(long, default_value = "read=0.005,write=0.005", value_parser = fault::Faults::parse)]
+        faults: fault::Faults,
+
+        /// Number of files to generate
+        #[arg(long, default_value_t = 200)]
+        files: usize,
+
+        /// Give up when the copy is still incomplete after this many runs
+        #[arg(long, default_value_t = 20)]
+        rounds: usize,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Apply a batch file written by `cpx write-batch` to a local directory
+    ApplyBatch {
+        /// Batch file to apply (the first volume of a spanned batch)
+        batch: PathBuf,
+
+        /// Local directory to apply the batch to
+        destination: PathBuf,
+    },
+}
+
+#[derive(clap::Args, Debug, Clone)]
+struct Args {
+    // Filled in from the command line or the plan
+    #[arg(skip)]
     source: PathBuf,
 
-    /// Destination in format user@host:path or local/path
-    #[clap(required = true)]
+    #[arg(skip)]
     destination: String,
 
+    // Sources given after the first one (`cpx a/ b/ c.txt DEST`)
+    #[arg(skip)]
+    more_sources: Vec<PathBuf>,
+
     /// Number of parallel workers
     #[arg(short, long, default_value_t = PARALLELISM)]
     jobs: usize,
 
+    /// Settings profile from ~/.config/cpx/config.json
+    #[arg(long)]
+    profile: Option<String>,
+
+    /// Transfers on the network at once, counting the parts of an S3 object sent in parallel (default: --jobs)
+    #[arg(long, value_name = "N")]
+    net_workers: Option<usize>,
+
+    /// Local files read at once, by local copies and by SSH uploads (default: --jobs)
+    #[arg(long, value_name = "N")]
+    disk_workers: Option<usize>,
+
+    /// SSH connections per host (default: --net-workers divided by --channels-per-session)
+    #[arg(long)]
+    sessions: Option<usize>,
+
+    /// Transfers multiplexed over each SSH connection; lowered automatically if
+    /// the server refuses channels (sshd's MaxSessions)
+    #[arg(long, default_value_t = CHANNELS_PER_SESSION)]
+    channels_per_session: usize,
+
+    /// Additional SSH destinations (user@host:path) to copy to as well
+    #[arg(long = "also", value_name = "DEST")]
+    also: Vec<String>,
+
+    /// Send matching files elsewhere: 'older_than=90d => DEST', 'newer_than=7d => DEST',
+    /// 'size>1G => DEST' or 'size<4K => DEST'; repeatable, the first matching rule wins
+    #[arg(long = "route", value_name = "RULE")]
+    routes: Vec<route::Route>,
+
+    /// Maximum number of SSH handshakes in flight when connecting to many hosts
+    #[arg(long, default_value_t = CONNECTORS)]
+    connectors: usize,
+
+    /// Continue with the reachable hosts when some destinations fail the connection check
+    #[arg(long)]
+    skip_unreachable: bool,
+
+    /// Compress data on the wire with zstd at the given level (SSH only, needs zstd on the host)
+    #[arg(short = 'C', long, value_name = "LEVEL", num_args = 0..=1, require_equals = true, default_missing_value = "3")]
+    compress: Option<i32>,
+
+    /// Re-read each source file after copying and compare hashes
+    #[arg(long)]
+    paranoid: bool,
+
+    /// Hash algorithm used for verification
+    #[arg(long, value_enum, default_value_t = hash::Algorithm::default())]
+    hash: hash::Algorithm,
+
+    /// After the copy, hash a random sample of this share of the copied files
+    /// (e.g. 5%) at the source and the destination and compare them
+    #[arg(long, value_name = "PERCENT", value_parser = utils::parse_percent)]
+    verify_sample: Option<f64>,
+
+    /// Threads dedicated to verification hashing (default: one per core)
+    #[arg(long)]
+    hash_threads: Option<usize>,
+
+    /// Cores that compression and hashing may use at once, so a copy on a
+    /// production host leaves the others to the services running there
+    #[arg(long, value_name = "CORES", value_parser = clap::value_parser!(u64).range(1..))]
+    cpu_limit: Option<u64>,
+
+    /// Copy setuid, setgid and sticky bits too; by default they are dropped
+    /// (and reported) so a copy never creates privileged binaries by accident
+    #[arg(long)]
+    preserve_special_bits: bool,
+
+    /// Limit reads from the source to this many bytes per second over all
+    /// transfers and verification (e.g. 50M), to go easy on shared storage
+    #[arg(long, value_name = "RATE", value_parser = utils::parse_size)]
+    read_bwlimit: Option<u64>,
+
+    /// Put a local destination behind a simulated link, as round-trip time
+    /// and/or bandwidth (e.g. 50ms/10Mbit), to try settings without the real
+    /// network; with --compress, the link carries the compressed size
+    #[arg(long, value_name = "RTT/RATE", value_parser = netsim::parse)]
+    simulate_network: Option<netsim::Link>,
+
+    /// Source data read ahead of each transfer's destination (e.g. 4M, 0 to
+    /// turn it off). Reading stops while this much is waiting, so a slow
+    /// destination holds back reads rather than filling memory.
+    #[arg(long, value_name = "SIZE", default_value_t = pipe::READ_AHEAD, value_parser = utils::parse_size)]
+    read_ahead: u64,
+
+    /// Memory for files read once and sent from there to every host when
+    /// copying to several (--also), instead of reading them from disk for
+    /// each; files that do not fit are read for each host, 0 always does
+    #[arg(long, value_name = "SIZE", default_value_t = readcache::BUDGET, value_parser = utils::parse_size)]
+    read_cache: u64,
+
+    /// Data written to SSH channels at once (e.g. 256K); by default sized to
+    /// the link from the round-trip time and throughput of the first seconds
+    #[arg(long, value_name = "SIZE", value_parser = utils::parse_size)]
+    chunk_size: Option<u64>,
+
+    /// Go easy on a laptop's battery: at most 2 transfers at once, no
+    /// read-ahead, large reused buffers, and a pause whenever the system is
+    /// saving power (low-power profile or a low, discharging battery)
+    #[arg(long)]
+    efficiency: bool,
+
+    /// Create missing components of the destination path itself
+    #[arg(long)]
+    mkpath: bool,
+
+    /// Skip files in directories that are unchanged since the last successful
+    /// run; at the dirs level unchanged directories are not even read, at the
+    /// journal level the filesystem's change journal tells which changed
+    #[arg(long, value_name = "LEVEL", num_args = 0..=1, require_equals = true, default_missing_value = "files")]
+    prune_unchanged: Option<prune::Level>,
+
+    /// Write each file to a hidden .cpx-delayed directory beside its own and
+    /// move them all into place once every transfer is done, so the
+    /// destination is never seen half updated; nothing is moved when a
+    /// transfer fails
+    #[arg(long)]
+    delay_updates: bool,
+
+    /// Percent-encode control characters, invalid UTF-8 and '%' in file names
+    /// that have any (a\nb becomes a%0Ab) instead of copying them as they are
+    #[arg(long)]
+    sanitize_names: bool,
+
+    /// Send files in path order, start their transfers in that order and sort
+    /// the lists in summaries and reports, so runs over the same tree can be
+    /// diffed; --verify-sample then checks the same files every time
+    #[arg(long)]
+    deterministic: bool,
+
+    /// Copy only the paths listed in FILE (- for stdin), one per line and
+    /// relative to the source, instead of walking it; each keeps its path
+    /// below the destination, and listed directories are copied whole
+    #[arg(long, value_name = "FILE")]
+    files_from: Option<PathBuf>,
+
+    /// Append overwrites to an audit log (--audit-log=FILE); without FILE the log is kept in the destination root
+    #[arg(long, value_name = "FILE", num_args = 0..=1, require_equals = true)]
+    audit_log: Option<Option<PathBuf>>,
+
+    /// Stop before the bytes written to a destination, counted across runs,
+    /// would exceed this size (e.g. 500G)
+    #[arg(long, value_parser = utils::parse_size)]
+    quota: Option<u64>,
+
+    /// When copying from a remote source (or with `cpx cat`), keep appending
+    /// data written to the files after they were copied, like tail -f
+    #[arg(short, long)]
+    follow: bool,
+
+    /// Seconds over which the displayed transfer rates and ETAs are averaged
+    #[arg(long, value_name = "SECS", default_value_t = progress::RATE_WINDOW,
+          value_parser = clap::value_parser!(u64).range(1..))]
+    rate_window: u64,
+
+    /// Write a JSON report of the run to this file
+    #[arg(long, value_name = "FILE")]
+    report: Option<PathBuf>,
+
+    /// Write the phases of the run (scan, transfer, verify, finalize) to this
+    /// file as JSON lines, as they begin
+    #[arg(long, value_name = "FILE")]
+    events: Option<PathBuf>,
+
+    /// What to do with a source file that grows while it is being copied
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = source::Grown::default())]
+    grown_files: source::Grown,
+
+    /// Leave out directories on kernel pseudo filesystems (/proc, /sys,
+    /// cgroup, debugfs...) when scanning sources
+    #[arg(long)]
+    skip_pseudo_fs: bool,
+
+    /// Count source files deleted between the scan and their copy (rotated
+    /// logs, temporary files) as failures instead of reporting them as vanished
+    #[arg(long)]
+    fail_on_vanished: bool,
+
+    /// Treat unreadable files and directories found while scanning as errors
+    /// (--ignore-walk-errors=false) instead of only reporting them
+    #[arg(long, default_value_t = true, action = clap::ArgAction::Set)]
+    ignore_walk_errors: bool,
+
+    /// Storage class of objects uploaded to a store (e.g. STANDARD_IA or
+    /// GLACIER_IR on S3, NEARLINE or COLDLINE on GCS, the access tier Cool,
+    /// Cold or Archive on Azure)
+    #[arg(long, value_name = "CLASS")]
+    storage_class: Option<String>,
+
+    /// Cache-Control header of objects uploaded to a store, which web
+    /// servers and CDNs serving them pass on (e.g. "public, max-age=3600")
+    #[arg(long, value_name = "VALUE")]
+    cache_control: Option<String>,
+
+    /// Metadata stored with objects uploaded to a store; repeatable
+    #[arg(long, value_name = "KEY=VALUE", value_parser = utils::parse_pair)]
+    metadata: Vec<(String, String)>,
+
+    /// Tag objects uploaded to a store; repeatable
+    #[arg(long = "tag", value_name = "KEY=VALUE", value_parser = utils::parse_pair)]
+    tags: Vec<(String, String)>,
+
+    /// How to talk to SSH hosts: scp channels plus remote shell commands,
+    /// SFTP only (works with restricted shells, never runs a remote command),
+    /// or exec, uploads streamed through `cat` in a remote shell and checked
+    /// by size and SHA-256 (hosts with neither SFTP nor a working scp)
+    #[arg(long, value_enum, default_value_t = ssh::Protocol::default())]
+    protocol: ssh::Protocol,
+
+    /// SSH port for hosts given without one (host:2222:/path); by default
+    /// the Port from ~/.ssh/config for the host, else 22
+    #[arg(short = 'P', long)]
+    port: Option<u16>,
+
+    /// Private key to authenticate with instead of the IdentityFile of
+    /// ~/.ssh/config or ~/.ssh/id_ed25519, id_ecdsa and id_rsa, as with scp
+    /// -i; repeat it to try several. An encrypted key's passphrase is asked
+    /// for once
+    #[arg(short = 'i', long, value_name = "FILE")]
+    identity: Vec<PathBuf>,
+
+    /// Add an encrypted key to the running ssh-agent once its passphrase is
+    /// entered, so later connections and runs need none, as with ssh's
+    /// AddKeysToAgent (also read from ~/.ssh/config)
+    #[arg(long)]
+    add_keys_to_agent: bool,
+
+    /// PKCS#11 library of a smartcard or hardware token whose keys to
+    /// authenticate with (e.g. /usr/lib/opensc-pkcs11.so), as with ssh -I.
+    /// The keys are loaded into the running ssh-agent by ssh-add, which asks
+    /// for the PIN once; PKCS11Provider in ~/.ssh/config is used when not given
+    #[arg(long, value_name = "LIBRARY")]
+    pkcs11: Option<PathBuf>,
+
+    /// Command whose stdin and stdout reach the host, as with ssh -o
+    /// ProxyCommand (e.g. "ssh -W %h:%p gateway"); %h, %p, %r, %n and %%
+    /// are replaced. Overrides the ProxyCommand of ~/.ssh/config, "none"
+    /// connects directly
+    #[arg(long, value_name = "COMMAND")]
+    proxy_command: Option<String>,
+
+    /// SOCKS5 or HTTP proxy to reach SSH hosts through, as
+    /// socks5://[user:password@]host:port or http://[user:password@]host:port
+    #[arg(long, value_name = "URL", value_parser = proxy::parse, conflicts_with = "proxy_command")]
+    proxy: Option<proxy::Proxy>,
+
+    /// How to treat hosts missing from ~/.ssh/known_hosts; a host whose key
+    /// changed is refused unless this is "no"
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = hostkeys::Strict::default())]
+    strict_host_key_checking: hostkeys::Strict,
+
+    /// Require the host key to have this fingerprint ([HOST=]SHA256:..., as
+    /// ssh-keygen -lf prints it) instead of consulting known_hosts; repeat it
+    /// for several hosts or keys
+    #[arg(long, value_name = "FINGERPRINT", value_parser = hostkeys::parse_pin)]
+    fingerprint: Vec<hostkeys::Pin>,
+
+    /// Disable Nagle's algorithm on SSH connections
+    #[arg(long)]
+    tcp_nodelay: bool,
+
+    /// Socket send buffer size for SSH connections (e.g. 4M)
+    #[arg(long, value_parser = utils::parse_size)]
+    sndbuf: Option<u64>,
+
+    /// Socket receive buffer size for SSH connections (e.g. 4M)
+    #[arg(long, value_parser = utils::parse_size)]
+    rcvbuf: Option<u64>,
+
+    /// SSH key exchange algorithms to offer, most preferred first
+    /// (e.g. curve25519-sha256,ecdh-sha2-nistp256)
+    #[arg(long, value_name = "LIST")]
+    kex: Option<String>,
+
+    /// SSH ciphers to offer, most preferred first (e.g. aes256-gcm@openssh.com,aes256-ctr)
+    #[arg(long, value_name = "LIST")]
+    ciphers: Option<String>,
+
+    /// SSH MACs to offer, most preferred first (e.g. hmac-sha2-512-etm@openssh.com)
+    #[arg(long, value_name = "LIST")]
+    macs: Option<String>,
+
+    /// Minutes to remember which SSH auth method worked per host (0 disables)
+    #[arg(long, default_value_t = AUTH_CACHE_TTL)]
+    auth_cache_ttl: u64,
 }
 
-async fn send_file(
-    src_root: PathBuf,
-    dest_root: PathBuf,
-    path: PathBuf,
-    pb: ProgressBar
-) -> anyhow::Result<()> {
-    let src_path = src_root.join(&path);
-    let dest_path = dest_root.join(&path);
-    fs::create_dir_all(dest_path.parent().unwrap())?;
-    let mut input = BufReader::new(File::open(&src_path)?);
-    let mut output = BufWriter::new(File::create(&dest_path)?);
-    let mut buffer = vec![0; 8192];
-    let mut written = 0u64;
-    loop {
-        let n = input.read(&mut buffer)?;
-        if n == 0 {
-            break;
+impl Args {
+    fn net_workers(&self) -> usize {
+        self.net_workers.unwrap_or(self.workers())
+    }
+
+    fn disk_workers(&self) -> usize {
+        self.disk_workers.unwrap_or(self.workers())
+    }
+
+    // --jobs, kept low by --efficiency
+    fn workers(&self) -> usize {
+        match self.efficiency {
+            true => self.jobs.min(EFFICIENT_WORKERS),
+            false => self.jobs,
         }
-        let data = &buffer[..n];
-        output.write_all(data)?;
-        written += n as u64;
-        pb.set_position(written);
     }
-    pb.finish_and_clear();
-    Ok(())
+
+    // --efficiency reads nothing ahead, data is read when it can be written
+    fn read_ahead(&self) -> u64 {
+        match self.efficiency {
+            true => 0,
+            false => self.read_ahead,
+        }
+    }
+
+    // Threads for a hashing pool that would otherwise get `wanted`
+    fn cpu_threads(&self, wanted: usize) -> usize {
+        match self.cpu_limit {
+            Some(cores) => wanted.min(cores as usize),
+            None => wanted,
+        }
+    }
+
+    // --net-workers is spread over the sessions unless their number is given
+    fn sessions(&self) -> usize {
+        self.sessions.unwrap_or(self.net_workers().div_ceil(self.channels_per_session.max(1)))
+    }
+
+    // How objects uploaded to a store are stored; their content type comes
+    // from their name
+    fn object_options(&self) -> backend::ObjectOptions {
+        backend::ObjectOptions {
+            storage_class: self.storage_class.clone(),
+            cache_control: self.cache_control.clone(),
+            metadata: self.metadata.clone(),
+            tags: self.tags.clone(),
+        }
+    }
+
+    // Every source, in the order given
+    fn sources(&self) -> impl Iterator<Item = &PathBuf> {
+        std::iter::once(&self.source).chain(self.more_sources.iter())
+    }
+
+    // Destinations given besides the main one may use placeholders too
+    fn expand_placeholders(&mut self) {
+        for destination in &mut self.also {
+            *destination = utils::expand_placeholders(destination);
+        }
+        for route in &mut self.routes {
+            route.destination = utils::expand_placeholders(&route.destination);
+        }
+    }
+}
+
+/// A file to send, relative to `root` (the parent of the source it came from)
+struct WorkItem {
+    root: Arc<Path>,
+    path: PathBuf,
+    size: u64,
+}
+
+/// Where the files to send come from
+enum Work {
+    // Walk the source tree
+    Walk,
+    // A precomputed list, e.g. the pending copies of a plan
+    Files(Vec<WorkItem>),
 }
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
-    let args = Args::parse();
+    let cli = Cli::parse();
+    if let Some(args) = cli.args() {
+        dirfd::skip_pseudo_fs(args.skip_pseudo_fs);
+        if args.efficiency {
+            power::efficiency();
+        }
+    }
 
-    let dest_parts = args.destination.split(":").collect::<Vec<_>>();
+    match cli.command {
+        Some(Command::Plan { source, destination, output, delete, batch_size, dest_inventory, dest_bloom, args }) => {
+            let destination = utils::expand_placeholders(&destination);
+            make_plan(source, destination, &output, delete, batch_size, dest_inventory.as_deref(), dest_bloom.as_deref(), args).await
+        }
+        Some(Command::Apply { plan, batches, args }) => apply_plan(&plan, batches, args).await,
+        Some(Command::WriteBatch { source, destination, output, delete, volume_size, dest_inventory, dest_bloom, args }) => {
+            let destination = utils::expand_placeholders(&destination);
+            let plan = scan_differences(&source, &destination, delete, dest_inventory.as_deref(), dest_bloom.as_deref(), &args)?;
+            plan.print();
+            let src_root = source_root(&source);
+            let volumes = batchfile::write(&plan, src_root, &output, volume_size, args.hash, make_read_limit(&args))?;
+            if volume_size.is_some() {
+                println!("✅ Batch written to {} volumes {}.001 to {}.{:03}", volumes, output.display(), output.display(), volumes);
+            } else {
+                println!("✅ Batch written to {}", output.display());
+            }
+            Ok(())
+        }
+        Some(Command::Inventory { root, output, with_hashes, bloom, args }) => inventory(&root, output.as_deref(), with_hashes, bloom, &args),
+        Some(Command::Cat { source, args }) => cat(&source, &args),
+        Some(Command::Selftest { faults, files, rounds, args }) => selftest(faults, files, rounds, args).await,
+        Some(Command::ApplyBatch { batch, destination }) => {
+            preflight::check_local_dest(&destination)?;
+            batchfile::apply(&batch, &destination)?;
+            println!("✅ Batch applied to {}", destination.display());
+            Ok(())
+        }
+        None => {
+            let mut args = cli.args;
+            let mut sources = cli.sources;
+            args.source = sources.remove(0);
+            args.more_sources = sources;
+            args.destination = utils::expand_placeholders(&cli.destination.unwrap());
+            args.expand_placeholders();
+            if !args.routes.is_empty() {
+                return copy_routed(args).await;
+            }
+            if let Some(list) = args.files_from.clone() {
+                return copy_files_from(args, &list).await;
+            }
+            let urls = args.sources().all(|source| is_url(source));
+            if !urls && (!args.more_sources.is_empty() || args.sources().any(|source| !is_remote(source) && glob::is_pattern(source))) {
+                return copy_sources(args).await;
+            }
+            copy(args, &new_transfer_id(), Work::Walk, &mut |_| {}).await
+        }
+    }
+}
 
-    if dest_parts.len() == 2 {
-        cp_ssh_files(args).await?;
-    } else if dest_parts.len() == 1 {
-        cp_local_files(args).await?;
+// `cpx inventory`: the inventory may go to stdout, so progress goes to stderr
+fn inventory(root: &str, output: Option<&Path>, with_hashes: bool, bloom: bool, args: &Args) -> anyhow::Result<()> {
+    eprintln!("🔍 Scanning {}...", root);
+    let hash = with_hashes.then_some(args.hash);
+    let threads = |workers| if hash.is_some() { args.cpu_threads(workers) } else { workers };
+    let (inventory, errors) = if is_remote(Path::new(root)) {
+        let (ssh_dest, remote_root) = parse_ssh_destination(root)?;
+        let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, args.sessions(), args.channels_per_session, connect_options(args)?)?);
+        let lease = pool.get_connection()?;
+        let remote_root = pool.transfer(&lease).resolve_path(&remote_root);
+        pool.return_connection(lease);
+        inventory::Inventory::scan_remote(pool, &remote_root?, hash, threads(args.net_workers()))?
+    } else {
+        inventory::Inventory::scan_local(Path::new(root), hash, threads(args.disk_workers()))?
+    };
+    for e in &errors {
+        eprintln!("Error: {}", e);
+    }
+    if bloom {
+        let bloom = bloom::Bloom::new(&inventory.root, &inventory.listing(Path::new("")));
+        bloom.save(output)?;
+        eprintln!("✅ {} files in a bloom filter of {}", bloom.files, HumanBytes(bloom.bytes() as u64));
     } else {
-        anyhow::bail!("Invalid destination format");
+        inventory.save(output)?;
+        eprintln!("✅ {} files listed", inventory.files.len());
+    }
+    if !errors.is_empty() {
+        anyhow::bail!("{} entries could not be read, the inventory is incomplete", errors.len());
     }
-    
     Ok(())
 }
 
-async fn cp_local_files(args: Args) -> anyhow::Result<()> {
-    let src_root = Path::new(&args.source).parent().unwrap_or(&args.source);
-    let dest_root = Path::new(&args.destination);
-    println!("Copying from {} to {}", src_root.display(), dest_root.display());
-    let m = Arc::new(MultiProgress::new());
+// `cpx cat`: the file goes to stdout, so everything else goes to stderr
+fn cat(source: &str, args: &Args) -> anyhow::Result<()> {
+    let (ssh_dest, remote_path) = parse_ssh_destination(source)?;
+    let pool = ssh::SshConnectionPool::new(ssh_dest, 1, 1, connect_options(args)?)?;
+    let lease = pool.get_connection()?;
+    let transfer = pool.transfer(&lease);
+    let pb = ProgressBar::new(0);
+    pb.set_style(progress::file_style("{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})", Duration::from_secs(args.rate_window)));
+    pb.set_message(source.to_string());
+    let result = transfer.resolve_path(&remote_path).and_then(|remote_path| {
+        let follow = args.follow.then_some(FOLLOW_INTERVAL);
+        transfer.cat(&remote_path, &mut std::io::stdout().lock(), follow, make_read_limit(args), &pb)
+    });
+    pb.finish_and_clear();
+    pool.return_connection(lease);
+    result
+}
 
-    let semaphore = Arc::new(Semaphore::new(args.jobs));
-    let mut handles = vec![];
+// Identifies a run in logs, reports and remote artifacts
+fn new_transfer_id() -> String {
+    let transfer_id = uuid::Uuid::new_v4().to_string();
+    println!("🆔 Transfer {}", transfer_id);
+    transfer_id
+}
 
-    let walker = walkdir::WalkDir::new(&args.source);
-    walker.into_iter().filter_map(Result::ok).for_each(|entry| {
-        let path = entry.path();
-        if path.is_file() {
-            let size = path.metadata().unwrap().len();
-            let src_root = src_root.to_path_buf();
-            let dest_root = dest_root.to_path_buf();
-            let path = path.strip_prefix(&src_root).unwrap().to_path_buf();
-            println!("processing file2 :{}, {}", src_root.display(), path.display());
-            let sem = semaphore.clone();
-            let m = m.clone();
+// Send `work` to the destination(s) in `args`, calling `on_done` as each file finishes
+async fn copy(
+    args: Args,
+    transfer_id: &str,
+    work: Work,
+    on_done: &mut dyn FnMut(&summary::FileResult),
+) -> anyhow::Result<()> {
+    let remote_dest = split_remote(&args.destination).is_some();
+    if args.follow && args.verify_sample.is_some() {
+        anyhow::bail!("--verify-sample cannot be combined with --follow, which never finishes");
+    }
+    if !backend::is_url(&args.destination) && !args.object_options().is_empty() {
+        anyhow::bail!("--storage-class, --cache-control, --metadata and --tag only apply to objects uploaded to a store");
+    }
+    let sample_args = args.verify_sample.is_some().then(|| args.clone());
+    let mut copied = vec![];
+    let on_done = &mut |result: &summary::FileResult| {
+        if result.ok && sample_args.is_some() {
+            copied.push(result.path.clone());
+        }
+        on_done(result);
+    };
 
-            let h = tokio::spawn(async move {
-                let _permit = sem.acquire().await.unwrap();
-                
-                let pb = m.add(ProgressBar::new(size));
-                let sty = ProgressStyle::with_template("{msg} {bar:40} {bytes}/{total_bytes} ({eta})")
-                    .unwrap()
-                    .progress_chars("=>-");
-                pb.set_style(sty);
-                pb.set_message(utils::align_str(path.to_str().unwrap(), 20));
-                let _ = send_file(src_root, dest_root, path, pb).await;
-            });
-            handles.push(h);
+    if is_url(&args.source) || is_remote(&args.source) && backend::is_url(&args.destination) {
+        let Work::Walk = work else {
+            anyhow::bail!("Plans cannot be applied from a remote source");
+        };
+        cp_between(args, transfer_id, on_done).await?;
+    } else if is_remote(&args.source) {
+        let Work::Walk = work else {
+            anyhow::bail!("Plans cannot be applied from a remote source");
+        };
+        if args.delay_updates {
+            anyhow::bail!("--delay-updates only applies when copying from a local source");
         }
-    });
+        if remote_dest {
+            cp_ssh_same_host(args, transfer_id, on_done).await?;
+        } else {
+            cp_ssh_download(args, transfer_id, on_done).await?;
+        }
+    } else if args.follow {
+        anyhow::bail!("--follow only applies when copying from a remote source");
+    } else if remote_dest {
+        if args.compress.is_some() && args.protocol == ssh::Protocol::Sftp {
+            anyhow::bail!("--compress needs a remote shell for zstd, it cannot be used with --protocol sftp");
+        }
+        if args.simulate_network.is_some() {
+            anyhow::bail!("--simulate-network only applies to local destinations");
+        }
+        cp_ssh_files(args, transfer_id, work, on_done).await?;
+    } else {
+        if !args.also.is_empty() {
+            anyhow::bail!("--also is only supported with SSH destinations");
+        }
+        if args.compress.is_some() && args.simulate_network.is_none() {
+            anyhow::bail!("--compress is only supported with SSH destinations (or --simulate-network)");
+        }
+        cp_backend_files(args, transfer_id, work, on_done).await?;
+    }
 
-    // Wait for all transfers
-    for h in handles {
-        let _ = h.await;
+    if let Some(args) = sample_args {
+        verify_sample(args, transfer_id, copied).await?;
     }
+    Ok(())
+}
 
-    println!("✅ Transfer completed!");
+// --verify-sample: spot-check a random share of the files copied by this run
+async fn verify_sample(args: Args, transfer_id: &str, copied: Vec<PathBuf>) -> anyhow::Result<()> {
+    let Some(fraction) = args.verify_sample else {
+        return Ok(());
+    };
+    let total = copied.len();
+    let seed = if args.deterministic { "" } else { transfer_id };
+    let sample = sample::pick(copied, fraction, seed);
+    if sample.is_empty() {
+        return Ok(());
+    }
+    tokio::task::spawn_blocking(move || {
+        let source = sample_endpoint(&args, &args.source.to_string_lossy(), true)?;
+        let destinations = std::iter::once(&args.destination)
+            .chain(args.also.iter())
+            .map(|destination| sample_endpoint(&args, destination, false))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        sample::verify(&source, &destinations, &sample, total, args.hash, args.cpu_threads(args.jobs), make_read_limit(&args))
+    })
+    .await?
+}
+
+// Where the files copied from or to `spec` (local path, URL or user@host:path) are
+// found: copied paths are relative to the parent of the source and to the
+// destination itself, unless a single file was copied to a new name
+fn sample_endpoint(args: &Args, spec: &str, is_source: bool) -> anyhow::Result<sample::Endpoint> {
+    let (root, is_dir, pool) = if split_remote(spec).is_some() {
+        let (ssh_dest, remote_path) = parse_ssh_destination(spec)?;
+        let sessions = args.sessions();
+        let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, sessions, args.channels_per_session, connect_options(args)?)?);
+        let lease = pool.get_connection()?;
+        let transfer = pool.transfer(&lease);
+        let resolved = transfer.resolve_path(&remote_path).and_then(|root| {
+            let is_dir = transfer.remote_kind(&root)? == Some(ssh::RemoteKind::Dir);
+            Ok((root, is_dir))
+        });
+        pool.return_connection(lease);
+        let (root, is_dir) = resolved?;
+        (root, is_dir, Some(pool))
+    } else if backend::is_url(spec) {
+        (PathBuf::from(spec), backend::names_dir(spec), None)
+    } else {
+        (PathBuf::from(spec), Path::new(spec).is_dir(), None)
+    };
+    let (root, rename) = if is_source {
+        (if copies_contents(Path::new(spec)) { root } else { source_root(&root).to_path_buf() }, None)
+    } else {
+        match single_file_target(&args.source, &root, is_dir) {
+            Some((root, name)) => (root, Some(name)),
+            None => (root, None),
+        }
+    };
+    let backend: Arc<dyn backend::Backend> = match pool {
+        Some(pool) => Arc::new(backend::SshBackend::new(pool, root)),
+        None if backend::is_url(spec) => backend::open_url(&root.to_string_lossy())?,
+        None => backend::open(&root),
+    };
+    Ok(sample::Endpoint { label: spec.to_string(), backend, rename })
+}
+
+// Walk the source once and hand each file to the destination its --route
+// rule picks, then copy to one destination after the other
+async fn copy_routed(args: Args) -> anyhow::Result<()> {
+    if args.sources().any(|source| is_remote(source) || is_url(source)) {
+        anyhow::bail!("--route needs a local source");
+    }
+    if !args.also.is_empty() {
+        anyhow::bail!("--also cannot be combined with --route");
+    }
+    if args.prune_unchanged.is_some() {
+        anyhow::bail!("--prune-unchanged cannot be combined with --route");
+    }
+    let transfer_id = new_transfer_id();
+    let now = utils::now_secs();
+    let mut groups: Vec<(String, Vec<WorkItem>)> = vec![];
+    let mut walk_errors = vec![];
+    for source in args.sources() {
+        let pattern = glob::is_pattern(source).then(|| glob::Pattern::new(source));
+        let (src_root, entries): (Arc<Path>, Box<dyn Iterator<Item = std::io::Result<dirfd::Entry>>>) = match &pattern {
+            Some(pattern) => (Arc::from(pattern.base.as_path()), Box::new(pattern.walk())),
+            None => (Arc::from(source_root(source)), Box::new(dirfd::walk(source))),
+        };
+        for entry in entries {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    walk_errors.push(e.to_string());
+                    continue;
+                }
+            };
+            if !entry.is_file() {
+                continue;
+            }
+            let destination = route::pick(&args.routes, &args.destination, &entry.stat(), now);
+            let item = WorkItem {
+                root: src_root.clone(),
+                path: entry.path().strip_prefix(&src_root).unwrap().to_path_buf(),
+                size: entry.size(),
+            };
+            match groups.iter_mut().find(|(dest, _)| dest == destination) {
+                Some((_, items)) => items.push(item),
+                None => groups.push((destination.to_string(), vec![item])),
+            }
+        }
+    }
+
+    if args.deterministic {
+        groups.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+
+    let mut failed = vec![];
+    // (destination, files copied, bytes copied, files failed)
+    let mut routed = vec![];
+    for (destination, items) in groups {
+        let bytes: u64 = items.iter().map(|item| item.size).sum();
+        println!("🧭 {} files ({}) to {}", items.len(), HumanBytes(bytes), destination);
+        let mut args = args.clone();
+        args.destination = destination.clone();
+        let (mut files, mut bytes, mut errors) = (0, 0, 0);
+        let r = copy(args, &transfer_id, Work::Files(items), &mut |result| {
+            if result.ok {
+                files += 1;
+                bytes += result.size;
+            } else if !result.vanished {
+                errors += 1;
+            }
+        }).await;
+        routed.push((destination.clone(), files, bytes, errors));
+        if let Err(e) = r {
+            eprintln!("Error: {}: {:#}", destination, e);
+            failed.push(destination);
+        }
+    }
+    let sources = args.sources().map(|source| source.display().to_string()).collect::<Vec<_>>();
+    println!("🧭 Routed {} -> {} destinations:", sources.join(" "), routed.len());
+    for (destination, files, bytes, errors) in &routed {
+        println!("   {}: {} files, {}, {} failed", destination, files, HumanBytes(*bytes), errors);
+    }
+    if !failed.is_empty() {
+        anyhow::bail!("Copying to {} failed", failed.join(", "));
+    }
+    if !args.ignore_walk_errors && !walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", walk_errors.len());
+    }
+    Ok(())
+}
+
+// `cpx a/ b/ c.txt DEST`: walk every source into one work queue. Paths stay
+// relative to the parent of their source, so each lands under its own name in
+// DEST; when two sources yield the same path the later source wins, as with cp.
+async fn copy_sources(args: Args) -> anyhow::Result<()> {
+    if args.sources().any(|source| is_remote(source) || is_url(source)) {
+        anyhow::bail!("A remote source must be the only source");
+    }
+    if args.prune_unchanged.is_some() {
+        anyhow::bail!("--prune-unchanged needs a single source directory");
+    }
+    if args.verify_sample.is_some() {
+        anyhow::bail!("--verify-sample needs a single source directory");
+    }
+    let transfer_id = new_transfer_id();
+    let mut summary = summary::Summary::new(&transfer_id, args.rate_window);
+    let mut queue: Vec<WorkItem> = vec![];
+    let mut index: HashMap<PathBuf, usize> = HashMap::new();
+    for source in args.sources() {
+        let items = if glob::is_pattern(source) {
+            let pattern = glob::Pattern::new(source);
+            let items = scan_entries(pattern.walk(), &pattern.base, None, &mut summary, None);
+            if items.is_empty() {
+                anyhow::bail!("No files match {}", source.display());
+            }
+            items
+        } else if source.exists() {
+            scan_source(source, source_root(source), None, &mut summary, None)
+        } else {
+            anyhow::bail!("Source {} does not exist", source.display());
+        };
+        for item in items {
+            match index.get(&item.path) {
+                Some(&i) => {
+                    println!(
+                        "⚠️  {} is in both {} and {}, copying the one from {}",
+                        item.path.display(), queue[i].root.display(), item.root.display(), item.root.display()
+                    );
+                    queue[i] = item;
+                }
+                None => {
+                    index.insert(item.path.clone(), queue.len());
+                    queue.push(item);
+                }
+            }
+        }
+    }
+    copy(args.clone(), &transfer_id, Work::Files(queue), &mut |_| {}).await?;
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the sources", summary.walk_errors.len());
+    }
+    Ok(())
+}
+
+// --files-from: copy the listed paths below the source, skipping the walk
+async fn copy_files_from(args: Args, list: &Path) -> anyhow::Result<()> {
+    if !args.more_sources.is_empty() || is_remote(&args.source) || !args.source.is_dir() {
+        anyhow::bail!("--files-from needs a single local source directory");
+    }
+    if args.prune_unchanged.is_some() {
+        anyhow::bail!("--prune-unchanged cannot be combined with --files-from");
+    }
+    if args.verify_sample.is_some() {
+        anyhow::bail!("--verify-sample cannot be combined with --files-from");
+    }
+    let lines = if list == Path::new("-") {
+        std::io::stdin().lines().collect::<Result<Vec<_>, _>>()?
+    } else {
+        std::fs::read_to_string(list)
+            .with_context(|| format!("Cannot read file list {}", list.display()))?
+            .lines()
+            .map(str::to_string)
+            .collect()
+    };
+    let transfer_id = new_transfer_id();
+    let mut summary = summary::Summary::new(&transfer_id, args.rate_window);
+    let mut queue = vec![];
+    let mut seen = HashSet::new();
+    for line in lines.iter().map(|line| line.trim_end_matches('\r')).filter(|line| !line.is_empty()) {
+        let Some(rel) = utils::contained_path(Path::new(line)) else {
+            anyhow::bail!("{} in {} is not below the source", line, list.display());
+        };
+        if dirfd::stat_beneath(&args.source, &rel)?.is_none() {
+            eprintln!("Error: {} is listed but does not exist", args.source.join(&rel).display());
+            summary.walk_errors.push(format!("{}: not found", rel.display()));
+            continue;
+        }
+        for item in scan_source(&args.source.join(&rel), &args.source, None, &mut summary, None) {
+            if seen.insert(item.path.clone()) {
+                queue.push(item);
+            }
+        }
+    }
+    println!("📝 {} files listed", queue.len());
+    copy(args.clone(), &transfer_id, Work::Files(queue), &mut |_| {}).await?;
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} listed entries could not be read", summary.walk_errors.len());
+    }
     Ok(())
 }
 
-async fn cp_ssh_files(args: Args) -> anyhow::Result<()> {
-    // Parse destination
-    let (ssh_dest, remote_path) = parse_ssh_destination(&args.destination)?;
-    let remote_root = Path::new(&remote_path);
+// The files to send: the source's, or those already picked, in path order
+// with --deterministic
+fn work_items(
+    args: &Args,
+    work: Work,
+    src_root: &Path,
+    fingerprints: Option<&mut prune::DirFingerprints>,
+    summary: &mut summary::Summary,
+    phases: &phase::Phases,
+) -> Vec<WorkItem> {
+    let mut items = match work {
+        Work::Walk => scan_source(&args.source, src_root, fingerprints, summary, Some(phases)),
+        Work::Files(items) => items,
+    };
+    if args.deterministic {
+        items.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+    for item in &items {
+        warn_name(args, &item.path);
+    }
+    items
+}
+
+// Point out a file name with newlines, control characters or invalid UTF-8
+fn warn_name(args: &Args, path: &Path) {
+    let Some(problem) = utils::path_problem(path) else {
+        return;
+    };
+    if args.sanitize_names {
+        println!("⚠️  {} {}, copied as {}", utils::printable(path), problem, utils::printable(&dest_path(args, path)));
+    } else {
+        println!("⚠️  {} {}, use --sanitize-names to rename it at the destination", utils::printable(path), problem);
+    }
+}
 
-    let src_root = Path::new(&args.source).parent().unwrap_or(&args.source);
+// Where a file is written below the destination root
+fn dest_path(args: &Args, path: &Path) -> PathBuf {
+    if args.sanitize_names {
+        return utils::sanitize_path(path);
+    }
+    path.to_path_buf()
+}
 
-    // Create SSH connection pool
-    println!("🔗 Creating SSH connection pool...");
-    let connection_pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, args.jobs)?);
+// Walk the source, collecting the files to send and recording fingerprints
+// and unreadable entries on the way
+fn scan_source(
+    source: &Path,
+    src_root: &Path,
+    fingerprints: Option<&mut prune::DirFingerprints>,
+    summary: &mut summary::Summary,
+    phases: Option<&phase::Phases>,
+) -> Vec<WorkItem> {
+    if let Some(phases) = phases {
+        phases.scan();
+    }
+    let known = fingerprints.as_ref().and_then(|fingerprints| fingerprints.known(src_root));
+    scan_entries(dirfd::walk_known(source, known), src_root, fingerprints, summary, phases)
+}
 
-    // Step 3: Transfer files
-    println!("🚀 Starting SSH transfer ({} jobs)...", args.jobs);
+fn scan_entries(
+    entries: impl Iterator<Item = std::io::Result<dirfd::Entry>>,
+    src_root: &Path,
+    mut fingerprints: Option<&mut prune::DirFingerprints>,
+    summary: &mut summary::Summary,
+    phases: Option<&phase::Phases>,
+) -> Vec<WorkItem> {
+    let mut items = vec![];
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                eprintln!("Error: {}", e);
+                summary.walk_errors.push(e.to_string());
+                continue;
+            }
+        };
+        let path = entry.path();
+        if entry.skipped() {
+            println!("⏭️  Skipping {} (pseudo filesystem)", path.display());
+            continue;
+        }
+        if let Some(fingerprints) = fingerprints.as_mut() && entry.is_dir() {
+            fingerprints.visit_dir(path.strip_prefix(src_root).unwrap(), &entry);
+        }
+        if entry.is_file() {
+            let path = path.strip_prefix(src_root).unwrap().to_path_buf();
+            if fingerprints.as_ref().is_some_and(|fp| fp.is_unchanged(&path)) {
+                continue;
+            }
+            items.push(WorkItem { root: Arc::from(src_root), path, size: entry.size() });
+            if let Some(phases) = phases {
+                phases.scanned(items.len());
+            }
+        }
+    }
+    items
+}
+
+// Copy to a destination other than user@host:path, through its backend
+async fn cp_backend_files(
+    args: Args,
+    transfer_id: &str,
+    work: Work,
+    on_done: &mut dyn FnMut(&summary::FileResult),
+) -> anyhow::Result<()> {
+    let src_root = source_root(&args.source);
+    let url = backend::is_url(&args.destination);
+    let destination = Path::new(&args.destination);
+    let is_dir = match url {
+        true => backend::names_dir(&args.destination),
+        false => destination.is_dir(),
+    };
+    let target = matches!(work, Work::Walk)
+        .then(|| single_file_target(&args.source, destination, is_dir))
+        .flatten();
+    let (dest_root, rename) = match &target {
+        Some((dest_root, name)) => (dest_root.as_path(), Some(name.clone())),
+        None => (destination, None),
+    };
+    let mut backend = match url {
+        true => backend::open_url_with(&dest_root.to_string_lossy(), args.object_options())?,
+        false => backend::open(dest_root),
+    };
+    if let Some(link) = args.simulate_network {
+        backend = netsim::wrap(backend, link, args.compress);
+    }
+    println!("Copying from {} to {}", src_root.display(), backend.describe());
+    if args.mkpath {
+        backend.mkdir(Path::new(""))?;
+    }
+    backend.check()?;
     let m = Arc::new(MultiProgress::new());
 
-    // let semaphore = Arc::new(Semaphore::new(args.jobs));
+    // A URL is written over the network, whose transfers may share their permits
+    let semaphore = Arc::new(Semaphore::new(match url {
+        true => args.net_workers(),
+        false => args.disk_workers(),
+    }));
+    backend.share_jobs(semaphore.clone());
     let mut handles = vec![];
-    let walker = walkdir::WalkDir::new(&args.source);
-    walker.into_iter().filter_map(Result::ok).for_each(|entry| {
-        let path = entry.path();
-        if path.is_file() {
-            let size = path.metadata().unwrap().len();
-            let src_root = src_root.to_path_buf();
-            let remote_root = remote_root.to_path_buf();
-            let path = path.strip_prefix(&src_root).unwrap().to_path_buf();
-            // let sem = semaphore.clone();
-            let m = m.clone();
-            let pool = connection_pool.clone();
-            let h = tokio::task::spawn_blocking(move || {
-                // let _permit = sem.acquire().await.unwrap();
-                
-                // Try to get connection from pool with retry logic
-                let ssh_session = loop {
-                    match pool.get_connection(){
-                        Ok(session) => break session,
-                        Err(e) => {
-                            eprintln!("Failed to get SSH connection from pool: {}. Retrying in 1 second...", e);
-                            std::thread::sleep(tokio::time::Duration::from_secs(1));
-                        }
-                    }
-                };
-                
-                // Wrap session in SshTransfer for compatibility
-                let ssh_transfer = ssh::SshTransfer::from_session(ssh_session);
-                println!("processing file: {}", path.display());
-                let pb = m.add(ProgressBar::new(size));
-                let sty = ProgressStyle::with_template("{msg} {bar:40} {bytes}/{total_bytes} ({eta})")
-                    .unwrap()
-                    .progress_chars("=>-");
-                pb.set_style(sty);
-                pb.set_message(utils::align_str(path.to_str().unwrap(), 20));
-                
-                // Send via SSH
-                let r = ssh_transfer.send_file(src_root, remote_root, path, size, pb);
-                
-                // Return connection to pool
-                pool.return_connection(ssh_transfer.into_session());
-                
-                match r {
-                    Ok(_) => {},
-                    Err(e) => {
-                        eprintln!("Error: {}", e);
-                    }
-                }
-            });
-            handles.push(h);
+    let read_limit = make_read_limit(&args);
+    let cpu_limit = make_cpu_limit(&args);
+    let hash_pool = make_hash_pool(&args, read_limit.clone(), cpu_limit.clone())?;
+    let audit = match &args.audit_log {
+        Some(Some(path)) => Some(Arc::new(audit::AuditLog::local(path, transfer_id)?)),
+        Some(None) if url => anyhow::bail!("--audit-log needs a FILE when the destination is a URL"),
+        Some(None) => Some(Arc::new(audit::AuditLog::local(&dest_root.join(audit::DEST_AUDIT_LOG), transfer_id)?)),
+        None => None,
+    };
+
+    let mut fingerprints = args.prune_unchanged
+        .map(|level| prune::DirFingerprints::load(&args.source, &args.destination, level));
+
+    let mut quota = args.quota.map(quota::Quota::new);
+    let quota_keys = match url {
+        true => vec![dest_root.display().to_string()],
+        false => vec![std::fs::canonicalize(dest_root)?.display().to_string()],
+    };
+
+    let mut summary = summary::Summary::new(transfer_id, args.rate_window);
+    let phases = phase::Phases::new(&m, transfer_id, args.events.as_deref(), hash_pool.is_some())?;
+    let items = work_items(&args, work, src_root, fingerprints.as_mut(), &mut summary, &phases);
+    let total = items.iter().map(|item| item.size).sum();
+    if let Some(object) = backend.object(Path::new("")) {
+        let mut estimate = cost::Estimate::new(args.storage_class.as_deref());
+        for item in &items {
+            estimate.upload(&object.store, item.size);
         }
-    });
-    println!("🚀 Starting SSH transfer ({} jobs)...{}", args.jobs, handles.len());
-    // Wait for all transfers
-    for h in handles {
-        let _ = h.await;
+        estimate.print();
     }
+    phases.transfer(items.len(), total);
+    let overall = progress::Overall::new(&m, total, Duration::from_secs(args.rate_window));
+    for WorkItem { root, path, size } in items {
+        if let Some(quota) = quota.as_mut() && !quota.admit(&quota_keys, size) {
+            // Held back, the next run must still send it
+            if let Some(fingerprints) = fingerprints.as_mut() {
+                fingerprints.mark_failed(&path);
+            }
+            overall.skip(size);
+            continue;
+        }
+        let src_root = root.to_path_buf();
+        let backend = backend.clone();
+        let overall = overall.clone();
+        let writing = phases.writing();
+        println!("processing file2 :{}, {}", src_root.display(), path.display());
+        let sem = semaphore.clone();
+        let m = m.clone();
+        let hash_pool = hash_pool.clone();
+        let audit = audit.clone();
+        let destination = args.destination.clone();
+        let dest_path = rename.clone().unwrap_or_else(|| dest_path(&args, &path));
+        let write_path = match args.delay_updates {
+            true => stage::staged(&dest_path),
+            false => dest_path.clone(),
+        };
+        let options = ssh::SendOptions {
+            verify: hash_pool.as_ref().map(|pool| pool.algorithm()),
+            preserve_special: args.preserve_special_bits,
+            read_limit: read_limit.clone(),
+            read_ahead: args.read_ahead(),
+            grown: args.grown_files,
+            cpu_limit: cpu_limit.clone(),
+            ..Default::default()
+        };
+        let rate_window = Duration::from_secs(args.rate_window);
+        let run_start = summary.started();
+        // Taking the slot here starts the transfers strictly in order
+        let permit = match args.deterministic {
+            true => Some(sem.clone().acquire_owned().await.unwrap()),
+            false => None,
+        };
 
-    println!("✅ SSH transfer completed!");
-    Ok(())
+        let task = (path.clone(), size);
+        let fail_on_vanished = args.fail_on_vanished;
+        let h = tokio::spawn(async move {
+            let permit = match permit {
+                Some(permit) => permit,
+                None => sem.acquire_owned().await.unwrap(),
+            };
+            let started = run_start.elapsed();
+            let clock = Instant::now();
+            
+            let pb = m.add(ProgressBar::new(size));
+            pb.set_style(progress::file_style("{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})", rate_window));
+            pb.set_message(utils::align_str(&utils::printable(&path), 20));
+            let counted = overall.start(&pb, size);
+            if let Some(audit) = &audit
+                && let Ok(Some(old)) = backend.stat(&dest_path)
+                && let Err(e) = audit.overwrite(&destination, &dest_path, old, None) {
+                eprintln!("Error: cannot write audit log: {}", e);
+            }
+            let r = backend.send(&src_root, &path, &write_path, &options, &pb);
+            // Hashing runs on its own pool, free the IO slot first
+            drop(permit);
+            drop(writing);
+            let vanished = r.is_err() && !fail_on_vanished && source_vanished(&src_root, &path);
+            let stripped = r.as_ref().is_ok_and(|sent| sent.stripped.is_some());
+            let r = match (r, &hash_pool) {
+                (Ok(ssh::Sent { digest: Some(sent), stripped: mode, .. }), Some(pool)) => {
+                    pool.verify(src_root, path.clone(), sent).await.map(|_| mode)
+                }
+                (r, _) => r.map(|sent| sent.stripped),
+            };
+            match &r {
+                Ok(Some(mode)) => utils::warn_stripped(&path, *mode),
+                Ok(None) => {}
+                Err(_) if vanished => warn_vanished(&path),
+                Err(e) => eprintln!("Error: {}: {}", path.display(), e),
+            }
+            counted.done(r.is_ok());
+            // The size taken when the file was opened
+            let size = pb.length().unwrap_or(size);
+            summary::FileResult {
+                path,
+                size,
+                wire_bytes: size,
+                ok: r.is_ok(),
+                vanished,
+                stripped,
+                started,
+                elapsed: clock.elapsed(),
+                session: None,
+            }
+        });
+        handles.push((task, h));
+    }
+    phases.all_started();
+
+    // Wait for all transfers
+    let mut delayed = vec![];
+    let mut failed = 0;
+    for (task, h) in handles {
+        let result = h.await.unwrap_or_else(|e| lost_task(task, e));
+        if !result.ok && let Some(fingerprints) = fingerprints.as_mut() {
+            fingerprints.mark_failed(&result.path);
+        }
+        if !result.ok && !result.vanished {
+            failed += 1;
+        }
+        if result.ok && args.delay_updates {
+            delayed.push(result.path.clone());
+        }
+        if result.ok && let Some(quota) = quota.as_mut() {
+            quota.record(&quota_keys[0], result.size);
+        }
+        on_done(&result);
+        summary.record(result);
+    }
+    overall.finish();
+    phases.finalize();
+    if args.delay_updates {
+        // Files left staged were not sent as far as the next run is concerned
+        if failed > 0 && let Some(fingerprints) = fingerprints.as_mut() {
+            delayed.iter().for_each(|path| fingerprints.mark_failed(path));
+        }
+        let files = delayed.iter()
+            .map(|path| rename.clone().unwrap_or_else(|| dest_path(&args, path)))
+            .collect::<Vec<_>>();
+        stage::publish(backend.as_ref(), &files, failed)?;
+    }
+    if let Some(fingerprints) = &fingerprints {
+        fingerprints.save()?;
+    }
+    phases.finish();
+
+    if args.deterministic {
+        summary.sort();
+    }
+    summary.print();
+    if let Some(report) = &args.report {
+        summary.write_report(report)?;
+    }
+    if let Some(quota) = quota.as_mut() {
+        quota.finish()?;
+    }
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", summary.walk_errors.len());
+    }
+
+    println!("✅ Transfer completed!");
+    Ok(())
+}
+
+async fn cp_ssh_files(
+    args: Args,
+    transfer_id: &str,
+    work: Work,
+    on_done: &mut dyn FnMut(&summary::FileResult),
+) -> anyhow::Result<()> {
+    // Parse destinations
+    let mut targets = vec![];
+    for destination in std::iter::once(&args.destination).chain(args.also.iter()) {
+        let (ssh_dest, remote_path) = parse_ssh_destination(destination)?;
+        targets.push((ssh_dest, PathBuf::from(remote_path)));
+    }
+
+    let src_root = source_root(&args.source);
+
+    // Create SSH connection pools
+    println!("🔗 Creating SSH connection pool...");
+    let options = connect_options(&args)?;
+    let sessions = args.sessions();
+    let mut destinations = vec![];
+    for (ssh_dest, remote_root) in targets {
+        let pool = ssh::SshConnectionPool::new(ssh_dest, sessions, args.channels_per_session, options.clone())?;
+        destinations.push((Arc::new(pool), remote_root));
+    }
+    let m = Arc::new(MultiProgress::new());
+
+    // Probe every host (TCP + auth) before any data moves
+    let results = cluster::connect_all(&destinations, args.connectors, &m).await;
+    // Fingerprints belong to the destinations that actually get the files
+    let reached = std::iter::once(&args.destination).chain(args.also.iter())
+        .zip(&results)
+        .filter(|(_, result)| result.is_ok())
+        .map(|(destination, _)| destination.as_str())
+        .collect::<Vec<_>>()
+        .join(" ");
+    let (destinations, failed) = cluster::partition(destinations, results);
+    if !failed.is_empty() {
+        if !args.skip_unreachable {
+            anyhow::bail!("{} host(s) unreachable or failed to authenticate; pass --skip-unreachable to continue without them", failed.len());
+        }
+        if destinations.is_empty() {
+            anyhow::bail!("No destination host is reachable");
+        }
+    }
+
+    // Make sure every destination root is usable before transferring anything
+    let mut destinations = destinations;
+    let mut renames = vec![];
+    for (pool, remote_root) in &mut destinations {
+        let lease = pool.get_connection()?;
+        let transfer = pool.transfer(&lease);
+        // ~ and $VARS are expanded on the host before anything is created
+        let r = transfer.resolve_path(&remote_root.to_string_lossy()).and_then(|resolved| {
+            *remote_root = resolved;
+            let mut rename = None;
+            if matches!(work, Work::Walk) {
+                let is_dir = transfer.remote_kind(remote_root)? == Some(ssh::RemoteKind::Dir);
+                if let Some((root, name)) = single_file_target(&args.source, remote_root, is_dir) {
+                    *remote_root = root;
+                    rename = Some(name);
+                }
+            }
+            if args.mkpath {
+                transfer.create_remote_dir(remote_root)?;
+            }
+            transfer.check_remote_dir(remote_root)?;
+            if args.compress.is_some() {
+                transfer.check_zstd()?;
+            }
+            if let Some(name) = rename.as_deref().or(source_name(&args.source)) {
+                check_remote_target(&transfer, &remote_root.join(name), &args.source)?;
+            }
+            Ok(rename)
+        });
+        pool.return_connection(lease);
+        renames.push(r.with_context(|| format!("Destination {} failed the pre-transfer check", pool.ssh_dest()))?);
+    }
+
+    let backends = destinations.iter()
+        .map(|(pool, remote_root)| Arc::new(backend::SshBackend::new(pool.clone(), remote_root.clone())))
+        .collect::<Vec<_>>();
+
+    // Step 3: Transfer files
+    println!("🚀 Starting SSH transfer ({} sessions x {} channels per host)...", sessions, args.channels_per_session);
+
+    let mut fingerprints = args.prune_unchanged
+        .map(|level| prune::DirFingerprints::load(&args.source, &reached, level));
+
+    // let semaphore = Arc::new(Semaphore::new(args.jobs));
+    let mut handles = vec![];
+    let read_limit = make_read_limit(&args);
+    let disk_slots = pipe::DiskSlots::new(args.disk_workers());
+    // Every file is read by one transfer per destination
+    let read_cache = (destinations.len() > 1 && args.read_cache > 0)
+        .then(|| Arc::new(readcache::ReadCache::new(args.read_cache, destinations.len())));
+    let cpu_limit = make_cpu_limit(&args);
+    let hash_pool = make_hash_pool(&args, read_limit.clone(), cpu_limit.clone())?;
+    let shared_audit = match &args.audit_log {
+        Some(Some(path)) => Some(Arc::new(audit::AuditLog::local(path, transfer_id)?)),
+        _ => None,
+    };
+    let audits = destinations.iter()
+        .map(|(_, remote_root)| match &args.audit_log {
+            Some(None) => Some(Arc::new(audit::AuditLog::remote(remote_root.join(audit::DEST_AUDIT_LOG), transfer_id))),
+            _ => shared_audit.clone(),
+        })
+        .collect::<Vec<_>>();
+    let mut summary = summary::Summary::new(transfer_id, args.rate_window);
+    let phases = phase::Phases::new(&m, transfer_id, args.events.as_deref(), hash_pool.is_some())?;
+    let items = work_items(&args, work, src_root, fingerprints.as_mut(), &mut summary, &phases);
+    let mut quota = args.quota.map(quota::Quota::new);
+    let quota_keys = destinations.iter()
+        .map(|(pool, remote_root)| format!("{}:{}", pool.ssh_dest(), remote_root.display()))
+        .collect::<Vec<_>>();
+    // Each file goes to every destination
+    let total = items.iter().map(|item| item.size).sum::<u64>() * destinations.len() as u64;
+    phases.transfer(items.len() * destinations.len(), total);
+    let overall = progress::Overall::new(&m, total, Duration::from_secs(args.rate_window));
+    for WorkItem { root, path, size } in items {
+        if let Some(quota) = quota.as_mut() && !quota.admit(&quota_keys, size) {
+            // Held back, the next run must still send it
+            if let Some(fingerprints) = fingerprints.as_mut() {
+                fingerprints.mark_failed(&path);
+            }
+            overall.skip(size * destinations.len() as u64);
+            continue;
+        }
+        for (dest, (((pool, _), backend), audit)) in destinations.iter().zip(&backends).zip(&audits).enumerate() {
+            let src_root = root.to_path_buf();
+            let path = path.clone();
+            let dest_path = renames[dest].clone().unwrap_or_else(|| dest_path(&args, &path));
+            let write_path = match args.delay_updates {
+                true => stage::staged(&dest_path),
+                false => dest_path.clone(),
+            };
+            let label = if destinations.len() > 1 {
+                format!("{} {}", pool.ssh_dest(), utils::align_str(&utils::printable(&path), 20))
+            } else {
+                utils::align_str(&utils::printable(&path), 20)
+            };
+            // let sem = semaphore.clone();
+            let m = m.clone();
+            let overall = overall.clone();
+            let writing = phases.writing();
+            let backend = backend.clone();
+            let ssh_dest = pool.ssh_dest().to_string();
+            let hash_pool = hash_pool.clone();
+            let audit = audit.clone();
+            let options = ssh::SendOptions {
+                verify: hash_pool.as_ref().map(|pool| pool.algorithm()),
+                compress: args.compress,
+                preserve_special: args.preserve_special_bits,
+                read_limit: read_limit.clone(),
+                read_ahead: args.read_ahead(),
+                disk_slots: Some(disk_slots.clone()),
+                grown: args.grown_files,
+                cpu_limit: cpu_limit.clone(),
+                read_cache: read_cache.clone(),
+            };
+            let rate_window = Duration::from_secs(args.rate_window);
+            let run_start = summary.started();
+            let task = (path.clone(), size);
+            let fail_on_vanished = args.fail_on_vanished;
+            let h = tokio::task::spawn_blocking(move || {
+                let started = run_start.elapsed();
+                let clock = Instant::now();
+
+                println!("processing file: {}", path.display());
+                let pb = m.add(ProgressBar::new(size));
+                let template = if options.compress.is_some() {
+                    "{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} {prefix} ({eta})"
+                } else {
+                    "{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})"
+                };
+                pb.set_style(progress::file_style(template, rate_window));
+                pb.set_message(label);
+                let counted = overall.start(&pb, size);
+
+                if let Some(audit) = &audit {
+                    let r = backend.with(|transfer, root| match transfer.remote_stat(&root.join(&dest_path)) {
+                        Ok(Some(old)) => audit.overwrite(&ssh_dest, &dest_path, old, Some(transfer)),
+                        _ => Ok(()),
+                    });
+                    if let Err(e) = r {
+                        eprintln!("Error: cannot write audit log: {}", e);
+                    }
+                }
+                let r = backend.send(&src_root, &path, &write_path, &options, &pb);
+                drop(writing);
+
+                let vanished = r.is_err() && !fail_on_vanished && source_vanished(&src_root, &path);
+                let stripped = r.as_ref().is_ok_and(|sent| sent.stripped.is_some());
+                let r = match (r, &hash_pool) {
+                    (Ok(ssh::Sent { digest: Some(digest), wire_bytes, stripped, session }), Some(pool)) => {
+                        pool.verify_blocking(src_root, path.clone(), digest).map(|_| (wire_bytes, stripped, session))
+                    }
+                    (r, _) => r.map(|sent| (sent.wire_bytes, sent.stripped, sent.session)),
+                };
+            
+                match &r {
+                    Ok((_, Some(mode), _)) => utils::warn_stripped(&path, *mode),
+                    Ok(_) => {}
+                    Err(_) if vanished => warn_vanished(&path),
+                    Err(e) => eprintln!("Error: {}", e),
+                }
+                counted.done(r.is_ok());
+                (dest, summary::FileResult {
+                    path,
+                    size: pb.length().unwrap_or(size),
+                    wire_bytes: r.as_ref().map_or(0, |(wire_bytes, _, _)| *wire_bytes),
+                    ok: r.is_ok(),
+                    vanished,
+                    stripped,
+                    started,
+                    elapsed: clock.elapsed(),
+                    session: r.ok().and_then(|(_, _, session)| session),
+                })
+            });
+            handles.push((dest, task, h));
+        }
+    }
+    phases.all_started();
+    println!("🚀 Starting SSH transfer ({} jobs)...{}", args.net_workers(), handles.len());
+    // Wait for all transfers
+    let mut delayed = vec![vec![]; destinations.len()];
+    let mut failed = vec![0; destinations.len()];
+    for (dest, task, h) in handles {
+        let (_, result) = h.await.unwrap_or_else(|e| (dest, lost_task(task, e)));
+        if !result.ok && let Some(fingerprints) = fingerprints.as_mut() {
+            fingerprints.mark_failed(&result.path);
+        }
+        if !result.ok && !result.vanished {
+            failed[dest] += 1;
+        }
+        if result.ok && args.delay_updates {
+            delayed[dest].push(result.path.clone());
+        }
+        if result.ok && let Some(quota) = quota.as_mut() {
+            quota.record(&quota_keys[dest], result.size);
+        }
+        on_done(&result);
+        summary.record(result);
+    }
+    overall.finish();
+    phases.finalize();
+    if args.delay_updates {
+        // Each destination is published on its own, a host where a transfer
+        // failed keeps its files staged
+        for dest in 0..destinations.len() {
+            if failed[dest] > 0 && let Some(fingerprints) = fingerprints.as_mut() {
+                delayed[dest].iter().for_each(|path| fingerprints.mark_failed(path));
+            }
+            let files = delayed[dest].iter()
+                .map(|path| renames[dest].clone().unwrap_or_else(|| dest_path(&args, path)))
+                .collect::<Vec<_>>();
+            stage::publish(backends[dest].as_ref(), &files, failed[dest])?;
+        }
+    }
+    if let Some(fingerprints) = &fingerprints {
+        fingerprints.save()?;
+    }
+    phases.finish();
+
+    if args.deterministic {
+        summary.sort();
+    }
+    summary.print();
+    if let Some(report) = &args.report {
+        summary.write_report(report)?;
+    }
+    if let Some(quota) = quota.as_mut() {
+        quota.finish()?;
+    }
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", summary.walk_errors.len());
+    }
+
+    println!("✅ SSH transfer completed!");
+    Ok(())
+}
+
+// Where a source's paths are taken relative to: its parent, so `cpx src dest`
+// creates dest/src, or with a trailing slash the source itself, so `cpx src/
+// dest` copies what is inside src straight into dest, as rsync does
+fn source_root(source: &Path) -> &Path {
+    if copies_contents(source) {
+        return source;
+    }
+    source.parent().unwrap_or(source)
+}
+
+// Spelled with a trailing slash (or /.), the source's contents are copied
+fn copies_contents(source: &Path) -> bool {
+    let bytes = source.as_os_str().as_encoded_bytes();
+    bytes.ends_with(b"/") || bytes.ends_with(b"/.") || bytes == b"."
+}
+
+// The directory or file a source creates below the destination, None when
+// only its contents are copied
+fn source_name(source: &Path) -> Option<&Path> {
+    if copies_contents(source) {
+        return None;
+    }
+    source.file_name().map(Path::new)
+}
+
+// `cpx a.txt dest/b.txt`: a single-file source is written under the
+// destination's own name, unless the destination is a directory (existing, or
+// spelled with a trailing slash). Returns the root to write into and the name.
+fn single_file_target(source: &Path, destination: &Path, is_dir: bool) -> Option<(PathBuf, PathBuf)> {
+    if !source.is_file() {
+        return None;
+    }
+    file_target(destination, is_dir)
+}
+
+// Split the destination of a single file into the root to write into and
+// the name, unless it is a directory
+fn file_target(destination: &Path, is_dir: bool) -> Option<(PathBuf, PathBuf)> {
+    if is_dir || destination.to_string_lossy().ends_with('/') {
+        return None;
+    }
+    // A URL spells the name its own way, %20 for a space on WebDAV
+    if let Some(url) = destination.to_str().filter(|url| backend::is_url(url)) {
+        return backend::split_url(url).map(|(root, name)| (PathBuf::from(root), name));
+    }
+    let name = destination.file_name()?;
+    let root = destination.parent().filter(|parent| !parent.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    Some((root.to_path_buf(), PathBuf::from(name)))
+}
+
+// Fail up front when the source would land on a file in the way of a
+// directory or the other way round, rather than on every file of the transfer
+fn check_remote_target(transfer: &ssh::SshTransfer, target: &Path, source: &Path) -> anyhow::Result<()> {
+    let source_is_dir = source.is_dir();
+    match transfer.remote_kind(target)? {
+        Some(ssh::RemoteKind::Other) if source_is_dir => {
+            anyhow::bail!("Cannot copy directory {} onto {}, which exists and is not a directory", source.display(), target.display())
+        }
+        Some(ssh::RemoteKind::Dir) if !source_is_dir => {
+            anyhow::bail!("Cannot copy file {} onto {}, which exists and is a directory", source.display(), target.display())
+        }
+        _ => Ok(()),
+    }
+}
+
+// Fetch a remote source (user@host:path) into a local destination over SFTP
+async fn cp_ssh_download(
+    args: Args,
+    transfer_id: &str,
+    on_done: &mut dyn FnMut(&summary::FileResult),
+) -> anyhow::Result<()> {
+    let unsupported = [
+        (!args.also.is_empty(), "--also"),
+        (args.compress.is_some(), "--compress"),
+        (args.paranoid, "--paranoid"),
+        (args.prune_unchanged.is_some(), "--prune-unchanged"),
+        (args.audit_log.is_some(), "--audit-log"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+        anyhow::bail!("{} is not supported when copying from a remote source", flag);
+    }
+    let source = args.source.to_string_lossy().into_owned();
+    let (ssh_dest, remote_path) = parse_ssh_destination(&source)?;
+    let destination = Path::new(&args.destination);
+    println!("Copying from {} to {}", source, destination.display());
+
+    println!("🔗 Creating SSH connection pool...");
+    let sessions = args.sessions();
+    let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, sessions, args.channels_per_session, connect_options(&args)?)?);
+    let m = Arc::new(MultiProgress::new());
+
+    let mut summary = summary::Summary::new(transfer_id, args.rate_window);
+    let phases = phase::Phases::new(&m, transfer_id, args.events.as_deref(), false)?;
+    phases.scan();
+    println!("🔍 Scanning {}...", source);
+    let lease = pool.get_connection()?;
+    let transfer = pool.transfer(&lease);
+    let listing = list_remote_source(&transfer, &source, &remote_path);
+    pool.return_connection(lease);
+    let (ssh::RemoteWalk { files, errors }, remote_root, single_file) = listing?;
+
+    // `cpx host:/tmp/a.txt b.txt` writes the one file as b.txt, like scp
+    let target = single_file.then(|| file_target(destination, destination.is_dir())).flatten();
+    let (dest_root, rename) = match &target {
+        Some((dest_root, name)) => (dest_root.as_path(), Some(name.clone())),
+        None => (destination, None),
+    };
+    if args.mkpath {
+        std::fs::create_dir_all(dest_root)
+            .with_context(|| format!("Cannot create destination {}", dest_root.display()))?;
+    }
+    preflight::check_local_dest(dest_root)?;
+    let files = files.into_iter().map(|(path, stat)| (path, stat.size)).collect::<Vec<_>>();
+    for e in errors {
+        eprintln!("Error: {}", e);
+        summary.walk_errors.push(e);
+    }
+
+    let mut handles = vec![];
+    let read_limit = make_read_limit(&args);
+    let mut quota = args.quota.map(quota::Quota::new);
+    let quota_keys = vec![std::fs::canonicalize(dest_root)?.display().to_string()];
+    println!("🚀 Starting SSH download ({} sessions x {} channels)...", sessions, args.channels_per_session);
+    let total = files.iter().map(|(_, size)| size).sum();
+    phases.transfer(files.len(), total);
+    let overall = progress::Overall::new(&m, total, Duration::from_secs(args.rate_window));
+    for (path, size) in files {
+        if let Some(quota) = quota.as_mut() && !quota.admit(&quota_keys, size) {
+            overall.skip(size);
+            continue;
+        }
+        warn_name(&args, &path);
+        let dest_path = rename.clone().unwrap_or_else(|| dest_path(&args, &path));
+        let remote_root = remote_root.clone();
+        let dest_root = dest_root.to_path_buf();
+        let m = m.clone();
+        let overall = overall.clone();
+        let pool = pool.clone();
+        let options = ssh::SendOptions {
+            preserve_special: args.preserve_special_bits,
+            read_limit: read_limit.clone(),
+            ..Default::default()
+        };
+        let rate_window = Duration::from_secs(args.rate_window);
+        let run_start = summary.started();
+        let task = (path.clone(), size);
+        let fail_on_vanished = args.fail_on_vanished;
+        let h = tokio::task::spawn_blocking(move || {
+            let acquire = || loop {
+                match pool.get_connection() {
+                    Ok(lease) => break lease,
+                    Err(e) => {
+                        eprintln!("Failed to get SSH connection from pool: {}. Retrying in 1 second...", e);
+                        std::thread::sleep(tokio::time::Duration::from_secs(1));
+                    }
+                }
+            };
+            let mut lease = acquire();
+            let started = run_start.elapsed();
+            let clock = Instant::now();
+
+            let pb = m.add(ProgressBar::new(size));
+            pb.set_style(progress::file_style("{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})", rate_window));
+            pb.set_message(utils::align_str(&utils::printable(&path), 20));
+            let counted = overall.start(&pb, size);
+            let mut transfer = pool.transfer(&lease);
+            let receive = |transfer: &ssh::SshTransfer| utils::catch_panic(|| {
+                transfer.receive_file(&remote_root, &dest_root, &path, &dest_path, &options, pb.clone())
+            });
+            let mut r = receive(&transfer);
+            let mut retries = 0;
+            while let Err(e) = &r && ssh::is_channel_refused(e) && retries < ssh::CHANNEL_RETRIES {
+                pool.throttle(lease);
+                lease = acquire();
+                transfer = pool.transfer(&lease);
+                r = receive(&transfer);
+                retries += 1;
+            }
+            // Gone from the host since the listing, rather than failed
+            let vanished = r.is_err() && !fail_on_vanished
+                && transfer.remote_stat(&remote_root.join(&path)).is_ok_and(|stat| stat.is_none());
+            pool.return_connection(lease);
+
+            match &r {
+                Ok(ssh::Sent { stripped: Some(mode), .. }) => utils::warn_stripped(&path, *mode),
+                Ok(_) => {}
+                Err(_) if vanished => warn_vanished(&path),
+                Err(e) => eprintln!("Error: {}: {}", path.display(), e),
+            }
+            counted.done(r.is_ok());
+            summary::FileResult {
+                path,
+                size,
+                wire_bytes: r.as_ref().map_or(0, |sent| sent.wire_bytes),
+                ok: r.is_ok(),
+                vanished,
+                stripped: r.as_ref().is_ok_and(|sent| sent.stripped.is_some()),
+                started,
+                elapsed: clock.elapsed(),
+                session: None,
+            }
+        });
+        handles.push((task, h));
+    }
+
+    let mut received = vec![];
+    for (task, h) in handles {
+        let result = h.await.unwrap_or_else(|e| lost_task(task, e));
+        if result.ok && let Some(quota) = quota.as_mut() {
+            quota.record(&quota_keys[0], result.size);
+        }
+        if result.ok {
+            received.push((result.path.clone(), rename.clone().unwrap_or_else(|| dest_path(&args, &result.path))));
+        }
+        on_done(&result);
+        summary.record(result);
+    }
+    overall.finish();
+    phases.finalize();
+    phases.finish();
+
+    if args.deterministic {
+        summary.sort();
+    }
+    summary.print();
+    if let Some(report) = &args.report {
+        summary.write_report(report)?;
+    }
+    if let Some(quota) = quota.as_mut() {
+        quota.finish()?;
+    }
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", summary.walk_errors.len());
+    }
+
+    println!("✅ SSH download completed!");
+    if args.follow {
+        let dest_root = dest_root.to_path_buf();
+        tokio::task::spawn_blocking(move || follow_remote(&pool, &remote_root, &dest_root, received, read_limit)).await??;
+    }
+    Ok(())
+}
+
+// List the files of the remote source `source` (at `remote_path` on its host),
+// returning them with the root their paths are relative to and whether the
+// source is a single file
+fn list_remote_source(transfer: &ssh::SshTransfer, source: &str, remote_path: &str) -> anyhow::Result<(ssh::RemoteWalk, PathBuf, bool)> {
+    let contents = copies_contents(Path::new(remote_path));
+    let remote_path = transfer.resolve_path(remote_path)?;
+    // Wildcards are matched against the remote listing, unless a file of
+    // that name exists; matches keep their path below the pattern's base
+    if glob::has_wildcard(&remote_path.to_string_lossy()) && transfer.remote_stat(&remote_path)?.is_none() {
+        let pattern = glob::Pattern::new(&remote_path);
+        let walk = transfer.walk_remote(&pattern.base, Path::new(""), Some(&pattern))?;
+        if walk.files.is_empty() && walk.errors.is_empty() {
+            anyhow::bail!("No files match {}", source);
+        }
+        return Ok((walk, pattern.base, false));
+    }
+    // Like local sources, paths are kept relative to the parent of the
+    // source, or to the source itself when it ends in a slash
+    if contents {
+        return Ok((transfer.walk_remote(&remote_path, Path::new(""), None)?, remote_path, false));
+    }
+    let Some(top) = remote_path.file_name().map(PathBuf::from) else {
+        anyhow::bail!("Cannot copy {}, name a file or directory below the root", source);
+    };
+    let remote_root = remote_path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let walk = transfer.walk_remote(&remote_root, &top, None)?;
+    let single_file = matches!(&walk.files[..], [(path, _)] if *path == top);
+    Ok((walk, remote_root, single_file))
+}
+
+// Source and destination on the same SSH host: every file is copied by `cp`
+// on the host, so none of the data goes through this machine
+async fn cp_ssh_same_host(
+    args: Args,
+    transfer_id: &str,
+    on_done: &mut dyn FnMut(&summary::FileResult),
+) -> anyhow::Result<()> {
+    let source = args.source.to_string_lossy().into_owned();
+    let (ssh_dest, remote_path) = parse_ssh_destination(&source)?;
+    let (dest_host, dest_remote) = parse_ssh_destination(&args.destination)?;
+    if dest_host != ssh_dest {
+        anyhow::bail!("Copying between two remote hosts is not supported");
+    }
+    let unsupported = [
+        (!args.also.is_empty(), "--also"),
+        (args.compress.is_some(), "--compress"),
+        (args.paranoid, "--paranoid"),
+        (args.prune_unchanged.is_some(), "--prune-unchanged"),
+        (args.audit_log.is_some(), "--audit-log"),
+        (args.follow, "--follow"),
+        (args.quota.is_some(), "--quota"),
+        (args.protocol == ssh::Protocol::Sftp, "--protocol sftp"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+        anyhow::bail!("{} is not supported when copying within one host", flag);
+    }
+    println!("Copying from {} to {} on the host", source, args.destination);
+
+    println!("🔗 Creating SSH connection pool...");
+    let sessions = args.sessions();
+    let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, sessions, args.channels_per_session, connect_options(&args)?)?);
+    let m = Arc::new(MultiProgress::new());
+
+    let mut summary = summary::Summary::new(transfer_id, args.rate_window);
+    let phases = phase::Phases::new(&m, transfer_id, args.events.as_deref(), false)?;
+    phases.scan();
+    println!("🔍 Scanning {}...", source);
+    let lease = pool.get_connection()?;
+    let transfer = pool.transfer(&lease);
+    let prepared = list_remote_source(&transfer, &source, &remote_path).and_then(|(walk, remote_root, single_file)| {
+        let mut dest_root = transfer.resolve_path(&dest_remote)?;
+        let is_dir = transfer.remote_kind(&dest_root)? == Some(ssh::RemoteKind::Dir);
+        // `cpx host:/tmp/a.txt host:/tmp/b.txt` writes the one file as b.txt
+        let mut rename = None;
+        if single_file && let Some((root, name)) = file_target(&dest_root, is_dir) {
+            dest_root = root;
+            rename = Some(name);
+        }
+        if args.mkpath {
+            transfer.create_remote_dir(&dest_root)?;
+        }
+        transfer.check_remote_dir(&dest_root)?;
+        Ok((walk, remote_root, dest_root, rename))
+    });
+    pool.return_connection(lease);
+    let (ssh::RemoteWalk { files, errors }, remote_root, dest_root, rename) = prepared?;
+    for e in errors {
+        eprintln!("Error: {}", e);
+        summary.walk_errors.push(e);
+    }
+
+    let mut handles = vec![];
+    println!("🚀 Starting copies on the host ({} sessions x {} channels)...", sessions, args.channels_per_session);
+    let total = files.iter().map(|(_, stat)| stat.size).sum();
+    phases.transfer(files.len(), total);
+    let overall = progress::Overall::new(&m, total, Duration::from_secs(args.rate_window));
+    for (path, stat) in files {
+        let size = stat.size;
+        warn_name(&args, &path);
+        let from = remote_root.join(&path);
+        let to = dest_root.join(rename.clone().unwrap_or_else(|| dest_path(&args, &path)));
+        let overall = overall.clone();
+        let pool = pool.clone();
+        let run_start = summary.started();
+        let task = (path.clone(), size);
+        let fail_on_vanished = args.fail_on_vanished;
+        let h = tokio::task::spawn_blocking(move || {
+            let acquire = || loop {
+                match pool.get_connection() {
+                    Ok(lease) => break lease,
+                    Err(e) => {
+                        eprintln!("Failed to get SSH connection from pool: {}. Retrying in 1 second...", e);
+                        std::thread::sleep(tokio::time::Duration::from_secs(1));
+                    }
+                }
+            };
+            let mut lease = acquire();
+            let started = run_start.elapsed();
+            let clock = Instant::now();
+
+            // Nothing to follow byte by byte, the bar fills when cp is done
+            let pb = ProgressBar::hidden();
+            pb.set_length(size);
+            let counted = overall.start(&pb, size);
+            let mut transfer = pool.transfer(&lease);
+            let mut r = utils::catch_panic(|| transfer.copy_on_host(&from, &to));
+            let mut retries = 0;
+            while let Err(e) = &r && ssh::is_channel_refused(e) && retries < ssh::CHANNEL_RETRIES {
+                pool.throttle(lease);
+                lease = acquire();
+                transfer = pool.transfer(&lease);
+                r = utils::catch_panic(|| transfer.copy_on_host(&from, &to));
+                retries += 1;
+            }
+            // Gone from the host since the listing, rather than failed
+            let vanished = r.is_err() && !fail_on_vanished
+                && transfer.remote_stat(&from).is_ok_and(|stat| stat.is_none());
+            pool.return_connection(lease);
+
+            match &r {
+                Ok(()) => pb.set_position(size),
+                Err(_) if vanished => warn_vanished(&path),
+                Err(e) => eprintln!("Error: {}: {}", path.display(), e),
+            }
+            counted.done(r.is_ok());
+            summary::FileResult {
+                path,
+                size,
+                wire_bytes: 0,
+                ok: r.is_ok(),
+                vanished,
+                stripped: false,
+                started,
+                elapsed: clock.elapsed(),
+                session: None,
+            }
+        });
+        handles.push((task, h));
+    }
+
+    for (task, h) in handles {
+        let result = h.await.unwrap_or_else(|e| lost_task(task, e));
+        on_done(&result);
+        summary.record(result);
+    }
+    overall.finish();
+    phases.finalize();
+    phases.finish();
+
+    if args.deterministic {
+        summary.sort();
+    }
+    summary.print();
+    if let Some(report) = &args.report {
+        summary.write_report(report)?;
+    }
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", summary.walk_errors.len());
+    }
+
+    println!("✅ Copy on the host completed!");
+    Ok(())
+}
+
+// Copy from URLs (s3://..., gs://..., az://..., dav://..., smb://..., https://...) to
+// anywhere, or from an SSH host to a URL: each file is read from the source's
+// backend and written to the destination's as it arrives, so nothing is kept
+// on this machine. A download into a local directory goes to a partial file
+// first, which the next run continues if it is interrupted.
+async fn cp_between(
+    args: Args,
+    transfer_id: &str,
+    on_done: &mut dyn FnMut(&summary::FileResult),
+) -> anyhow::Result<()> {
+    let unsupported = [
+        (!args.also.is_empty(), "--also"),
+        (args.compress.is_some(), "--compress"),
+        (args.paranoid, "--paranoid"),
+        (args.prune_unchanged.is_some(), "--prune-unchanged"),
+        (args.audit_log.is_some(), "--audit-log"),
+        (args.follow, "--follow"),
+        (args.delay_updates, "--delay-updates"),
+        (args.simulate_network.is_some(), "--simulate-network"),
+    ];
+    if let Some((_, flag)) = unsupported.iter().find(|(set, _)| *set) {
+        anyhow::bail!("{} is not supported when copying from or to a URL", flag);
+    }
+    let sources = args.sources().map(|source| source.to_string_lossy().into_owned()).collect::<Vec<_>>();
+    println!("Copying from {} to {}", sources.join(" "), args.destination);
+    let m = Arc::new(MultiProgress::new());
+
+    let mut summary = summary::Summary::new(transfer_id, args.rate_window);
+    let phases = phase::Phases::new(&m, transfer_id, args.events.as_deref(), false)?;
+    phases.scan();
+    let endpoints = {
+        let args = args.clone();
+        tokio::task::spawn_blocking(move || {
+            // As with local sources, a path two sources share comes from the later one
+            let mut files: Vec<(Arc<dyn backend::Backend>, PathBuf, dirfd::Stat)> = vec![];
+            let mut index = HashMap::new();
+            let mut errors = vec![];
+            let mut single_file = false;
+            for source in &sources {
+                println!("🔍 Scanning {}...", source);
+                let (from, listed, more_errors, single) = open_source(&args, source)?;
+                single_file = single && sources.len() == 1;
+                for (path, stat) in listed {
+                    match index.get(&path) {
+                        Some(&i) => files[i] = (from.clone(), path, stat),
+                        None => {
+                            index.insert(path.clone(), files.len());
+                            files.push((from.clone(), path, stat));
+                        }
+                    }
+                }
+                errors.extend(more_errors);
+            }
+            let (to, rename) = open_destination(&args, single_file)?;
+            anyhow::Ok((files, errors, to, rename))
+        })
+    };
+    let (files, errors, to, rename) = endpoints.await??;
+    for e in errors {
+        eprintln!("Error: {}", e);
+        summary.walk_errors.push(e);
+    }
+
+    let semaphore = Arc::new(Semaphore::new(args.net_workers()));
+    to.share_jobs(semaphore.clone());
+    let mut handles = vec![];
+    let read_limit = make_read_limit(&args);
+    let mut quota = args.quota.map(quota::Quota::new);
+    let quota_keys = vec![to.describe()];
+    let mut estimate = cost::Estimate::new(args.storage_class.as_deref());
+    let dest_store = to.object(Path::new("")).map(|object| object.store);
+    for (from, path, stat) in &files {
+        match (from.object(path).map(|object| object.store), &dest_store) {
+            (Some(store), Some(dest_store)) if store == *dest_store => estimate.copy(&store, stat.size),
+            (source_store, dest_store) => {
+                if let Some(store) = source_store {
+                    estimate.download(&store, stat.size);
+                }
+                if let Some(store) = dest_store {
+                    estimate.upload(store, stat.size);
+                }
+            }
+        }
+    }
+    estimate.print();
+    println!("🚀 Starting transfers to {} ({} at once)...", to.describe(), args.net_workers());
+    let total = files.iter().map(|(_, _, stat)| stat.size).sum();
+    phases.transfer(files.len(), total);
+    let overall = progress::Overall::new(&m, total, Duration::from_secs(args.rate_window));
+    for (from, path, stat) in files {
+        let size = stat.size;
+        if let Some(quota) = quota.as_mut() && !quota.admit(&quota_keys, size) {
+            overall.skip(size);
+            continue;
+        }
+        warn_name(&args, &path);
+        let dest_path = rename.clone().unwrap_or_else(|| dest_path(&args, &path));
+        let to = to.clone();
+        let m = m.clone();
+        let overall = overall.clone();
+        let read_limit = read_limit.clone();
+        let rate_window = Duration::from_secs(args.rate_window);
+        let run_start = summary.started();
+        let task = (path.clone(), size);
+        let fail_on_vanished = args.fail_on_vanished;
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let h = tokio::task::spawn_blocking(move || {
+            let started = run_start.elapsed();
+            let clock = Instant::now();
+
+            let pb = m.add(ProgressBar::new(size));
+            pb.set_style(progress::file_style("{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})", rate_window));
+            pb.set_message(utils::align_str(&utils::printable(&path), 20));
+            let counted = overall.start(&pb, size);
+            let r = utils::catch_panic(|| stream_file(from.as_ref(), to.as_ref(), &path, stat, &dest_path, read_limit, &pb));
+            drop(permit);
+            // Gone from the source since the listing, rather than failed
+            let vanished = r.is_err() && !fail_on_vanished && from.stat(&path).is_ok_and(|stat| stat.is_none());
+            match &r {
+                Ok(_) => {}
+                Err(_) if vanished => warn_vanished(&path),
+                Err(e) => eprintln!("Error: {}: {:#}", path.display(), e),
+            }
+            counted.done(r.is_ok());
+            summary::FileResult {
+                path,
+                size,
+                wire_bytes: *r.as_ref().unwrap_or(&0),
+                ok: r.is_ok(),
+                vanished,
+                stripped: false,
+                started,
+                elapsed: clock.elapsed(),
+                session: None,
+            }
+        });
+        handles.push((task, h));
+    }
+    phases.all_started();
+
+    for (task, h) in handles {
+        let result = h.await.unwrap_or_else(|e| lost_task(task, e));
+        if result.ok && let Some(quota) = quota.as_mut() {
+            quota.record(&quota_keys[0], result.size);
+        }
+        on_done(&result);
+        summary.record(result);
+    }
+    overall.finish();
+    phases.finalize();
+    phases.finish();
+
+    if args.deterministic {
+        summary.sort();
+    }
+    summary.print();
+    if let Some(report) = &args.report {
+        summary.write_report(report)?;
+    }
+    if let Some(quota) = quota.as_mut() {
+        quota.finish()?;
+    }
+    if !args.ignore_walk_errors && !summary.walk_errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", summary.walk_errors.len());
+    }
+
+    println!("✅ Transfer completed!");
+    Ok(())
+}
+
+// The backend a URL or SSH source is read from and its files, relative to the
+// parent of the source (or to the source itself when it ends in a slash, or
+// names a whole bucket), with listing errors and whether it is a single file
+#[allow(clippy::type_complexity)]
+fn open_source(args: &Args, source: &str) -> anyhow::Result<(Arc<dyn backend::Backend>, Vec<(PathBuf, dirfd::Stat)>, Vec<String>, bool)> {
+    if !backend::is_url(source) {
+        let (ssh_dest, remote_path) = parse_ssh_destination(source)?;
+        let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, args.sessions(), args.channels_per_session, connect_options(args)?)?);
+        let lease = pool.get_connection()?;
+        let listing = list_remote_source(&pool.transfer(&lease), source, &remote_path);
+        pool.return_connection(lease);
+        let (ssh::RemoteWalk { files, errors }, remote_root, single_file) = listing?;
+        return Ok((Arc::new(backend::SshBackend::new(pool, remote_root)), files, errors, single_file));
+    }
+    let split = (!backend::names_dir(source)).then(|| backend::split_url(source)).flatten();
+    let Some((parent, name)) = split else {
+        let from = backend::open_url(source)?;
+        let files = from.list(Path::new(""))?;
+        return Ok((from, files, vec![], false));
+    };
+    let from = backend::open_url(&parent)?;
+    if let Some(stat) = from.stat(&name)? {
+        return Ok((from, vec![(name, stat)], vec![], true));
+    }
+    let files = from.list(&name).with_context(|| format!("Source {} does not exist", source))?;
+    if files.is_empty() {
+        anyhow::bail!("Source {} does not exist", source);
+    }
+    Ok((from, files, vec![], false))
+}
+
+// The backend of the destination of `cp_between`, checked, with the name to
+// write a single-file source under (see `file_target`)
+fn open_destination(args: &Args, single_file: bool) -> anyhow::Result<(Arc<dyn backend::Backend>, Option<PathBuf>)> {
+    let destination = &args.destination;
+    let (to, rename): (Arc<dyn backend::Backend>, _) = if backend::is_url(destination) {
+        match single_file.then(|| file_target(Path::new(destination), backend::names_dir(destination))).flatten() {
+            Some((root, name)) => (backend::open_url_with(&root.to_string_lossy(), args.object_options())?, Some(name)),
+            None => (backend::open_url_with(destination, args.object_options())?, None),
+        }
+    } else if split_remote(destination).is_some() {
+        let (ssh_dest, remote_path) = parse_ssh_destination(destination)?;
+        let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, args.sessions(), args.channels_per_session, connect_options(args)?)?);
+        let lease = pool.get_connection()?;
+        let transfer = pool.transfer(&lease);
+        let target = transfer.resolve_path(&remote_path).and_then(|root| {
+            let is_dir = transfer.remote_kind(&root)? == Some(ssh::RemoteKind::Dir);
+            Ok(match single_file.then(|| file_target(&root, is_dir)).flatten() {
+                Some((root, name)) => (root, Some(name)),
+                None => (root, None),
+            })
+        });
+        pool.return_connection(lease);
+        let (root, rename) = target?;
+        (Arc::new(backend::SshBackend::new(pool, root)), rename)
+    } else {
+        let destination = Path::new(destination);
+        match single_file.then(|| file_target(destination, destination.is_dir())).flatten() {
+            Some((root, name)) => (backend::open(&root), Some(name)),
+            None => (backend::open(destination), None),
+        }
+    };
+    if args.mkpath {
+        to.mkdir(Path::new(""))?;
+    }
+    to.check()?;
+    Ok((to, rename))
+}
+
+// Copy `path` of `from`, of size and mtime `stat`, to `dest_path` of `to`,
+// returning the bytes that passed through here
+fn stream_file(
+    from: &dyn backend::Backend,
+    to: &dyn backend::Backend,
+    path: &Path,
+    stat: dirfd::Stat,
+    dest_path: &Path,
+    read_limit: Option<Arc<throttle::Throttle>>,
+    pb: &ProgressBar,
+) -> anyhow::Result<u64> {
+    // Between buckets of one store, the store copies the object itself
+    if let Some(object) = from.object(path) && to.copy_object(&object, dest_path, stat.size)? {
+        pb.finish_and_clear();
+        return Ok(0);
+    }
+    let partial = to.resumable().then(|| partial_path(dest_path));
+    // What an earlier run left is only continued when written after the
+    // source last changed, as it must then be the start of the same data
+    let offset = match &partial {
+        Some(partial) => match to.stat(partial)? {
+            Some(done) if done.size < stat.size && stat.mtime > 0 && done.mtime >= stat.mtime => done.size,
+            _ => 0,
+        },
+        None => 0,
+    };
+    let mut output = match &partial {
+        Some(partial) if offset > 0 => {
+            println!("⏯️  Resuming {} at {}", utils::printable(path), HumanBytes(offset));
+            to.append(partial)?
+        }
+        Some(partial) => to.create(partial, utils::dest_mode(None, false).0, stat.size)?,
+        None => to.create(dest_path, utils::dest_mode(None, false).0, stat.size)?,
+    };
+    let mut input = throttle::Throttled::new(from.open_at(path, offset)?, read_limit);
+    let mut copied = 0u64;
+    pb.set_position(offset);
+    power::with_buffer(power::BUFFER, |buffer| loop {
+        power::wait();
+        let n = input.read(buffer)?;
+        if n == 0 {
+            return anyhow::Ok(());
+        }
+        output.write_all(&buffer[..n])?;
+        copied += n as u64;
+        pb.set_position(offset + copied);
+    })?;
+    output.finalize()?;
+    if let Some(partial) = &partial {
+        to.rename(partial, dest_path)?;
+    }
+    pb.finish_and_clear();
+    Ok(copied)
+}
+
+// Where a download to `dest_path` is kept until it is complete
+fn partial_path(dest_path: &Path) -> PathBuf {
+    let name = dest_path.file_name().unwrap_or(dest_path.as_os_str()).to_string_lossy();
+    dest_path.with_file_name(format!(".{}.cpx-partial", name))
+}
+
+// --follow: keep appending what is written to the remote files to their local
+// copies until interrupted. A lost connection is retried at the next check; a
+// file that got shorter was truncated or rotated and is copied again from the start.
+fn follow_remote(
+    pool: &ssh::SshConnectionPool,
+    remote_root: &Path,
+    dest_root: &Path,
+    files: Vec<(PathBuf, PathBuf)>,
+    read_limit: Option<Arc<throttle::Throttle>>,
+) -> anyhow::Result<()> {
+    // Files may have grown while they were copied, start from what actually arrived
+    let mut offsets = files.into_iter()
+        .map(|(path, dest_path)| {
+            let offset = dirfd::stat_beneath(dest_root, &dest_path).ok().flatten().map_or(0, |stat| stat.size);
+            (path, dest_path, offset)
+        })
+        .collect::<Vec<_>>();
+    println!("👀 Following {} files, press Ctrl-C to stop", offsets.len());
+    loop {
+        std::thread::sleep(FOLLOW_INTERVAL);
+        let lease = match pool.get_connection() {
+            Ok(lease) => lease,
+            Err(e) => {
+                eprintln!("⚠️  Lost connection to {}: {:#}; retrying", pool.ssh_dest(), e);
+                continue;
+            }
+        };
+        let transfer = pool.transfer(&lease);
+        for (path, dest_path, offset) in &mut offsets {
+            if let Err(e) = follow_file(&transfer, remote_root, dest_root, path, dest_path, offset, read_limit.clone()) {
+                eprintln!("⚠️  {}: {:#}", path.display(), e);
+            }
+        }
+        pool.return_connection(lease);
+    }
+}
+
+// Bring one followed file up to date, `offset` being the length of the local copy
+fn follow_file(
+    transfer: &ssh::SshTransfer,
+    remote_root: &Path,
+    dest_root: &Path,
+    path: &Path,
+    dest_path: &Path,
+    offset: &mut u64,
+    read_limit: Option<Arc<throttle::Throttle>>,
+) -> anyhow::Result<()> {
+    let remote_path = remote_root.join(path);
+    let mut output = dirfd::append_beneath(dest_root, dest_path)?;
+    let copied = match transfer.read_from(&remote_path, *offset, &mut output, read_limit.clone())? {
+        Some(copied) => copied,
+        None => {
+            println!("🔄 {} was truncated or rotated, copying it again", path.display());
+            output.set_len(0)?;
+            *offset = 0;
+            transfer.read_from(&remote_path, 0, &mut output, read_limit)?.unwrap_or(0)
+        }
+    };
+    *offset += copied;
+    Ok(())
+}
+
+// Scan both sides and write the plan, without transferring anything
+#[allow(clippy::too_many_arguments)]
+async fn make_plan(
+    source: PathBuf,
+    destination: String,
+    output: &Path,
+    delete: bool,
+    batch_size: Option<u64>,
+    dest_inventory: Option<&Path>,
+    dest_bloom: Option<&Path>,
+    args: Args,
+) -> anyhow::Result<()> {
+    let mut plan = scan_differences(&source, &destination, delete, dest_inventory, dest_bloom, &args)?;
+    if let Some(batch_size) = batch_size {
+        plan.split_batches(batch_size);
+    }
+    plan.save(Some(output))?;
+    plan.print();
+    if backend::is_url(&plan.destination) && let Some(object) = backend::open_url(&plan.destination)?.object(Path::new("")) {
+        let mut estimate = cost::Estimate::new(args.storage_class.as_deref());
+        for (_, entry) in plan.pending(plan::Action::Copy) {
+            estimate.upload(&object.store, entry.size);
+        }
+        estimate.print();
+    }
+    println!("✅ Plan written to {}", output.display());
+    Ok(())
+}
+
+// Compare the source tree with its copy under the destination
+fn scan_differences(
+    source: &Path,
+    destination: &str,
+    delete: bool,
+    dest_inventory: Option<&Path>,
+    dest_bloom: Option<&Path>,
+    args: &Args,
+) -> anyhow::Result<plan::Plan> {
+    if !args.routes.is_empty() {
+        anyhow::bail!("--route cannot be used when planning, a plan has a single destination");
+    }
+    let src_root = source_root(source);
+    // Where the copy of the source goes below the destination
+    let name = match source_name(source) {
+        Some(name) => name,
+        None if copies_contents(source) => Path::new(""),
+        None => anyhow::bail!("Cannot plan a copy of {}", source.display()),
+    };
+    println!("🔍 Scanning {}...", source.display());
+    let (src, errors) = plan::scan_local(src_root, source);
+    for e in &errors {
+        eprintln!("Error: {}", e);
+    }
+    if !args.ignore_walk_errors && !errors.is_empty() {
+        anyhow::bail!("{} entries could not be read while scanning the source", errors.len());
+    }
+
+    if let Some(file) = dest_inventory {
+        let inventory = inventory::Inventory::load(file)?;
+        println!(
+            "📒 Using the inventory of {} from {} ago instead of scanning {}",
+            inventory.root,
+            HumanDuration(Duration::from_secs(utils::now_secs().saturating_sub(inventory.created))),
+            destination
+        );
+        return Ok(plan::Plan::new(source, destination, &src, &inventory.listing(name), delete));
+    }
+
+    if let Some(file) = dest_bloom {
+        if delete {
+            anyhow::bail!("--delete needs the list of destination files, a bloom filter cannot give it");
+        }
+        let bloom = bloom::Bloom::load(file)?;
+        println!(
+            "📒 Using the bloom filter of {} from {} ago instead of scanning {}",
+            bloom.root,
+            HumanDuration(Duration::from_secs(utils::now_secs().saturating_sub(bloom.created))),
+            destination
+        );
+        let mut unchanged = vec![];
+        let mut uncertain = vec![];
+        for (path, stat) in &src {
+            match bloom.lookup(path, stat) {
+                bloom::Lookup::Unchanged => unchanged.push(path.clone()),
+                bloom::Lookup::Stale => {}
+                bloom::Lookup::Uncertain => uncertain.push(path.clone()),
+            }
+        }
+        // Files the filter has as copied count as found unchanged once a
+        // sample of them is; a miss in the sample has them all looked up
+        let fraction = (bloom::CHECKED as f64 / unchanged.len().max(1) as f64).min(1.0);
+        let sample = sample::pick(unchanged.clone(), fraction, &uuid::Uuid::new_v4().to_string());
+        println!("🔍 Checking {} of the {} files the filter has as copied on {}...", sample.len(), unchanged.len(), destination);
+        let found = stat_files(destination, &sample, args)?;
+        let misses = sample.iter()
+            .filter(|path| found.get(*path).is_none_or(|stat| stat.size != src[*path].size))
+            .count();
+        let mut dest = plan::Listing::new();
+        if misses == 0 {
+            println!("📒 {} files taken as copied, {} of them checked", unchanged.len(), sample.len());
+            dest.extend(unchanged.into_iter().map(|path| {
+                let stat = src[&path];
+                (path, stat)
+            }));
+        } else {
+            println!("⚠️  {} of {} checked files are not on {} as the filter has them, looking up the other {}", misses, sample.len(), destination, unchanged.len() - sample.len());
+            let checked = sample.into_iter().collect::<std::collections::HashSet<_>>();
+            uncertain.extend(unchanged.into_iter().filter(|path| !checked.contains(path)));
+            dest.extend(found);
+        }
+        println!("🔍 Looking up {} of {} files on {}...", uncertain.len(), src.len(), destination);
+        dest.extend(stat_files(destination, &uncertain, args)?);
+        return Ok(plan::Plan::new(source, destination, &src, &dest, false));
+    }
+
+    println!("🔍 Scanning {}...", destination);
+    let dest = match split_remote(destination) {
+        Some(_) => {
+            let (ssh_dest, remote_root) = parse_ssh_destination(destination)?;
+            let pool = ssh::SshConnectionPool::new(ssh_dest, 1, 1, connect_options(args)?)?;
+            let lease = pool.get_connection()?;
+            let transfer = pool.transfer(&lease);
+            let files = transfer.resolve_path(&remote_root)
+                .and_then(|remote_root| transfer.list_remote_files(&remote_root, name));
+            pool.return_connection(lease);
+            files?.into_iter().collect()
+        }
+        None if backend::is_url(destination) => backend::open_url(destination)?.list(name)?.into_iter().collect(),
+        None => {
+            let dest_root = Path::new(destination);
+            let (dest, errors) = plan::scan_local(dest_root, &dest_root.join(name));
+            if !errors.is_empty() {
+                anyhow::bail!("{} entries could not be read while scanning the destination: {}", errors.len(), errors[0]);
+            }
+            dest
+        }
+    };
+
+    Ok(plan::Plan::new(source, destination, &src, &dest, delete))
+}
+
+// Size and modification time of each of `files` that exists under the
+// destination, looked up on several threads (connections when remote)
+fn stat_files(destination: &str, files: &[PathBuf], args: &Args) -> anyhow::Result<plan::Listing> {
+    let (backend, workers): (Arc<dyn backend::Backend>, _) = match split_remote(destination) {
+        Some(_) => {
+            let (ssh_dest, remote_root) = parse_ssh_destination(destination)?;
+            let workers = args.net_workers().min(files.len()).max(1);
+            let pool = Arc::new(ssh::SshConnectionPool::new(ssh_dest, workers, 1, connect_options(args)?)?);
+            let lease = pool.get_connection()?;
+            let remote_root = pool.transfer(&lease).resolve_path(&remote_root);
+            pool.return_connection(lease);
+            (Arc::new(backend::SshBackend::new(pool, remote_root?)), workers)
+        }
+        None if backend::is_url(destination) => (backend::open_url(destination)?, args.net_workers()),
+        None => (backend::open(Path::new(destination)), args.disk_workers()),
+    };
+    let threads = rayon::ThreadPoolBuilder::new().num_threads(workers).build()?;
+    threads.install(|| {
+        files.par_iter()
+            .map(|path| Ok(backend.stat(path)?.map(|stat| (path.clone(), stat))))
+            .filter_map(Result::transpose)
+            .collect()
+    })
+}
+
+// Stat the copies of a batch at the destination and mark those found at the
+// size that was sent; any other stays pending for the next run
+fn verify_batch(plan: &mut plan::Plan, copied: &[(PathBuf, u64)], args: &Args) -> anyhow::Result<()> {
+    println!("🔍 Verifying {} copies at the destination", copied.len());
+    let paths = copied.iter().map(|(path, _)| dest_path(args, path)).collect::<Vec<_>>();
+    let found = stat_files(&args.destination, &paths, args)
+        .context("Cannot verify the batch at the destination")?;
+    for ((path, size), dest) in copied.iter().zip(&paths) {
+        match found.get(dest) {
+            Some(stat) if stat.size == *size => plan.mark_copied(path),
+            Some(stat) => eprintln!("⚠️  {}: the copy has {} bytes, {} were sent", path.display(), stat.size, size),
+            None => eprintln!("⚠️  {}: the copy is missing at the destination", path.display()),
+        }
+    }
+    Ok(())
+}
+
+// Run the pending entries of a plan, recording progress in the plan file
+async fn apply_plan(file: &Path, max_batches: Option<usize>, mut args: Args) -> anyhow::Result<()> {
+    let mut plan = plan::Plan::load(file)?;
+    if !args.also.is_empty() {
+        anyhow::bail!("--also cannot be used with a plan, it has a single destination");
+    }
+    if !args.routes.is_empty() {
+        anyhow::bail!("--route cannot be used with a plan, it has a single destination");
+    }
+    plan.print();
+    args.source = plan.source.clone();
+    args.destination = plan.destination.clone();
+    // The plan decides what to send, a partial walk must not replace the fingerprints
+    args.prune_unchanged = None;
+    // Every batch is verified at the destination before it counts as done
+    let batched = plan.batch_size.is_some();
+
+    let transfer_id = new_transfer_id();
+    let src_root: Arc<Path> = Arc::from(source_root(&args.source));
+    let batches = plan.pending_batches();
+    let total = plan.batch_count();
+    for (run, batch) in batches.into_iter().enumerate() {
+        if max_batches.is_some_and(|max| run >= max) {
+            plan.save(None)?;
+            println!("⏸️  Stopped after {} batches, run apply again to continue", run);
+            return Ok(());
+        }
+        let copies = plan.pending(plan::Action::Copy)
+            .filter(|(_, entry)| entry.batch == batch)
+            .map(|(_, entry)| WorkItem { root: src_root.clone(), path: entry.path.clone(), size: entry.size })
+            .collect::<Vec<_>>();
+        if total > 1 {
+            let bytes: u64 = copies.iter().map(|item| item.size).sum();
+            println!("📦 Batch {} of {}: {} files, {}", batch + 1, total, copies.len(), HumanBytes(bytes));
+        }
+        let mut copied = vec![];
+        let r = copy(args.clone(), &transfer_id, Work::Files(copies), &mut |result| {
+            if result.ok && batched {
+                // Marked once the batch is verified
+                copied.push((result.path.clone(), result.size));
+            } else if result.ok || result.vanished {
+                // A vanished source has nothing left to copy
+                plan.mark_copied(&result.path);
+                if let Err(e) = plan.checkpoint() {
+                    eprintln!("Error: cannot save plan progress: {}", e);
+                }
+            }
+        }).await;
+        if !copied.is_empty() {
+            verify_batch(&mut plan, &copied, &args)?;
+        }
+        plan.save(None)?;
+        r?;
+        if plan.pending(plan::Action::Copy).any(|(_, entry)| entry.batch == batch) {
+            anyhow::bail!("Some copies in batch {} failed, later batches and deletes are held back; run apply again to retry", batch + 1);
+        }
+    }
+
+    apply_deletes(&mut plan, &args, &transfer_id)?;
+    plan.save(None)?;
+    plan.print();
+    Ok(())
+}
+
+// `cpx selftest`: plan a copy of a generated tree, then apply the plan with
+// faults injected until nothing is pending, as a user would resume a failed
+// run, and compare every copy with its source with injection off again
+async fn selftest(faults: fault::Faults, files: usize, rounds: usize, args: Args) -> anyhow::Result<()> {
+    let dir = std::env::temp_dir().join(format!("cpx-selftest-{}", uuid::Uuid::new_v4()));
+    let source = dir.join("src");
+    let destination = dir.join("dst");
+    let plan_file = dir.join("plan.json");
+    println!("🧪 Generating {} files in {}", files, source.display());
+    let mut expected = vec![];
+    for i in 0..files {
+        // Sizes from empty to a few hundred KiB, so files span many chunks
+        let size = utils::fnv1a(&i.to_le_bytes(), 0) as usize % (384 * 1024);
+        let data = (0..size).map(|n| utils::fnv1a(&(i * 7919 + n).to_le_bytes(), 0) as u8).collect::<Vec<_>>();
+        let rel = PathBuf::from(format!("src/dir{}/file{}.bin", i % 10, i));
+        let path = dir.join(&rel);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(&path, &data)?;
+        expected.push((rel, hash::hash_reader(args.hash, &data[..])?));
+    }
+    std::fs::create_dir_all(&destination)?;
+    let destination = destination.to_string_lossy().into_owned();
+    scan_differences(&source, &destination, false, None, None, &args)?.save(Some(&plan_file))?;
+
+    fault::set(Some(faults));
+    let mut run = 0;
+    let result = loop {
+        run += 1;
+        println!("🔁 Run {} of at most {}", run, rounds);
+        match apply_plan(&plan_file, None, args.clone()).await {
+            Ok(()) => break Ok(()),
+            Err(e) if run < rounds => eprintln!("⚠️  Run {} failed, resuming: {:#}", run, e),
+            Err(e) => break Err(e),
+        }
+    };
+    fault::set(None);
+    result.with_context(|| format!("Copy still incomplete after {} runs, files left in {}", rounds, dir.display()))?;
+
+    let mut bad = 0;
+    for (rel, digest) in &expected {
+        let copy = std::fs::File::open(Path::new(&destination).join(rel))
+            .and_then(|file| hash::hash_reader(args.hash, file));
+        match copy {
+            Ok(copy) if copy == *digest => {}
+            Ok(copy) => {
+                eprintln!("❌ {}: copy differs from the source (source {}, copy {})", rel.display(), digest, copy);
+                bad += 1;
+            }
+            Err(e) => {
+                eprintln!("❌ {}: {}", rel.display(), e);
+                bad += 1;
+            }
+        }
+    }
+    if bad > 0 {
+        anyhow::bail!("Self-test failed: {} of {} copies are wrong, files left in {}", bad, files, dir.display());
+    }
+    std::fs::remove_dir_all(&dir)?;
+    println!("✅ Self-test passed: {} files intact after {} runs and {} injected faults", files, run, fault::injected());
+    Ok(())
+}
+
+// Remove the files a plan marks for deletion, one at a time
+fn apply_deletes(plan: &mut plan::Plan, args: &Args, transfer_id: &str) -> anyhow::Result<()> {
+    let deletes = plan.pending(plan::Action::Delete).map(|(i, _)| i).collect::<Vec<_>>();
+    if deletes.is_empty() {
+        return Ok(());
+    }
+    println!("🗑️  Deleting {} files...", deletes.len());
+    let remote = match split_remote(&args.destination) {
+        Some(_) => {
+            let (ssh_dest, remote_root) = parse_ssh_destination(&args.destination)?;
+            let pool = ssh::SshConnectionPool::new(ssh_dest, 1, 1, connect_options(args)?)?;
+            let lease = pool.get_connection()?;
+            let transfer = pool.transfer(&lease);
+            let remote_root = transfer.resolve_path(&remote_root)?;
+            Some((pool, lease, transfer, remote_root))
+        }
+        _ => None,
+    };
+    let audit = match (&args.audit_log, &remote) {
+        (Some(Some(path)), _) => Some(audit::AuditLog::local(path, transfer_id)?),
+        (Some(None), None) => Some(audit::AuditLog::local(&Path::new(&args.destination).join(audit::DEST_AUDIT_LOG), transfer_id)?),
+        (Some(None), Some((_, _, _, remote_root))) => Some(audit::AuditLog::remote(remote_root.join(audit::DEST_AUDIT_LOG), transfer_id)),
+        (None, _) => None,
+    };
+
+    let mut r = Ok(());
+    for i in deletes {
+        let path = plan.entries[i].path.clone();
+        r = match &remote {
+            Some((pool, _, transfer, remote_root)) => {
+                let remote_path = remote_root.join(&path);
+                if let Some(audit) = &audit
+                    && let Ok(Some(old)) = transfer.remote_stat(&remote_path)
+                    && let Err(e) = audit.delete(pool.ssh_dest(), &path, old, Some(transfer)) {
+                    eprintln!("Error: cannot write audit log: {}", e);
+                }
+                transfer.remove_remote(&remote_path)
+            }
+            None => {
+                let backend = backend::open(Path::new(&args.destination));
+                if let Some(audit) = &audit
+                    && let Ok(Some(old)) = backend.stat(&path)
+                    && let Err(e) = audit.delete(&args.destination, &path, old, None) {
+                    eprintln!("Error: cannot write audit log: {}", e);
+                }
+                backend.remove(&path)
+            }
+        };
+        if r.is_err() {
+            break;
+        }
+        plan.entries[i].done = true;
+        plan.checkpoint()?;
+    }
+    if let Some((pool, lease, _, _)) = remote {
+        pool.return_connection(lease);
+    }
+    r
+}
+
+fn connect_options(args: &Args) -> anyhow::Result<Arc<ssh::ConnectOptions>> {
+    let profile = match &args.profile {
+        Some(name) => config::Config::load()?.profile(name)?,
+        None => config::Profile::default(),
+    };
+    if let Some(identity) = args.identity.iter().find(|identity| !identity.is_file()) {
+        anyhow::bail!("Identity file {} not found", identity.display());
+    }
+    if let Some(library) = &args.pkcs11 {
+        if !library.is_file() {
+            anyhow::bail!("PKCS#11 library {} not found", library.display());
+        }
+        // libssh2 cannot use a token itself, only keys the agent holds
+        if std::env::var_os("SSH_AUTH_SOCK").is_none() {
+            anyhow::bail!("--pkcs11 needs a running ssh-agent (SSH_AUTH_SOCK is not set), start one with eval $(ssh-agent)");
+        }
+    }
+    let options = ssh::ConnectOptions {
+        auth_cache: cache::AuthCache::new(args.auth_cache_ttl),
+        tcp_nodelay: args.tcp_nodelay,
+        send_buffer: args.sndbuf.map(|size| size as usize),
+        recv_buffer: args.rcvbuf.map(|size| size as usize),
+        kex: args.kex.clone(),
+        ciphers: args.ciphers.clone(),
+        macs: args.macs.clone(),
+        passwords: credentials::Passwords::new(),
+        provider: profile.credentials.as_ref().map(credentials::ProviderConfig::build),
+        host_keys: profile.host_keys,
+        fingerprints: args.fingerprint.clone(),
+        known_hosts: hostkeys::KnownHosts::new(args.strict_host_key_checking),
+        protocol: args.protocol,
+        port: args.port,
+        identity: args.identity.clone(),
+        add_keys_to_agent: args.add_keys_to_agent,
+        pkcs11: args.pkcs11.clone(),
+        proxy_command: args.proxy_command.clone(),
+        proxy: args.proxy.clone(),
+        chunk_size: args.chunk_size.map(|size| size as usize),
+    };
+    options.check_methods()?;
+    Ok(Arc::new(options))
+}
+
+// Shared limit on source reads, from --read-bwlimit
+fn make_read_limit(args: &Args) -> Option<Arc<throttle::Throttle>> {
+    args.read_bwlimit.map(|rate| Arc::new(throttle::Throttle::new(rate)))
+}
+
+// Verification pool, only needed when --paranoid is on
+fn make_hash_pool(
+    args: &Args,
+    read_limit: Option<Arc<throttle::Throttle>>,
+    cpu_limit: Option<Arc<cpu::CpuLimit>>,
+) -> anyhow::Result<Option<Arc<hash::HashPool>>> {
+    if !args.paranoid {
+        return Ok(None);
+    }
+    // No more threads than --cpu-limit cores, the rest would only wait
+    let threads = match (args.hash_threads, args.cpu_limit) {
+        (Some(threads), _) => Some(args.cpu_threads(threads)),
+        (None, Some(cores)) => Some(cores as usize),
+        (None, None) => None,
+    };
+    Ok(Some(Arc::new(hash::HashPool::new(args.hash, threads, read_limit, cpu_limit)?)))
+}
+
+// Shared cap on the cores used for compression and hashing, from --cpu-limit
+fn make_cpu_limit(args: &Args) -> Option<Arc<cpu::CpuLimit>> {
+    args.cpu_limit.map(|cores| Arc::new(cpu::CpuLimit::new(cores as usize)))
+}
+
+// Whether a source file that failed to copy is gone, deleted or rotated away
+// since the scan found it
+fn source_vanished(src_root: &Path, path: &Path) -> bool {
+    matches!(dirfd::stat_beneath(src_root, path), Ok(None))
+}
+
+fn warn_vanished(path: &Path) {
+    eprintln!("⚠️  {} vanished before it could be copied", path.display());
+}
+
+// The result of a transfer task that ended without returning one (it
+// panicked outside the transfer itself): the file counts as failed instead of
+// dropping out of the summary
+fn lost_task((path, size): (PathBuf, u64), e: tokio::task::JoinError) -> summary::FileResult {
+    eprintln!("Error: {}: {}", path.display(), e);
+    summary::FileResult {
+        path,
+        size,
+        wire_bytes: 0,
+        ok: false,
+        vanished: false,
+        stripped: false,
+        started: Duration::ZERO,
+        elapsed: Duration::ZERO,
+        session: None,
+    }
+}
+
+// A source given as a URL (s3://bucket/prefix, gs://bucket/prefix, az://container/prefix)
+fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(backend::is_url)
+}
+
+// A source or destination in format user@host:path
+fn is_remote(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| split_remote(path).is_some())
+}
+
+// Split `[user@]host[:port]:path` into the login (with the port) and the
+// path, None for a local path. The host may be a bracketed IPv6 address
+// (user@[2001:db8::1]:2222:/data); digits up to a second colon are taken for
+// a port. As with scp, a colon after a path separator belongs to a local name
+// (./a:b), and on Windows so does the colon of a drive letter (C:\data,
+// C:data); UNC paths (\\server\share) start with a separator. URLs
+// (s3://bucket/key) are not SSH endpoints either.
+fn split_remote(spec: &str) -> Option<(&str, &str)> {
+    if backend::is_url(spec) {
+        return None;
+    }
+    let separators: &[char] = if cfg!(windows) { &['/', '\\'] } else { &['/'] };
+    let bytes = spec.as_bytes();
+    if cfg!(windows) && bytes.len() >= 2 && bytes[0].is_ascii_alphabetic() && bytes[1] == b':' {
+        return None;
+    }
+    let mut bracketed = false;
+    for (i, c) in spec.char_indices() {
+        match c {
+            '[' => bracketed = true,
+            ']' => bracketed = false,
+            ':' if !bracketed => {
+                if i == 0 {
+                    return None;
+                }
+                let rest = &spec[i + 1..];
+                let login = match rest.find(':') {
+                    Some(end) if end > 0 && rest[..end].bytes().all(|b| b.is_ascii_digit()) => i + 1 + end,
+                    _ => i,
+                };
+                return Some((&spec[..login], &spec[login + 1..]));
+            }
+            c if separators.contains(&c) => return None,
+            _ => {}
+        }
+    }
+    None
 }
 
 // Helper function to parse SSH destination
 fn parse_ssh_destination(destination: &str) -> anyhow::Result<(String, String)> {
     // Format: user@host:path
-    let dest_parts: Vec<&str> = destination.split(":").collect();
-    if dest_parts.len() == 2 {
-        return Ok((dest_parts[0].to_string(), dest_parts[1].to_string()));
-    }else{
-        Err(anyhow::anyhow!("Invalid SSH destination format. Expected user@host:path"))
+    match split_remote(destination) {
+        Some((ssh_dest, path)) => Ok((ssh_dest.to_string(), path.to_string())),
+        None => Err(anyhow::anyhow!("Invalid SSH destination format. Expected user@host:path")),
     }
 }
\ No newline at end of file
@@ -0,0 +1,192 @@
+// --simulate-network: make a local destination behave like a slow link, to
+// try chunk sizes, parallelism and compression without the production WAN.
+//
+// The link is a round-trip time and a bandwidth shared by every transfer of
+// the run. Each backend operation (stat, mkdir, opening and finishing a file)
+// waits one round trip, as it would for a reply from the far side, and file
+// data is held to the bandwidth. With --compress the bandwidth is charged for
+// the zstd-compressed size of the data, as it would be on the wire.
+
+use anyhow::Result;
+use indicatif::HumanBytes;
+use std::fmt;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Semaphore;
+
+use crate::backend::{Backend, Upload};
+use crate::dirfd::Stat;
+use crate::throttle::Throttle;
+use crate::utils;
+
+/// A simulated link, parsed from "50ms/10Mbit", "50ms" or "10Mbit"
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Link {
+    pub rtt: Duration,
+    // Bytes per second, unlimited when None
+    pub bandwidth: Option<u64>,
+}
+
+impl fmt::Display for Link {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}ms", self.rtt.as_millis())?;
+        if let Some(bandwidth) = self.bandwidth {
+            write!(f, "/{}/s", HumanBytes(bandwidth))?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse the round-trip time (us, ms or s) and the bandwidth (kbit, mbit or
+/// gbit per second, or a byte size per second such as 1M), separated by '/'
+pub fn parse(spec: &str) -> Result<Link, String> {
+    let mut link = Link { rtt: Duration::ZERO, bandwidth: None };
+    for part in spec.split('/').map(str::trim) {
+        if let Some(rtt) = parse_duration(part) {
+            link.rtt = rtt;
+        } else if let Some(bandwidth) = parse_bits(part).or_else(|| utils::parse_size(part).ok()) {
+            if bandwidth == 0 {
+                return Err(format!("bandwidth must be above 0: {}", part));
+            }
+            link.bandwidth = Some(bandwidth);
+        } else {
+            return Err(format!("expected a round-trip time (50ms) or a bandwidth (10Mbit): {}", part));
+        }
+    }
+    Ok(link)
+}
+
+fn parse_duration(s: &str) -> Option<Duration> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let value: f64 = s[..split].parse().ok()?;
+    let seconds = match &s[split..] {
+        "us" => value / 1e6,
+        "ms" => value / 1e3,
+        "s" => value,
+        _ => return None,
+    };
+    Some(Duration::from_secs_f64(seconds))
+}
+
+// Bits per second with a decimal unit, returned as bytes per second
+fn parse_bits(s: &str) -> Option<u64> {
+    let split = s.find(|c: char| !c.is_ascii_digit() && c != '.')?;
+    let value: f64 = s[..split].parse().ok()?;
+    let multiplier = match s[split..].to_ascii_lowercase().trim_end_matches("/s").trim_end_matches("ps") {
+        "kbit" | "kb" => 1e3,
+        "mbit" | "mb" => 1e6,
+        "gbit" | "gb" => 1e9,
+        _ => return None,
+    };
+    Some((value * multiplier / 8.0) as u64)
+}
+
+/// Put `inner` behind the simulated link
+pub fn wrap(inner: Arc<dyn Backend>, link: Link, compress: Option<i32>) -> Arc<dyn Backend> {
+    let throttle = link.bandwidth.map(|bandwidth| Arc::new(Throttle::new(bandwidth)));
+    Arc::new(SimulatedBackend { inner, link, throttle, compress })
+}
+
+struct SimulatedBackend {
+    inner: Arc<dyn Backend>,
+    link: Link,
+    throttle: Option<Arc<Throttle>>,
+    compress: Option<i32>,
+}
+
+impl SimulatedBackend {
+    fn round_trip(&self) {
+        std::thread::sleep(self.link.rtt);
+    }
+}
+
+impl Backend for SimulatedBackend {
+    fn describe(&self) -> String {
+        format!("{} (simulated {})", self.inner.describe(), self.link)
+    }
+
+    fn check(&self) -> Result<()> {
+        self.round_trip();
+        self.inner.check()
+    }
+
+    fn mkdir(&self, rel: &Path) -> Result<()> {
+        self.round_trip();
+        self.inner.mkdir(rel)
+    }
+
+    fn stat(&self, rel: &Path) -> Result<Option<Stat>> {
+        self.round_trip();
+        self.inner.stat(rel)
+    }
+
+    fn open(&self, rel: &Path) -> Result<Box<dyn Read + Send>> {
+        self.round_trip();
+        self.inner.open(rel)
+    }
+
+    fn create(&self, rel: &Path, mode: u32, size: u64) -> Result<Box<dyn Upload>> {
+        self.round_trip();
+        let inner = self.inner.create(rel, mode, size)?;
+        Ok(Box::new(SimulatedUpload { inner, throttle: self.throttle.clone(), compress: self.compress, rtt: self.link.rtt }))
+    }
+
+    fn remove(&self, rel: &Path) -> Result<()> {
+        self.round_trip();
+        self.inner.remove(rel)
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        self.round_trip();
+        self.inner.rename(from, to)
+    }
+
+    fn remove_dir(&self, rel: &Path) -> Result<()> {
+        self.round_trip();
+        self.inner.remove_dir(rel)
+    }
+
+    fn list(&self, rel: &Path) -> Result<Vec<(PathBuf, Stat)>> {
+        self.round_trip();
+        self.inner.list(rel)
+    }
+
+    fn share_jobs(&self, jobs: Arc<Semaphore>) {
+        self.inner.share_jobs(jobs)
+    }
+}
+
+struct SimulatedUpload {
+    inner: Box<dyn Upload>,
+    throttle: Option<Arc<Throttle>>,
+    compress: Option<i32>,
+    rtt: Duration,
+}
+
+impl Write for SimulatedUpload {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if let Some(throttle) = &self.throttle {
+            let on_wire = match self.compress {
+                Some(level) => zstd::bulk::compress(&buf[..n], level)?.len(),
+                None => n,
+            };
+            throttle.consume(on_wire);
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Upload for SimulatedUpload {
+    fn finalize(self: Box<Self>) -> Result<()> {
+        // The last data and the far side's acknowledgement
+        std::thread::sleep(self.rtt);
+        self.inner.finalize()
+    }
+}
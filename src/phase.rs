@@ -0,0 +1,156 @@
+// The phases of a run: scanning the source, transferring the data, verifying
+// the checksums still pending once the last file is written, and finalizing
+// (fingerprints, reports, quota). Long runs spend minutes outside the
+// transfer; a status line above the progress bars says which phase is
+// running, and with --events each phase change is written as a JSON line for
+// whatever drives cpx.
+
+use anyhow::{Context, Result};
+use indicatif::{HumanBytes, MultiProgress, ProgressBar, ProgressStyle};
+use serde::Serialize;
+use std::fs::File;
+use std::io::Write;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Scan,
+    Transfer,
+    Verify,
+    Finalize,
+}
+
+#[derive(Serialize)]
+struct Event<'a> {
+    transfer_id: &'a str,
+    phase: Phase,
+    elapsed_ms: u64,
+    // What the transfer phase is about to copy
+    #[serde(skip_serializing_if = "Option::is_none")]
+    files: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes: Option<u64>,
+}
+
+pub struct Phases {
+    status: ProgressBar,
+    events: Option<Mutex<File>>,
+    transfer_id: String,
+    started: Instant,
+    current: Mutex<Option<Phase>>,
+    // Checksums are checked after the data is written
+    verify: bool,
+    // Files whose data is still being written, and whether they all started
+    writing: AtomicUsize,
+    all_started: AtomicBool,
+}
+
+impl Phases {
+    /// Add the status line to `m`, writing events to `events` if given
+    pub fn new(m: &MultiProgress, transfer_id: &str, events: Option<&Path>, verify: bool) -> Result<Arc<Self>> {
+        let events = match events {
+            Some(path) => Some(Mutex::new(
+                File::create(path).with_context(|| format!("Cannot create events file {}", path.display()))?,
+            )),
+            None => None,
+        };
+        let status = m.add(ProgressBar::new_spinner());
+        status.set_style(ProgressStyle::with_template("{spinner} {msg}").unwrap());
+        status.enable_steady_tick(Duration::from_millis(100));
+        Ok(Arc::new(Phases {
+            status,
+            events,
+            transfer_id: transfer_id.to_string(),
+            started: Instant::now(),
+            current: Mutex::new(None),
+            verify,
+            writing: AtomicUsize::new(0),
+            all_started: AtomicBool::new(false),
+        }))
+    }
+
+    // Move on to `phase`; phases only go forward, entering one again or an
+    // earlier one does nothing
+    fn enter(&self, phase: Phase, files: Option<usize>, bytes: Option<u64>) -> bool {
+        let mut current = self.current.lock().unwrap();
+        if current.is_some_and(|current| current >= phase) {
+            return false;
+        }
+        *current = Some(phase);
+        if let Some(events) = &self.events {
+            let event = Event {
+                transfer_id: &self.transfer_id,
+                phase,
+                elapsed_ms: self.started.elapsed().as_millis() as u64,
+                files,
+                bytes,
+            };
+            let line = serde_json::to_string(&event).unwrap();
+            if let Err(e) = writeln!(events.lock().unwrap(), "{}", line) {
+                eprintln!("Error: cannot write events: {}", e);
+            }
+        }
+        true
+    }
+
+    pub fn scan(&self) {
+        if self.enter(Phase::Scan, None, None) {
+            self.status.set_message("🔍 Scanning");
+        }
+    }
+
+    /// `files` found so far by the scan
+    pub fn scanned(&self, files: usize) {
+        self.status.set_message(format!("🔍 Scanning: {} files found", files));
+    }
+
+    pub fn transfer(&self, files: usize, bytes: u64) {
+        if self.enter(Phase::Transfer, Some(files), Some(bytes)) {
+            self.status.set_message(format!("🚀 Transferring {} files ({})", files, HumanBytes(bytes)));
+        }
+    }
+
+    /// A file starts to be written; the verify phase begins once every file
+    /// has been started and their guards dropped
+    pub fn writing(self: &Arc<Self>) -> Writing {
+        self.writing.fetch_add(1, Ordering::SeqCst);
+        Writing(self.clone())
+    }
+
+    /// No more files will be started
+    pub fn all_started(&self) {
+        self.all_started.store(true, Ordering::SeqCst);
+        self.check_written();
+    }
+
+    fn check_written(&self) {
+        if self.verify && self.all_started.load(Ordering::SeqCst) && self.writing.load(Ordering::SeqCst) == 0
+            && self.enter(Phase::Verify, None, None) {
+            self.status.set_message("🔎 Verifying checksums");
+        }
+    }
+
+    pub fn finalize(&self) {
+        if self.enter(Phase::Finalize, None, None) {
+            self.status.set_message("📝 Finalizing");
+        }
+    }
+
+    pub fn finish(&self) {
+        self.status.finish_and_clear();
+    }
+}
+
+/// A file being written, see `Phases::writing`
+pub struct Writing(Arc<Phases>);
+
+impl Drop for Writing {
+    fn drop(&mut self) {
+        self.0.writing.fetch_sub(1, Ordering::SeqCst);
+        self.0.check_written();
+    }
+}
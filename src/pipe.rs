@@ -0,0 +1,128 @@
+// Read-ahead between the source and the destination of one transfer.
+//
+// A thread reads the source into a bounded channel while the transfer writes
+// what comes out of it, so reading overlaps with writing. When the destination
+// is slower the channel fills up and the reading thread blocks: a slow
+// destination holds back source reads instead of letting data pile up in
+// memory, however many transfers run at once.
+
+use std::io::{self, Read};
+use std::sync::mpsc::{self, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+
+/// Size of the chunks passed from the reading thread
+pub const CHUNK: usize = 256 * 1024;
+
+/// Default read-ahead per transfer, in bytes
+pub const READ_AHEAD: u64 = 1 << 20;
+
+/// Limits how many sources are read at once (--disk-workers), independently
+/// of how many transfers are on the network
+pub struct DiskSlots {
+    free: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl DiskSlots {
+    pub fn new(slots: usize) -> Arc<Self> {
+        Arc::new(DiskSlots { free: Mutex::new(slots.max(1)), freed: Condvar::new() })
+    }
+
+    // Wait for a free slot, held until the returned guard is dropped
+    fn acquire(self: &Arc<Self>) -> DiskSlot {
+        let mut free = self.freed.wait_while(self.free.lock().unwrap(), |free| *free == 0).unwrap();
+        *free -= 1;
+        DiskSlot(self.clone())
+    }
+}
+
+struct DiskSlot(Arc<DiskSlots>);
+
+impl Drop for DiskSlot {
+    fn drop(&mut self) {
+        *self.0.free.lock().unwrap() += 1;
+        self.0.freed.notify_one();
+    }
+}
+
+/// Read `input` through a read-ahead of about `read_ahead` bytes (a whole
+/// number of chunks, at least one), or directly when it is 0. With `slots`,
+/// the source is only read while holding one of them.
+pub fn reader<R: Read + Send + 'static>(input: R, read_ahead: u64, slots: Option<Arc<DiskSlots>>) -> Box<dyn Read + Send> {
+    if read_ahead == 0 {
+        let slot = slots.map(|slots| slots.acquire());
+        return Box::new(Held { inner: input, _slot: slot });
+    }
+    let depth = (read_ahead as usize).div_ceil(CHUNK).max(1);
+    Box::new(ReadAhead::new(input, depth, slots))
+}
+
+// A source read inline, with the disk slot it holds
+struct Held<R> {
+    inner: R,
+    _slot: Option<DiskSlot>,
+}
+
+impl<R: Read> Read for Held<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+struct ReadAhead {
+    chunks: Receiver<io::Result<Vec<u8>>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl ReadAhead {
+    fn new<R: Read + Send + 'static>(mut input: R, depth: usize, slots: Option<Arc<DiskSlots>>) -> Self {
+        let (tx, chunks) = mpsc::sync_channel(depth);
+        std::thread::spawn(move || {
+            let _slot = slots.map(|slots| slots.acquire());
+            read_chunks(&mut input, &tx);
+        });
+        ReadAhead { chunks, chunk: vec![], pos: 0 }
+    }
+}
+
+// Send `input` down the channel in chunks until it ends, fails, or nobody listens
+fn read_chunks(input: &mut impl Read, tx: &mpsc::SyncSender<io::Result<Vec<u8>>>) {
+    loop {
+        let mut chunk = vec![0; CHUNK];
+        match input.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => {
+                chunk.truncate(n);
+                // Blocks while the channel is full; fails once the writer gave up
+                if tx.send(Ok(chunk)).is_err() {
+                    break;
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => {
+                let _ = tx.send(Err(e));
+                break;
+            }
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.chunk.len() {
+            match self.chunks.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk?;
+                    self.pos = 0;
+                }
+                // The reading thread is done and everything was handed out
+                Err(_) => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.pos);
+        buf[..n].copy_from_slice(&self.chunk[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
@@ -0,0 +1,227 @@
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::dirfd::{self, Stat};
+use crate::rawpath;
+use crate::utils;
+
+const PLAN_VERSION: u32 = 1;
+
+// How often progress is written back to the plan while applying it
+const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Files found under a root, keyed by their path relative to it. Sorted, so
+/// plans list their entries in a stable order.
+pub type Listing = BTreeMap<PathBuf, Stat>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Copy,
+    Delete,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct PlanEntry {
+    pub action: Action,
+    // Relative to the parent of the source, like the paths sent by a normal run
+    #[serde(with = "rawpath")]
+    pub path: PathBuf,
+    pub size: u64,
+    // Copies run one batch at a time, in increasing order
+    #[serde(default)]
+    pub batch: usize,
+    #[serde(default)]
+    pub done: bool,
+}
+
+/// A migration computed up front by `cpx plan` and carried out by `cpx apply`,
+/// possibly over several sessions. Entries are executed in file order: all
+/// copies first, then deletes, so nothing is removed before the new data is in
+/// place. Finished entries are marked `done` in the file as they complete.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Plan {
+    pub version: u32,
+    pub created: u64,
+    #[serde(with = "rawpath")]
+    pub source: PathBuf,
+    pub destination: String,
+    // Set when the copies were split with --batch-size
+    #[serde(default)]
+    pub batch_size: Option<u64>,
+    pub entries: Vec<PlanEntry>,
+    #[serde(skip)]
+    file: PathBuf,
+    #[serde(skip)]
+    index: HashMap<PathBuf, usize>,
+    #[serde(skip)]
+    saved: Option<Instant>,
+}
+
+impl Plan {
+    /// Compare the two listings: files missing on the destination, with a
+    /// different size, or older than the source are copied; with `delete`,
+    /// files only present on the destination are removed.
+    pub fn new(source: &Path, destination: &str, src: &Listing, dest: &Listing, delete: bool) -> Self {
+        let mut entries = vec![];
+        for (path, stat) in src {
+            let stale = dest
+                .get(path)
+                .is_none_or(|old| old.size != stat.size || old.mtime < stat.mtime);
+            if stale {
+                entries.push(PlanEntry { action: Action::Copy, path: path.clone(), size: stat.size, batch: 0, done: false });
+            }
+        }
+        if delete {
+            for (path, stat) in dest {
+                if !src.contains_key(path) {
+                    entries.push(PlanEntry { action: Action::Delete, path: path.clone(), size: stat.size, batch: 0, done: false });
+                }
+            }
+        }
+        Plan {
+            version: PLAN_VERSION,
+            created: utils::now_secs(),
+            source: source.to_path_buf(),
+            destination: destination.to_string(),
+            batch_size: None,
+            entries,
+            file: PathBuf::new(),
+            index: HashMap::new(),
+            saved: None,
+        }
+    }
+
+    pub fn load(file: &Path) -> anyhow::Result<Self> {
+        let data = fs::read(file).with_context(|| format!("Cannot read plan {}", file.display()))?;
+        let mut plan: Plan = serde_json::from_slice(&data)
+            .with_context(|| format!("Invalid plan {}", file.display()))?;
+        if plan.version != PLAN_VERSION {
+            anyhow::bail!("Plan {} has unsupported version {}", file.display(), plan.version);
+        }
+        // Plans can be edited by hand, never let one reach outside the destination
+        let mut escaping = vec![];
+        for entry in &mut plan.entries {
+            match utils::contained_path(&entry.path) {
+                Some(path) => entry.path = path,
+                None => escaping.push(entry.path.display().to_string()),
+            }
+        }
+        if !escaping.is_empty() {
+            anyhow::bail!("Plan {} has entries outside the destination: {}", file.display(), escaping.join(", "));
+        }
+        plan.file = file.to_path_buf();
+        plan.index = plan.entries.iter().enumerate()
+            .filter(|(_, entry)| entry.action == Action::Copy)
+            .map(|(i, entry)| (entry.path.clone(), i))
+            .collect();
+        Ok(plan)
+    }
+
+    /// Write the plan to `file` (or where it was loaded from), replacing it
+    /// atomically so an interrupted run never leaves a truncated plan
+    pub fn save(&mut self, file: Option<&Path>) -> anyhow::Result<()> {
+        if let Some(file) = file {
+            self.file = file.to_path_buf();
+        }
+        let tmp = self.file.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(&tmp, &self.file)
+            .with_context(|| format!("Cannot write plan {}", self.file.display()))?;
+        self.saved = Some(Instant::now());
+        Ok(())
+    }
+
+    /// Save if the last save is older than the checkpoint interval
+    pub fn checkpoint(&mut self) -> anyhow::Result<()> {
+        if self.saved.is_none_or(|saved| saved.elapsed() >= CHECKPOINT_INTERVAL) {
+            self.save(None)?;
+        }
+        Ok(())
+    }
+
+    /// Group the copies, in plan order, into batches of at most `batch_size`
+    /// bytes; a file larger than that gets a batch of its own
+    pub fn split_batches(&mut self, batch_size: u64) {
+        let mut batch = 0;
+        let mut filled = 0;
+        for entry in self.entries.iter_mut().filter(|entry| entry.action == Action::Copy) {
+            if filled > 0 && filled + entry.size > batch_size {
+                batch += 1;
+                filled = 0;
+            }
+            entry.batch = batch;
+            filled += entry.size;
+        }
+        self.batch_size = Some(batch_size);
+    }
+
+    pub fn batch_count(&self) -> usize {
+        self.entries.iter().map(|entry| entry.batch + 1).max().unwrap_or(0)
+    }
+
+    /// Batches that still have copies to run, in order
+    pub fn pending_batches(&self) -> Vec<usize> {
+        let mut batches = self.pending(Action::Copy).map(|(_, entry)| entry.batch).collect::<Vec<_>>();
+        batches.dedup();
+        batches
+    }
+
+    /// Entries of the given kind that still have to run, in plan order
+    pub fn pending(&self, action: Action) -> impl Iterator<Item = (usize, &PlanEntry)> {
+        self.entries.iter().enumerate().filter(move |(_, entry)| entry.action == action && !entry.done)
+    }
+
+    pub fn mark_copied(&mut self, path: &Path) {
+        if let Some(&i) = self.index.get(path) {
+            self.entries[i].done = true;
+        }
+    }
+
+    pub fn print(&self) {
+        let count = |action| self.entries.iter().filter(move |entry| entry.action == action);
+        let copies = count(Action::Copy).count();
+        let bytes: u64 = count(Action::Copy).map(|entry| entry.size).sum();
+        let deletes = count(Action::Delete).count();
+        let done = self.entries.iter().filter(|entry| entry.done).count();
+        if self.batch_size.is_some() {
+            let pending = self.pending_batches().len();
+            println!("📦 {} batches, {} still to run", self.batch_count(), pending);
+        }
+        println!(
+            "📋 {} -> {}: {} files to copy ({} bytes), {} to delete, {} of {} entries done",
+            self.source.display(),
+            self.destination,
+            copies,
+            bytes,
+            deletes,
+            done,
+            self.entries.len()
+        );
+    }
+}
+
+/// List the regular files under `top`, keyed relative to `root`. Unreadable
+/// entries are returned as errors and left out of the listing.
+pub fn scan_local(root: &Path, top: &Path) -> (Listing, Vec<String>) {
+    let mut listing = Listing::new();
+    let mut errors = vec![];
+    if !top.exists() {
+        return (listing, errors);
+    }
+    for entry in dirfd::walk(top) {
+        match entry {
+            Ok(entry) if entry.is_file() => {
+                let path = entry.path().strip_prefix(root).unwrap().to_path_buf();
+                listing.insert(path, entry.stat());
+            }
+            Ok(_) => {}
+            Err(e) => errors.push(e.to_string()),
+        }
+    }
+    (listing, errors)
+}
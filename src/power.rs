@@ -0,0 +1,95 @@
+// --efficiency: long personal backups from a laptop, where finishing fast
+// matters less than battery and fan noise. Fewer transfers run at once and
+// nothing is read ahead (see `Args`); data is copied through large buffers
+// kept by each worker from file to file, so the CPU wakes up and allocates
+// less often; and transfers pause while the machine is saving power (a
+// low-power platform profile, or a nearly empty battery that is discharging)
+// and resume when it stops.
+
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+/// Buffer per local transfer; SSH transfers size theirs to the link
+pub const BUFFER: usize = 8192;
+// Smallest buffer in efficiency mode
+const EFFICIENT_BUFFER: usize = 256 * 1024;
+
+// Battery level under which a discharging laptop counts as saving power
+#[cfg(target_os = "linux")]
+const LOW_BATTERY: u32 = 20;
+
+static EFFICIENT: AtomicBool = AtomicBool::new(false);
+static SAVING: AtomicBool = AtomicBool::new(false);
+// A pause was announced, by whichever transfer noticed it first
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    static BUFFERS: RefCell<Vec<u8>> = const { RefCell::new(vec![]) };
+}
+
+/// Turn on efficiency mode for the run, watching for battery saving
+pub fn efficiency() {
+    EFFICIENT.store(true, Ordering::Relaxed);
+    std::thread::spawn(|| loop {
+        SAVING.store(saving_power(), Ordering::Relaxed);
+        std::thread::sleep(Duration::from_secs(10));
+    });
+}
+
+/// Run `f` with a copy buffer of `size` bytes, the one this thread keeps in
+/// efficiency mode
+pub fn with_buffer<T>(size: usize, f: impl FnOnce(&mut [u8]) -> T) -> T {
+    if !EFFICIENT.load(Ordering::Relaxed) {
+        return f(&mut vec![0; size]);
+    }
+    let size = size.max(EFFICIENT_BUFFER);
+    BUFFERS.with(|buffer| match buffer.try_borrow_mut() {
+        Ok(mut buffer) => {
+            buffer.resize(size, 0);
+            f(&mut buffer)
+        }
+        // Already in use further up this thread
+        Err(_) => f(&mut vec![0; size]),
+    })
+}
+
+/// Block while the machine is saving power, in efficiency mode
+pub fn wait() {
+    if !SAVING.load(Ordering::Relaxed) {
+        return;
+    }
+    if !PAUSED.swap(true, Ordering::Relaxed) {
+        println!("⏸️  Paused while the system saves power");
+    }
+    while SAVING.load(Ordering::Relaxed) {
+        std::thread::sleep(Duration::from_secs(1));
+    }
+    if PAUSED.swap(false, Ordering::Relaxed) {
+        println!("▶️  Resumed");
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn saving_power() -> bool {
+    use std::fs;
+    use std::path::Path;
+
+    let read = |path: &Path| fs::read_to_string(path).map(|text| text.trim().to_string()).unwrap_or_default();
+    if read(Path::new("/sys/firmware/acpi/platform_profile")) == "low-power" {
+        return true;
+    }
+    let Ok(supplies) = fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    supplies.flatten().map(|supply| supply.path()).any(|supply| {
+        read(&supply.join("type")) == "Battery"
+            && read(&supply.join("status")) == "Discharging"
+            && read(&supply.join("capacity")).parse::<u32>().is_ok_and(|level| level <= LOW_BATTERY)
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn saving_power() -> bool {
+    false
+}
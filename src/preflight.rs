@@ -0,0 +1,36 @@
+use std::path::Path;
+
+/// Check that a local destination root exists, is a directory and is
+/// writable by the effective user, so a bad destination fails once up front
+/// instead of once per file.
+pub fn check_local_dest(dest_root: &Path) -> anyhow::Result<()> {
+    let meta = match std::fs::metadata(dest_root) {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            anyhow::bail!("Destination {} does not exist (use --mkpath to create it)", dest_root.display());
+        }
+        Err(e) => anyhow::bail!("Cannot access destination {}: {}", dest_root.display(), e),
+    };
+    if !meta.is_dir() {
+        anyhow::bail!("Destination {} is not a directory", dest_root.display());
+    }
+    if !writable(dest_root, &meta) {
+        anyhow::bail!("Destination {} is not writable", dest_root.display());
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn writable(dir: &Path, _meta: &std::fs::Metadata) -> bool {
+    use std::os::unix::ffi::OsStrExt;
+    let Ok(path) = std::ffi::CString::new(dir.as_os_str().as_bytes()) else {
+        return false;
+    };
+    // AT_EACCESS checks against the effective rather than the real user
+    unsafe { libc::faccessat(libc::AT_FDCWD, path.as_ptr(), libc::W_OK | libc::X_OK, libc::AT_EACCESS) == 0 }
+}
+
+#[cfg(not(unix))]
+fn writable(_dir: &Path, meta: &std::fs::Metadata) -> bool {
+    !meta.permissions().readonly()
+}
@@ -0,0 +1,175 @@
+// Progress bar styles whose rate and ETA are averaged over a sliding window.
+//
+// indicatif's own estimate reacts to every refresh, so on bursty links the
+// per-file ETA swings between seconds and hours. The trackers here keep the
+// position samples of the last few seconds and derive both from those. The
+// run as a whole gets one more bar, which follows the per-file ones.
+
+use indicatif::style::ProgressTracker;
+use indicatif::{HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressState, ProgressStyle};
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::utils;
+
+/// Default length of the rate window, in seconds
+pub const RATE_WINDOW: u64 = 5;
+
+#[derive(Debug, Clone, Copy)]
+enum Show {
+    Rate,
+    Eta,
+}
+
+#[derive(Debug, Clone)]
+struct WindowTracker {
+    window: Duration,
+    show: Show,
+    // (time, position) at each refresh, oldest first
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl WindowTracker {
+    fn rate(&self) -> Option<f64> {
+        let (first, last) = (self.samples.front()?, self.samples.back()?);
+        let secs = last.0.duration_since(first.0).as_secs_f64();
+        (secs > 0.0).then(|| last.1.saturating_sub(first.1) as f64 / secs)
+    }
+}
+
+impl ProgressTracker for WindowTracker {
+    fn clone_box(&self) -> Box<dyn ProgressTracker> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, state: &ProgressState, now: Instant) {
+        self.samples.push_back((now, state.pos()));
+        // Keep one sample from before the window so it is always fully covered
+        while self.samples.len() > 2 && now.duration_since(self.samples[1].0) >= self.window {
+            self.samples.pop_front();
+        }
+    }
+
+    fn reset(&mut self, _: &ProgressState, _: Instant) {
+        self.samples.clear();
+    }
+
+    fn write(&self, state: &ProgressState, w: &mut dyn fmt::Write) {
+        let _ = match (self.show, self.rate()) {
+            (Show::Rate, Some(rate)) => write!(w, "{}/s", HumanBytes(rate as u64)),
+            (Show::Eta, Some(rate)) if rate > 0.0 => {
+                let remaining = state.len().unwrap_or(0).saturating_sub(state.pos());
+                write!(w, "{:#}", HumanDuration(Duration::from_secs_f64(remaining as f64 / rate)))
+            }
+            _ => write!(w, "-"),
+        };
+    }
+}
+
+/// Style for a per-file bar, with `{bytes_per_sec}` and `{eta}` computed over
+/// the last `window` instead of by indicatif
+pub fn file_style(template: &str, window: Duration) -> ProgressStyle {
+    let tracker = |show| WindowTracker { window, show, samples: VecDeque::new() };
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars("=>-")
+        .with_key("bytes_per_sec", tracker(Show::Rate))
+        .with_key("eta", tracker(Show::Eta))
+}
+
+/// The bar for the whole run. Its length is the bytes the run still expects
+/// to copy: files held back or failed give theirs back as soon as that is
+/// known, and a file whose size changed by the time it was opened counts
+/// with the new one. That keeps the total ETA about what is really left
+/// rather than about the scan.
+#[derive(Clone)]
+pub struct Overall {
+    bar: ProgressBar,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    // Bytes of the files not started yet
+    queued: u64,
+    // Bytes of the files copied
+    done: u64,
+    next_id: u64,
+    // Running files: their bar and scanned size
+    running: HashMap<u64, (ProgressBar, u64)>,
+}
+
+impl Overall {
+    /// Add the bar to `m` for files totalling `bytes`
+    pub fn new(m: &MultiProgress, bytes: u64, window: Duration) -> Self {
+        let bar = m.add(ProgressBar::new(bytes));
+        bar.set_style(file_style("{msg} {bar:40} {bytes}/{total_bytes} {bytes_per_sec} ({eta})", window));
+        bar.set_message(utils::align_str("total", 20));
+        let state = Arc::new(Mutex::new(State { queued: bytes, ..Default::default() }));
+        // The per-file bars move on their own, follow them until the run ends
+        let (follow, weak) = (bar.clone(), Arc::downgrade(&state));
+        std::thread::spawn(move || {
+            while !follow.is_finished() && let Some(state) = weak.upgrade() {
+                update(&follow, &state.lock().unwrap());
+                drop(state);
+                std::thread::sleep(Duration::from_millis(200));
+            }
+        });
+        Overall { bar, state }
+    }
+
+    /// Take a file of `size` bytes out of the total, it will not be copied
+    pub fn skip(&self, size: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.queued = state.queued.saturating_sub(size);
+        update(&self.bar, &state);
+    }
+
+    /// Count `pb` towards the total while the file of `size` bytes runs. The
+    /// file is taken out again if the guard is dropped without `done(true)`.
+    pub fn start(&self, pb: &ProgressBar, size: u64) -> Counted {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.queued = state.queued.saturating_sub(size);
+        state.running.insert(id, (pb.clone(), size));
+        Counted { overall: self.clone(), id, ok: false }
+    }
+
+    pub fn finish(&self) {
+        self.bar.finish_and_clear();
+    }
+}
+
+fn update(bar: &ProgressBar, state: &State) {
+    let running = state.running.values();
+    let length = running.clone().map(|(pb, size)| pb.length().unwrap_or(*size)).sum::<u64>();
+    let position = running.map(|(pb, _)| pb.position()).sum::<u64>();
+    bar.set_length(state.queued + state.done + length);
+    bar.set_position(state.done + position);
+}
+
+/// A running file of an `Overall`
+pub struct Counted {
+    overall: Overall,
+    id: u64,
+    ok: bool,
+}
+
+impl Counted {
+    pub fn done(mut self, ok: bool) {
+        self.ok = ok;
+    }
+}
+
+impl Drop for Counted {
+    fn drop(&mut self) {
+        let mut state = self.overall.state.lock().unwrap();
+        if let Some((pb, size)) = state.running.remove(&self.id) && self.ok {
+            state.done += pb.length().unwrap_or(size);
+        }
+        update(&self.overall.bar, &state);
+    }
+}
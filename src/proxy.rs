@@ -0,0 +1,249 @@
+// Reaching hosts that no direct TCP connection gets to.
+//
+// A ProxyCommand is a command whose stdin and stdout carry the SSH stream
+// (`ssh -W %h:%p gateway`, a corporate connector). As OpenSSH does, the
+// command gets one end of a socket pair as its stdin and stdout and the
+// session the other, since libssh2 only talks to sockets.
+//
+// --proxy goes through a SOCKS5 or HTTP proxy instead: the connection is made
+// to the proxy, which is asked to open a tunnel to the host (SOCKS5 CONNECT
+// or HTTP CONNECT) before the session starts on it. The host name is passed
+// to the proxy unresolved, the proxy may be the only one able to resolve it.
+
+use anyhow::{Context, Result};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD;
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{IpAddr, TcpStream};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Socks5,
+    Http,
+}
+
+/// A proxy given as socks5://[user:password@]host[:port] or
+/// http://[user:password@]host[:port]
+#[derive(Debug, Clone)]
+pub struct Proxy {
+    kind: Kind,
+    host: String,
+    port: u16,
+    credentials: Option<(String, String)>,
+}
+
+impl fmt::Display for Proxy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.kind {
+            Kind::Socks5 => "socks5",
+            Kind::Http => "http",
+        };
+        write!(f, "{}://{}:{}", scheme, self.host, self.port)
+    }
+}
+
+/// Parse a proxy URL; the port defaults to 1080 for SOCKS5 and 8080 for HTTP
+pub fn parse(spec: &str) -> Result<Proxy, String> {
+    let (scheme, rest) = spec.split_once("://").ok_or_else(|| format!("expected socks5://host:port or http://host:port: {}", spec))?;
+    let kind = match scheme.to_ascii_lowercase().as_str() {
+        "socks5" | "socks5h" => Kind::Socks5,
+        "http" => Kind::Http,
+        _ => return Err(format!("unsupported proxy type {}, use socks5 or http", scheme)),
+    };
+    let rest = rest.trim_end_matches('/');
+    let (credentials, address) = match rest.rsplit_once('@') {
+        Some((userinfo, address)) => match userinfo.split_once(':') {
+            Some((user, password)) => (Some((user.to_string(), password.to_string())), address),
+            None => (Some((userinfo.to_string(), String::new())), address),
+        },
+        None => (None, rest),
+    };
+    // [v6 address]:port, host:port or host
+    let (host, port) = match address.strip_prefix('[').and_then(|rest| rest.split_once(']')) {
+        Some((host, port)) => (host, port.strip_prefix(':')),
+        None => match address.rsplit_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (address, None),
+        },
+    };
+    if host.is_empty() {
+        return Err(format!("no proxy host in {}", spec));
+    }
+    let port = match port {
+        Some(port) => port.parse().map_err(|_| format!("invalid proxy port: {}", port))?,
+        None if kind == Kind::Socks5 => 1080,
+        None => 8080,
+    };
+    Ok(Proxy { kind, host: host.to_string(), port, credentials })
+}
+
+impl Proxy {
+    /// A connection to `host`:`port` tunnelled through the proxy
+    pub fn connect(&self, host: &str, port: u16) -> Result<TcpStream> {
+        let mut stream = TcpStream::connect((self.host.as_str(), self.port))
+            .with_context(|| format!("cannot connect to proxy {}", self))?;
+        match self.kind {
+            Kind::Socks5 => self.socks5(&mut stream, host, port),
+            Kind::Http => self.http(&mut stream, host, port),
+        }
+        .with_context(|| format!("proxy {} cannot reach {}:{}", self, host, port))?;
+        Ok(stream)
+    }
+
+    fn socks5(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        // Offer no authentication, and username/password when we have one
+        let methods: &[u8] = if self.credentials.is_some() { &[0, 2] } else { &[0] };
+        stream.write_all(&[&[5, methods.len() as u8], methods].concat())?;
+        let mut reply = [0u8; 2];
+        stream.read_exact(&mut reply)?;
+        match (reply, &self.credentials) {
+            ([5, 0], _) => {}
+            ([5, 2], Some((user, password))) => {
+                if user.len() > 255 || password.len() > 255 {
+                    anyhow::bail!("SOCKS5 user name and password are limited to 255 bytes");
+                }
+                let mut request = vec![1, user.len() as u8];
+                request.extend_from_slice(user.as_bytes());
+                request.push(password.len() as u8);
+                request.extend_from_slice(password.as_bytes());
+                stream.write_all(&request)?;
+                stream.read_exact(&mut reply)?;
+                if reply[1] != 0 {
+                    anyhow::bail!("SOCKS5 proxy rejected the user name and password");
+                }
+            }
+            ([5, _], _) => anyhow::bail!("SOCKS5 proxy accepts none of our authentication methods"),
+            _ => anyhow::bail!("not a SOCKS5 proxy"),
+        }
+
+        let mut request = vec![5, 1, 0];
+        match host.parse::<IpAddr>() {
+            Ok(IpAddr::V4(ip)) => {
+                request.push(1);
+                request.extend_from_slice(&ip.octets());
+            }
+            Ok(IpAddr::V6(ip)) => {
+                request.push(4);
+                request.extend_from_slice(&ip.octets());
+            }
+            Err(_) if host.len() <= 255 => {
+                request.extend_from_slice(&[3, host.len() as u8]);
+                request.extend_from_slice(host.as_bytes());
+            }
+            Err(_) => anyhow::bail!("host name too long for SOCKS5"),
+        }
+        request.extend_from_slice(&port.to_be_bytes());
+        stream.write_all(&request)?;
+
+        let mut reply = [0u8; 4];
+        stream.read_exact(&mut reply)?;
+        if reply[1] != 0 {
+            anyhow::bail!("SOCKS5 proxy refused the connection: {}", match reply[1] {
+                1 => "general failure",
+                2 => "not allowed by the proxy's rules",
+                3 => "network unreachable",
+                4 => "host unreachable",
+                5 => "connection refused",
+                6 => "TTL expired",
+                7 => "command not supported",
+                8 => "address type not supported",
+                _ => "unknown error",
+            });
+        }
+        // The address the proxy bound, of no use here
+        let len = match reply[3] {
+            1 => 4,
+            4 => 16,
+            3 => {
+                let mut len = [0u8];
+                stream.read_exact(&mut len)?;
+                len[0] as usize
+            }
+            _ => anyhow::bail!("malformed SOCKS5 reply"),
+        };
+        stream.read_exact(&mut vec![0; len + 2])?;
+        Ok(())
+    }
+
+    fn http(&self, stream: &mut TcpStream, host: &str, port: u16) -> Result<()> {
+        let target = match host.parse::<IpAddr>() {
+            Ok(IpAddr::V6(_)) => format!("[{}]:{}", host, port),
+            _ => format!("{}:{}", host, port),
+        };
+        let mut request = format!("CONNECT {0} HTTP/1.1\r\nHost: {0}\r\n", target);
+        if let Some((user, password)) = &self.credentials {
+            let token = STANDARD.encode(format!("{}:{}", user, password));
+            request.push_str(&format!("Proxy-Authorization: Basic {}\r\n", token));
+        }
+        request.push_str("\r\n");
+        stream.write_all(request.as_bytes())?;
+
+        // Read the response headers byte by byte, what follows them is the
+        // SSH server's and must stay in the socket
+        let mut response = vec![];
+        while !response.ends_with(b"\r\n\r\n") {
+            if response.len() > 16 << 10 {
+                anyhow::bail!("HTTP proxy response headers too long");
+            }
+            let mut byte = [0u8];
+            if stream.read(&mut byte)? == 0 {
+                anyhow::bail!("HTTP proxy closed the connection");
+            }
+            response.push(byte[0]);
+        }
+        let response = String::from_utf8_lossy(&response);
+        let status = response.lines().next().unwrap_or("");
+        match status.split_whitespace().nth(1) {
+            Some(code) if code.starts_with('2') => Ok(()),
+            _ => anyhow::bail!("HTTP proxy answered: {}", status),
+        }
+    }
+}
+
+/// The command with its tokens replaced: %h the host name connected to, %p
+/// the port, %r the user, %n the host as given and %% a percent sign
+pub fn expand(command: &str, address: &str, port: u16, user: &str, host: &str) -> String {
+    let mut expanded = String::new();
+    let mut chars = command.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('h')) => expanded.push_str(address),
+            ('%', Some('p')) => expanded.push_str(&port.to_string()),
+            ('%', Some('r')) => expanded.push_str(user),
+            ('%', Some('n')) => expanded.push_str(host),
+            ('%', Some('%')) => expanded.push('%'),
+            _ => {
+                expanded.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    expanded
+}
+
+/// Start `command` through the shell and return the session's end of its
+/// stream. The command ends when the session closes its end.
+#[cfg(unix)]
+pub fn connect(command: &str) -> Result<std::os::unix::net::UnixStream> {
+    use std::os::unix::net::UnixStream;
+    use std::process::{Command, Stdio};
+
+    let (ours, theirs) = UnixStream::pair()?;
+    let mut child = Command::new("sh")
+        .arg("-c")
+        .arg(format!("exec {}", command))
+        .stdin(Stdio::from(std::os::fd::OwnedFd::from(theirs.try_clone()?)))
+        .stdout(Stdio::from(std::os::fd::OwnedFd::from(theirs)))
+        .spawn()
+        .with_context(|| format!("Cannot start proxy command: {}", command))?;
+    // Reap it once it exits
+    std::thread::spawn(move || child.wait());
+    Ok(ours)
+}
+
+#[cfg(not(unix))]
+pub fn connect(_command: &str) -> Result<std::net::TcpStream> {
+    anyhow::bail!("ProxyCommand is only supported on Unix")
+}
@@ -0,0 +1,225 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use crate::dirfd;
+use crate::journal;
+use crate::utils;
+
+/// How much of an unchanged source tree --prune-unchanged leaves out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Level {
+    /// Every directory is still read and its files stat'd, but the files of an
+    /// unchanged directory are not sent
+    Files,
+    /// A directory with no entry added, removed or renamed since the last run
+    /// is not read at all; only the subdirectories it had are entered. Files
+    /// rewritten in place are missed
+    Dirs,
+    /// Like dirs, but the directories to read are those the filesystem's change
+    /// journal (USN on NTFS, FSEvents on macOS) has any change in, files
+    /// rewritten in place included. The whole tree is read when the journal
+    /// no longer goes back to the last run
+    Journal,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Fingerprint {
+    hash: u64,
+    // Directories directly inside, entered without reading a pruned directory
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    subdirs: Vec<PathBuf>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct Saved {
+    // Where the change journal was read up to, at the `journal` level
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    journal: Option<journal::Cursor>,
+    dirs: HashMap<PathBuf, Fingerprint>,
+}
+
+/// Per-directory fingerprints from the previous run of the same
+/// source/destination pair. At the `files` level a directory's fingerprint
+/// covers its own mtime and the name, size and mtime of every file directly
+/// inside it, so files in a directory whose fingerprint has not changed do not
+/// need to be sent again. At the `dirs` level it is the directory's own
+/// identity and change times, and the walk does not read it at all. At the
+/// `journal` level a directory the change journal has no change in is not
+/// read. Changes made on the destination side are not detected.
+pub struct DirFingerprints {
+    level: Level,
+    path: Option<PathBuf>,
+    previous: Arc<HashMap<PathBuf, Fingerprint>>,
+    // What the journal has changed since the last run, at the `journal` level
+    changes: Option<Arc<journal::Changes>>,
+    current: Saved,
+    unchanged: HashSet<PathBuf>,
+}
+
+impl DirFingerprints {
+    /// The fingerprints of copying `source` to `destination`, which names
+    /// every destination the files actually went to
+    pub fn load(source: &Path, destination: &str, level: Level) -> Self {
+        let source = fs::canonicalize(source).unwrap_or_else(|_| source.to_path_buf());
+        let mut key = utils::fnv1a(destination.as_bytes(), utils::fnv1a(source.as_os_str().as_encoded_bytes(), 0));
+        match level {
+            Level::Files => {}
+            Level::Dirs => key = utils::fnv1a(b"dirs", key),
+            Level::Journal => key = utils::fnv1a(b"journal", key),
+        }
+        let path = utils::cache_dir()
+            .map(|dir| dir.join("fingerprints").join(format!("{:016x}.json", key)));
+        let previous: Saved = path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|data| {
+                // Fingerprints were saved bare before the journal cursor was kept
+                serde_json::from_slice(&data).ok()
+                    .or_else(|| Some(Saved { journal: None, dirs: serde_json::from_slice(&data).ok()? }))
+            })
+            .unwrap_or_default();
+        let (cursor, changes) = match level {
+            Level::Journal => read_journal(&source, previous.journal.as_ref()),
+            _ => (None, None),
+        };
+        DirFingerprints {
+            level,
+            path,
+            previous: Arc::new(previous.dirs),
+            changes: changes.map(Arc::new),
+            current: Saved { journal: cursor, dirs: HashMap::new() },
+            unchanged: HashSet::new(),
+        }
+    }
+
+    /// The callback that keeps a walk of `src_root` out of directories that
+    /// are unchanged, at the `dirs` level
+    pub fn known(&self, src_root: &Path) -> Option<dirfd::Known> {
+        let changes = match self.level {
+            Level::Files => return None,
+            Level::Dirs => None,
+            // Without the journal every directory is read
+            Level::Journal => Some(self.changes.clone()?),
+        };
+        let previous = self.previous.clone();
+        let src_root = src_root.to_path_buf();
+        Some(Box::new(move |path: &Path, id: u64| {
+            let rel = path.strip_prefix(&src_root).ok()?;
+            let fingerprint = previous.get(rel)?;
+            let unchanged = match &changes {
+                Some(changes) => !changes.changed(rel),
+                None => fingerprint.hash == id,
+            };
+            unchanged.then(|| fingerprint.subdirs.iter().map(|name| name.clone().into_os_string()).collect())
+        }))
+    }
+
+    /// Fingerprint a directory (`rel` is relative to the source root) and
+    /// remember whether it matches the previous run.
+    pub fn visit_dir(&mut self, rel: &Path, entry: &dirfd::Entry) {
+        let hash = match self.level {
+            Level::Files => fingerprint_dir(entry.path()),
+            Level::Dirs => entry.id(),
+            // Only the journal tells a directory is unchanged, the id is kept for show
+            Level::Journal => Some(entry.id().unwrap_or(0)),
+        };
+        let Some(hash) = hash else {
+            return;
+        };
+        if entry.pruned()
+            || (self.level != Level::Journal && self.previous.get(rel).is_some_and(|previous| previous.hash == hash)) {
+            self.unchanged.insert(rel.to_path_buf());
+        }
+        // Subdirectories add themselves as they are entered
+        self.current.dirs.insert(rel.to_path_buf(), Fingerprint { hash, subdirs: vec![] });
+        if self.level != Level::Files
+            && let (Some(parent), Some(name)) = (rel.parent(), rel.file_name())
+            && let Some(parent) = self.current.dirs.get_mut(parent)
+        {
+            parent.subdirs.push(PathBuf::from(name));
+        }
+    }
+
+    /// Whether the file's directory is unchanged since the last successful run
+    pub fn is_unchanged(&self, rel_file: &Path) -> bool {
+        rel_file.parent().is_some_and(|dir| self.unchanged.contains(dir))
+    }
+
+    /// Forget the fingerprint of a directory whose files did not all make it,
+    /// so they are retried next time
+    pub fn mark_failed(&mut self, rel_file: &Path) {
+        if let Some(dir) = rel_file.parent() {
+            self.current.dirs.remove(dir);
+        }
+    }
+
+    pub fn save(&self) -> anyhow::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec(&self.current)?)?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+// Where the change journal of `source` is now, and what it changed since
+// `since`; a source without a readable journal is walked whole every time
+fn read_journal(source: &Path, since: Option<&journal::Cursor>) -> (Option<journal::Cursor>, Option<journal::Changes>) {
+    let cursor = match journal::cursor(source) {
+        Ok(cursor) => cursor,
+        Err(e) => {
+            eprintln!("⚠️  {:#}, reading every directory", e);
+            return (None, None);
+        }
+    };
+    let changes = match since.map(|since| journal::changes(source, since)) {
+        Some(Ok(Some(changes))) => Some(changes),
+        Some(Ok(None)) => {
+            eprintln!("⚠️  The change journal of {} no longer goes back to the last run, reading every directory", source.display());
+            None
+        }
+        Some(Err(e)) => {
+            eprintln!("⚠️  {:#}, reading every directory", e);
+            None
+        }
+        None => None,
+    };
+    (Some(cursor), changes)
+}
+
+fn fingerprint_dir(dir: &Path) -> Option<u64> {
+    let mut hash = utils::fnv1a(&mtime_nanos(&fs::metadata(dir).ok()?).to_le_bytes(), 0);
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).ok()? {
+        let entry = entry.ok()?;
+        let meta = entry.metadata().ok()?;
+        if meta.is_file() {
+            files.push((entry.file_name(), meta.len(), mtime_nanos(&meta)));
+        }
+    }
+    // read_dir order is unspecified, sort so the rollup is stable
+    files.sort();
+    for (name, size, mtime) in files {
+        hash = utils::fnv1a(name.as_encoded_bytes(), hash);
+        hash = utils::fnv1a(&size.to_le_bytes(), hash);
+        hash = utils::fnv1a(&mtime.to_le_bytes(), hash);
+    }
+    Some(hash)
+}
+
+fn mtime_nanos(meta: &fs::Metadata) -> u128 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0)
+}
@@ -0,0 +1,122 @@
+use anyhow::Context;
+use indicatif::HumanBytes;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::utils;
+
+const QUOTA_FILE: &str = "quota.json";
+
+/// Enforces `--quota` for one run. The bytes written to each destination are
+/// kept in the cache directory and carried over from run to run, so the
+/// quota covers everything cpx has written there, not only this invocation.
+/// A run that fails or stops early still adds what it wrote, when the quota
+/// is dropped. Remove the ledger file to start counting again.
+pub struct Quota {
+    limit: u64,
+    path: Option<PathBuf>,
+    // Written by earlier runs, per destination
+    used: HashMap<String, u64>,
+    // Queued and written by this run
+    queued: HashMap<String, u64>,
+    written: HashMap<String, u64>,
+    // Destination and size of the first file that did not fit
+    exceeded: Option<(String, u64)>,
+    // This run's bytes are in the ledger
+    saved: bool,
+}
+
+impl Quota {
+    pub fn new(limit: u64) -> Self {
+        let path = utils::cache_dir().map(|dir| dir.join(QUOTA_FILE));
+        let used = load(path.as_ref());
+        Quota {
+            limit,
+            path,
+            used,
+            queued: HashMap::new(),
+            written: HashMap::new(),
+            exceeded: None,
+            saved: false,
+        }
+    }
+
+    /// Reserve `size` bytes on every destination in `keys`, or on none of
+    /// them when it would take one over the quota. Once a file has been
+    /// refused every later one is too, so the run stops at that point.
+    pub fn admit(&mut self, keys: &[String], size: u64) -> bool {
+        if self.exceeded.is_some() {
+            return false;
+        }
+        let full = keys.iter().find(|key| {
+            let used = self.used.get(*key).copied().unwrap_or(0);
+            let queued = self.queued.get(*key).copied().unwrap_or(0);
+            used + queued + size > self.limit
+        });
+        if let Some(key) = full {
+            self.exceeded = Some((key.clone(), size));
+            return false;
+        }
+        for key in keys {
+            *self.queued.entry(key.clone()).or_default() += size;
+        }
+        true
+    }
+
+    /// Count bytes that actually reached a destination
+    pub fn record(&mut self, key: &str, bytes: u64) {
+        *self.written.entry(key.to_string()).or_default() += bytes;
+    }
+
+    /// Add this run's bytes to the ledger, then fail if files were held back
+    pub fn finish(&mut self) -> anyhow::Result<()> {
+        self.save()?;
+        if let Some((key, size)) = &self.exceeded {
+            let used = self.used.get(key).copied().unwrap_or(0) + self.written.get(key).copied().unwrap_or(0);
+            anyhow::bail!(
+                "Quota of {} for {} reached: {} written so far, the next file needs {} more; stopped before it",
+                HumanBytes(self.limit),
+                key,
+                HumanBytes(used),
+                HumanBytes(*size)
+            );
+        }
+        Ok(())
+    }
+
+    fn save(&mut self) -> anyhow::Result<()> {
+        if self.saved {
+            return Ok(());
+        }
+        self.saved = true;
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        if self.written.is_empty() {
+            return Ok(());
+        }
+        // Added to the ledger as it is now, runs against the same destination
+        // may have written to it since this one started
+        utils::update_state(path, |ledger: &mut HashMap<String, u64>| {
+            for (key, bytes) in &self.written {
+                *ledger.entry(key.clone()).or_default() += bytes;
+            }
+        })
+        .with_context(|| format!("Cannot update quota ledger {}", path.display()))
+    }
+}
+
+impl Drop for Quota {
+    fn drop(&mut self) {
+        if let Err(e) = self.save() {
+            eprintln!("Error: {:#}", e);
+        }
+    }
+}
+
+fn load(path: Option<&PathBuf>) -> HashMap<String, u64> {
+    path.and_then(|path| fs::read(path).ok())
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
@@ -0,0 +1,45 @@
+// Paths in plans, manifests, inventories and reports. A JSON string must be
+// valid UTF-8 and file names on old servers often are not (Latin-1 names), so
+// such a path is written as the array of its raw bytes instead. UTF-8 paths
+// stay plain strings and files written before read as they did.
+//
+// Use with `#[serde(with = "rawpath")]` on a path field.
+
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::path::{Path, PathBuf};
+
+use crate::utils;
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Raw<'a> {
+    Text(std::borrow::Cow<'a, str>),
+    Bytes(Vec<u8>),
+}
+
+fn raw(path: &Path) -> Raw<'_> {
+    match path.to_str() {
+        Some(text) => Raw::Text(text.into()),
+        None => Raw::Bytes(path.as_os_str().as_encoded_bytes().to_vec()),
+    }
+}
+
+pub fn serialize<S: Serializer>(path: &Path, serializer: S) -> Result<S::Ok, S::Error> {
+    raw(path).serialize(serializer)
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<PathBuf, D::Error> {
+    Ok(match Raw::deserialize(deserializer)? {
+        Raw::Text(text) => PathBuf::from(text.into_owned()),
+        Raw::Bytes(bytes) => utils::path_from_bytes(&bytes),
+    })
+}
+
+/// The same for a list of paths
+pub mod list {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(paths: &[PathBuf], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| raw(path)))
+    }
+}
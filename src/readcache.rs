@@ -0,0 +1,89 @@
+// Reading a source once for several destinations. With --also every file goes
+// to each host, and each of those transfers would read it from disk again; the
+// first to open a file reads it into memory and the others are sent from
+// there, until the last of them has opened it. What is held at once is
+// bounded by --read-cache: a file that does not fit in what is left is read
+// from disk by every transfer, as without the cache.
+
+use std::collections::HashMap;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::source::{self, Source};
+
+/// Default for --read-cache
+pub const BUDGET: u64 = 256 << 20;
+
+pub struct ReadCache {
+    budget: u64,
+    // Transfers that open each file
+    readers: usize,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    used: u64,
+    files: HashMap<PathBuf, Arc<Entry>>,
+}
+
+// A file's data and permission bits
+type Contents = (Arc<[u8]>, Option<u32>);
+
+#[derive(Default)]
+struct Entry {
+    // Set by the first transfer to open the file; None when it did not fit
+    // or could not be read, and is read from disk by each
+    data: OnceLock<Option<Contents>>,
+    // Transfers that have not opened it yet
+    left: AtomicUsize,
+}
+
+impl ReadCache {
+    /// A cache of `budget` bytes for files opened by `readers` transfers each
+    pub fn new(budget: u64, readers: usize) -> Self {
+        ReadCache { budget, readers, state: Mutex::new(State::default()) }
+    }
+
+    /// Open `rel` (relative to `root`) like `source::open`, from memory for
+    /// all but the first transfer to send it
+    pub fn open(&self, root: &Path, rel: &Path, grown: source::Grown) -> io::Result<Source> {
+        let key = root.join(rel);
+        let entry = self.state.lock().unwrap().files.entry(key.clone())
+            .or_insert_with(|| Arc::new(Entry { left: AtomicUsize::new(self.readers), ..Entry::default() }))
+            .clone();
+        // Others opening the file wait here while the first one reads it
+        let data = entry.data.get_or_init(|| self.load(root, rel, grown)).clone();
+        if entry.left.fetch_sub(1, Ordering::Relaxed) == 1 {
+            let mut state = self.state.lock().unwrap();
+            state.files.remove(&key);
+            if let Some((data, _)) = &data {
+                state.used -= data.len() as u64;
+            }
+        }
+        match data {
+            Some((data, mode)) => Ok(Source::shared(data, mode)),
+            None => source::open(root, rel, grown),
+        }
+    }
+
+    // Read the whole file if it fits in what is left of the budget
+    fn load(&self, root: &Path, rel: &Path, grown: source::Grown) -> Option<Contents> {
+        let mut input = source::open(root, rel, grown).ok()?;
+        {
+            let mut state = self.state.lock().unwrap();
+            if state.used + input.size > self.budget {
+                return None;
+            }
+            state.used += input.size;
+        }
+        let mut data = Vec::with_capacity(input.size as usize);
+        if input.read_to_end(&mut data).is_err() {
+            self.state.lock().unwrap().used -= input.size;
+            return None;
+        }
+        Some((data.into(), input.mode))
+    }
+}
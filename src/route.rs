@@ -0,0 +1,91 @@
+use std::str::FromStr;
+
+use crate::dirfd::Stat;
+use crate::utils;
+
+/// What a `--route` rule matches on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    // Last modified more than this many seconds ago
+    Older(u64),
+    // Last modified at most this many seconds ago
+    Newer(u64),
+    // Size in bytes
+    Larger(u64),
+    Smaller(u64),
+}
+
+/// `--route 'CONDITION => DEST'`: files matching the condition are sent to
+/// DEST instead of the main destination. Rules are tried in order, the first
+/// match wins.
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub condition: Condition,
+    pub destination: String,
+}
+
+impl Route {
+    pub fn matches(&self, stat: &Stat, now: u64) -> bool {
+        let age = now.saturating_sub(stat.mtime);
+        match self.condition {
+            Condition::Older(secs) => age > secs,
+            Condition::Newer(secs) => age <= secs,
+            Condition::Larger(size) => stat.size > size,
+            Condition::Smaller(size) => stat.size < size,
+        }
+    }
+}
+
+impl FromStr for Route {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let Some((condition, destination)) = s.split_once("=>") else {
+            return Err(format!("expected 'CONDITION => DEST': {}", s));
+        };
+        let destination = destination.trim();
+        if destination.is_empty() {
+            return Err(format!("missing destination: {}", s));
+        }
+        if destination.contains("://") {
+            return Err(format!("only local and SSH destinations are supported: {}", destination));
+        }
+        let condition = condition.trim();
+        let condition = if let Some(size) = condition.strip_prefix("size>") {
+            Condition::Larger(utils::parse_size(size)?)
+        } else if let Some(size) = condition.strip_prefix("size<") {
+            Condition::Smaller(utils::parse_size(size)?)
+        } else if let Some(age) = condition.strip_prefix("older_than=") {
+            Condition::Older(parse_age(age)?)
+        } else if let Some(age) = condition.strip_prefix("newer_than=") {
+            Condition::Newer(parse_age(age)?)
+        } else {
+            return Err(format!("unknown condition {}, expected older_than=AGE, newer_than=AGE, size>SIZE or size<SIZE", condition));
+        };
+        Ok(Route { condition, destination: destination.to_string() })
+    }
+}
+
+// Parse an age such as "90d", "12h", "30m", "45s" or "2w"
+fn parse_age(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits.parse().map_err(|_| format!("invalid age: {}", s))?;
+    let multiplier: u64 = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        "w" => 7 * 24 * 60 * 60,
+        _ => return Err(format!("invalid age unit: {}", s)),
+    };
+    value.checked_mul(multiplier).ok_or_else(|| format!("age too large: {}", s))
+}
+
+/// Destination for a file: that of the first matching route, else `default`
+pub fn pick<'a>(routes: &'a [Route], default: &'a str, stat: &Stat, now: u64) -> &'a str {
+    routes.iter()
+        .find(|route| route.matches(stat, now))
+        .map_or(default, |route| route.destination.as_str())
+}
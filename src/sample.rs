@@ -0,0 +1,100 @@
+// --verify-sample: after a copy, hash a random sample of the copied files at
+// the source and at each destination and compare them. For jobs too large to
+// verify every file this still bounds how many bad copies could go unnoticed.
+
+use rayon::prelude::*;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::backend::Backend;
+use crate::hash::{self, Algorithm, Digest};
+use crate::throttle::{Throttle, Throttled};
+use crate::utils;
+
+/// One side of a copy
+pub struct Endpoint {
+    pub label: String,
+    pub backend: Arc<dyn Backend>,
+    // A single file copied under another name (`cpx a.txt dest/b.txt`)
+    pub rename: Option<PathBuf>,
+}
+
+impl Endpoint {
+    fn hash(&self, path: &Path, algorithm: Algorithm, read_limit: Option<Arc<Throttle>>) -> anyhow::Result<Digest> {
+        let input = self.backend.open(self.rename.as_deref().unwrap_or(path))?;
+        Ok(hash::hash_reader(algorithm, Throttled::new(input, read_limit))?)
+    }
+}
+
+/// Pick `fraction` of `files`, rounded up so at least one is checked. The
+/// choice depends on `seed`, so every run samples different files.
+pub fn pick(files: Vec<PathBuf>, fraction: f64, seed: &str) -> Vec<PathBuf> {
+    let count = ((files.len() as f64 * fraction).ceil() as usize).min(files.len());
+    let seed = utils::fnv1a(seed.as_bytes(), 0);
+    let mut keyed = files.into_iter()
+        .map(|path| (utils::fnv1a(path.as_os_str().as_encoded_bytes(), seed), path))
+        .collect::<Vec<_>>();
+    keyed.sort_unstable();
+    keyed.into_iter().take(count).map(|(_, path)| path).collect()
+}
+
+/// Compare the sampled files at the source with their copies at every
+/// destination, on `threads` threads. Fails when any copy differs or cannot be
+/// read; `total` is the number of files the sample was drawn from.
+pub fn verify(
+    source: &Endpoint,
+    destinations: &[Endpoint],
+    sample: &[PathBuf],
+    total: usize,
+    algorithm: Algorithm,
+    threads: usize,
+    read_limit: Option<Arc<Throttle>>,
+) -> anyhow::Result<()> {
+    println!("🔬 Verifying a sample of {} of {} files...", sample.len(), total);
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|i| format!("cpx-sample-{}", i))
+        .build()?;
+    let failed = pool.install(|| {
+        sample.par_iter()
+            .map(|path| {
+                let expected = match source.hash(path, algorithm, read_limit.clone()) {
+                    Ok(digest) => digest,
+                    Err(e) => {
+                        eprintln!("❌ {}: {:#}", path.display(), e);
+                        return destinations.len();
+                    }
+                };
+                destinations.iter()
+                    .filter(|dest| match dest.hash(path, algorithm, None) {
+                        Ok(digest) if digest == expected => false,
+                        Ok(digest) => {
+                            eprintln!("❌ {} on {} differs from the source (source {}, copy {})", path.display(), dest.label, expected, digest);
+                            true
+                        }
+                        Err(e) => {
+                            eprintln!("❌ {} on {}: {:#}", path.display(), dest.label, e);
+                            true
+                        }
+                    })
+                    .count()
+            })
+            .sum::<usize>()
+    });
+    if failed > 0 {
+        anyhow::bail!("{} sampled copies differ from the source or could not be checked", failed);
+    }
+    if sample.len() == total {
+        println!("✅ All {} files match the source", total);
+    } else {
+        // With k clean draws, more than 1 - 0.05^(1/k) of the files being bad
+        // would have shown up in the sample at least 95% of the time
+        let bound = 1.0 - 0.05f64.powf(1.0 / sample.len() as f64);
+        println!(
+            "✅ Sample of {} files matches the source; at most {:.2}% of the files are likely bad (95% confidence)",
+            sample.len(),
+            bound * 100.0
+        );
+    }
+    Ok(())
+}
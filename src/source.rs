@@ -0,0 +1,96 @@
+// Opening a local file for copying.
+//
+// The size found by the scan can be stale by the time the file is opened, so
+// the size to send is taken again from the open file and the copy is held to
+// it: scp announces the size before the data, and a destination that gets
+// more or less than announced is corrupt. A file that grows while it is read
+// (a log being written) is cut at the size it had when opened, or fails with
+// --grown-files fail; a file that shrinks fails. Files on kernel pseudo
+// filesystems (/proc, /sys) report sizes unrelated to their contents, so they
+// are read whole first and sent with the length that was read.
+
+use clap::ValueEnum;
+use std::fs::File;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+use std::sync::Arc;
+
+use crate::dirfd;
+
+// Largest pseudo-file read into memory; they are generated text, a bigger
+// one (/proc/kcore) is not something to copy
+const PSEUDO_LIMIT: u64 = 16 << 20;
+
+/// What to do with a file that grows while it is being copied
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum Grown {
+    /// Copy the size it had when opened, later data is left for the next run
+    #[default]
+    Truncate,
+    /// Fail the file
+    Fail,
+}
+
+/// A source file, read up to the size it is sent with
+pub struct Source {
+    pub size: u64,
+    // Permission bits, as `dirfd::file_mode` reports them
+    pub mode: Option<u32>,
+    inner: Inner,
+}
+
+enum Inner {
+    File { file: File, remaining: u64, grown: Grown },
+    Pseudo(Cursor<Vec<u8>>),
+    // Read once for several transfers, see `readcache`
+    Shared(Cursor<Arc<[u8]>>),
+}
+
+/// Open `rel` (relative to `root`) for copying
+pub fn open(root: &Path, rel: &Path, grown: Grown) -> io::Result<Source> {
+    let mut file = dirfd::open_beneath(root, rel)?;
+    let mode = dirfd::file_mode(&file)?;
+    if dirfd::is_pseudo_fs(&file) {
+        let mut data = vec![];
+        (&mut file).take(PSEUDO_LIMIT + 1).read_to_end(&mut data)?;
+        if data.len() as u64 > PSEUDO_LIMIT {
+            return Err(io::Error::other(format!("pseudo-file larger than {} bytes", PSEUDO_LIMIT)));
+        }
+        return Ok(Source { size: data.len() as u64, mode, inner: Inner::Pseudo(Cursor::new(data)) });
+    }
+    let size = file.metadata()?.len();
+    Ok(Source { size, mode, inner: Inner::File { file, remaining: size, grown } })
+}
+
+impl Source {
+    /// A file already read into memory, sent with the length that was read
+    pub fn shared(data: Arc<[u8]>, mode: Option<u32>) -> Self {
+        Source { size: data.len() as u64, mode, inner: Inner::Shared(Cursor::new(data)) }
+    }
+}
+
+impl Read for Source {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (file, remaining, grown) = match &mut self.inner {
+            Inner::Pseudo(data) => return data.read(buf),
+            Inner::Shared(data) => return data.read(buf),
+            Inner::File { file, remaining, grown } => (file, remaining, *grown),
+        };
+        if *remaining == 0 {
+            if grown == Grown::Fail && file.read(&mut [0])? > 0 {
+                return Err(io::Error::other("file grew while being copied (--grown-files fail)"));
+            }
+            return Ok(0);
+        }
+        let len = buf.len().min(usize::try_from(*remaining).unwrap_or(usize::MAX));
+        let n = file.read(&mut buf[..len])?;
+        if n == 0 && len > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("file shrank while being copied, {} bytes short", remaining),
+            ));
+        }
+        *remaining -= n as u64;
+        Ok(n)
+    }
+}
@@ -0,0 +1,115 @@
+// Settings read from the OpenSSH client configuration (~/.ssh/config), so a
+// host alias that `ssh` knows reaches the same machine, as the same user, on
+// the same port and with the same key when given to cpx. Only HostName, User,
+// Port, IdentityFile, ProxyCommand, AddKeysToAgent and PKCS11Provider are
+// read, from Host blocks; Match blocks are skipped.
+
+use std::fs;
+use std::path::PathBuf;
+
+use crate::glob;
+
+/// What the configuration says about one host. As with ssh the first value
+/// found for a setting is the one that applies, except for IdentityFile,
+/// whose files are all tried in order.
+#[derive(Debug, Default)]
+pub struct HostConfig {
+    pub hostname: Option<String>,
+    pub user: Option<String>,
+    pub port: Option<u16>,
+    pub identity_files: Vec<PathBuf>,
+    // Unexpanded, its tokens refer to the connection
+    pub proxy_command: Option<String>,
+    // Whether an unlocked key goes to ssh-agent; "ask", "confirm" and a
+    // lifetime count as yes
+    pub add_keys_to_agent: Option<bool>,
+    // "none" turns off a provider set for a wider pattern
+    pub pkcs11_provider: Option<String>,
+}
+
+pub fn home_dir() -> Option<PathBuf> {
+    std::env::var("HOME").or_else(|_err| std::env::var("USERPROFILE")).ok().map(PathBuf::from)
+}
+
+/// The settings for `host` (the name as given, before any HostName)
+pub fn lookup(host: &str) -> HostConfig {
+    let Some(home) = home_dir() else {
+        return HostConfig::default();
+    };
+    match fs::read_to_string(home.join(".ssh").join("config")) {
+        Ok(text) => parse(&text, host, &home),
+        Err(_) => HostConfig::default(),
+    }
+}
+
+fn parse(text: &str, host: &str, home: &std::path::Path) -> HostConfig {
+    let mut config = HostConfig::default();
+    // Lines before the first Host apply to every host
+    let mut applies = true;
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let Some((keyword, value)) = line.split_once(|c: char| c.is_whitespace() || c == '=') else {
+            continue;
+        };
+        let value = value.trim_start_matches(|c: char| c.is_whitespace() || c == '=').trim();
+        let value = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')).unwrap_or(value);
+        match keyword.to_ascii_lowercase().as_str() {
+            "host" => applies = host_matches(value, host),
+            "match" => applies = false,
+            _ if !applies => {}
+            "hostname" if config.hostname.is_none() => config.hostname = Some(expand(value, host, home)),
+            "user" if config.user.is_none() => config.user = Some(value.to_string()),
+            "port" if config.port.is_none() => config.port = value.parse().ok(),
+            "proxycommand" if config.proxy_command.is_none() => config.proxy_command = Some(value.to_string()),
+            "pkcs11provider" if config.pkcs11_provider.is_none() => config.pkcs11_provider = Some(expand(value, host, home)),
+            "addkeystoagent" if config.add_keys_to_agent.is_none() => {
+                config.add_keys_to_agent = Some(!value.eq_ignore_ascii_case("no"));
+            }
+            "identityfile" => {
+                let file = expand(value, host, home);
+                config.identity_files.push(match file.strip_prefix("~/") {
+                    Some(rest) => home.join(rest),
+                    None => PathBuf::from(file),
+                });
+            }
+            _ => {}
+        }
+    }
+    config
+}
+
+// Replace the %h (host as given), %d (home directory) and %% tokens
+fn expand(value: &str, host: &str, home: &std::path::Path) -> String {
+    let mut expanded = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('%', Some('h')) => expanded.push_str(host),
+            ('%', Some('d')) => expanded.push_str(&home.to_string_lossy()),
+            ('%', Some('%')) => expanded.push('%'),
+            _ => {
+                expanded.push(c);
+                continue;
+            }
+        }
+        chars.next();
+    }
+    expanded
+}
+
+// Whether `host` matches a Host line: any of its patterns matches and none of
+// its negated (!pattern) ones does
+fn host_matches(patterns: &str, host: &str) -> bool {
+    let mut matched = false;
+    for pattern in patterns.split_whitespace() {
+        match pattern.strip_prefix('!') {
+            Some(negated) if glob::match_text(negated, host) => return false,
+            Some(_) => {}
+            None => matched |= glob::match_text(pattern, host),
+        }
+    }
+    matched
+}
@@ -0,0 +1,51 @@
+// --delay-updates: every file is written to a hidden directory beside the one
+// it belongs in and renamed into place only once all transfers of the run are
+// done, so the destination shows each directory with its old files or with the
+// new ones, not with whichever had arrived while the copy ran. The renames stay
+// within each directory's filesystem and take moments for the whole tree. A
+// run where a transfer failed moves nothing and leaves what it staged behind.
+
+use anyhow::{Context, Result};
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+
+use crate::backend::Backend;
+
+/// Name of the staging directory, in the directory of the files it holds
+pub const DIR: &str = ".cpx-delayed";
+
+/// Where the file `dest_path` is written until the run publishes it
+pub fn staged(dest_path: &Path) -> PathBuf {
+    let name = dest_path.file_name().unwrap_or(dest_path.as_os_str());
+    dest_path.parent().unwrap_or(Path::new("")).join(DIR).join(name)
+}
+
+/// Move the staged copies of `files` into place, then remove the staging
+/// directories; when `failed` transfers did not complete, leave them all
+pub fn publish(backend: &dyn Backend, files: &[PathBuf], failed: usize) -> Result<()> {
+    if failed > 0 {
+        println!(
+            "⚠️  {} transfer(s) failed, {} file(s) on {} stay staged in {} directories",
+            failed,
+            files.len(),
+            backend.describe(),
+            DIR
+        );
+        return Ok(());
+    }
+    println!("📦 Moving {} delayed file(s) into place on {}", files.len(), backend.describe());
+    let mut dirs = BTreeSet::new();
+    for file in files {
+        let staged = staged(file);
+        backend.rename(&staged, file)
+            .with_context(|| format!("Cannot move {} into place", file.display()))?;
+        if let Some(dir) = staged.parent() {
+            dirs.insert(dir.to_path_buf());
+        }
+    }
+    // Files left by an earlier failed run keep their directory
+    for dir in dirs {
+        let _ = backend.remove_dir(&dir);
+    }
+    Ok(())
+}
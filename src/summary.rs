@@ -0,0 +1,298 @@
+use indicatif::HumanBytes;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::rawpath;
+
+// Upper bounds of the file size buckets used for latency percentiles
+const SIZE_BUCKETS: [(u64, &str); 5] = [
+    (64 << 10, "<64KiB"),
+    (1 << 20, "64KiB-1MiB"),
+    (16 << 20, "1-16MiB"),
+    (256 << 20, "16-256MiB"),
+    (u64::MAX, ">=256MiB"),
+];
+
+/// Outcome of one file transfer, as returned by a worker
+#[derive(Debug)]
+pub struct FileResult {
+    pub path: PathBuf,
+    pub size: u64,
+    // Bytes that crossed the network, differs from `size` when compressing
+    pub wire_bytes: u64,
+    pub ok: bool,
+    // Not ok because the source was deleted after the scan found it, which is
+    // reported apart from failures (unless --fail-on-vanished)
+    pub vanished: bool,
+    // Setuid/setgid/sticky bits were dropped from the copy
+    pub stripped: bool,
+    // When the transfer started, relative to the start of the run
+    pub started: Duration,
+    pub elapsed: Duration,
+    // The SSH session that carried it (host#id), for compression statistics
+    pub session: Option<String>,
+}
+
+/// Bytes read and sent for one session or file type
+#[derive(Debug, Default, Serialize)]
+struct Compression {
+    name: String,
+    raw_bytes: u64,
+    sent_bytes: u64,
+    ratio: f64,
+}
+
+impl Compression {
+    fn line(&self) -> String {
+        format!("{}: {} -> {} ({:.2})", self.name, HumanBytes(self.raw_bytes), HumanBytes(self.sent_bytes), self.ratio)
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct LatencyBucket {
+    size: &'static str,
+    files: usize,
+    p50_ms: f64,
+    p95_ms: f64,
+    p99_ms: f64,
+}
+
+/// Totals reported at the end of a run
+#[derive(Debug, Serialize)]
+pub struct Summary {
+    pub transfer_id: String,
+    pub files: u64,
+    pub bytes: u64,
+    // Bytes that crossed the network, differs from `bytes` when compressing
+    pub bytes_sent: u64,
+    #[serde(serialize_with = "rawpath::list::serialize")]
+    pub failed: Vec<PathBuf>,
+    // Deleted at the source between the scan and their copy
+    #[serde(serialize_with = "rawpath::list::serialize")]
+    pub vanished: Vec<PathBuf>,
+    // Copied without their setuid/setgid/sticky bits
+    #[serde(serialize_with = "rawpath::list::serialize")]
+    pub stripped: Vec<PathBuf>,
+    pub walk_errors: Vec<String>,
+    // Span in seconds of the rates and ETAs shown while running
+    pub rate_window_secs: u64,
+    #[serde(skip)]
+    started: Instant,
+    // (size, start offset, duration) of every successful transfer
+    #[serde(skip)]
+    timings: Vec<(u64, Duration, Duration)>,
+    // (raw, sent) bytes by session and by file extension
+    #[serde(skip)]
+    by_session: BTreeMap<String, (u64, u64)>,
+    #[serde(skip)]
+    by_type: BTreeMap<String, (u64, u64)>,
+}
+
+#[derive(Serialize)]
+struct Report<'a> {
+    #[serde(flatten)]
+    summary: &'a Summary,
+    latency: Vec<LatencyBucket>,
+    // Bytes per second for each second of the run
+    throughput: Vec<u64>,
+    // With --compress, how well each session and file type compressed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<CompressionReport>,
+}
+
+#[derive(Serialize)]
+struct CompressionReport {
+    sessions: Vec<Compression>,
+    file_types: Vec<Compression>,
+}
+
+impl Summary {
+    pub fn new(transfer_id: &str, rate_window_secs: u64) -> Self {
+        Summary {
+            transfer_id: transfer_id.to_string(),
+            files: 0,
+            bytes: 0,
+            bytes_sent: 0,
+            failed: vec![],
+            vanished: vec![],
+            stripped: vec![],
+            walk_errors: vec![],
+            rate_window_secs,
+            started: Instant::now(),
+            timings: vec![],
+            by_session: BTreeMap::new(),
+            by_type: BTreeMap::new(),
+        }
+    }
+
+    /// Start of the run, workers report their timings relative to it
+    pub fn started(&self) -> Instant {
+        self.started
+    }
+
+    pub fn record(&mut self, result: FileResult) {
+        if result.ok {
+            self.files += 1;
+            self.bytes += result.size;
+            self.bytes_sent += result.wire_bytes;
+            self.timings.push((result.size, result.started, result.elapsed));
+            if let Some(session) = &result.session {
+                let file_type = match result.path.extension() {
+                    Some(ext) => format!(".{}", ext.to_string_lossy().to_lowercase()),
+                    None => "(no extension)".to_string(),
+                };
+                for totals in [self.by_session.entry(session.clone()).or_default(), self.by_type.entry(file_type).or_default()] {
+                    totals.0 += result.size;
+                    totals.1 += result.wire_bytes;
+                }
+            }
+            if result.stripped {
+                self.stripped.push(result.path);
+            }
+        } else if result.vanished {
+            self.vanished.push(result.path);
+        } else {
+            self.failed.push(result.path);
+        }
+    }
+
+    /// Put the lists of paths and errors in a stable order
+    pub fn sort(&mut self) {
+        self.failed.sort();
+        self.vanished.sort();
+        self.stripped.sort();
+        self.walk_errors.sort();
+    }
+
+    fn compressed(&self) -> bool {
+        self.bytes_sent != self.bytes && self.bytes_sent > 0
+    }
+
+    // Sessions by name, file types with the most data first
+    fn compression(&self) -> Option<CompressionReport> {
+        if !self.compressed() {
+            return None;
+        }
+        let stats = |totals: &BTreeMap<String, (u64, u64)>| totals.iter()
+            .map(|(name, &(raw_bytes, sent_bytes))| Compression {
+                name: name.clone(),
+                raw_bytes,
+                sent_bytes,
+                ratio: raw_bytes as f64 / sent_bytes.max(1) as f64,
+            })
+            .collect::<Vec<_>>();
+        let mut file_types = stats(&self.by_type);
+        file_types.sort_by_key(|file_type| std::cmp::Reverse(file_type.raw_bytes));
+        Some(CompressionReport { sessions: stats(&self.by_session), file_types })
+    }
+
+    fn latency(&self) -> Vec<LatencyBucket> {
+        let mut buckets = vec![vec![]; SIZE_BUCKETS.len()];
+        for (size, _, elapsed) in &self.timings {
+            let idx = SIZE_BUCKETS.iter().position(|(limit, _)| size < limit).unwrap_or(SIZE_BUCKETS.len() - 1);
+            buckets[idx].push(elapsed.as_secs_f64() * 1000.0);
+        }
+        buckets
+            .into_iter()
+            .zip(SIZE_BUCKETS)
+            .filter(|(samples, _)| !samples.is_empty())
+            .map(|(mut samples, (_, label))| {
+                samples.sort_by(f64::total_cmp);
+                let pct = |p: f64| samples[((samples.len() - 1) as f64 * p).round() as usize];
+                LatencyBucket {
+                    size: label,
+                    files: samples.len(),
+                    p50_ms: pct(0.50),
+                    p95_ms: pct(0.95),
+                    p99_ms: pct(0.99),
+                }
+            })
+            .collect()
+    }
+
+    // Spread each file's bytes evenly over the seconds it was in flight
+    fn throughput(&self) -> Vec<u64> {
+        let mut series = vec![0f64; self.started.elapsed().as_secs() as usize + 1];
+        for (size, started, elapsed) in &self.timings {
+            let start = started.as_secs_f64();
+            let end = start + elapsed.as_secs_f64().max(1e-6);
+            let rate = *size as f64 / (end - start);
+            let mut t = start;
+            while t < end {
+                let second = t.floor();
+                let next = (second + 1.0).min(end);
+                if let Some(slot) = series.get_mut(second as usize) {
+                    *slot += rate * (next - t);
+                }
+                t = next;
+            }
+        }
+        series.into_iter().map(|bytes| bytes.round() as u64).collect()
+    }
+
+    pub fn print(&self) {
+        println!("📊 {} files, {} bytes transferred (transfer {})", self.files, self.bytes, self.transfer_id);
+        if self.compressed() {
+            println!(
+                "   {} read, {} sent, compression ratio {:.2}",
+                HumanBytes(self.bytes),
+                HumanBytes(self.bytes_sent),
+                self.bytes as f64 / self.bytes_sent as f64
+            );
+        }
+        if let Some(compression) = self.compression() {
+            println!("   by session:");
+            for session in &compression.sessions {
+                println!("     {}", session.line());
+            }
+            println!("   by file type:");
+            for file_type in &compression.file_types {
+                println!("     {}", file_type.line());
+            }
+        }
+        for bucket in self.latency() {
+            println!(
+                "   {:>10}: {} files, latency p50 {:.1}ms p95 {:.1}ms p99 {:.1}ms",
+                bucket.size, bucket.files, bucket.p50_ms, bucket.p95_ms, bucket.p99_ms
+            );
+        }
+        if !self.failed.is_empty() {
+            println!("   {} files failed:", self.failed.len());
+            for path in &self.failed {
+                println!("     {}", path.display());
+            }
+        }
+        if !self.vanished.is_empty() {
+            println!("   {} files vanished before they could be copied:", self.vanished.len());
+            for path in &self.vanished {
+                println!("     {}", path.display());
+            }
+        }
+        if !self.stripped.is_empty() {
+            println!("   {} files copied without their setuid/setgid/sticky bits:", self.stripped.len());
+            for path in &self.stripped {
+                println!("     {}", path.display());
+            }
+        }
+        if !self.walk_errors.is_empty() {
+            println!("   {} entries could not be read while scanning:", self.walk_errors.len());
+            for e in &self.walk_errors {
+                println!("     {}", e);
+            }
+        }
+    }
+
+    /// Write the summary as JSON for scripts and later inspection
+    pub fn write_report(&self, path: &Path) -> anyhow::Result<()> {
+        let report = Report {
+            summary: self,
+            latency: self.latency(),
+            throughput: self.throughput(),
+            compression: self.compression(),
+        };
+        std::fs::write(path, serde_json::to_vec_pretty(&report)?)?;
+        Ok(())
+    }
+}
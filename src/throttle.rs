@@ -0,0 +1,60 @@
+use std::io::{self, Read};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Byte rate limit shared by every reader it is handed to, so the limit holds
+/// for the whole run however many files are being read at once
+pub struct Throttle {
+    bytes_per_sec: f64,
+    // When the bytes accounted so far will have been "paid for"
+    next: Mutex<Instant>,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_sec: u64) -> Self {
+        Throttle {
+            bytes_per_sec: bytes_per_sec.max(1) as f64,
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Account for `bytes` just read, sleeping while the run is ahead of the limit
+    pub fn consume(&self, bytes: usize) {
+        let delay = {
+            let mut next = self.next.lock().unwrap();
+            let now = Instant::now();
+            // Idle time earns no credit, a quiet period is not followed by a burst
+            *next = (*next).max(now) + Duration::from_secs_f64(bytes as f64 / self.bytes_per_sec);
+            next.saturating_duration_since(now)
+        };
+        if !delay.is_zero() {
+            std::thread::sleep(delay);
+        }
+    }
+}
+
+/// Reader that reports what it reads to an optional throttle
+pub struct Throttled<R> {
+    inner: R,
+    throttle: Option<Arc<Throttle>>,
+}
+
+impl<R> Throttled<R> {
+    pub fn new(inner: R, throttle: Option<Arc<Throttle>>) -> Self {
+        Throttled { inner, throttle }
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.inner
+    }
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(throttle) = &self.throttle {
+            throttle.consume(n);
+        }
+        Ok(n)
+    }
+}
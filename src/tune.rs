@@ -0,0 +1,87 @@
+// Write sizes for SSH transfers, tuned to the link.
+//
+// What a transfer hands libssh2 in one write is what it keeps in flight: an
+// SFTP write is split into 32 KiB packets that are all sent before the first
+// acknowledgement is awaited, so the write size is also the pipelining depth.
+// Too small and a long link idles waiting for acknowledgements, too large and
+// a LAN copy only grows its buffers. The round-trip time is taken from each
+// connection to the host and the throughput from the first seconds of data;
+// their product, the data the link holds, is the size used from then on by
+// transfers that start. --chunk-size sets it instead.
+
+use indicatif::HumanBytes;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+// Until the link is measured
+const INITIAL: usize = 64 * 1024;
+const MIN: usize = 32 * 1024;
+const MAX: usize = 4 * 1024 * 1024;
+// How long throughput is measured for
+const SAMPLE: Duration = Duration::from_secs(3);
+
+pub struct Tuner {
+    size: AtomicUsize,
+    // Set by --chunk-size, or once the measurement is done
+    settled: AtomicBool,
+    // Lowest round trip seen when connecting
+    rtt: Mutex<Option<Duration>>,
+    // When the first data was written, and how much since
+    first: Mutex<Option<Instant>>,
+    bytes: AtomicU64,
+    host: String,
+}
+
+impl Tuner {
+    /// Sizes for `host`, fixed to `chunk_size` if given
+    pub fn new(host: &str, chunk_size: Option<usize>) -> Self {
+        Tuner {
+            size: AtomicUsize::new(chunk_size.unwrap_or(INITIAL)),
+            settled: AtomicBool::new(chunk_size.is_some()),
+            rtt: Mutex::new(None),
+            first: Mutex::new(None),
+            bytes: AtomicU64::new(0),
+            host: host.to_string(),
+        }
+    }
+
+    /// Bytes to write at once
+    pub fn size(&self) -> usize {
+        self.size.load(Ordering::Relaxed)
+    }
+
+    /// A connection to the host took `rtt` to set up
+    pub fn round_trip(&self, rtt: Duration) {
+        let mut known = self.rtt.lock().unwrap();
+        *known = Some(known.map_or(rtt, |known| known.min(rtt)));
+    }
+
+    /// `n` bytes were written by a transfer
+    pub fn sent(&self, n: usize) {
+        if self.settled.load(Ordering::Relaxed) {
+            return;
+        }
+        let bytes = self.bytes.fetch_add(n as u64, Ordering::Relaxed) + n as u64;
+        let elapsed = {
+            let mut first = self.first.lock().unwrap();
+            first.get_or_insert_with(Instant::now).elapsed()
+        };
+        if elapsed < SAMPLE || self.settled.swap(true, Ordering::Relaxed) {
+            return;
+        }
+        let Some(rtt) = *self.rtt.lock().unwrap() else {
+            return;
+        };
+        let rate = bytes as f64 / elapsed.as_secs_f64();
+        let size = ((rate * rtt.as_secs_f64()) as usize).clamp(MIN, MAX).next_power_of_two();
+        self.size.store(size, Ordering::Relaxed);
+        println!(
+            "📐 {}: {:.1}ms round trip, {}/s, writing {} at a time",
+            self.host,
+            rtt.as_secs_f64() * 1000.0,
+            HumanBytes(rate as u64),
+            HumanBytes(size as u64)
+        );
+    }
+}
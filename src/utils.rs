@@ -1,4 +1,4 @@
-pub(crate) fn align_str(origin: &str, width: usize) -> String { 
+pub fn align_str(origin: &str, width: usize) -> String { 
     let last: String = origin.chars()
         .rev()
         .take(width)
@@ -8,4 +8,311 @@ pub(crate) fn align_str(origin: &str, width: usize) -> String {
         .collect::<String>();
     let padding = width - last.chars().count();
     format!("{}{}", " ".repeat(padding), last)
-}
\ No newline at end of file
+}
+
+// Directory for state kept between invocations (~/.cache/cpx or $XDG_CACHE_HOME/cpx)
+pub fn cache_dir() -> Option<std::path::PathBuf> {
+    let base = match std::env::var("XDG_CACHE_HOME") {
+        Ok(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => {
+            let home = std::env::var("HOME")
+                .or_else(|_err| std::env::var("USERPROFILE"))
+                .ok()?;
+            std::path::PathBuf::from(home).join(".cache")
+        }
+    };
+    Some(base.join("cpx"))
+}
+
+// Directory for user configuration (~/.config/cpx or $XDG_CONFIG_HOME/cpx)
+pub fn config_dir() -> Option<std::path::PathBuf> {
+    let base = match std::env::var("XDG_CONFIG_HOME") {
+        Ok(dir) if !dir.is_empty() => std::path::PathBuf::from(dir),
+        _ => {
+            let home = std::env::var("HOME")
+                .or_else(|_err| std::env::var("USERPROFILE"))
+                .ok()?;
+            std::path::PathBuf::from(home).join(".config")
+        }
+    };
+    Some(base.join("cpx"))
+}
+
+// Normalize a relative path taken from untrusted input (a batch, a plan, a
+// remote listing), or None when it would escape the root it is applied to
+pub fn contained_path(path: &std::path::Path) -> Option<std::path::PathBuf> {
+    use std::path::Component;
+    let mut normalized = std::path::PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => normalized.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !normalized.pop() {
+                    return None;
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+    (!normalized.as_os_str().is_empty()).then_some(normalized)
+}
+
+// Seconds since the unix epoch
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// Read a JSON state file of the cache directory, change it and write it back.
+// A lock file next to it is held throughout, so runs (or threads) updating it
+// at once do not lose each other's changes, and the new contents are renamed
+// into place, so a reader never sees a half-written file. A missing or
+// unreadable file starts from the default.
+pub fn update_state<T>(path: &std::path::Path, change: impl FnOnce(&mut T)) -> std::io::Result<()>
+where
+    T: Default + serde::Serialize + serde::de::DeserializeOwned,
+{
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let mut lock_name = path.as_os_str().to_owned();
+    lock_name.push(".lock");
+    let lock = std::fs::OpenOptions::new().create(true).truncate(false).write(true).open(&lock_name)?;
+    lock.lock()?;
+    let mut state = std::fs::read(path).ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default();
+    change(&mut state);
+    let mut tmp_name = path.as_os_str().to_owned();
+    tmp_name.push(".tmp");
+    std::fs::write(&tmp_name, serde_json::to_vec_pretty(&state)?)?;
+    std::fs::rename(&tmp_name, path)
+}
+
+// Expand {date} (UTC, YYYY-MM-DD), {hostname} and {user} in a destination,
+// so scheduled runs can write to a fresh directory without a shell wrapper
+pub fn expand_placeholders(destination: &str) -> String {
+    if !destination.contains('{') {
+        return destination.to_string();
+    }
+    let (year, month, day) = civil_date(now_secs() / 86400);
+    destination
+        .replace("{date}", &format!("{:04}-{:02}-{:02}", year, month, day))
+        .replace("{hostname}", &whoami::fallible::hostname().unwrap_or_else(|_| "localhost".to_string()))
+        .replace("{user}", &whoami::username())
+}
+
+// Year, month and day of a day count since the epoch (proleptic Gregorian)
+fn civil_date(days: u64) -> (i64, i64, i64) {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    (year, month, day)
+}
+
+/// setuid, setgid and sticky
+pub const SPECIAL_BITS: u32 = 0o7000;
+
+// Mode to give a copy of a file with `source` permissions (0o666 when the
+// platform has none, leaving it to the umask), and the source mode when
+// special bits had to be dropped from it
+pub fn dest_mode(source: Option<u32>, preserve_special: bool) -> (u32, Option<u32>) {
+    match source {
+        Some(mode) if preserve_special => (mode & 0o7777, None),
+        Some(mode) if mode & SPECIAL_BITS != 0 => (mode & 0o777, Some(mode)),
+        Some(mode) => (mode & 0o777, None),
+        None => (0o666, None),
+    }
+}
+
+pub fn warn_stripped(path: &std::path::Path, mode: u32) {
+    println!(
+        "⚠️  {}: dropped setuid/setgid/sticky bits (mode {:04o}, copied as {:04o}), use --preserve-special-bits to keep them",
+        path.display(), mode & 0o7777, mode & 0o777
+    );
+}
+
+// Parse a byte size such as "512", "64K", "4M" or "100G" (binary multiples)
+pub fn parse_size(s: &str) -> Result<u64, String> {
+    let s = s.trim();
+    let split = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let (digits, unit) = s.split_at(split);
+    let value: u64 = digits.parse().map_err(|_| format!("invalid size: {}", s))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit: {}", s)),
+    };
+    value.checked_mul(multiplier).ok_or_else(|| format!("size too large: {}", s))
+}
+
+// Parse a percentage such as "5%" or "0.5", returning it as a fraction
+pub fn parse_percent(s: &str) -> Result<f64, String> {
+    let value: f64 = s.trim().trim_end_matches('%').parse().map_err(|_| format!("invalid percentage: {}", s))?;
+    if !(value > 0.0 && value <= 100.0) {
+        return Err(format!("percentage must be above 0 and at most 100: {}", s));
+    }
+    Ok(value / 100.0)
+}
+
+// Parse "key=value", for object metadata and tags
+pub fn parse_pair(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((key, value)) if !key.is_empty() => Ok((key.to_string(), value.to_string())),
+        _ => Err(format!("expected KEY=VALUE: {}", s)),
+    }
+}
+
+// FNV-1a, used where a hash must stay stable across builds (state file names, fingerprints)
+pub fn fnv1a(data: &[u8], seed: u64) -> u64 {
+    let mut hash = if seed == 0 { 0xcbf29ce484222325 } else { seed };
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+// Why a file name needs care on the far side (a newline ends an scp header
+// and breaks line-based tools, terminals act on control characters), if it does
+pub fn name_problem(name: &std::ffi::OsStr) -> Option<&'static str> {
+    match name.to_str() {
+        None => Some("is not valid UTF-8"),
+        Some(name) if name.contains('\n') => Some("contains a newline"),
+        Some(name) if name.chars().any(char::is_control) => Some("contains control characters"),
+        Some(_) => None,
+    }
+}
+
+// The first problem with any component of `path`
+pub fn path_problem(path: &std::path::Path) -> Option<&'static str> {
+    path.components().find_map(|component| name_problem(component.as_os_str()))
+}
+
+// --sanitize-names: percent-encode the control characters, invalid UTF-8
+// bytes and '%' signs of every awkward component, leaving the others alone
+pub fn sanitize_path(path: &std::path::Path) -> std::path::PathBuf {
+    use std::fmt::Write;
+    path.components()
+        .map(|component| {
+            let name = component.as_os_str();
+            if name_problem(name).is_none() {
+                return name.to_os_string();
+            }
+            let mut encoded = String::new();
+            for chunk in name.as_encoded_bytes().utf8_chunks() {
+                for c in chunk.valid().chars() {
+                    if c.is_control() || c == '%' {
+                        for byte in c.encode_utf8(&mut [0; 4]).bytes() {
+                            let _ = write!(encoded, "%{:02X}", byte);
+                        }
+                    } else {
+                        encoded.push(c);
+                    }
+                }
+                for byte in chunk.invalid() {
+                    let _ = write!(encoded, "%{:02X}", byte);
+                }
+            }
+            encoded.into()
+        })
+        .collect()
+}
+
+// A path for progress bars and messages: never fails, and control
+// characters are shown escaped rather than sent to the terminal
+pub fn printable(path: &std::path::Path) -> String {
+    let mut shown = String::new();
+    for c in path.to_string_lossy().chars() {
+        if c.is_control() {
+            shown.extend(c.escape_default());
+        } else {
+            shown.push(c);
+        }
+    }
+    shown
+}
+
+// Run one file's transfer with a panic turned into an error for that file, so
+// the worker still gives back its connection and the run counts the failure
+pub fn catch_panic<T>(transfer: impl FnOnce() -> anyhow::Result<T>) -> anyhow::Result<T> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(transfer)).unwrap_or_else(|panic| {
+        let message = panic.downcast_ref::<&str>().copied()
+            .or_else(|| panic.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("unknown cause");
+        Err(anyhow::anyhow!("transfer panicked: {}", message))
+    })
+}
+
+// Quote a string for a POSIX shell command line
+pub fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "'\\''"))
+}
+
+// Quote a path for a POSIX shell command line, byte for byte. The command is
+// sent as a string, so bytes that are not valid UTF-8 are produced by printf
+// on the far side instead.
+pub fn shell_quote_path(path: &std::path::Path) -> String {
+    let mut quoted = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        if !chunk.valid().is_empty() {
+            quoted.push_str(&shell_quote(chunk.valid()));
+        }
+        if !chunk.invalid().is_empty() {
+            let escaped: String = chunk.invalid().iter().map(|byte| format!("\\{:03o}", byte)).collect();
+            quoted.push_str(&format!("\"$(printf '{}')\"", escaped));
+        }
+    }
+    if quoted.is_empty() {
+        quoted.push_str("''");
+    }
+    quoted
+}
+
+// A path from the raw bytes a remote command printed
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> std::path::PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> std::path::PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+// Writer adapter counting the bytes that reach the inner writer
+pub struct CountingWriter<'a, W: std::io::Write> {
+    inner: W,
+    count: &'a std::cell::Cell<u64>,
+}
+
+impl<'a, W: std::io::Write> CountingWriter<'a, W> {
+    pub fn new(inner: W, count: &'a std::cell::Cell<u64>) -> Self {
+        CountingWriter { inner, count }
+    }
+}
+
+impl<W: std::io::Write> std::io::Write for CountingWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}